@@ -0,0 +1,397 @@
+use crate::config::ServerConfig;
+use crate::types::ConnectionInfo;
+
+/// IRCv3 capabilities that a client can enable with CAP REQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Capability {
+    Batch,
+    ChgHost,
+    /// the replies to the commands tagged with a label carry the label, batched when there are
+    /// several (the client has to enable batch as well)
+    LabeledResponse,
+    /// all the channel prefixes of the members are shown in NAMES and WHO, not only the highest
+    MultiPrefix,
+    /// draft/resume-0.5: a client can take over its previous session with RESUME
+    Resume,
+    /// the messages sent to the client are tagged with the time at which the server sent them
+    ServerTime,
+    Sts,
+}
+
+/// Description of a capability in the registry.
+struct CapabilitySpec {
+    capability: Capability,
+    name: &'static str,
+    /// some capabilities are only informative and cannot be enabled
+    requestable: bool,
+    /// whether the capability is advertised to the client, and with which value
+    offer: fn(&ServerConfig, &ConnectionInfo) -> Option<Option<String>>,
+}
+
+fn always(_: &ServerConfig, _: &ConnectionInfo) -> Option<Option<String>> {
+    Some(None)
+}
+
+/// The STS policy gives the secure port to the plaintext connections, and its duration to the
+/// TLS ones.
+fn sts_offer(config: &ServerConfig, connection_info: &ConnectionInfo) -> Option<Option<String>> {
+    let sts_policy = config.sts_policy.as_ref()?;
+    let value = if connection_info.is_tls {
+        format!("duration={}", sts_policy.duration.as_secs())
+    } else {
+        format!("port={}", sts_policy.port)
+    };
+    Some(Some(value))
+}
+
+/// All the capabilities supported by the server, in the order of CAP LS.
+const REGISTRY: &[CapabilitySpec] = &[
+    CapabilitySpec {
+        capability: Capability::Batch,
+        name: "batch",
+        requestable: true,
+        offer: always,
+    },
+    CapabilitySpec {
+        capability: Capability::ChgHost,
+        name: "chghost",
+        requestable: true,
+        offer: always,
+    },
+    CapabilitySpec {
+        capability: Capability::LabeledResponse,
+        name: "labeled-response",
+        requestable: true,
+        offer: always,
+    },
+    CapabilitySpec {
+        capability: Capability::MultiPrefix,
+        name: "multi-prefix",
+        requestable: true,
+        offer: always,
+    },
+    CapabilitySpec {
+        capability: Capability::Resume,
+        name: "draft/resume-0.5",
+        requestable: true,
+        offer: always,
+    },
+    CapabilitySpec {
+        capability: Capability::ServerTime,
+        name: "server-time",
+        requestable: true,
+        offer: always,
+    },
+    CapabilitySpec {
+        capability: Capability::Sts,
+        name: "sts",
+        requestable: false,
+        offer: sts_offer,
+    },
+];
+
+impl Capability {
+    fn spec(self) -> Option<&'static CapabilitySpec> {
+        REGISTRY.iter().find(|spec| spec.capability == self)
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        self.spec().map_or("", |spec| spec.name)
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        REGISTRY
+            .iter()
+            .find(|spec| spec.name == name)
+            .map(|spec| spec.capability)
+    }
+
+    fn is_requestable(self) -> bool {
+        self.spec().is_some_and(|spec| spec.requestable)
+    }
+}
+
+/// Capabilities advertised to a client, with their optional value.
+pub(crate) type CapOffer = [(Capability, Option<String>)];
+
+/// Capabilities that the registry advertises to a connection.
+pub(crate) fn capability_offer(
+    config: &ServerConfig,
+    connection_info: &ConnectionInfo,
+) -> Vec<(Capability, Option<String>)> {
+    REGISTRY
+        .iter()
+        .filter_map(|spec| {
+            let value = (spec.offer)(config, connection_info)?;
+            Some((spec.capability, value))
+        })
+        .collect()
+}
+
+/// Longest list of capabilities in a CAP reply, so that the line fits in 512 bytes with the
+/// server name and the nickname.
+const MAX_CAPABILITIES_LENGTH: usize = 400;
+
+/// Splits the names into lines for the clients of CAP 302 and above, the others only understand
+/// a single line.
+fn split_lines(names: Vec<String>, multiline: bool, max_length: usize) -> Vec<String> {
+    if !multiline {
+        return vec![names.join(" ")];
+    }
+    let mut lines = vec![String::new()];
+    for name in names {
+        let Some(line) = lines.last_mut() else {
+            break;
+        };
+        if line.is_empty() {
+            line.push_str(&name);
+        } else if line.len() + 1 + name.len() <= max_length {
+            line.push(' ');
+            line.push_str(&name);
+        } else {
+            lines.push(name);
+        }
+    }
+    lines
+}
+
+/// Capabilities enabled by a client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Capabilities {
+    enabled: Vec<Capability>,
+    /// version given with CAP LS, 0 without one
+    version: u32,
+}
+
+pub(crate) enum CapResponse {
+    /// the lines are all sent, with a continuation marker on all but the last one
+    Reply {
+        subcommand: &'static str,
+        lines: Vec<String>,
+    },
+    End,
+    InvalidCommand,
+}
+
+impl Capabilities {
+    pub(crate) fn has(&self, capability: Capability) -> bool {
+        self.enabled.contains(&capability)
+    }
+
+    fn enable(&mut self, capability: Capability) {
+        if !self.has(capability) {
+            self.enabled.push(capability);
+        }
+    }
+
+    fn disable(&mut self, capability: Capability) {
+        self.enabled.retain(|&c| c != capability);
+    }
+
+    /// Handles a CAP subcommand. The requests are atomic: if one of the requested capabilities
+    /// is unknown, none is changed.
+    pub(crate) fn negotiate(
+        &mut self,
+        subcommand: &str,
+        param: Option<&str>,
+        offer: &CapOffer,
+    ) -> CapResponse {
+        match subcommand.to_ascii_uppercase().as_str() {
+            "LS" => {
+                if let Some(version) = param.and_then(|version| version.parse::<u32>().ok()) {
+                    self.version = self.version.max(version);
+                }
+                // values are only understood by clients using CAP LS 302 or above,
+                // the capabilities that need one are hidden from the others
+                let with_values = self.version >= 302;
+                let names = offer
+                    .iter()
+                    .filter_map(|(capability, value)| match value {
+                        Some(value) if with_values => {
+                            Some(format!("{}={value}", capability.name()))
+                        }
+                        Some(_) => None,
+                        None => Some(capability.name().to_string()),
+                    })
+                    .collect::<Vec<_>>();
+                CapResponse::Reply {
+                    subcommand: "LS",
+                    lines: split_lines(names, with_values, MAX_CAPABILITIES_LENGTH),
+                }
+            }
+            "LIST" => {
+                let names = self.enabled.iter().map(|c| c.name().to_string()).collect();
+                CapResponse::Reply {
+                    subcommand: "LIST",
+                    lines: split_lines(names, self.version >= 302, MAX_CAPABILITIES_LENGTH),
+                }
+            }
+            "REQ" => {
+                let requested = param.unwrap_or_default();
+                let is_offered = |c: &Capability| {
+                    c.is_requestable() && offer.iter().any(|(offered, _)| offered == c)
+                };
+                let changes = requested
+                    .split_whitespace()
+                    .map(|name| match name.strip_prefix('-') {
+                        Some(name) => Capability::from_name(name).map(|c| (c, false)),
+                        None => Capability::from_name(name).map(|c| (c, true)),
+                    })
+                    .map(|change| change.filter(|(c, _)| is_offered(c)))
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|changes| !changes.is_empty());
+
+                // an empty request is rejected as well
+                let Some(changes) = changes else {
+                    return CapResponse::Reply {
+                        subcommand: "NAK",
+                        lines: vec![requested.to_string()],
+                    };
+                };
+                for (capability, enable) in changes {
+                    if enable {
+                        self.enable(capability);
+                    } else {
+                        self.disable(capability);
+                    }
+                }
+                CapResponse::Reply {
+                    subcommand: "ACK",
+                    lines: vec![requested.to_string()],
+                }
+            }
+            "END" => CapResponse::End,
+            _ => CapResponse::InvalidCommand,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)] // fine in tests
+    use super::*;
+
+    fn reply(response: CapResponse) -> (&'static str, String) {
+        match response {
+            CapResponse::Reply { subcommand, lines } => (subcommand, lines.join("\n")),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offer = [(Capability::ChgHost, None)];
+        let mut caps = Capabilities::default();
+        assert_eq!(
+            reply(caps.negotiate("ls", None, &offer)),
+            ("LS", "chghost".into())
+        );
+
+        assert_eq!(
+            reply(caps.negotiate("REQ", Some("chghost unknown"), &offer)),
+            ("NAK", "chghost unknown".into())
+        );
+        assert!(!caps.has(Capability::ChgHost));
+        assert_eq!(
+            reply(caps.negotiate("REQ", Some(""), &offer)),
+            ("NAK", "".into())
+        );
+        assert_eq!(
+            reply(caps.negotiate("REQ", None, &offer)),
+            ("NAK", "".into())
+        );
+
+        assert_eq!(
+            reply(caps.negotiate("REQ", Some("chghost"), &offer)),
+            ("ACK", "chghost".into())
+        );
+        assert!(caps.has(Capability::ChgHost));
+        assert_eq!(
+            reply(caps.negotiate("LIST", None, &offer)),
+            ("LIST", "chghost".into())
+        );
+
+        caps.negotiate("REQ", Some("-chghost"), &offer);
+        assert!(!caps.has(Capability::ChgHost));
+
+        assert!(matches!(
+            caps.negotiate("END", None, &offer),
+            CapResponse::End
+        ));
+        assert!(matches!(
+            caps.negotiate("FOO", None, &offer),
+            CapResponse::InvalidCommand
+        ));
+    }
+
+    #[test]
+    fn test_negotiate_values() {
+        let offer = [
+            (Capability::ChgHost, None),
+            (Capability::Sts, Some("port=6697".to_string())),
+        ];
+        let mut caps = Capabilities::default();
+        assert_eq!(
+            reply(caps.negotiate("LS", None, &offer)),
+            ("LS", "chghost".into())
+        );
+        assert_eq!(
+            reply(caps.negotiate("LS", Some("302"), &offer)),
+            ("LS", "chghost sts=port=6697".into())
+        );
+        assert_eq!(
+            reply(caps.negotiate("REQ", Some("sts"), &offer)),
+            ("NAK", "sts".into())
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut config = ServerConfig::new("srv", &Default::default(), None, None, None);
+        let connection_info = ConnectionInfo::default();
+        let names = |offer: Vec<(Capability, Option<String>)>| {
+            offer
+                .into_iter()
+                .map(|(capability, value)| (capability.name(), value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(capability_offer(&config, &connection_info)),
+            vec![
+                ("batch", None),
+                ("chghost", None),
+                ("labeled-response", None),
+                ("multi-prefix", None),
+                ("draft/resume-0.5", None),
+                ("server-time", None),
+            ]
+        );
+
+        config.set_sts_policy(Some(crate::StsPolicy {
+            port: 6697,
+            duration: std::time::Duration::from_secs(60),
+        }));
+        let offer = capability_offer(&config, &connection_info);
+        assert_eq!(
+            offer.last(),
+            Some(&(Capability::Sts, Some("port=6697".into())))
+        );
+        assert_eq!(Capability::from_name("sts"), Some(Capability::Sts));
+        assert!(!Capability::Sts.is_requestable());
+    }
+
+    #[test]
+    fn test_multiline_replies() {
+        let names = ["aaa", "bb", "cc", "d"].map(String::from).to_vec();
+        assert_eq!(split_lines(names.clone(), false, 5), vec!["aaa bb cc d"]);
+        assert_eq!(split_lines(names, true, 5), vec!["aaa", "bb cc", "d"]);
+        assert_eq!(split_lines(vec![], true, 5), vec![""]);
+
+        // the version of CAP LS is kept for the next replies
+        let offer = [(Capability::ChgHost, None)];
+        let mut caps = Capabilities::default();
+        caps.negotiate("LS", Some("302"), &offer);
+        caps.negotiate("LS", None, &offer);
+        assert_eq!(caps.version, 302);
+    }
+}
@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Translations of the texts generated by the server (notices, error descriptions and welcome
+/// text).
+///
+/// The translations are keyed by the English templates, such as `K-line added for {mask}`, and
+/// can use the same `{variables}` as the templates. Texts without translation stay in English.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    language: String,
+    translations: HashMap<String, String>,
+}
+
+impl Catalog {
+    pub fn new(language: &str, translations: HashMap<String, String>) -> Self {
+        Self {
+            language: language.to_string(),
+            translations,
+        }
+    }
+
+    /// Language of the translations, empty for the built-in English texts.
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub(crate) fn translate<'a>(&'a self, template: &'a str) -> &'a str {
+        self.translations
+            .get(template)
+            .map(String::as_str)
+            .unwrap_or(template)
+    }
+
+    /// Translates the template and replaces its variables with their values.
+    pub(crate) fn format<'a>(
+        &'a self,
+        template: &'a str,
+        variables: &[(&str, &str)],
+    ) -> Cow<'a, str> {
+        substitute(self.translate(template), variables)
+    }
+}
+
+/// Replaces the `{name}` of the template by the value of the variable `name`. Unknown variables
+/// are left untouched, and the values are not themselves substituted.
+pub(crate) fn substitute<'a>(template: &'a str, variables: &[(&str, &str)]) -> Cow<'a, str> {
+    if variables.is_empty() || !template.contains('{') {
+        return Cow::Borrowed(template);
+    }
+
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let (before, after) = rest.split_at(start);
+        out.push_str(before);
+        let value = after.find('}').and_then(|end| {
+            let name = after.get(1..end)?;
+            let (_, value) = variables.iter().find(|(n, _)| *n == name)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                out.push_str(value);
+                rest = after.get(end + 1..).unwrap_or_default();
+            }
+            None => {
+                out.push('{');
+                rest = after.get(1..).unwrap_or_default();
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let catalog = Catalog::new(
+            "fr",
+            [(
+                "K-line added for {mask}".to_string(),
+                "K-line ajoutée pour {mask}".to_string(),
+            )]
+            .into(),
+        );
+        assert_eq!(catalog.language(), "fr");
+        assert_eq!(
+            catalog.format("K-line added for {mask}", &[("mask", "*@1.2.3.4")]),
+            "K-line ajoutée pour *@1.2.3.4"
+        );
+        assert_eq!(
+            catalog.format("No K-line for {mask}", &[("mask", "{mask}")]),
+            "No K-line for {mask}"
+        );
+        assert_eq!(catalog.translate("Unknown command"), "Unknown command");
+
+        assert_eq!(
+            substitute("{a}{b} {c} {", &[("a", "1"), ("b", "}")]),
+            "1} {c} {"
+        );
+        assert_eq!(substitute("{a", &[("a", "1")]), "{a");
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use unicase::UniCase;
 
 #[derive(Debug, Default, PartialEq)]
@@ -15,13 +17,25 @@ pub(crate) enum ListOperation {
     Sup,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub(crate) struct ListOption {
     pub filter: ListFilter,
     pub operation: ListOperation,
     pub number: u64,
 }
 
+#[derive(Debug)]
+pub(crate) enum SpamFilterCommand<'m> {
+    Add {
+        action: &'m str,
+        pattern: &'m str,
+        reason: Option<&'m [u8]>,
+    },
+    Del(&'m str),
+    List,
+    Unknown(&'m str),
+}
+
 #[derive(Debug)]
 pub(crate) enum Message<'m> {
     Nick(&'m str),
@@ -29,34 +43,78 @@ pub(crate) enum Message<'m> {
     Pass(&'m [u8]),
     Ping(&'m [u8]),
     Pong(&'m [u8]),
-    Join(Vec<&'m str>),
+    Join(Vec<&'m str>, Vec<&'m str>),
     Names(Vec<&'m str>),
     GetTopic(&'m str),
     SetTopic(&'m str, &'m [u8]),
     AskModeChannel(&'m str),
     ChangeModeChannel(&'m str, &'m str, Option<&'m str>),
+    /// changes of the list modes (+b and +I), which can be combined, such as `+bI-b`
+    ChangeListModesChannel(&'m str, &'m str, Vec<&'m str>),
+    AskModeUser(&'m str),
+    ChangeModeUser(&'m str, &'m str),
     PrivMsg(&'m str, &'m [u8]),
     Notice(&'m str, &'m [u8]),
     Part(Vec<&'m str>, Option<&'m [u8]>),
     List(Option<Vec<String>>, Option<Vec<ListOption>>),
     #[allow(clippy::upper_case_acronyms)]
     MOTD(),
+    Rules(),
+    Admin(),
+    Links(),
+    /// MAP, USERS or SUMMON, which some clients probe
+    Unsupported(&'m str),
+    Version(),
     Away(Option<&'m [u8]>),
     Userhost(Vec<&'m str>),
+    Userip(Vec<&'m str>),
     Whois(&'m str),
+    /// nickname, and the maximum number of entries
+    Whowas(&'m str, Option<usize>),
     Who(&'m str),
     Lusers(),
+    Stats(&'m str),
+    Oper(&'m str, &'m [u8]),
+    Invite(&'m str, &'m str),
+    ListInvites(),
+    Kline(Option<Duration>, &'m str, Option<&'m [u8]>),
+    Unkline(&'m str),
+    SpamFilter(SpamFilterCommand<'m>),
+    Cap(&'m str, Option<&'m str>),
+    ChgHost(&'m str, &'m str),
+    ResetChan(&'m str, Option<&'m str>),
+    /// channel whose recent operator actions (+H) are listed
+    ActionLog(&'m str),
+    /// token of the session to resume, the timestamp of the last message seen is ignored since
+    /// there is no history
+    Resume(&'m str),
     Quit(Option<&'m [u8]>),
     Unknown(&'m str),
 }
 
 pub(crate) enum MessageDecodingError<'m> {
-    CannotDecodeUtf8 { command: &'m [u8] },
-    NotEnoughParameters { command: &'m str },
-    CannotParseInteger { command: &'m [u8] },
+    CannotDecodeUtf8 {
+        command: &'m [u8],
+    },
+    /// a parameter that must be valid UTF-8 is not, depending on the UTF8ONLY setting the message
+    /// is rejected or its parameters are transliterated
+    InvalidUtf8 {
+        command: &'m str,
+        parameter: &'static str,
+        parameters: Vec<&'m [u8]>,
+    },
+    NotEnoughParameters {
+        command: &'m str,
+    },
     NoNicknameGiven {},
     NoTextToSend {},
-    NoRecipient { command: &'m str },
+    NoRecipient {
+        command: &'m str,
+    },
+    TooManyTargets {
+        target: &'m [u8],
+        max: usize,
+    },
     SilentError {},
 }
 
@@ -66,11 +124,38 @@ fn str2<'a, 'm>(command: &'m str, s: &'a [u8]) -> Result<&'a str, MessageDecodin
     })
 }
 
-fn opt2<'m, 'a: 'm>(
-    command: &'a str,
-    opt: Option<&'a [u8]>,
-) -> Result<&'a [u8], MessageDecodingError<'m>> {
-    opt.ok_or(MessageDecodingError::NotEnoughParameters { command })
+fn utf8_param<'m>(
+    message: &cirque_parser::Message<'m>,
+    command: &'m str,
+    parameter: &'static str,
+    s: &'m [u8],
+) -> Result<&'m str, MessageDecodingError<'m>> {
+    std::str::from_utf8(s).map_err(|_| MessageDecodingError::InvalidUtf8 {
+        command,
+        parameter,
+        parameters: message.parameters().to_vec(),
+    })
+}
+
+/// Decodes the parameters that are not valid UTF-8 as Latin-1, the most common legacy encoding.
+pub(crate) fn transliterate_parameters(parameters: &[&[u8]]) -> Vec<Vec<u8>> {
+    parameters
+        .iter()
+        .map(|&p| match std::str::from_utf8(p) {
+            Ok(_) => p.to_vec(),
+            Err(_) => p
+                .iter()
+                .map(|&b| b as char)
+                .collect::<String>()
+                .into_bytes(),
+        })
+        .collect()
+}
+
+/// Parameter at an index below the minimum number of parameters of the command, the registry
+/// ensures that it exists.
+fn param<'m>(message: &cirque_parser::Message<'m>, index: usize) -> &'m [u8] {
+    message.parameters().get(index).copied().unwrap_or_default()
 }
 
 fn optstr<'m, 'a: 'm>(
@@ -90,11 +175,9 @@ fn handle_user<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let user = optstr(command, message.first_parameter())?;
-    let params = message.parameters();
-    let Some(realname) = params.get(3) else {
-        return Err(MessageDecodingError::NotEnoughParameters { command });
-    };
+    let user = utf8_param(&message, command, "username", param(&message, 0))?;
+    let realname = param(&message, 3);
+    utf8_param(&message, command, "realname", realname)?;
     if user.is_empty() || realname.is_empty() {
         return Err(MessageDecodingError::NotEnoughParameters { command });
     }
@@ -108,7 +191,7 @@ fn handle_nick<'m>(
     let nick = message
         .first_parameter()
         .ok_or(MessageDecodingError::NoNicknameGiven {})?;
-    let nick = str2(command, nick)?;
+    let nick = utf8_param(&message, command, "nickname", nick)?;
     if nick.is_empty() {
         return Err(MessageDecodingError::NoNicknameGiven {});
     }
@@ -117,48 +200,48 @@ fn handle_nick<'m>(
 
 fn handle_pass<'m>(
     message: cirque_parser::Message<'m>,
-    command: &'m str,
+    _command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let pass = message
-        .first_parameter()
-        .ok_or(MessageDecodingError::NotEnoughParameters { command })?;
-    Ok(Message::Pass(pass))
+    Ok(Message::Pass(param(&message, 0)))
 }
 
 fn handle_ping<'m>(
     message: cirque_parser::Message<'m>,
-    command: &'m str,
+    _command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    Ok(Message::Ping(opt2(command, message.first_parameter())?))
+    Ok(Message::Ping(param(&message, 0)))
 }
 
 fn handle_pong<'m>(
     message: cirque_parser::Message<'m>,
-    command: &'m str,
+    _command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    Ok(Message::Pong(opt2(command, message.first_parameter())?))
+    Ok(Message::Pong(param(&message, 0)))
 }
 
 fn handle_join<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let channels = message
-        .first_parameter()
-        .ok_or(MessageDecodingError::NotEnoughParameters { command })?
+    let channels = param(&message, 0)
         .split(|&c| c == b',')
-        .flat_map(|s| str2(command, s))
-        .collect::<Vec<_>>();
-    Ok(Message::Join(channels))
+        .map(|s| utf8_param(&message, command, "channel", s))
+        .collect::<Result<Vec<_>, _>>()?;
+    let keys = match message.parameters().get(1) {
+        Some(keys) => keys
+            .split(|&c| c == b',')
+            .map(|s| utf8_param(&message, command, "key", s))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![],
+    };
+    Ok(Message::Join(channels, keys))
 }
 
 fn handle_names<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let channels = message
-        .first_parameter()
-        .ok_or(MessageDecodingError::NotEnoughParameters { command })?
+    let channels = param(&message, 0)
         .split(|&c| c == b',')
         .flat_map(|s| str2(command, s))
         .collect::<Vec<_>>();
@@ -169,10 +252,13 @@ fn handle_topic<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let target = optstr(command, message.first_parameter())?;
+    let target = utf8_param(&message, command, "channel", param(&message, 0))?;
     let params = message.parameters();
     let msg = match params.get(1) {
-        Some(content) => Message::SetTopic(target, content),
+        Some(content) => {
+            utf8_param(&message, command, "topic", content)?;
+            Message::SetTopic(target, content)
+        }
         None => Message::GetTopic(target),
     };
     Ok(msg)
@@ -182,15 +268,29 @@ fn handle_mode<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let target = optstr(command, message.first_parameter())?;
+    let target = str2(command, param(&message, 0))?;
+    let params = message.parameters();
 
-    // for now we will assume that the target is a channel
     if !target.starts_with('#') {
-        return Err(MessageDecodingError::NoRecipient { command });
+        return if let Some(change) = params.get(1) {
+            Ok(Message::ChangeModeUser(target, str2(command, change)?))
+        } else {
+            Ok(Message::AskModeUser(target))
+        };
     }
 
-    let params = message.parameters();
     if let Some(change) = params.get(1) {
+        let is_list_change = change.iter().all(|c| b"+-bI".contains(c));
+        if is_list_change && params.len() > 2 {
+            let modechars = str2(command, change)?;
+            let masks = params
+                .iter()
+                .skip(2)
+                .map(|param| str2(command, param))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(Message::ChangeListModesChannel(target, modechars, masks));
+        }
+
         let param = if let Some(param) = params.get(2) {
             Some(str2(command, param)?)
         } else {
@@ -233,9 +333,7 @@ fn handle_part<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let channels = message
-        .first_parameter()
-        .ok_or(MessageDecodingError::NotEnoughParameters { command })?
+    let channels = param(&message, 0)
         .split(|&c| c == b',')
         .flat_map(|s| str2(command, s))
         .collect::<Vec<_>>();
@@ -244,90 +342,110 @@ fn handle_part<'m>(
     Ok(Message::Part(channels, reason))
 }
 
+/// Parses an ELIST condition such as `>5`, `C<60` or `T>10`, without a letter it filters on the
+/// number of users.
+fn parse_list_option(condition: &[u8]) -> Option<ListOption> {
+    let (filter, rest) = match condition.split_first()? {
+        (b'C', rest) => (ListFilter::ChannelCreation, rest),
+        (b'T', rest) => (ListFilter::TopicUpdate, rest),
+        (b'U', rest) => (ListFilter::UserNumber, rest),
+        _ => (ListFilter::UserNumber, condition),
+    };
+    let (operation, number) = match rest.split_first()? {
+        (b'<', number) => (ListOperation::Inf, number),
+        (b'>', number) => (ListOperation::Sup, number),
+        _ => return None,
+    };
+    let number = std::str::from_utf8(number).ok()?.parse().ok()?;
+    Some(ListOption {
+        filter,
+        operation,
+        number,
+    })
+}
+
+fn parse_list_options(conditions: &[u8]) -> Option<Vec<ListOption>> {
+    conditions
+        .split(|&c| c == b',')
+        .map(parse_list_option)
+        .collect()
+}
+
 fn handle_list<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let (channels, start_index) = if let Some(first_parameter) = message.first_parameter() {
-        let chans = first_parameter
+    let params = message.parameters();
+
+    // the conditions can be given without a list of channels
+    if let Some(list_options) = params.first().and_then(|p| parse_list_options(p)) {
+        return Ok(Message::List(None, Some(list_options)));
+    }
+
+    let channels = params.first().map(|first_parameter| {
+        first_parameter
             .split(|&c| c == b',')
             .flat_map(|s| String::from_utf8(s.to_owned()))
             .map(|mut s| {
                 s.make_ascii_lowercase();
                 s
             })
-            .collect::<Vec<_>>();
-        let empty = chans.is_empty();
-        (Some(chans), if empty { 0 } else { 1 })
-    } else {
-        (None, 0)
-    };
-
-    let mut list_options = Vec::new();
-    let params = message.parameters();
-    for (param_index, option) in params.get(start_index..).into_iter().flatten().enumerate() {
-        // offset because enumerate starts at 0 and not param_index
-        let param_index = param_index + start_index;
-
-        let mut list_option: ListOption = Default::default();
+            .collect::<Vec<_>>()
+    });
+    let list_options = params
+        .get(1)
+        .map(|conditions| {
+            parse_list_options(conditions)
+                .ok_or(MessageDecodingError::NotEnoughParameters { command })
+        })
+        .transpose()?;
+    Ok(Message::List(channels, list_options))
+}
 
-        // TODO/kid: not sure this implementation is correct, the spec is not clear
+fn handle_motd<'m>(
+    _message: cirque_parser::Message<'m>,
+    _command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    // don't parse the "server" argument, we don't support multi-server setups
+    Ok(Message::MOTD())
+}
 
-        let mut index = param_index;
-        let Some(option) = option.first() else {
-            return Err(MessageDecodingError::NotEnoughParameters { command });
-        };
-        if option.is_ascii() {
-            list_option.filter = match option {
-                b'C' => ListFilter::ChannelCreation,
-                b'U' => ListFilter::UserNumber,
-                b'T' => ListFilter::TopicUpdate,
-                _ => {
-                    return Err(MessageDecodingError::NotEnoughParameters { command });
-                }
-            };
-            index += 1;
-        }
+fn handle_rules<'m>(
+    _message: cirque_parser::Message<'m>,
+    _command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    Ok(Message::Rules())
+}
 
-        let operation = message.parameters().get(param_index + index);
-        if let Some(operation) = operation {
-            list_option.operation = match *operation {
-                b"<" => ListOperation::Inf,
-                b">" => ListOperation::Sup,
-                _ => return Err(MessageDecodingError::NotEnoughParameters { command }),
-            };
-            index += 1;
-        }
+fn handle_admin<'m>(
+    _message: cirque_parser::Message<'m>,
+    _command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    // don't parse the "server" argument, we don't support multi-server setups
+    Ok(Message::Admin())
+}
 
-        let number = message.parameters().get(param_index + index);
-        if let Some(number) = number {
-            let count = str2(command, number)?;
-            let count =
-                count
-                    .parse::<u64>()
-                    .map_err(|_| MessageDecodingError::CannotParseInteger {
-                        command: command.as_bytes(),
-                    })?;
-            list_option.number = count;
-        }
-        list_options.push(list_option);
-    }
-    Ok(Message::List(
-        channels,
-        if list_options.is_empty() {
-            None
-        } else {
-            Some(list_options)
-        },
-    ))
+fn handle_version<'m>(
+    _message: cirque_parser::Message<'m>,
+    _command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    // don't parse the "target" argument, we don't support multi-server setups
+    Ok(Message::Version())
 }
 
-fn handle_motd<'m>(
+fn handle_links<'m>(
     _message: cirque_parser::Message<'m>,
     _command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    // don't parse the "server" argument, we don't support multi-server setups
-    Ok(Message::MOTD())
+    // don't parse the "remote server" and "server mask" arguments, all the servers are listed
+    Ok(Message::Links())
+}
+
+fn handle_unsupported<'m>(
+    _message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    Ok(Message::Unsupported(command))
 }
 
 fn handle_away<'m>(
@@ -345,18 +463,35 @@ fn handle_userhost<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    parse_userhost_nicknames(message, command).map(Message::Userhost)
+}
+
+fn handle_userip<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    parse_userhost_nicknames(message, command).map(Message::Userip)
+}
+
+fn parse_userhost_nicknames<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Vec<&'m str>, MessageDecodingError<'m>> {
+    const MAX_NICKNAMES: usize = 5;
     let params = message.parameters();
     // up-to five nicknames, in separate parameters
-    // the first one is mandatory
-    if params.is_empty() {
-        return Err(MessageDecodingError::NotEnoughParameters { command });
+    if let Some(target) = params.get(MAX_NICKNAMES) {
+        return Err(MessageDecodingError::TooManyTargets {
+            target,
+            max: MAX_NICKNAMES,
+        });
     }
     let mut nicknames = vec![];
-    for p in params.iter().take(5) {
+    for p in params {
         let nick = str2(command, p)?;
         nicknames.push(nick);
     }
-    Ok(Message::Userhost(nicknames))
+    Ok(nicknames)
 }
 
 fn handle_whois<'m>(
@@ -364,19 +499,33 @@ fn handle_whois<'m>(
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
     let params = message.parameters();
-    let nickname = if let Some(p) = params.get(1) {
-        str2(command, p)?
-    } else {
-        optstr(command, message.first_parameter())?
-    };
+    // the first parameter is the server when there are two
+    let nickname = str2(
+        command,
+        params.get(1).copied().unwrap_or(param(&message, 0)),
+    )?;
     Ok(Message::Whois(nickname))
 }
 
+fn handle_whowas<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let nickname = str2(command, param(&message, 0))?;
+    // a count that is not positive means all the entries
+    let count = message
+        .parameters()
+        .get(1)
+        .and_then(|count| std::str::from_utf8(count).ok()?.parse::<usize>().ok())
+        .filter(|&count| count > 0);
+    Ok(Message::Whowas(nickname, count))
+}
+
 fn handle_who<'m>(
     message: cirque_parser::Message<'m>,
     command: &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>> {
-    let mask = optstr(command, message.first_parameter())?;
+    let mask = str2(command, param(&message, 0))?;
     Ok(Message::Who(mask))
 }
 
@@ -387,6 +536,169 @@ fn handle_lusers<'m>(
     Ok(Message::Lusers())
 }
 
+fn handle_stats<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let query = str2(command, param(&message, 0))?;
+    Ok(Message::Stats(query))
+}
+
+fn handle_oper<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let name = str2(command, param(&message, 0))?;
+    let password = param(&message, 1);
+    Ok(Message::Oper(name, password))
+}
+
+fn handle_invite<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let params = message.parameters();
+    match (params.first(), params.get(1)) {
+        (None, _) => Ok(Message::ListInvites()),
+        (Some(nickname), Some(channel)) => Ok(Message::Invite(
+            str2(command, nickname)?,
+            str2(command, channel)?,
+        )),
+        (Some(_), None) => Err(MessageDecodingError::NotEnoughParameters { command }),
+    }
+}
+
+/// Parses durations like "1d", "2h30m" or "45s". A number without unit is a number of minutes.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    if let Ok(minutes) = s.parse::<u64>() {
+        return Some(Duration::from_secs(minutes.checked_mul(60)?));
+    }
+
+    let mut total: u64 = 0;
+    let mut number: Option<u64> = None;
+    for c in s.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            let n = number.unwrap_or(0);
+            number = Some(n.checked_mul(10)?.checked_add(u64::from(digit))?);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(number.take()?.checked_mul(unit)?)?;
+    }
+
+    if number.is_some() || total == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(total))
+}
+
+fn handle_kline<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let params = message.parameters();
+    let first = str2(command, param(&message, 0))?;
+
+    // the duration is optional
+    let (duration, params) = match (parse_duration(first), params.get(1..)) {
+        (Some(duration), Some(rest)) if !rest.is_empty() => (Some(duration), rest),
+        _ => (None, &params[..]),
+    };
+
+    let mask = optstr(command, params.first().copied())?;
+    let reason = params.get(1).copied();
+    Ok(Message::Kline(duration, mask, reason))
+}
+
+fn handle_unkline<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let mask = str2(command, param(&message, 0))?;
+    Ok(Message::Unkline(mask))
+}
+
+fn handle_spamfilter<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let params = message.parameters();
+    let Some(subcommand) = params.first() else {
+        return Ok(Message::SpamFilter(SpamFilterCommand::List));
+    };
+    let subcommand = str2(command, subcommand)?;
+    let spam_filter_command = match subcommand.to_ascii_uppercase().as_str() {
+        "ADD" => SpamFilterCommand::Add {
+            action: optstr(command, params.get(1).copied())?,
+            pattern: optstr(command, params.get(2).copied())?,
+            reason: params.get(3).copied(),
+        },
+        "DEL" => SpamFilterCommand::Del(optstr(command, params.get(1).copied())?),
+        "LIST" => SpamFilterCommand::List,
+        _ => SpamFilterCommand::Unknown(subcommand),
+    };
+    Ok(Message::SpamFilter(spam_filter_command))
+}
+
+fn handle_cap<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let subcommand = str2(command, param(&message, 0))?;
+    let param = message
+        .parameters()
+        .get(1)
+        .map(|p| str2(command, p))
+        .transpose()?;
+    Ok(Message::Cap(subcommand, param))
+}
+
+fn handle_chghost<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let nickname = str2(command, param(&message, 0))?;
+    let hostname = str2(command, param(&message, 1))?;
+    Ok(Message::ChgHost(nickname, hostname))
+}
+
+fn handle_resetchan<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let channel = str2(command, param(&message, 0))?;
+    let nickname = message
+        .parameters()
+        .get(1)
+        .map(|nickname| str2(command, nickname))
+        .transpose()?;
+    Ok(Message::ResetChan(channel, nickname))
+}
+
+fn handle_actionlog<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let channel = str2(command, param(&message, 0))?;
+    Ok(Message::ActionLog(channel))
+}
+
+fn handle_resume<'m>(
+    message: cirque_parser::Message<'m>,
+    command: &'m str,
+) -> Result<Message<'m>, MessageDecodingError<'m>> {
+    let token = str2(command, param(&message, 0))?;
+    Ok(Message::Resume(token))
+}
+
 fn handle_quit<'m>(
     message: cirque_parser::Message<'m>,
     _command: &'m str,
@@ -400,29 +712,96 @@ type Handler = for<'m> fn(
     &'m str,
 ) -> Result<Message<'m>, MessageDecodingError<'m>>;
 
-static REGISTRY: phf::Map<unicase::UniCase<&str>, Handler> = phf::phf_map! {
-    UniCase::ascii("USER") => handle_user,
-    UniCase::ascii("NICK") => handle_nick,
-    UniCase::ascii("PASS") => handle_pass,
-    UniCase::ascii("PING") => handle_ping,
-    UniCase::ascii("PONG") => handle_pong,
-    UniCase::ascii("JOIN") => handle_join,
-    UniCase::ascii("NAMES") => handle_names,
-    UniCase::ascii("TOPIC") => handle_topic,
-    UniCase::ascii("MODE") => handle_mode,
-    UniCase::ascii("PRIVMSG") => handle_privmsg,
-    UniCase::ascii("NOTICE") => handle_notice,
-    UniCase::ascii("PART") => handle_part,
-    UniCase::ascii("LIST") => handle_list,
-    UniCase::ascii("MOTD") => handle_motd,
-    UniCase::ascii("AWAY") => handle_away,
-    UniCase::ascii("USERHOST") => handle_userhost,
-    UniCase::ascii("WHOIS") => handle_whois,
-    UniCase::ascii("WHO") => handle_who,
-    UniCase::ascii("LUSERS") => handle_lusers,
-    UniCase::ascii("QUIT") => handle_quit,
+/// Entry of the command registry.
+struct CommandSpec {
+    handler: Handler,
+    /// with fewer parameters, ERR_NEEDMOREPARAMS is returned without calling the handler
+    min_parameters: usize,
+    /// the other commands receive ERR_NOTREGISTERED until the registration is complete
+    allowed_before_registration: bool,
+}
+
+impl CommandSpec {
+    const fn new(handler: Handler, min_parameters: usize) -> Self {
+        Self {
+            handler,
+            min_parameters,
+            allowed_before_registration: false,
+        }
+    }
+
+    const fn allowed_before_registration(self) -> Self {
+        Self {
+            allowed_before_registration: true,
+            ..self
+        }
+    }
+}
+
+static REGISTRY: phf::Map<unicase::UniCase<&str>, CommandSpec> = phf::phf_map! {
+    UniCase::ascii("USER") => CommandSpec::new(handle_user, 4).allowed_before_registration(),
+    UniCase::ascii("NICK") => CommandSpec::new(handle_nick, 0).allowed_before_registration(),
+    UniCase::ascii("PASS") => CommandSpec::new(handle_pass, 1).allowed_before_registration(),
+    UniCase::ascii("PING") => CommandSpec::new(handle_ping, 1).allowed_before_registration(),
+    UniCase::ascii("PONG") => CommandSpec::new(handle_pong, 1).allowed_before_registration(),
+    UniCase::ascii("JOIN") => CommandSpec::new(handle_join, 1),
+    UniCase::ascii("NAMES") => CommandSpec::new(handle_names, 1),
+    UniCase::ascii("TOPIC") => CommandSpec::new(handle_topic, 1),
+    UniCase::ascii("MODE") => CommandSpec::new(handle_mode, 1),
+    UniCase::ascii("PRIVMSG") => CommandSpec::new(handle_privmsg, 0),
+    UniCase::ascii("NOTICE") => CommandSpec::new(handle_notice, 0),
+    UniCase::ascii("PART") => CommandSpec::new(handle_part, 1),
+    UniCase::ascii("LIST") => CommandSpec::new(handle_list, 0),
+    UniCase::ascii("MOTD") => CommandSpec::new(handle_motd, 0),
+    UniCase::ascii("RULES") => CommandSpec::new(handle_rules, 0),
+    UniCase::ascii("ADMIN") => CommandSpec::new(handle_admin, 0),
+    UniCase::ascii("LINKS") => CommandSpec::new(handle_links, 0),
+    UniCase::ascii("MAP") => CommandSpec::new(handle_unsupported, 0),
+    UniCase::ascii("USERS") => CommandSpec::new(handle_unsupported, 0),
+    UniCase::ascii("SUMMON") => CommandSpec::new(handle_unsupported, 0),
+    UniCase::ascii("VERSION") => CommandSpec::new(handle_version, 0),
+    UniCase::ascii("AWAY") => CommandSpec::new(handle_away, 0),
+    UniCase::ascii("USERHOST") => CommandSpec::new(handle_userhost, 1),
+    UniCase::ascii("USERIP") => CommandSpec::new(handle_userip, 1),
+    UniCase::ascii("WHOIS") => CommandSpec::new(handle_whois, 1),
+    UniCase::ascii("WHOWAS") => CommandSpec::new(handle_whowas, 1),
+    UniCase::ascii("WHO") => CommandSpec::new(handle_who, 1),
+    UniCase::ascii("LUSERS") => CommandSpec::new(handle_lusers, 0),
+    UniCase::ascii("STATS") => CommandSpec::new(handle_stats, 1),
+    UniCase::ascii("OPER") => CommandSpec::new(handle_oper, 2),
+    UniCase::ascii("INVITE") => CommandSpec::new(handle_invite, 0),
+    UniCase::ascii("KLINE") => CommandSpec::new(handle_kline, 1),
+    UniCase::ascii("UNKLINE") => CommandSpec::new(handle_unkline, 1),
+    UniCase::ascii("SPAMFILTER") => CommandSpec::new(handle_spamfilter, 0),
+    UniCase::ascii("CAP") => CommandSpec::new(handle_cap, 1).allowed_before_registration(),
+    UniCase::ascii("CHGHOST") => CommandSpec::new(handle_chghost, 2),
+    UniCase::ascii("RESETCHAN") => CommandSpec::new(handle_resetchan, 1),
+    UniCase::ascii("ACTIONLOG") => CommandSpec::new(handle_actionlog, 1),
+    UniCase::ascii("RESUME") => CommandSpec::new(handle_resume, 1).allowed_before_registration(),
+    UniCase::ascii("QUIT") => CommandSpec::new(handle_quit, 0).allowed_before_registration(),
 };
 
+/// Longest label accepted from the clients (labeled-response).
+const MAX_LABEL_LENGTH: usize = 64;
+
+/// Value of the label tag among the raw tags of a message, kept escaped as it is sent back as is.
+pub(crate) fn label_tag(tags: &[u8]) -> Option<&str> {
+    let tags = std::str::from_utf8(tags).ok()?;
+    tags.split(';')
+        .find_map(|tag| tag.strip_prefix("label="))
+        .filter(|label| !label.is_empty() && label.len() <= MAX_LABEL_LENGTH)
+}
+
+/// Unknown commands are allowed, they receive ERR_UNKNOWNCOMMAND instead.
+pub(crate) fn is_allowed_before_registration(command: &[u8]) -> bool {
+    let Ok(command) = std::str::from_utf8(command) else {
+        return true;
+    };
+    REGISTRY
+        .get(&command.into())
+        .is_none_or(|spec| spec.allowed_before_registration)
+}
+
 impl<'m> TryFrom<cirque_parser::Message<'m>> for Message<'m> {
     type Error = MessageDecodingError<'m>;
 
@@ -431,10 +810,70 @@ impl<'m> TryFrom<cirque_parser::Message<'m>> for Message<'m> {
         let command = std::str::from_utf8(command)
             .map_err(|_| MessageDecodingError::CannotDecodeUtf8 { command })?;
 
-        let Some(handler) = REGISTRY.get(&command.into()) else {
+        let Some(spec) = REGISTRY.get(&command.into()) else {
             return Ok(Message::Unknown(command));
         };
 
-        handler(message, command)
+        if message.parameters().len() < spec.min_parameters {
+            return Err(MessageDecodingError::NotEnoughParameters { command });
+        }
+
+        (spec.handler)(message, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        label_tag, parse_duration, parse_list_options, ListFilter, ListOperation, ListOption,
+    };
+
+    #[test]
+    fn test_label_tag() {
+        assert_eq!(label_tag(b"label=123"), Some("123"));
+        assert_eq!(label_tag(b"+draft/x=1;label=a\\sb;time=0"), Some("a\\sb"));
+        assert_eq!(label_tag(b"labels=1"), None);
+        assert_eq!(label_tag(b"label="), None);
+        assert_eq!(label_tag(&[b'x'; 70]), None);
+        let long = format!("label={}", "x".repeat(65));
+        assert_eq!(label_tag(long.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("5"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("2h30m"), Some(Duration::from_secs(9000)));
+        assert_eq!(parse_duration("1w"), Some(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("1d2"), None);
+        assert_eq!(parse_duration("*@10.0.0.1"), None);
+    }
+
+    #[test]
+    fn test_parse_list_options() {
+        assert_eq!(
+            parse_list_options(b"C<60,>2"),
+            Some(vec![
+                ListOption {
+                    filter: ListFilter::ChannelCreation,
+                    operation: ListOperation::Inf,
+                    number: 60,
+                },
+                ListOption {
+                    filter: ListFilter::UserNumber,
+                    operation: ListOperation::Sup,
+                    number: 2,
+                },
+            ])
+        );
+        assert_eq!(parse_list_options(b"#chan"), None);
+        assert_eq!(parse_list_options(b"C<"), None);
+        assert_eq!(parse_list_options(b"C=5"), None);
     }
 }
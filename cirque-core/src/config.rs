@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::catalog::Catalog;
+use crate::hooks::MessageHook;
+use crate::mask::{mask_matches, IpMask};
+use crate::nickname::cure_nickname;
+use crate::reply_cache::ReplyCache;
+use crate::server_to_client::MessageContext;
+use crate::templates::Templates;
+use crate::throttle::ThrottleConfig;
+use crate::types::{ChannelMode, CompatFlags, ConnectionInfo, WelcomeConfig};
+use crate::TimeoutConfig;
+
+/// Strict Transport Security policy, advertised with the sts capability.
+///
+/// Clients connected in plaintext are told to reconnect with TLS on `port`, clients connected
+/// with TLS are told to only use TLS for this server during `duration`.
+#[derive(Debug, Clone)]
+pub struct StsPolicy {
+    pub port: u16,
+    pub duration: Duration,
+}
+
+/// Contact information returned by the ADMIN command.
+#[derive(Debug, Clone)]
+pub struct AdminInfo {
+    pub location: String,
+    pub email: String,
+}
+
+/// Server returned by the LINKS command. There is no server-to-server link, these are other
+/// servers of the same network that the clients might want to know about.
+#[derive(Debug, Clone)]
+pub struct RelatedServer {
+    pub name: String,
+    pub description: String,
+    pub address: String,
+}
+
+/// Mode given to the channels whose name matches the mask when they are created, instead of the
+/// default channel mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelModeRule {
+    /// `*` and `?` wildcards, ignoring the case (such as `#help-*`)
+    pub mask: String,
+    pub mode: ChannelMode,
+}
+
+/// Limits on the connections that did not complete their registration, against the clients that
+/// keep a connection (and its buffers) busy by sending their bytes slowly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnregisteredLimits {
+    /// Bytes that a connection can send before completing its registration.
+    pub max_bytes: Option<u64>,
+    /// Time given to complete a message once its first bytes are received.
+    pub partial_message_deadline: Option<Duration>,
+    /// Time given to complete the registration after connecting, lookups and capability
+    /// negotiation included.
+    pub registration_deadline: Option<Duration>,
+}
+
+/// Lookups about the peer of the new connections, done by the sessions while the clients register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LookupConfig {
+    /// Reverse DNS name of the IP, shown with `HostnamePolicy::Resolved`.
+    pub resolve_hostname: bool,
+    /// Ident (RFC 1413) answer, which replaces the username. Without answer, the username is
+    /// prefixed with `~`.
+    pub check_ident: bool,
+    /// Time after which a lookup is considered failed.
+    pub timeout: Duration,
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        Self {
+            resolve_hostname: false,
+            check_ident: false,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What is shown as the host of the users: in their fullspec (nick!user@host), and in the replies
+/// to WHO, WHOIS and USERHOST. Operators can still change it with CHGHOST.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HostnamePolicy {
+    /// the same constant for everyone, `hidden`
+    #[default]
+    Hidden,
+    /// an HMAC-SHA256 of the IP with the key, so that the users can be told apart without
+    /// revealing their IP (the cloak of an IP stays the same as long as the key does)
+    Cloaked { key: String },
+    /// the reverse DNS name if it is known, otherwise the IP
+    Resolved,
+    /// the IP
+    Ip,
+}
+
+impl HostnamePolicy {
+    pub(crate) fn hostname_for(&self, connection_info: &ConnectionInfo) -> String {
+        let Some(ip) = connection_info.ip else {
+            return "hidden".to_string();
+        };
+        match self {
+            HostnamePolicy::Hidden => "hidden".to_string(),
+            HostnamePolicy::Cloaked { key } => {
+                let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.as_bytes());
+                let tag = ring::hmac::sign(&key, ip.to_string().as_bytes());
+                let hash = tag.as_ref().iter().take(8).map(|b| format!("{b:02x}"));
+                format!("{}.cloak", hash.collect::<String>())
+            }
+            HostnamePolicy::Resolved => match &connection_info.resolved_hostname {
+                Some(hostname) => hostname.clone(),
+                None => ip_hostname(ip),
+            },
+            HostnamePolicy::Ip => ip_hostname(ip),
+        }
+    }
+}
+
+/// Which DCC offers (CTCP DCC requests, for file transfers and direct chats) are relayed by
+/// PRIVMSG and NOTICE. The transfers themselves do not go through the server, but they expose the
+/// IP of the users and are a common way to send malware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DccPolicy {
+    #[default]
+    Allow,
+    /// only between users sharing a channel, and to the channels from their members
+    CoMembers,
+    /// never, PRIVMSG fails with a standard reply and NOTICE is dropped silently
+    Strip,
+}
+
+/// IPv6 addresses starting with `:` would be taken as the trailing parameter of the replies.
+fn ip_hostname(ip: IpAddr) -> String {
+    let ip = ip.to_canonical().to_string();
+    match ip.starts_with(':') {
+        true => format!("0{ip}"),
+        false => ip,
+    }
+}
+
+/// Read-mostly part of the server state.
+///
+/// It is stored separately from the users and channels, and swapped atomically on modifications,
+/// so that reading the config never waits on the state lock.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub(crate) server_name: String,
+    pub(crate) welcome_config: WelcomeConfig,
+    pub(crate) password: Option<Vec<u8>>,
+    pub(crate) oper_name: Option<String>,
+    pub(crate) oper_password: Option<Vec<u8>>,
+    pub(crate) oper_hosts: Vec<String>,
+    pub(crate) motd: Option<Vec<Vec<u8>>>,
+    pub(crate) listener_motds: HashMap<String, Vec<Vec<u8>>>,
+    pub(crate) tls_motd: Option<Vec<Vec<u8>>>,
+    pub(crate) oper_only_listeners: HashMap<String, Duration>,
+    pub(crate) rules: Option<Vec<Vec<u8>>>,
+    pub(crate) reserved_nicknames: Vec<String>,
+    pub(crate) admin_info: Option<AdminInfo>,
+    pub(crate) related_servers: Vec<RelatedServer>,
+    pub(crate) default_channel_mode: ChannelMode,
+    pub(crate) channel_mode_rules: Vec<ChannelModeRule>,
+    pub(crate) throttle_config: Arc<ThrottleConfig>,
+    pub(crate) timeout_config: Option<TimeoutConfig>,
+    pub(crate) sts_policy: Option<StsPolicy>,
+    pub(crate) message_hooks: Vec<Arc<dyn MessageHook>>,
+    pub(crate) membership_coalescing: Option<Duration>,
+    pub(crate) utf8_only: bool,
+    pub(crate) dcc_policy: DccPolicy,
+    pub(crate) max_registering_users: Option<usize>,
+    pub(crate) max_registering_users_per_ip: Option<usize>,
+    pub(crate) exempt_ips: Vec<IpMask>,
+    pub(crate) unregistered_limits: UnregisteredLimits,
+    pub(crate) lookup_config: LookupConfig,
+    pub(crate) slow_command_threshold: Option<Duration>,
+    pub(crate) nickname_grace_period: Option<Duration>,
+    pub(crate) max_channels_per_user: Option<usize>,
+    pub(crate) hostname_policy: HostnamePolicy,
+    pub(crate) reply_cache: ReplyCache,
+    pub(crate) message_context: MessageContext,
+}
+
+impl ServerConfig {
+    pub(crate) fn new(
+        server_name: &str,
+        welcome_config: &WelcomeConfig,
+        motd: Option<Vec<Vec<u8>>>,
+        password: Option<Vec<u8>>,
+        timeout_config: Option<TimeoutConfig>,
+    ) -> Self {
+        Self {
+            server_name: server_name.to_owned(),
+            welcome_config: welcome_config.to_owned(),
+            password,
+            oper_name: None,
+            oper_password: None,
+            oper_hosts: vec![],
+            motd,
+            listener_motds: HashMap::new(),
+            tls_motd: None,
+            oper_only_listeners: HashMap::new(),
+            rules: None,
+            reserved_nicknames: vec![],
+            admin_info: None,
+            related_servers: vec![],
+            default_channel_mode: Default::default(),
+            channel_mode_rules: vec![],
+            throttle_config: Default::default(),
+            timeout_config,
+            sts_policy: None,
+            message_hooks: vec![],
+            membership_coalescing: None,
+            utf8_only: false,
+            dcc_policy: DccPolicy::Allow,
+            max_registering_users: None,
+            max_registering_users_per_ip: None,
+            exempt_ips: vec![],
+            unregistered_limits: Default::default(),
+            lookup_config: Default::default(),
+            slow_command_threshold: None,
+            nickname_grace_period: None,
+            max_channels_per_user: None,
+            hostname_policy: HostnamePolicy::Hidden,
+            reply_cache: Default::default(),
+            message_context: MessageContext {
+                server_name: server_name.to_string(),
+                network_name: None,
+                catalog: Default::default(),
+                templates: Default::default(),
+                compat: welcome_config.compat,
+            },
+        }
+    }
+
+    pub fn set_server_name(&mut self, server_name: &str) {
+        self.server_name = server_name.to_string();
+        self.message_context.server_name = server_name.to_string();
+    }
+
+    /// Applies to the users registering after the change.
+    pub fn set_hostname_policy(&mut self, hostname_policy: HostnamePolicy) {
+        self.hostname_policy = hostname_policy;
+    }
+
+    pub fn set_compat_flags(&mut self, compat: CompatFlags) {
+        self.welcome_config.compat = compat;
+        self.message_context.compat = compat;
+    }
+
+    /// Name of the network in the templates, the server name by default.
+    pub fn set_network_name(&mut self, network_name: Option<&str>) {
+        self.message_context.network_name = network_name.map(|n| n.to_string());
+    }
+
+    /// Texts of the welcome burst and of the disconnections.
+    pub fn set_templates(&mut self, templates: Templates) {
+        self.message_context.templates = Arc::new(templates);
+    }
+
+    /// Translations of the texts generated by the server, English by default.
+    pub fn set_catalog(&mut self, catalog: Catalog) {
+        self.message_context.catalog = Arc::new(catalog);
+    }
+
+    pub fn set_password(&mut self, password: Option<&[u8]>) {
+        self.password = password.map(|s| s.into());
+    }
+
+    /// Name expected by the OPER command. When unset, any name is accepted.
+    pub fn set_oper_name(&mut self, oper_name: Option<&str>) {
+        self.oper_name = oper_name.map(|s| s.into());
+    }
+
+    /// Password for the OPER command. When unset, no one can become operator.
+    pub fn set_oper_password(&mut self, oper_password: Option<&[u8]>) {
+        self.oper_password = oper_password.map(|s| s.into());
+    }
+
+    /// Masks (such as "*@192.168.1.*") matched against the username and IP of the users asking
+    /// for OPER. When empty, OPER is allowed from anywhere.
+    pub fn set_oper_hosts(&mut self, oper_hosts: Vec<String>) {
+        self.oper_hosts = oper_hosts;
+    }
+
+    pub fn set_motd(&mut self, motd: Option<Vec<Vec<u8>>>) {
+        self.motd = motd;
+    }
+
+    /// MOTD overrides for the connections accepted by the named listeners.
+    pub fn set_listener_motds(&mut self, listener_motds: HashMap<String, Vec<Vec<u8>>>) {
+        self.listener_motds = listener_motds;
+    }
+
+    /// MOTD override for the connections using TLS, unless their listener has its own.
+    pub fn set_tls_motd(&mut self, tls_motd: Option<Vec<Vec<u8>>>) {
+        self.tls_motd = tls_motd;
+    }
+
+    /// The users connecting through these listeners have to become operators within the given
+    /// delay after their connection, otherwise they are disconnected.
+    /// The delays are checked during the maintenance, so they are not precise.
+    pub fn set_oper_only_listeners(&mut self, oper_only_listeners: HashMap<String, Duration>) {
+        self.oper_only_listeners = oper_only_listeners;
+    }
+
+    pub(crate) fn oper_deadline_for(&self, connection_info: &ConnectionInfo) -> Option<Duration> {
+        let listener = connection_info.listener.as_ref()?;
+        self.oper_only_listeners.get(listener).copied()
+    }
+
+    pub(crate) fn motd_for(&self, connection_info: &ConnectionInfo) -> Option<&[Vec<u8>]> {
+        let listener_motd = connection_info
+            .listener
+            .as_ref()
+            .and_then(|listener| self.listener_motds.get(listener));
+        let tls_motd = self.tls_motd.as_ref().filter(|_| connection_info.is_tls);
+        listener_motd
+            .or(tls_motd)
+            .or(self.motd.as_ref())
+            .map(|motd| motd.as_slice())
+    }
+
+    /// Lines returned by the RULES command.
+    pub fn set_rules(&mut self, rules: Option<Vec<Vec<u8>>>) {
+        self.rules = rules;
+    }
+
+    /// Nicknames that the users cannot take, such as the ones of the services.
+    /// Use ServerState::set_reserved_nicknames to also rename the users currently holding them.
+    pub fn set_reserved_nicknames(&mut self, reserved_nicknames: Vec<String>) {
+        self.reserved_nicknames = reserved_nicknames;
+    }
+
+    pub(crate) fn is_reserved_nickname(&self, cured_nickname: &str) -> bool {
+        self.reserved_nicknames.iter().any(|reserved| {
+            cure_nickname(reserved).is_some_and(|r| r.eq_ignore_ascii_case(cured_nickname))
+        })
+    }
+
+    pub fn set_admin_info(&mut self, admin_info: Option<AdminInfo>) {
+        self.admin_info = admin_info;
+    }
+
+    pub fn set_related_servers(&mut self, related_servers: Vec<RelatedServer>) {
+        self.related_servers = related_servers;
+    }
+
+    /// The connected clients are throttled with the new values from their next command.
+    pub fn set_throttle_config(&mut self, throttle_config: ThrottleConfig) {
+        self.throttle_config = Arc::new(throttle_config);
+    }
+
+    pub fn set_default_channel_mode(&mut self, default_channel_mode: &ChannelMode) {
+        self.default_channel_mode = default_channel_mode.clone();
+    }
+
+    /// The first rule matching the name of a new channel gives its mode, the channels matching
+    /// none get the default channel mode.
+    /// Warning: changing the rules does not affect the existing channels.
+    pub fn set_channel_mode_rules(&mut self, channel_mode_rules: Vec<ChannelModeRule>) {
+        self.channel_mode_rules = channel_mode_rules;
+    }
+
+    pub fn set_timeout_config(&mut self, timeout: Option<TimeoutConfig>) {
+        self.timeout_config = timeout;
+    }
+
+    pub fn set_sts_policy(&mut self, sts_policy: Option<StsPolicy>) {
+        self.sts_policy = sts_policy;
+    }
+
+    /// The hooks are run in order on each PRIVMSG and NOTICE.
+    pub fn set_message_hooks(&mut self, message_hooks: Vec<Arc<dyn MessageHook>>) {
+        self.message_hooks = message_hooks;
+    }
+
+    /// When set, the JOIN, PART and QUIT messages sent to a client within this window are written
+    /// together, and wrapped in a BATCH for the clients supporting it.
+    /// Warning: changing the value does not affect existing clients.
+    pub fn set_membership_coalescing(&mut self, window: Option<Duration>) {
+        self.membership_coalescing = window;
+    }
+
+    /// When set, UTF8ONLY is advertised and the messages with invalid UTF-8 in their nicknames,
+    /// channels, topics, usernames, realnames or text are rejected. Otherwise, the invalid
+    /// parameters of NICK, JOIN, TOPIC and USER are decoded as Latin-1.
+    pub fn set_utf8_only(&mut self, utf8_only: bool) {
+        self.utf8_only = utf8_only;
+    }
+
+    pub fn set_dcc_policy(&mut self, dcc_policy: DccPolicy) {
+        self.dcc_policy = dcc_policy;
+    }
+
+    /// Limits the number of connections that did not complete their registration, in total and
+    /// for each IP. When a limit is reached, the oldest of these connections is closed to make
+    /// room for the new one.
+    pub fn set_max_registering_users(&mut self, total: Option<usize>, per_ip: Option<usize>) {
+        self.max_registering_users = total;
+        self.max_registering_users_per_ip = per_ip;
+    }
+
+    /// IPs and networks of the trusted gateways (bridges, monitoring probes...), exempt from the
+    /// limit of registering users per IP, from the connection throttling and from the flood
+    /// protection.
+    /// Warning: changing the value does not affect the flood protection of existing clients.
+    pub fn set_exempt_ips(&mut self, exempt_ips: Vec<IpMask>) {
+        self.exempt_ips = exempt_ips;
+    }
+
+    /// Disconnects the unregistered connections sending too many bytes, or too slowly.
+    /// Warning: changing the value does not affect existing clients.
+    pub fn set_unregistered_limits(&mut self, unregistered_limits: UnregisteredLimits) {
+        self.unregistered_limits = unregistered_limits;
+    }
+
+    /// Enables the hostname and ident lookups of the new connections.
+    /// Warning: changing the value does not affect the clients already registering.
+    pub fn set_lookup_config(&mut self, lookup_config: LookupConfig) {
+        self.lookup_config = lookup_config;
+    }
+
+    /// When set, the commands holding the state lock for longer are logged.
+    pub fn set_slow_command_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_command_threshold = threshold;
+    }
+
+    /// When set, the nickname of a user whose connection is lost (rather than who quits) cannot
+    /// be taken from another IP during this period, so that they can reconnect and get it back.
+    pub fn set_nickname_grace_period(&mut self, grace_period: Option<Duration>) {
+        self.nickname_grace_period = grace_period;
+    }
+
+    pub(crate) fn is_exempt_ip(&self, ip: IpAddr) -> bool {
+        self.exempt_ips.iter().any(|mask| mask.contains(ip))
+    }
+
+    pub(crate) fn mode_of_new_channel(&self, channel_name: &str) -> &ChannelMode {
+        self.channel_mode_rules
+            .iter()
+            .find(|rule| mask_matches(&rule.mask, channel_name))
+            .map_or(&self.default_channel_mode, |rule| &rule.mode)
+    }
+
+    /// Limits the number of channels that a user can be in, advertised as CHANLIMIT.
+    /// Warning: lowering the value does not remove the users from their channels.
+    pub fn set_max_channels_per_user(&mut self, max_channels: Option<usize>) {
+        self.max_channels_per_user = max_channels;
+    }
+}
@@ -0,0 +1,138 @@
+use regex::bytes::Regex;
+
+use crate::hooks::{HookVerdict, MessageHook, MessageHookContext};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentFilterAction {
+    /// the message is not delivered
+    Block,
+    /// the matching parts are replaced by "***"
+    Censor,
+    /// the message is delivered and logged
+    Flag,
+}
+
+/// Filters the messages matching a list of words or regular expressions.
+#[derive(Debug, Clone)]
+pub struct ContentFilter {
+    patterns: Vec<Regex>,
+    action: ContentFilterAction,
+    /// None if the filter applies to the whole server, including private messages
+    channels: Option<Vec<String>>,
+}
+
+impl ContentFilter {
+    pub fn new(action: ContentFilterAction) -> Self {
+        Self {
+            patterns: vec![],
+            action,
+            channels: None,
+        }
+    }
+
+    /// Words are matched as whole words, ignoring the case.
+    pub fn with_word(mut self, word: &str) -> Result<Self, regex::Error> {
+        let pattern = format!(r"(?i)\b{}\b", regex::escape(word));
+        self.patterns.push(Regex::new(&pattern)?);
+        Ok(self)
+    }
+
+    pub fn with_regex(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.patterns.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Restricts the filter to some channels.
+    pub fn for_channels(mut self, channels: Vec<String>) -> Self {
+        self.channels = Some(channels);
+        self
+    }
+
+    fn applies_to(&self, context: &MessageHookContext<'_>) -> bool {
+        match &self.channels {
+            None => true,
+            Some(channels) => {
+                context.is_channel_message()
+                    && channels
+                        .iter()
+                        .any(|c| c.eq_ignore_ascii_case(context.target))
+            }
+        }
+    }
+}
+
+impl MessageHook for ContentFilter {
+    fn on_message(&self, context: &MessageHookContext<'_>) -> HookVerdict {
+        if !self.applies_to(context) || !self.patterns.iter().any(|p| p.is_match(context.content)) {
+            return HookVerdict::Accept;
+        }
+
+        match self.action {
+            ContentFilterAction::Block => HookVerdict::Reject,
+            ContentFilterAction::Censor => {
+                let content = self.patterns.iter().fold(context.content.to_vec(), |c, p| {
+                    p.replace_all(&c, b"***".as_slice()).into_owned()
+                });
+                HookVerdict::Replace(content)
+            }
+            ContentFilterAction::Flag => {
+                log::warn!(
+                    "flagged message from {} to {}: {}",
+                    context.sender_nickname,
+                    context.target,
+                    String::from_utf8_lossy(context.content)
+                );
+                HookVerdict::Accept
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)] // fine in tests
+    use super::*;
+
+    fn context<'a>(target: &'a str, content: &'a [u8]) -> MessageHookContext<'a> {
+        MessageHookContext {
+            sender_nickname: "nick",
+            target,
+            content,
+        }
+    }
+
+    #[test]
+    fn test_censor_words() {
+        let filter = ContentFilter::new(ContentFilterAction::Censor)
+            .with_word("heck")
+            .unwrap();
+        assert_eq!(
+            filter.on_message(&context("#chan", b"what the Heck, heckling")),
+            HookVerdict::Replace(b"what the ***, heckling".to_vec())
+        );
+        assert_eq!(
+            filter.on_message(&context("#chan", b"hello")),
+            HookVerdict::Accept
+        );
+    }
+
+    #[test]
+    fn test_block_regex_in_channels() {
+        let filter = ContentFilter::new(ContentFilterAction::Block)
+            .with_regex("buy .* now")
+            .unwrap()
+            .for_channels(vec!["#chan".to_string()]);
+        assert_eq!(
+            filter.on_message(&context("#CHAN", b"buy this now")),
+            HookVerdict::Reject
+        );
+        assert_eq!(
+            filter.on_message(&context("#other", b"buy this now")),
+            HookVerdict::Accept
+        );
+        assert_eq!(
+            filter.on_message(&context("nick2", b"buy this now")),
+            HookVerdict::Accept
+        );
+    }
+}
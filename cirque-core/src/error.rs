@@ -1,58 +1,506 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::catalog::Catalog;
 use crate::client_to_server::MessageDecodingError;
 use crate::message_writer::OnGoingMessage;
+use crate::numeric::Numeric;
 
-#[derive(thiserror::Error, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub(crate) enum ServerStateError {
-    // NOTE: for this one, we cannot use string interpolation since the command is not a string
-    // (it might not be valid utf8)
-    #[error("400 {client} ____ :{info}")]
     UnknownError {
         client: String,
+        /// might not be valid UTF-8
         command: Vec<u8>,
         info: String,
     },
-    #[error("401 {client} {target} :No such nick/channel")]
-    NoSuchNick { client: String, target: String },
-    #[error("403 {client} {channel} :No such channel")]
-    NoSuchChannel { client: String, channel: String },
-    #[error("404 {client} {channel} :Cannot send to channel")]
-    CannotSendToChan { client: String, channel: String },
-    #[error("411 {client} :No recipient given ({command})")]
-    NoRecipient { client: String, command: String },
-    #[error("412 {client} :No text to send")]
-    NoTextToSend { client: String },
-    #[error("421 {client} {command} :Unknown command")]
-    UnknownCommand { client: String, command: String },
-    #[error("431 {client} :No nickname given")]
-    NoNicknameGiven { client: String },
-    #[error("432 {client} {nickname} :Erroneous nickname")]
-    ErroneousNickname { client: String, nickname: String },
-    #[error("433 {client} {nickname} :Nickname is already in use")]
-    NicknameInUse { client: String, nickname: String },
-    #[error("441 {client} {nickname} {channel} :They aren't on that channel")]
+    InvalidUtf8 {
+        command: String,
+        parameter: String,
+    },
+    CapNegotiationTimeout {},
+    DccBlocked {
+        target: String,
+    },
+    ResumeInvalidToken {},
+    ResumeInsecureSession {},
+    ResumeRegistrationCompleted {},
+    NoSuchNick {
+        client: String,
+        target: String,
+    },
+    WasNoSuchNick {
+        client: String,
+        nickname: String,
+    },
+    NoSuchChannel {
+        client: String,
+        channel: String,
+    },
+    CannotSendToChan {
+        client: String,
+        channel: String,
+    },
+    FloodLimited {
+        client: String,
+        channel: String,
+    },
+    InvalidCapCmd {
+        client: String,
+        subcommand: String,
+    },
+    TooManyTargets {
+        client: String,
+        target: String,
+        max: usize,
+    },
+    NoRecipient {
+        client: String,
+        command: String,
+    },
+    NoTextToSend {
+        client: String,
+    },
+    UnknownCommand {
+        client: String,
+        command: String,
+    },
+    NoNicknameGiven {
+        client: String,
+    },
+    ErroneousNickname {
+        client: String,
+        nickname: String,
+    },
+    ReservedNickname {
+        client: String,
+        nickname: String,
+    },
+    NicknameInUse {
+        client: String,
+        nickname: String,
+    },
+    NickTemporarilyUnavailable {
+        client: String,
+        nickname: String,
+    },
     UserNotInChannel {
         client: String,
         nickname: String,
         channel: String,
     },
-    #[error("442 {client} {channel} :You're not on that channel")]
-    NotOnChannel { client: String, channel: String },
-    #[error("451 {client} :You have not registered")]
-    NotRegistered { client: String },
-    #[error("461 {client} {command} :Not enough parameters")]
-    NeedMoreParams { client: String, command: String },
-    #[error("464 {client} :Password incorrect")]
-    PasswdMismatch { client: String },
-    #[error("472 {client} {modechar} :is unknown mode char to me")]
-    UnknownMode { client: String, modechar: String },
-    #[error("476 {client} {channel} :Bad Channel Mask")]
-    BadChanMask { client: String, channel: String },
-    #[error("482 {client} {channel} :You're not channel operator")]
-    ChanOpPrivsNeeded { client: String, channel: String },
+    NotOnChannel {
+        client: String,
+        channel: String,
+    },
+    UserOnChannel {
+        client: String,
+        nickname: String,
+        channel: String,
+    },
+    NoNickChange {
+        client: String,
+        channel: String,
+    },
+    NotRegistered {
+        client: String,
+    },
+    NeedMoreParams {
+        client: String,
+        command: String,
+    },
+    PasswdMismatch {
+        client: String,
+    },
+    YoureBannedCreep {
+        client: String,
+    },
+    UnknownMode {
+        client: String,
+        modechar: String,
+    },
+    InviteOnlyChan {
+        client: String,
+        channel: String,
+    },
+    BannedFromChan {
+        client: String,
+        channel: String,
+    },
+    BadChannelKey {
+        client: String,
+        channel: String,
+    },
+    JoinThrottled {
+        client: String,
+        channel: String,
+    },
+    LinkChannel {
+        client: String,
+        channel: String,
+        forward: String,
+    },
+    TooManyChannels {
+        client: String,
+        channel: String,
+    },
+    BadChanMask {
+        client: String,
+        channel: String,
+    },
+    BanListFull {
+        client: String,
+        channel: String,
+        modechar: char,
+    },
+    NoPrivileges {
+        client: String,
+    },
+    SummonDisabled {
+        client: String,
+    },
+    UsersDisabled {
+        client: String,
+    },
+    ChanOpPrivsNeeded {
+        client: String,
+        channel: String,
+    },
+    NoOperHost {
+        client: String,
+    },
+    UModeUnknownFlag {
+        client: String,
+    },
+    UsersDontMatch {
+        client: String,
+    },
+    InvalidModeParam {
+        client: String,
+        target: String,
+        modechar: char,
+        param: String,
+        description: String,
+    },
+}
+
+/// Error as sent to the client (without the server prefix): either a numeric, whose first
+/// parameter is the client, or a FAIL standard reply.
+///
+/// The text is a template whose `{variables}` are replaced after its translation.
+#[derive(Debug)]
+enum ErrorReply<'a> {
+    Numeric {
+        numeric: Numeric,
+        client: &'a str,
+        params: Vec<Cow<'a, str>>,
+        text: Cow<'a, str>,
+        variables: Vec<(&'static str, Cow<'a, str>)>,
+    },
+    Fail {
+        command: &'a str,
+        code: &'static str,
+        text: Cow<'a, str>,
+        variables: Vec<(&'static str, Cow<'a, str>)>,
+    },
+}
+
+impl<'a> ErrorReply<'a> {
+    fn numeric(numeric: Numeric, client: &'a str) -> Self {
+        ErrorReply::Numeric {
+            numeric,
+            client,
+            params: vec![],
+            text: Cow::Borrowed(""),
+            variables: vec![],
+        }
+    }
+
+    fn fail(command: &'a str, code: &'static str) -> Self {
+        ErrorReply::Fail {
+            command,
+            code,
+            text: Cow::Borrowed(""),
+            variables: vec![],
+        }
+    }
+
+    fn param(mut self, param: impl Into<Cow<'a, str>>) -> Self {
+        if let ErrorReply::Numeric { params, .. } = &mut self {
+            params.push(param.into());
+        }
+        self
+    }
+
+    fn text(mut self, text: impl Into<Cow<'a, str>>) -> Self {
+        match &mut self {
+            ErrorReply::Numeric { text: t, .. } | ErrorReply::Fail { text: t, .. } => {
+                *t = text.into();
+            }
+        }
+        self
+    }
+
+    fn var(mut self, name: &'static str, value: impl Into<Cow<'a, str>>) -> Self {
+        match &mut self {
+            ErrorReply::Numeric { variables, .. } | ErrorReply::Fail { variables, .. } => {
+                variables.push((name, value.into()));
+            }
+        }
+        self
+    }
+
+    fn render(&self, catalog: &Catalog) -> String {
+        let format = |text: &str, variables: &[(&'static str, Cow<'_, str>)]| {
+            let variables = variables
+                .iter()
+                .map(|(name, value)| (*name, value.as_ref()))
+                .collect::<Vec<_>>();
+            catalog.format(text, &variables).into_owned()
+        };
+        match self {
+            ErrorReply::Numeric {
+                numeric,
+                client,
+                params,
+                text,
+                variables,
+            } => {
+                let mut out = format!("{numeric} {client}");
+                for param in params {
+                    out.push(' ');
+                    out.push_str(param);
+                }
+                out.push_str(" :");
+                out.push_str(&format(text, variables));
+                out
+            }
+            ErrorReply::Fail {
+                command,
+                code,
+                text,
+                variables,
+            } => format!("FAIL {command} {code} :{}", format(text, variables)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorReply<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(&Catalog::default()))
+    }
 }
 
 impl ServerStateError {
-    pub(crate) fn write_to<'b, 'c>(&self, mut m: OnGoingMessage<'b, 'c>) -> OnGoingMessage<'b, 'c> {
+    fn reply(&self) -> ErrorReply<'_> {
+        use ErrorReply as R;
+        use ServerStateError as E;
+        match self {
+            E::UnknownError { client, info, .. } => {
+                // the command is added by write_to, it might not be valid UTF-8
+                R::numeric(Numeric::ERR_UNKNOWNERROR, client).text(info)
+            }
+            E::InvalidUtf8 { command, parameter } => R::fail(command, "INVALID_UTF8")
+                .text("Invalid UTF-8 in the {parameter}")
+                .var("parameter", parameter),
+            E::CapNegotiationTimeout {} => {
+                R::fail("CAP", "TIMEOUT").text("Capability negotiation was not ended in time")
+            }
+            E::DccBlocked { target } => R::fail("PRIVMSG", "DCC_BLOCKED")
+                .text("DCC offers to {target} are not relayed by this server")
+                .var("target", target),
+            E::ResumeInvalidToken {} => R::fail("RESUME", "INVALID_TOKEN")
+                .text("Cannot resume connection, token is not valid"),
+            E::ResumeInsecureSession {} => R::fail("RESUME", "INSECURE_SESSION")
+                .text("Cannot resume connection, old and new clients must use TLS"),
+            E::ResumeRegistrationCompleted {} => R::fail("RESUME", "REGISTRATION_IS_COMPLETED")
+                .text(
+                    "Cannot resume connection, connection registration has already been completed",
+                ),
+            E::NoSuchNick { client, target } => R::numeric(Numeric::ERR_NOSUCHNICK, client)
+                .param(target)
+                .text("No such nick/channel"),
+            E::WasNoSuchNick { client, nickname } => R::numeric(Numeric::ERR_WASNOSUCHNICK, client)
+                .param(nickname)
+                .text("There was no such nickname"),
+            E::NoSuchChannel { client, channel } => R::numeric(Numeric::ERR_NOSUCHCHANNEL, client)
+                .param(channel)
+                .text("No such channel"),
+            E::CannotSendToChan { client, channel } => {
+                R::numeric(Numeric::ERR_CANNOTSENDTOCHAN, client)
+                    .param(channel)
+                    .text("Cannot send to channel")
+            }
+            E::FloodLimited { client, channel } => {
+                R::numeric(Numeric::ERR_CANNOTSENDTOCHAN, client)
+                    .param(channel)
+                    .text("Cannot send to channel (+f, too many messages)")
+            }
+            E::InvalidCapCmd { client, subcommand } => {
+                R::numeric(Numeric::ERR_INVALIDCAPCMD, client)
+                    .param(subcommand)
+                    .text("Invalid CAP command")
+            }
+            E::TooManyTargets {
+                client,
+                target,
+                max,
+            } => R::numeric(Numeric::ERR_TOOMANYTARGETS, client)
+                .param(target)
+                .text("Too many targets (at most {max})")
+                .var("max", max.to_string()),
+            E::NoRecipient { client, command } => R::numeric(Numeric::ERR_NORECIPIENT, client)
+                .text("No recipient given ({command})")
+                .var("command", command),
+            E::NoTextToSend { client } => {
+                R::numeric(Numeric::ERR_NOTEXTTOSEND, client).text("No text to send")
+            }
+            E::UnknownCommand { client, command } => {
+                R::numeric(Numeric::ERR_UNKNOWNCOMMAND, client)
+                    .param(command)
+                    .text("Unknown command")
+            }
+            E::NoNicknameGiven { client } => {
+                R::numeric(Numeric::ERR_NONICKNAMEGIVEN, client).text("No nickname given")
+            }
+            E::ErroneousNickname { client, nickname } => {
+                R::numeric(Numeric::ERR_ERRONEUSNICKNAME, client)
+                    .param(nickname)
+                    .text("Erroneous nickname")
+            }
+            E::ReservedNickname { client, nickname } => {
+                R::numeric(Numeric::ERR_ERRONEUSNICKNAME, client)
+                    .param(nickname)
+                    .text("Nickname is reserved")
+            }
+            E::NicknameInUse { client, nickname } => R::numeric(Numeric::ERR_NICKNAMEINUSE, client)
+                .param(nickname)
+                .text("Nickname is already in use"),
+            E::NickTemporarilyUnavailable { client, nickname } => {
+                R::numeric(Numeric::ERR_UNAVAILRESOURCE, client)
+                    .param(nickname)
+                    .text("Nick/channel is temporarily unavailable")
+            }
+            E::UserNotInChannel {
+                client,
+                nickname,
+                channel,
+            } => R::numeric(Numeric::ERR_USERNOTINCHANNEL, client)
+                .param(nickname)
+                .param(channel)
+                .text("They aren't on that channel"),
+            E::NotOnChannel { client, channel } => R::numeric(Numeric::ERR_NOTONCHANNEL, client)
+                .param(channel)
+                .text("You're not on that channel"),
+            E::UserOnChannel {
+                client,
+                nickname,
+                channel,
+            } => R::numeric(Numeric::ERR_USERONCHANNEL, client)
+                .param(nickname)
+                .param(channel)
+                .text("is already on channel"),
+            E::NoNickChange { client, channel } => R::numeric(Numeric::ERR_NONICKCHANGE, client)
+                .text("Cannot change nickname while on {channel} (+N)")
+                .var("channel", channel),
+            E::NotRegistered { client } => {
+                R::numeric(Numeric::ERR_NOTREGISTERED, client).text("You have not registered")
+            }
+            E::NeedMoreParams { client, command } => {
+                R::numeric(Numeric::ERR_NEEDMOREPARAMS, client)
+                    .param(command)
+                    .text("Not enough parameters")
+            }
+            E::PasswdMismatch { client } => {
+                R::numeric(Numeric::ERR_PASSWDMISMATCH, client).text("Password incorrect")
+            }
+            E::YoureBannedCreep { client } => R::numeric(Numeric::ERR_YOUREBANNEDCREEP, client)
+                .text("You are banned from this server"),
+            E::UnknownMode { client, modechar } => R::numeric(Numeric::ERR_UNKNOWNMODE, client)
+                .param(modechar)
+                .text("is unknown mode char to me"),
+            E::InviteOnlyChan { client, channel } => {
+                R::numeric(Numeric::ERR_INVITEONLYCHAN, client)
+                    .param(channel)
+                    .text("Cannot join channel (+i)")
+            }
+            E::BannedFromChan { client, channel } => {
+                R::numeric(Numeric::ERR_BANNEDFROMCHAN, client)
+                    .param(channel)
+                    .text("Cannot join channel (+b)")
+            }
+            E::BadChannelKey { client, channel } => R::numeric(Numeric::ERR_BADCHANNELKEY, client)
+                .param(channel)
+                .text("Cannot join channel (+k)"),
+            E::JoinThrottled { client, channel } => {
+                R::numeric(Numeric::ERR_UNAVAILRESOURCE, client)
+                    .param(channel)
+                    .text("Cannot join channel (+j, too many joins), try again later")
+            }
+            E::LinkChannel {
+                client,
+                channel,
+                forward,
+            } => R::numeric(Numeric::ERR_LINKCHANNEL, client)
+                .param(channel)
+                .param(forward)
+                .text("Forwarding to another channel"),
+            E::TooManyChannels { client, channel } => {
+                R::numeric(Numeric::ERR_TOOMANYCHANNELS, client)
+                    .param(channel)
+                    .text("You have joined too many channels")
+            }
+            E::BadChanMask { client, channel } => R::numeric(Numeric::ERR_BADCHANMASK, client)
+                .param(channel)
+                .text("Bad Channel Mask"),
+            E::BanListFull {
+                client,
+                channel,
+                modechar,
+            } => R::numeric(Numeric::ERR_BANLISTFULL, client)
+                .param(channel)
+                .param(modechar.to_string())
+                .text("Channel list is full"),
+            E::NoPrivileges { client } => R::numeric(Numeric::ERR_NOPRIVILEGES, client)
+                .text("Permission Denied- You're not an IRC operator"),
+            E::SummonDisabled { client } => {
+                R::numeric(Numeric::ERR_SUMMONDISABLED, client).text("SUMMON has been disabled")
+            }
+            E::UsersDisabled { client } => {
+                R::numeric(Numeric::ERR_USERSDISABLED, client).text("USERS has been disabled")
+            }
+            E::ChanOpPrivsNeeded { client, channel } => {
+                R::numeric(Numeric::ERR_CHANOPRIVSNEEDED, client)
+                    .param(channel)
+                    .text("You're not channel operator")
+            }
+            E::NoOperHost { client } => {
+                R::numeric(Numeric::ERR_NOOPERHOST, client).text("No O-lines for your host")
+            }
+            E::UModeUnknownFlag { client } => {
+                R::numeric(Numeric::ERR_UMODEUNKNOWNFLAG, client).text("Unknown MODE flag")
+            }
+            E::UsersDontMatch { client } => R::numeric(Numeric::ERR_USERSDONTMATCH, client)
+                .text("Cant change mode for other users"),
+            E::InvalidModeParam {
+                client,
+                target,
+                modechar,
+                param,
+                description,
+            } => R::numeric(Numeric::ERR_INVALIDMODEPARAM, client)
+                .param(target)
+                .param(modechar.to_string())
+                .param(param)
+                .text(description),
+        }
+    }
+
+    pub(crate) fn write_to<'b, 'c>(
+        &self,
+        mut m: OnGoingMessage<'b, 'c>,
+        catalog: &Catalog,
+    ) -> OnGoingMessage<'b, 'c> {
         match self {
             ServerStateError::UnknownError {
                 client,
@@ -61,20 +509,17 @@ impl ServerStateError {
             } => {
                 message_push!(
                     m,
-                    b"400 {client} ____ :{info}",
+                    &Numeric::ERR_UNKNOWNERROR.to_string(),
+                    b" ",
                     &client,
                     b" ",
                     command,
                     b" :",
-                    &info
+                    &catalog.translate(info)
                 );
                 m
             }
-            err => {
-                // NOTE: later we can optimize to avoid the to_string call
-                // currently it prevents us from using Vec<u8> in ServerStateError
-                m.write(&err.to_string())
-            }
+            err => m.write(&err.reply().render(catalog)),
         }
     }
 
@@ -88,19 +533,18 @@ impl ServerStateError {
                 command: command.into(),
                 info: "Cannot decode utf8".into(),
             },
+            MessageDecodingError::InvalidUtf8 {
+                command, parameter, ..
+            } => ServerStateError::InvalidUtf8 {
+                command: command.to_ascii_uppercase(),
+                parameter: parameter.into(),
+            },
             MessageDecodingError::NotEnoughParameters { command } => {
                 ServerStateError::NeedMoreParams {
                     client,
                     command: command.into(),
                 }
             }
-            MessageDecodingError::CannotParseInteger { command } => {
-                ServerStateError::UnknownError {
-                    client,
-                    command: command.into(),
-                    info: "Cannot parse integer".into(),
-                }
-            }
             MessageDecodingError::NoNicknameGiven {} => {
                 ServerStateError::NoNicknameGiven { client }
             }
@@ -109,8 +553,111 @@ impl ServerStateError {
                 client,
                 command: command.into(),
             },
+            MessageDecodingError::TooManyTargets { target, max } => {
+                ServerStateError::TooManyTargets {
+                    client,
+                    target: String::from_utf8_lossy(target).into(),
+                    max,
+                }
+            }
             MessageDecodingError::SilentError {} => return None,
         };
         Some(err)
     }
 }
+
+impl fmt::Display for ServerStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.reply().fmt(f)
+    }
+}
+
+impl std::error::Error for ServerStateError {}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)] // fine in tests
+    use super::*;
+    use crate::message_writer::Mailbox;
+    use crate::server_to_client::{Message, MessageContext};
+
+    fn reply_bytes(err: ServerStateError) -> Vec<u8> {
+        let (mailbox, mut sink) = Mailbox::new(1);
+        let context = MessageContext {
+            server_name: "srv".to_string(),
+            network_name: None,
+            catalog: Default::default(),
+            templates: Default::default(),
+            compat: Default::default(),
+        };
+        mailbox.ingest(&Message::Err(err), &context);
+        sink.try_recv().unwrap().bytes().to_vec()
+    }
+
+    #[test]
+    fn test_error_replies() {
+        assert_eq!(Numeric::ERR_NOSUCHNICK.to_string(), "401");
+        assert_eq!(
+            reply_bytes(ServerStateError::UserNotInChannel {
+                client: "nick1".into(),
+                nickname: "nick2".into(),
+                channel: "#chan".into(),
+            }),
+            b":srv 441 nick1 nick2 #chan :They aren't on that channel\r\n"
+        );
+        assert_eq!(
+            reply_bytes(ServerStateError::InvalidModeParam {
+                client: "nick1".into(),
+                target: "#chan".into(),
+                modechar: 'f',
+                param: "x".into(),
+                description: "invalid flood limit".into(),
+            }),
+            b":srv 696 nick1 #chan f x :invalid flood limit\r\n"
+        );
+        assert_eq!(
+            reply_bytes(ServerStateError::InvalidUtf8 {
+                command: "NICK".into(),
+                parameter: "nickname".into(),
+            }),
+            b":srv FAIL NICK INVALID_UTF8 :Invalid UTF-8 in the nickname\r\n"
+        );
+        assert_eq!(
+            reply_bytes(ServerStateError::UnknownError {
+                client: "*".into(),
+                command: b"J\xffIN".to_vec(),
+                info: "Cannot decode utf8".into(),
+            }),
+            b":srv 400 * J\xffIN :Cannot decode utf8\r\n"
+        );
+    }
+
+    #[test]
+    fn test_translated_error_replies() {
+        let (mailbox, mut sink) = Mailbox::new(1);
+        let context = MessageContext {
+            server_name: "srv".to_string(),
+            network_name: None,
+            templates: Default::default(),
+            compat: Default::default(),
+            catalog: std::sync::Arc::new(Catalog::new(
+                "fr",
+                [(
+                    "Too many targets (at most {max})".to_string(),
+                    "Trop de destinataires (au plus {max})".to_string(),
+                )]
+                .into(),
+            )),
+        };
+        let err = ServerStateError::TooManyTargets {
+            client: "nick1".into(),
+            target: "a,b,c".into(),
+            max: 2,
+        };
+        mailbox.ingest(&Message::Err(err), &context);
+        assert_eq!(
+            sink.try_recv().unwrap().bytes(),
+            b":srv 407 nick1 a,b,c :Trop de destinataires (au plus 2)\r\n"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use std::borrow::Cow;
+
+/// Decision of a hook about a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookVerdict {
+    Accept,
+    /// the message is delivered with a different content
+    Replace(Vec<u8>),
+    /// the message is not delivered
+    Reject,
+}
+
+/// A PRIVMSG or NOTICE sent by a user to a channel or to another user.
+#[derive(Debug)]
+pub struct MessageHookContext<'a> {
+    pub sender_nickname: &'a str,
+    /// channel name or nickname
+    pub target: &'a str,
+    pub content: &'a [u8],
+}
+
+impl MessageHookContext<'_> {
+    pub fn is_channel_message(&self) -> bool {
+        self.target.starts_with('#')
+    }
+}
+
+/// Hooks let the embedder of the server state inspect and alter the messages exchanged by the
+/// users. They are called while holding the state lock, so they should be fast.
+pub trait MessageHook: std::fmt::Debug + Send + Sync {
+    fn on_message(&self, context: &MessageHookContext<'_>) -> HookVerdict;
+}
+
+/// Runs the hooks in order, each one seeing the content as modified by the previous ones.
+/// Returns None if the message is rejected.
+pub(crate) fn run_message_hooks<'c>(
+    hooks: &[std::sync::Arc<dyn MessageHook>],
+    sender_nickname: &str,
+    target: &str,
+    content: &'c [u8],
+) -> Option<Cow<'c, [u8]>> {
+    let mut content = Cow::Borrowed(content);
+    for hook in hooks {
+        let context = MessageHookContext {
+            sender_nickname,
+            target,
+            content: &content,
+        };
+        match hook.on_message(&context) {
+            HookVerdict::Accept => {}
+            HookVerdict::Replace(new_content) => content = Cow::Owned(new_content),
+            HookVerdict::Reject => return None,
+        }
+    }
+    Some(content)
+}
@@ -1,17 +1,54 @@
 #[macro_use]
 mod message_writer;
+mod capabilities;
+mod catalog;
 mod client_to_server;
+mod config;
+mod content_filter;
 mod error;
+mod hooks;
+mod mask;
+mod metrics;
 mod nickname;
+mod numeric;
+mod reply_cache;
 mod server_state;
 mod server_to_client;
+mod spam_filter;
+mod templates;
+mod throttle;
 mod timeout;
 mod types;
 mod user_state;
+mod visibility;
 
+pub use catalog::Catalog;
+pub use config::{
+    AdminInfo, ChannelModeRule, DccPolicy, HostnamePolicy, LookupConfig, RelatedServer,
+    ServerConfig, StsPolicy, UnregisteredLimits,
+};
+pub use content_filter::{ContentFilter, ContentFilterAction};
+pub use hooks::{HookVerdict, MessageHook, MessageHookContext};
+pub use mask::IpMask;
+pub use message_writer::{MailboxSink, SerializedMessage};
+pub use metrics::{
+    HandshakeFailure, HandshakeFailures, Histogram, PingLatencies, ServerMetrics, Traffic,
+    COMMAND_DURATION_BUCKETS,
+};
 pub use server_state::ServerState;
+pub use templates::Templates;
+pub use throttle::{CommandClass, ThrottleAction, ThrottleConfig};
 pub use timeout::TimeoutConfig;
+pub use types::ChannelMember;
 pub use types::ChannelMode;
+pub use types::ChannelSnapshot;
+pub use types::CompatFlags;
+pub use types::ConnectionInfo;
+pub use types::FloodLimit;
+pub use types::JoinThrottle;
+pub use types::Lookup;
+pub use types::TlsInfo;
+pub use types::Topic;
 pub use types::UserID;
 pub use types::WelcomeConfig;
 pub use user_state::UserState;
@@ -0,0 +1,145 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Matches a mask against a value, ignoring the ASCII case.
+/// In the mask, '*' matches any sequence of characters (including none), and '?' matches exactly
+/// one character.
+pub(crate) fn mask_matches(mask: &str, value: &str) -> bool {
+    let mask = mask.as_bytes();
+    let value = value.as_bytes();
+
+    // iterative matching with backtracking on the last '*'
+    let (mut m, mut v) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match (mask.get(m), value.get(v)) {
+            (Some(b'*'), _) => {
+                last_star = Some((m, v));
+                m += 1;
+            }
+            (Some(b'?'), _) => {
+                m += 1;
+                v += 1;
+            }
+            (Some(a), Some(b)) if a.eq_ignore_ascii_case(b) => {
+                m += 1;
+                v += 1;
+            }
+            _ => {
+                // mismatch: let the last '*' consume one more character
+                let Some((star_m, star_v)) = last_star else {
+                    return false;
+                };
+                last_star = Some((star_m, star_v + 1));
+                m = star_m + 1;
+                v = star_v + 1;
+            }
+        }
+    }
+
+    mask.iter().skip(m).all(|&c| c == b'*')
+}
+
+/// IP address or CIDR block, such as `192.0.2.1`, `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpMask {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for IpMask {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || format!("'{value}' is neither an IP nor a CIDR block");
+        let (network, prefix_len) = match value.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network = network
+            .parse::<IpAddr>()
+            .map_err(|_| error())?
+            .to_canonical();
+        let max_prefix_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u32>().map_err(|_| error())?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(error());
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl IpMask {
+    /// The IPv4-mapped IPv6 addresses match the IPv4 masks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mask_matches, IpMask};
+
+    #[test]
+    fn test_exact() {
+        assert!(mask_matches("user@10.0.0.1", "user@10.0.0.1"));
+        assert!(mask_matches("USER@10.0.0.1", "user@10.0.0.1"));
+        assert!(!mask_matches("user@10.0.0.1", "user@10.0.0.12"));
+        assert!(!mask_matches("user@10.0.0.12", "user@10.0.0.1"));
+        assert!(mask_matches("", ""));
+        assert!(!mask_matches("", "a"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(mask_matches("*", ""));
+        assert!(mask_matches("*", "anything"));
+        assert!(mask_matches("*@10.0.0.*", "user@10.0.0.1"));
+        assert!(!mask_matches("*@10.0.0.*", "user@10.0.1.1"));
+        assert!(mask_matches("u?er@*", "user@host"));
+        assert!(!mask_matches("u?er@*", "uer@host"));
+        assert!(mask_matches("*a*b*c", "xxaxxbxxbxc"));
+        assert!(!mask_matches("*a*b*c", "xxaxxbxxbx"));
+        assert!(mask_matches("a**", "a"));
+    }
+
+    #[test]
+    fn test_ip_masks() {
+        let mask = |mask: &str| mask.parse::<IpMask>().unwrap();
+        let ip = |ip: &str| ip.parse().unwrap();
+        assert!(mask("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!mask("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(mask("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(mask("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!mask("192.0.2.1").contains(ip("192.0.2.2")));
+        assert!(mask("0.0.0.0/0").contains(ip("203.0.113.5")));
+        assert!(!mask("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(mask("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!mask("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(mask("::/0").contains(ip("::1")));
+
+        assert!("10.0.0.0/33".parse::<IpMask>().is_err());
+        assert!("10.0.0.0/".parse::<IpMask>().is_err());
+        assert!("host.example.com".parse::<IpMask>().is_err());
+    }
+}
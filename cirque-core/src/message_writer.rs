@@ -1,7 +1,17 @@
-use std::{io::Write, marker::PhantomData};
-
+use std::{
+    io::Write,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
 use tokio::sync::mpsc::{error::TryRecvError, Permit, Receiver, Sender};
 
+use crate::capabilities::{Capabilities, Capability};
 use crate::server_to_client::{self, MessageContext};
 
 const IRC_MESSAGE_MAX_SIZE: usize = 512;
@@ -10,6 +20,7 @@ const IRC_MESSAGE_MAX_SIZE: usize = 512;
 pub struct SerializedMessage {
     bytes: Vec<u8>,
     is_important: bool,
+    is_membership_change: bool,
 }
 
 impl SerializedMessage {
@@ -20,17 +31,90 @@ impl SerializedMessage {
     pub fn is_important(&self) -> bool {
         self.is_important
     }
+
+    /// JOIN, PART and QUIT messages, which can be coalesced by the sessions.
+    pub fn is_membership_change(&self) -> bool {
+        self.is_membership_change
+    }
+
+    fn tags(&self) -> &[u8] {
+        self.bytes
+            .strip_prefix(b"@")
+            .and_then(|tagged| tagged.split(|&b| b == b' ').next())
+            .unwrap_or_default()
+    }
+
+    fn has_tag(&self, key: &str) -> bool {
+        self.tags().split(|&b| b == b';').any(|tag| {
+            tag.strip_prefix(key.as_bytes())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(b"="))
+        })
+    }
+
+    /// Adds the tag in front of the existing ones. The message is not coalesced anymore, as it
+    /// belongs to a response.
+    fn with_tag(mut self, tag: &str) -> Self {
+        if !tag.is_empty() {
+            let tag = match self.bytes.first() {
+                Some(b'@') => format!("{tag};"),
+                _ => format!("@{tag} "),
+            };
+            let at = usize::from(self.bytes.first() == Some(&b'@'));
+            self.bytes.splice(at..at, tag.into_bytes());
+        }
+        self.is_membership_change = false;
+        self
+    }
 }
 
+/// Queue of the messages to send to a client, written to the connection by its session.
+///
+/// The messages are ingested while the state is locked and the session writes them in the same
+/// order, so that the messages caused by a client reach each recipient in the order of its
+/// commands, and the membership changes are seen in the same order by everyone. Messages sent
+/// concurrently by different clients can interleave differently for each recipient.
+/// When the mailbox is full, the new messages are dropped rather than reordered.
 #[derive(Debug)]
 pub(crate) struct Mailbox {
     sender: Sender<SerializedMessage>,
+    /// number of messages dropped because the mailbox was full, reported by the sink
+    dropped_messages: Arc<AtomicU64>,
+    /// reference of the batch in which the messages are currently written
+    batch: Option<String>,
+    /// whether the replies made of several messages are wrapped in a batch (batch capability)
+    batches_replies: bool,
+    /// whether the messages are tagged with their time (server-time capability)
+    server_time: bool,
+    /// whether the replies to a labeled command carry its label (labeled-response capability)
+    labels_replies: bool,
+    /// replies to the labeled command being handled, sent once it is done
+    labeled_response: Mutex<Option<LabeledResponse>>,
+}
+
+#[derive(Debug)]
+struct LabeledResponse {
+    label: String,
+    messages: Vec<SerializedMessage>,
 }
 
 impl Mailbox {
     pub(crate) fn new(capacity: usize) -> (Self, MailboxSink) {
         let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
-        (Self { sender }, MailboxSink { receiver })
+        let dropped_messages = Arc::new(AtomicU64::new(0));
+        let mailbox = Self {
+            sender,
+            dropped_messages: dropped_messages.clone(),
+            batch: None,
+            batches_replies: false,
+            server_time: false,
+            labels_replies: false,
+            labeled_response: Mutex::new(None),
+        };
+        let sink = MailboxSink {
+            receiver,
+            dropped_messages,
+        };
+        (mailbox, sink)
     }
 
     pub(crate) fn ingest(&self, message: &server_to_client::Message<'_>, context: &MessageContext) {
@@ -38,14 +122,163 @@ impl Mailbox {
             return;
         }
 
+        let batch_type = message.batch_type().filter(|_| self.batches_replies);
+        let Some(batch_type) = batch_type else {
+            self.ingest_in_batch(message, context, self.batch.as_deref());
+            return;
+        };
+
+        // nested in the current batch, if any
+        let reference = uuid::Uuid::new_v4().simple().to_string();
+        let start = server_to_client::Message::BatchStart {
+            reference: &reference,
+            batch_type,
+        };
+        self.ingest_in_batch(&start, context, self.batch.as_deref());
+        self.ingest_in_batch(message, context, Some(&reference));
+        let end = server_to_client::Message::BatchEnd {
+            reference: &reference,
+        };
+        self.ingest_in_batch(&end, context, self.batch.as_deref());
+    }
+
+    fn ingest_in_batch(
+        &self,
+        message: &server_to_client::Message<'_>,
+        context: &MessageContext,
+        batch: Option<&str>,
+    ) {
         let mut mw = self.writer(message.is_important());
+        mw.batch = batch.map(str::to_string);
+        // all the lines of a message get the same time
+        mw.time = self
+            .server_time
+            .then(|| format_server_time(SystemTime::now()));
+        // the messages of a batch are not coalesced, they have to stay in their batch
+        mw.messages_are_membership_changes = message.is_membership_change() && batch.is_none();
         message.write_to(&mut mw, context);
     }
 
+    /// Tags the messages ingested from now on with the batch reference, until it is unset.
+    /// Opening and closing the batch is left to the caller.
+    pub(crate) fn set_batch(&mut self, batch: Option<String>) {
+        self.batch = batch;
+    }
+
+    /// Adapts the messages ingested from now on to the capabilities of the client, to be called
+    /// after they changed.
+    pub(crate) fn set_capabilities(&mut self, capabilities: &Capabilities) {
+        self.batches_replies = capabilities.has(Capability::Batch);
+        self.server_time = capabilities.has(Capability::ServerTime);
+        self.labels_replies = capabilities.has(Capability::LabeledResponse);
+    }
+
+    /// Holds the messages ingested from now on, until end_labeled_response sends them with the
+    /// label of the command that caused them. Returns false, without holding them, if the client
+    /// did not negotiate labeled-response.
+    pub(crate) fn start_labeled_response(&self, label: &str) -> bool {
+        if self.labels_replies {
+            *self.labeled_response.lock() = Some(LabeledResponse {
+                label: label.to_string(),
+                messages: vec![],
+            });
+        }
+        self.labels_replies
+    }
+
+    /// Sends the held messages with the label: on the message if there is only one, on a batch
+    /// grouping them if there are several, and on an ACK if there is none. Without the batch
+    /// capability, several messages are sent without the label.
+    pub(crate) fn end_labeled_response(&self, server_name: &str) {
+        let Some(LabeledResponse { label, messages }) = self.labeled_response.lock().take() else {
+            return;
+        };
+
+        let framing = |line: String| SerializedMessage {
+            bytes: line.into_bytes(),
+            is_important: false,
+            is_membership_change: false,
+        };
+        let label_tag = format!("label={label}");
+        let response = match messages.len() {
+            0 => vec![framing(format!("@{label_tag} :{server_name} ACK\r\n"))],
+            1 => messages
+                .into_iter()
+                .map(|message| message.with_tag(&label_tag))
+                .collect(),
+            _ if !self.batches_replies => messages,
+            _ => {
+                let batch_id = uuid::Uuid::new_v4().simple().to_string();
+                let batch_tag = format!("batch={batch_id}");
+                let start =
+                    format!("@{label_tag} :{server_name} BATCH +{batch_id} labeled-response\r\n");
+                let end = format!(":{server_name} BATCH -{batch_id}\r\n");
+                std::iter::once(framing(start))
+                    .chain(messages.into_iter().map(|message| {
+                        // the messages of a nested batch are already tagged with it
+                        if message.has_tag("batch") {
+                            message.with_tag("")
+                        } else {
+                            message.with_tag(&batch_tag)
+                        }
+                    }))
+                    .chain(std::iter::once(framing(end)))
+                    .collect()
+            }
+        };
+        for message in response {
+            if self.sender.try_send(message).is_err() {
+                self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
     fn writer(&self, messages_are_important: bool) -> MessageWriter<'_> {
         MessageWriter {
             mailbox: self,
             messages_are_important,
+            messages_are_membership_changes: false,
+            batch: self.batch.clone(),
+            time: None,
+        }
+    }
+}
+
+/// Formats the time as in the server-time specification, `2011-10-19T16:40:51.620Z`.
+fn format_server_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // civil date from the number of days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The messages held for a labeled response are not lost when the client quits or is
+/// disconnected by its command, they are sent without the label.
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        if let Some(labeled_response) = self.labeled_response.get_mut().take() {
+            for message in labeled_response.messages {
+                let _ = self.sender.try_send(message);
+            }
         }
     }
 }
@@ -53,6 +286,7 @@ impl Mailbox {
 #[derive(Debug)]
 pub struct MailboxSink {
     receiver: Receiver<SerializedMessage>,
+    dropped_messages: Arc<AtomicU64>,
 }
 
 impl MailboxSink {
@@ -67,6 +301,11 @@ impl MailboxSink {
     pub fn close(&mut self) {
         self.receiver.close();
     }
+
+    /// Returns the number of messages dropped since the last call, because the mailbox was full.
+    pub fn take_dropped_count(&self) -> u64 {
+        self.dropped_messages.swap(0, Ordering::Relaxed)
+    }
 }
 
 /// A single server_to_client::Message might generate multiple 512-bytes IRC messages.
@@ -75,6 +314,11 @@ impl MailboxSink {
 pub(crate) struct MessageWriter<'m> {
     mailbox: &'m Mailbox,
     messages_are_important: bool,
+    messages_are_membership_changes: bool,
+    /// reference of the batch of the messages, if they are in one
+    batch: Option<String>,
+    /// value of the time tag of the messages, if the client wants one
+    time: Option<String>,
 }
 
 impl<'m> MessageWriter<'m> {
@@ -85,13 +329,33 @@ impl<'m> MessageWriter<'m> {
     /// If the mailbox is full, returns None. This allows to skip allocation and buffer preparation
     /// for nothing, as the message won't be sent anyway.
     pub(crate) fn new_message<'w>(&'w mut self) -> Option<OnGoingMessage<'m, 'w>> {
-        let permit = self.mailbox.sender.try_reserve().ok()?;
+        // the messages of a labeled response are held, as many as the mailbox could
+        let held = self
+            .mailbox
+            .labeled_response
+            .lock()
+            .as_ref()
+            .map(|labeled_response| labeled_response.messages.len());
+        let permit = match held {
+            Some(held) => (held < self.mailbox.sender.max_capacity()).then_some(None),
+            None => self.mailbox.sender.try_reserve().ok().map(Some),
+        };
+        let Some(permit) = permit else {
+            self.mailbox
+                .dropped_messages
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
         let buf = vec![0_u8; IRC_MESSAGE_MAX_SIZE].into();
         let buf = std::io::Cursor::new(buf);
         Some(OnGoingMessage {
             buf,
             permit,
+            mailbox: self.mailbox,
+            batch: self.batch.as_deref(),
+            time: self.time.as_deref(),
             is_important: self.messages_are_important,
+            is_membership_change: self.messages_are_membership_changes,
             phantom: PhantomData,
         })
     }
@@ -101,8 +365,13 @@ impl<'m> MessageWriter<'m> {
 #[must_use = "You should call OnGoingMessage::validate() to send the message."]
 pub(crate) struct OnGoingMessage<'m, 'w> {
     buf: std::io::Cursor<Box<[u8]>>,
-    permit: Permit<'m, SerializedMessage>,
+    /// None if the message is held for a labeled response
+    permit: Option<Permit<'m, SerializedMessage>>,
+    mailbox: &'m Mailbox,
+    batch: Option<&'w str>,
+    time: Option<&'w str>,
     is_important: bool,
+    is_membership_change: bool,
     phantom: PhantomData<&'w mut MessageWriter<'m>>,
 }
 
@@ -119,6 +388,11 @@ impl OnGoingMessage<'_, '_> {
         self
     }
 
+    /// Whether `n` more bytes can be written without the message being cut.
+    pub(crate) fn has_room_for(&self, n: usize) -> bool {
+        self.buf.position() as usize + n <= IRC_MESSAGE_MAX_SIZE - 2
+    }
+
     pub(crate) fn validate(self) {
         fn is_utf8_char_boundary(c: u8) -> bool {
             // see u8::is_utf8_char_boundary (private method)
@@ -142,11 +416,32 @@ impl OnGoingMessage<'_, '_> {
         buf.push(b'\r');
         buf.push(b'\n');
 
+        // the tags do not count in the size limit
+        let tags = [
+            self.batch.map(|batch| format!("batch={batch}")),
+            self.time.map(|time| format!("time={time}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            buf.splice(0..0, format!("@{} ", tags.join(";")).into_bytes());
+        }
+
         // send
-        self.permit.send(SerializedMessage {
+        let message = SerializedMessage {
             bytes: buf,
             is_important: self.is_important,
-        });
+            is_membership_change: self.is_membership_change,
+        };
+        match self.permit {
+            Some(permit) => permit.send(message),
+            None => {
+                if let Some(labeled_response) = self.mailbox.labeled_response.lock().as_mut() {
+                    labeled_response.messages.push(message);
+                }
+            }
+        }
     }
 }
 
@@ -170,7 +465,9 @@ macro_rules! message_push {
 
 #[cfg(test)]
 mod tests {
-    use super::Mailbox;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format_server_time, Mailbox};
 
     macro_rules! message {
         ($s:expr, $($args:expr),*) => {{
@@ -182,6 +479,24 @@ mod tests {
         }}
     }
 
+    #[test]
+    fn test_format_server_time() {
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        assert_eq!(format_server_time(at(0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_server_time(at(1_319_042_451_620)),
+            "2011-10-19T16:40:51.620Z"
+        );
+        assert_eq!(
+            format_server_time(at(951_868_799_999)),
+            "2000-02-29T23:59:59.999Z"
+        );
+        assert_eq!(
+            format_server_time(at(1_704_067_200_000)),
+            "2024-01-01T00:00:00.000Z"
+        );
+    }
+
     #[test]
     fn test_empty() {
         let (mailbox, mut sink) = Mailbox::new(10);
@@ -209,14 +524,18 @@ mod tests {
         sink.try_recv().unwrap();
         sink.try_recv().unwrap();
         sink.try_recv().unwrap_err();
+        assert_eq!(sink.take_dropped_count(), 1);
+        assert_eq!(sink.take_dropped_count(), 0);
 
         // the mailbox still works after reading messages
         mw.new_message().unwrap().validate();
         mw.new_message().unwrap().validate();
         assert!(mw.new_message().is_none());
+        assert!(mw.new_message().is_none());
         sink.try_recv().unwrap();
         sink.try_recv().unwrap();
         sink.try_recv().unwrap_err();
+        assert_eq!(sink.take_dropped_count(), 2);
     }
 
     #[test]
@@ -372,4 +691,25 @@ mod tests {
 
         sink.try_recv().unwrap_err();
     }
+
+    #[test]
+    fn test_batch_tag() {
+        let (mut mailbox, mut sink) = Mailbox::new(10);
+        mailbox.set_batch(Some("ref".to_string()));
+        let mut mw = mailbox.writer(false);
+        message!(mw, &"test");
+        let long = "a".repeat(600);
+        message!(mw, &long);
+        let msg = sink.try_recv().unwrap();
+        assert_eq!(String::from_utf8(msg.bytes).unwrap(), "@batch=ref test\r\n");
+        // the tag does not take room from the message
+        let msg = sink.try_recv().unwrap();
+        assert_eq!(msg.bytes.len(), "@batch=ref ".len() + 512);
+
+        mailbox.set_batch(None);
+        let mut mw = mailbox.writer(false);
+        message!(mw, &"test");
+        let msg = sink.try_recv().unwrap();
+        assert_eq!(String::from_utf8(msg.bytes).unwrap(), "test\r\n");
+    }
 }
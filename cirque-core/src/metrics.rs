@@ -0,0 +1,301 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+/// Number of bytes exchanged with the clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl TrafficCounters {
+    fn add(&self, traffic: Traffic) {
+        self.bytes_received
+            .fetch_add(traffic.bytes_received, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(traffic.bytes_sent, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> Traffic {
+        Traffic {
+            bytes_received: self.bytes_received.swap(0, Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn get(&self) -> Traffic {
+        Traffic {
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Reason why the handshake of a connection (the TLS one) failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// not completed before the deadline of the listener
+    Timeout,
+    /// the client rejected the certificate of the server
+    BadCertificate,
+    /// the client does not speak TLS, or no common version or cipher was found
+    Protocol,
+    /// the connection was closed or reset during the handshake
+    Connection,
+}
+
+/// Number of failed handshakes, by reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeFailures {
+    pub timeout: u64,
+    pub bad_certificate: u64,
+    pub protocol: u64,
+    pub connection: u64,
+}
+
+#[derive(Debug, Default)]
+struct HandshakeFailureCounters {
+    timeout: AtomicU64,
+    bad_certificate: AtomicU64,
+    protocol: AtomicU64,
+    connection: AtomicU64,
+}
+
+impl HandshakeFailureCounters {
+    fn add(&self, failure: HandshakeFailure) {
+        let counter = match failure {
+            HandshakeFailure::Timeout => &self.timeout,
+            HandshakeFailure::BadCertificate => &self.bad_certificate,
+            HandshakeFailure::Protocol => &self.protocol,
+            HandshakeFailure::Connection => &self.connection,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> HandshakeFailures {
+        HandshakeFailures {
+            timeout: self.timeout.load(Ordering::Relaxed),
+            bad_certificate: self.bad_certificate.load(Ordering::Relaxed),
+            protocol: self.protocol.load(Ordering::Relaxed),
+            connection: self.connection.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Upper bounds of the buckets of the command durations, the last bucket counts the longer ones.
+pub const COMMAND_DURATION_BUCKETS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Distribution of durations, as in COMMAND_DURATION_BUCKETS.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    /// number of durations in each bucket (not cumulative), one more than the bounds
+    pub counts: Vec<u64>,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct DurationCounters {
+    counts: [AtomicU64; COMMAND_DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl DurationCounters {
+    fn add(&self, duration: Duration) {
+        let bucket = COMMAND_DURATION_BUCKETS
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(COMMAND_DURATION_BUCKETS.len());
+        if let Some(count) = self.counts.get(bucket) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Histogram {
+        Histogram {
+            counts: self
+                .counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Distribution of the round trip times of the pings sent by the server, among the users that
+/// answered one. A high latency for most users points at the server, and for a few at their
+/// connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingLatencies {
+    pub users: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+}
+
+impl PingLatencies {
+    pub(crate) fn from_round_trips(mut round_trips: Vec<Duration>) -> Option<Self> {
+        round_trips.sort_unstable();
+        // nearest rank
+        let percentile = |p: usize| {
+            let rank = (round_trips.len() * p).div_ceil(100).max(1);
+            round_trips.get(rank - 1).copied()
+        };
+        Some(Self {
+            users: round_trips.len(),
+            p50: percentile(50)?,
+            p95: percentile(95)?,
+        })
+    }
+}
+
+/// Counters about the server. The sizes of the state are kept up to date after each modification
+/// of the state, and the traffic is reported periodically by the sessions.
+/// Reading them does not require to take the state lock.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    users: AtomicUsize,
+    registering_users: AtomicUsize,
+    channels: AtomicUsize,
+    plaintext_traffic: TrafficCounters,
+    tls_traffic: TrafficCounters,
+    /// traffic since the last rotation (plaintext and TLS)
+    period_traffic: TrafficCounters,
+    reloads: AtomicUsize,
+    command_durations: DurationCounters,
+    handshake_failures: HandshakeFailureCounters,
+    ping_latencies: Mutex<Option<PingLatencies>>,
+}
+
+impl ServerMetrics {
+    /// Number of registered users.
+    pub fn users(&self) -> usize {
+        self.users.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections that did not complete the registration yet.
+    pub fn registering_users(&self) -> usize {
+        self.registering_users.load(Ordering::Relaxed)
+    }
+
+    /// Number of channels with at least one user.
+    pub fn channels(&self) -> usize {
+        self.channels.load(Ordering::Relaxed)
+    }
+
+    /// Total traffic of the connections without TLS.
+    pub fn plaintext_traffic(&self) -> Traffic {
+        self.plaintext_traffic.get()
+    }
+
+    /// Total traffic of the connections with TLS.
+    pub fn tls_traffic(&self) -> Traffic {
+        self.tls_traffic.get()
+    }
+
+    /// Sessions accumulate their traffic locally and report it from time to time.
+    pub fn add_traffic(&self, is_tls: bool, traffic: Traffic) {
+        if is_tls {
+            self.tls_traffic.add(traffic);
+        } else {
+            self.plaintext_traffic.add(traffic);
+        }
+        self.period_traffic.add(traffic);
+    }
+
+    /// Number of times the configuration was reloaded since the start of the server.
+    pub fn reloads(&self) -> usize {
+        self.reloads.load(Ordering::Relaxed)
+    }
+
+    pub fn add_reload(&self) {
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Connections closed before the end of their handshake, reported by the sessions.
+    pub fn handshake_failures(&self) -> HandshakeFailures {
+        self.handshake_failures.get()
+    }
+
+    pub fn add_handshake_failure(&self, failure: HandshakeFailure) {
+        self.handshake_failures.add(failure);
+    }
+
+    /// Time during which the commands of the clients held the state lock.
+    pub fn command_durations(&self) -> Histogram {
+        self.command_durations.get()
+    }
+
+    pub(crate) fn add_command_duration(&self, duration: Duration) {
+        self.command_durations.add(duration);
+    }
+
+    /// Latency of the users as of the last maintenance, None if no user had answered a ping.
+    pub fn ping_latencies(&self) -> Option<PingLatencies> {
+        *self.ping_latencies.lock()
+    }
+
+    pub(crate) fn set_ping_latencies(&self, latencies: Option<PingLatencies>) {
+        *self.ping_latencies.lock() = latencies;
+    }
+
+    /// Returns the traffic since the previous rotation.
+    pub(crate) fn rotate_traffic(&self) -> Traffic {
+        self.period_traffic.take()
+    }
+
+    pub(crate) fn update_sizes(&self, users: usize, registering_users: usize, channels: usize) {
+        self.users.store(users, Ordering::Relaxed);
+        self.registering_users
+            .store(registering_users, Ordering::Relaxed);
+        self.channels.store(channels, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)] // fine in tests
+    use super::*;
+
+    #[test]
+    fn test_ping_latencies() {
+        assert_eq!(PingLatencies::from_round_trips(vec![]), None);
+
+        let round_trips = (1..=20).rev().map(Duration::from_millis).collect();
+        assert_eq!(
+            PingLatencies::from_round_trips(round_trips),
+            Some(PingLatencies {
+                users: 20,
+                p50: Duration::from_millis(10),
+                p95: Duration::from_millis(19),
+            })
+        );
+
+        let round_trips = vec![Duration::from_millis(7)];
+        let latencies = PingLatencies::from_round_trips(round_trips).unwrap();
+        assert_eq!(latencies.p50, Duration::from_millis(7));
+        assert_eq!(latencies.p95, Duration::from_millis(7));
+    }
+}
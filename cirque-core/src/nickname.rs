@@ -1,3 +1,11 @@
 pub(crate) fn cure_nickname(nickname: &str) -> Option<String> {
     decancer::cure!(nickname).map(|s| s.into()).ok()
 }
+
+/// Whether the nicknames are the same for the nickname registry, which compares them once cured.
+pub(crate) fn nicknames_match(nickname1: &str, nickname2: &str) -> bool {
+    match (cure_nickname(nickname1), cure_nickname(nickname2)) {
+        (Some(cured1), Some(cured2)) => cured1.eq_ignore_ascii_case(&cured2),
+        _ => nickname1.eq_ignore_ascii_case(nickname2),
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt;
+
+/// Three-digit code of a numeric reply. The constants are named as in the specifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Numeric(u16);
+
+impl Numeric {
+    pub(crate) const ERR_UNKNOWNERROR: Numeric = Numeric(400);
+    pub(crate) const ERR_NOSUCHNICK: Numeric = Numeric(401);
+    pub(crate) const ERR_NOSUCHCHANNEL: Numeric = Numeric(403);
+    pub(crate) const ERR_CANNOTSENDTOCHAN: Numeric = Numeric(404);
+    pub(crate) const ERR_TOOMANYCHANNELS: Numeric = Numeric(405);
+    pub(crate) const ERR_WASNOSUCHNICK: Numeric = Numeric(406);
+    pub(crate) const ERR_TOOMANYTARGETS: Numeric = Numeric(407);
+    pub(crate) const ERR_INVALIDCAPCMD: Numeric = Numeric(410);
+    pub(crate) const ERR_NORECIPIENT: Numeric = Numeric(411);
+    pub(crate) const ERR_NOTEXTTOSEND: Numeric = Numeric(412);
+    pub(crate) const ERR_UNKNOWNCOMMAND: Numeric = Numeric(421);
+    pub(crate) const ERR_NONICKNAMEGIVEN: Numeric = Numeric(431);
+    pub(crate) const ERR_ERRONEUSNICKNAME: Numeric = Numeric(432);
+    pub(crate) const ERR_NICKNAMEINUSE: Numeric = Numeric(433);
+    pub(crate) const ERR_UNAVAILRESOURCE: Numeric = Numeric(437);
+    pub(crate) const ERR_USERNOTINCHANNEL: Numeric = Numeric(441);
+    pub(crate) const ERR_NOTONCHANNEL: Numeric = Numeric(442);
+    pub(crate) const ERR_USERONCHANNEL: Numeric = Numeric(443);
+    pub(crate) const ERR_SUMMONDISABLED: Numeric = Numeric(445);
+    pub(crate) const ERR_USERSDISABLED: Numeric = Numeric(446);
+    pub(crate) const ERR_NONICKCHANGE: Numeric = Numeric(447);
+    pub(crate) const ERR_NOTREGISTERED: Numeric = Numeric(451);
+    pub(crate) const ERR_NEEDMOREPARAMS: Numeric = Numeric(461);
+    pub(crate) const ERR_PASSWDMISMATCH: Numeric = Numeric(464);
+    pub(crate) const ERR_YOUREBANNEDCREEP: Numeric = Numeric(465);
+    pub(crate) const ERR_LINKCHANNEL: Numeric = Numeric(470);
+    pub(crate) const ERR_UNKNOWNMODE: Numeric = Numeric(472);
+    pub(crate) const ERR_INVITEONLYCHAN: Numeric = Numeric(473);
+    pub(crate) const ERR_BANNEDFROMCHAN: Numeric = Numeric(474);
+    pub(crate) const ERR_BADCHANNELKEY: Numeric = Numeric(475);
+    pub(crate) const ERR_BANLISTFULL: Numeric = Numeric(478);
+    pub(crate) const ERR_BADCHANMASK: Numeric = Numeric(476);
+    pub(crate) const ERR_NOPRIVILEGES: Numeric = Numeric(481);
+    pub(crate) const ERR_CHANOPRIVSNEEDED: Numeric = Numeric(482);
+    pub(crate) const ERR_NOOPERHOST: Numeric = Numeric(491);
+    pub(crate) const ERR_UMODEUNKNOWNFLAG: Numeric = Numeric(501);
+    pub(crate) const ERR_USERSDONTMATCH: Numeric = Numeric(502);
+    pub(crate) const ERR_INVALIDMODEPARAM: Numeric = Numeric(696);
+}
+
+impl fmt::Display for Numeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03}", self.0)
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use parking_lot::Mutex;
+
+use crate::message_writer::Mailbox;
+use crate::server_to_client::{self, MessageContext};
+
+/// Stands for the nickname of the client while rendering a reply. It cannot be part of a
+/// nickname nor of the server name, so its first occurrence is always the nickname.
+const CLIENT_PLACEHOLDER: &str = "\0";
+
+/// Reply serialized once, whose lines are split around the nickname of the client.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct CachedReply {
+    pub(crate) lines: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CachedReply {
+    fn render<'a>(
+        message: impl FnOnce(&'a str) -> server_to_client::Message<'a>,
+        max_lines: usize,
+        context: &MessageContext,
+    ) -> Self {
+        let (mailbox, mut sink) = Mailbox::new(max_lines.max(1));
+        mailbox.ingest(&message(CLIENT_PLACEHOLDER), context);
+
+        let mut lines = vec![];
+        while let Ok(message) = sink.try_recv() {
+            let line = message.bytes();
+            let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+            let (prefix, suffix) = match line.iter().position(|&b| b == b'\0') {
+                Some(i) => (
+                    line.get(..i).unwrap_or_default(),
+                    line.get(i + 1..).unwrap_or_default(),
+                ),
+                None => (line, &b""[..]),
+            };
+            lines.push((prefix.to_vec(), suffix.to_vec()));
+        }
+        Self { lines }
+    }
+}
+
+/// The listener and whether the connection uses TLS determine the MOTD.
+type MotdKey = (Option<String>, bool);
+
+/// Replies of the registration that only depend on the config (ISUPPORT and MOTD), so that they
+/// are not rebuilt for each user while the state is locked.
+///
+/// The cache belongs to a version of the config, and a modified config starts with an empty one.
+#[derive(Debug, Default)]
+pub(crate) struct ReplyCache {
+    isupport: OnceLock<Arc<CachedReply>>,
+    motds: Mutex<HashMap<MotdKey, Arc<CachedReply>>>,
+}
+
+impl Clone for ReplyCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl ReplyCache {
+    pub(crate) fn isupport(
+        &self,
+        utf8_only: bool,
+        channel_limit: Option<usize>,
+        context: &MessageContext,
+    ) -> Arc<CachedReply> {
+        let reply = self.isupport.get_or_init(|| {
+            let message = |client| server_to_client::Message::ISupport {
+                client,
+                utf8_only,
+                channel_limit,
+            };
+            Arc::new(CachedReply::render(message, 1, context))
+        });
+        Arc::clone(reply)
+    }
+
+    pub(crate) fn motd(
+        &self,
+        listener: Option<&str>,
+        is_tls: bool,
+        motd: Option<&[Vec<u8>]>,
+        context: &MessageContext,
+    ) -> Arc<CachedReply> {
+        let key = (listener.map(str::to_string), is_tls);
+        let mut motds = self.motds.lock();
+        let reply = motds.entry(key).or_insert_with(|| {
+            let max_lines = motd.map_or(0, |motd| motd.len()) + 2;
+            let message = |client| server_to_client::Message::MOTD { client, motd };
+            Arc::new(CachedReply::render(message, max_lines, context))
+        });
+        Arc::clone(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::indexing_slicing)] // fine in tests
+    use super::*;
+
+    fn context() -> MessageContext {
+        MessageContext {
+            server_name: "srv".to_string(),
+            network_name: None,
+            catalog: Default::default(),
+            templates: Default::default(),
+            compat: Default::default(),
+        }
+    }
+
+    fn send(message: &server_to_client::Message<'_>) -> Vec<Vec<u8>> {
+        let (mailbox, mut sink) = Mailbox::new(10);
+        mailbox.ingest(message, &context());
+        std::iter::from_fn(|| sink.try_recv().ok())
+            .map(|m| m.bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_cached_replies() {
+        let cache = ReplyCache::default();
+        let motd = vec![b"hello".to_vec(), b"world".to_vec()];
+        let reply = cache.motd(None, false, Some(&motd), &context());
+        assert_eq!(reply.lines.len(), 4);
+
+        let cached = send(&server_to_client::Message::Cached {
+            client: "nick1",
+            reply: &reply,
+        });
+        let direct = send(&server_to_client::Message::MOTD {
+            client: "nick1",
+            motd: Some(&motd),
+        });
+        assert_eq!(cached, direct);
+        assert_eq!(cached[1], b":srv 372 nick1 :- hello\r\n");
+
+        // the same MOTD is returned until the config changes
+        let other_motd = vec![b"bye".to_vec()];
+        let reply = cache.motd(None, false, Some(&other_motd), &context());
+        assert_eq!(reply.lines.len(), 4);
+        let reply = cache
+            .clone()
+            .motd(None, false, Some(&other_motd), &context());
+        assert_eq!(reply.lines.len(), 3);
+
+        let reply = cache.isupport(true, Some(5), &context());
+        let cached = send(&server_to_client::Message::Cached {
+            client: "nick1",
+            reply: &reply,
+        });
+        let direct = send(&server_to_client::Message::ISupport {
+            client: "nick1",
+            utf8_only: true,
+            channel_limit: Some(5),
+        });
+        assert_eq!(cached, direct);
+    }
+}
@@ -1,21 +1,38 @@
+use std::cell::Cell;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
-use parking_lot::RwLock;
+use arc_swap::ArcSwap;
+use cirque_parser::{LendingIterator, StreamParser};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::client_to_server::{ListFilter, ListOperation, ListOption, MessageDecodingError};
+use crate::capabilities::{capability_offer, CapResponse, Capability};
+use crate::client_to_server::{
+    parse_duration, ListFilter, ListOperation, ListOption, MessageDecodingError, SpamFilterCommand,
+};
+use crate::config::{DccPolicy, LookupConfig, ServerConfig, UnregisteredLimits};
 use crate::error::ServerStateError;
-use crate::message_writer::MailboxSink;
-use crate::nickname::cure_nickname;
+use crate::hooks::run_message_hooks;
+use crate::mask::mask_matches;
+use crate::message_writer::{MailboxSink, SerializedMessage};
+use crate::metrics::{PingLatencies, ServerMetrics};
+use crate::nickname::{cure_nickname, nicknames_match};
 use crate::server_to_client::{
-    self, ChannelInfo, MessageContext, NamesReply, UserhostReply, WhoReply,
+    self, format_duration, ChannelInfo, KLineInfo, MessageContext, NamesReply, OwnConnection,
+    UserhostReply, WhoReply, BATCH_VENDOR,
 };
+use crate::spam_filter::{SpamFilter, SpamFilterAction};
+use crate::throttle::ThrottleConfig;
 use crate::types::{
-    Channel, ChannelMode, ChannelUserMode, RegisteredUser, RegisteringUser, UserID, WelcomeConfig,
+    unix_timestamp, BanMask, Channel, ChannelMember, ChannelMode, ChannelSnapshot, ChannelUserMode,
+    ConnectionInfo, FloodLimit, HeldNickname, JoinThrottle, KLine, Lookup, RegisteredUser,
+    RegisteringUser, Topic, UserID, WelcomeConfig, WhowasEntry,
 };
 use crate::user_state::{RegisteredState, RegisteringState, UserState};
+use crate::visibility::{can_see_user, channel_visible, connection_visible, operator_visible};
 use crate::TimeoutConfig;
 
 enum LookupResult<'r> {
@@ -89,22 +106,89 @@ impl std::hash::Hash for BorrowedChannelID {
 }
 
 #[derive(Clone)]
-pub struct ServerState(Arc<RwLock<ServerStateInner>>);
+pub struct ServerState {
+    inner: Arc<RwLock<ServerStateInner>>,
+    config: Arc<ArcSwap<ServerConfig>>,
+    metrics: Arc<ServerMetrics>,
+    started_at: Instant,
+    /// users whose connection is closed, to be removed by the next access to the state
+    pending_disposals: Arc<Mutex<Vec<UserID>>>,
+}
+
+thread_local! {
+    /// Total time during which the current thread held the state lock, for reading or writing, so
+    /// that the time spent by a command can be measured around its handler.
+    static LOCK_HELD_FOR: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+pub(crate) fn lock_held_by_thread() -> Duration {
+    LOCK_HELD_FOR.get()
+}
+
+/// Read access to the state, counted in the time during which the thread held the lock.
+struct StateReadGuard<'a> {
+    guard: RwLockReadGuard<'a, ServerStateInner>,
+    locked_at: Instant,
+}
+
+impl std::ops::Deref for StateReadGuard<'_> {
+    type Target = ServerStateInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl Drop for StateReadGuard<'_> {
+    fn drop(&mut self) {
+        LOCK_HELD_FOR.set(LOCK_HELD_FOR.get() + self.locked_at.elapsed());
+    }
+}
+
+/// Write access to the state. The metrics are updated when the guard is released, so that they
+/// always reflect the last modification.
+struct StateWriteGuard<'a> {
+    guard: RwLockWriteGuard<'a, ServerStateInner>,
+    metrics: &'a ServerMetrics,
+    locked_at: Instant,
+}
+
+impl std::ops::Deref for StateWriteGuard<'_> {
+    type Target = ServerStateInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl std::ops::DerefMut for StateWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for StateWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.update_sizes(
+            self.guard.users.len(),
+            self.guard.registering_users.len(),
+            self.guard.channels.len(),
+        );
+        LOCK_HELD_FOR.set(LOCK_HELD_FOR.get() + self.locked_at.elapsed());
+    }
+}
 
 struct ServerStateInner {
     users: HashMap<UserID, RegisteredUser>,
     registering_users: HashMap<UserID, RegisteringUser>,
     channels: HashMap<ChannelID, Channel>,
-
-    // related to config:
-    server_name: String,
-    welcome_config: WelcomeConfig,
-    password: Option<Vec<u8>>,
-    motd: Option<Vec<Vec<u8>>>,
-    default_channel_mode: ChannelMode,
-    message_context: MessageContext,
-    messages_per_second_limit: u32,
-    timeout_config: Option<TimeoutConfig>,
+    klines: Vec<KLine>,
+    spam_filters: Vec<SpamFilter>,
+    held_nicknames: Vec<HeldNickname>,
+    /// users who left or changed nickname, the most recent last
+    whowas: VecDeque<WhowasEntry>,
+    /// recent failed OPER attempts, by IP
+    failed_opers: Vec<(IpAddr, Instant)>,
 }
 
 impl ServerState {
@@ -115,34 +199,292 @@ impl ServerState {
         password: Option<Vec<u8>>,
         timeout_config: Option<TimeoutConfig>,
     ) -> Self {
+        let config = ServerConfig::new(server_name, welcome_config, motd, password, timeout_config);
+        let config = Arc::new(ArcSwap::from_pointee(config));
         let sv = ServerStateInner {
             users: Default::default(),
             registering_users: Default::default(),
             channels: Default::default(),
+            klines: Default::default(),
+            spam_filters: Default::default(),
+            held_nicknames: Default::default(),
+            whowas: Default::default(),
+            failed_opers: Default::default(),
+        };
+        ServerState {
+            inner: Arc::new(RwLock::new(sv)),
+            config,
+            metrics: Default::default(),
+            started_at: Instant::now(),
+            pending_disposals: Default::default(),
+        }
+    }
 
-            server_name: server_name.to_owned(),
-            welcome_config: welcome_config.to_owned(),
-            motd,
-            password,
-            message_context: server_to_client::MessageContext {
-                server_name: server_name.to_string(),
-            },
-            default_channel_mode: Default::default(),
-            messages_per_second_limit: 10,
-            timeout_config,
+    /// The pending disposals are processed first (under the write lock), so that the replies
+    /// never mention a user whose connection is already closed.
+    fn read(&self) -> StateReadGuard<'_> {
+        if !self.pending_disposals.lock().is_empty() {
+            drop(self.write());
+        }
+        StateReadGuard {
+            guard: self.inner.read(),
+            locked_at: Instant::now(),
+        }
+    }
+
+    /// The pending disposals are processed first, so that the modifications never see a user
+    /// whose connection is already closed.
+    fn write(&self) -> StateWriteGuard<'_> {
+        let mut sv = StateWriteGuard {
+            guard: self.inner.write(),
+            metrics: &self.metrics,
+            locked_at: Instant::now(),
         };
-        ServerState(Arc::new(RwLock::new(sv)))
+        self.process_pending_disposals(&mut sv);
+        sv
     }
 
-    pub fn dispose_state(&self, state: UserState) {
-        match state {
-            UserState::Registering(state) => {
-                self.ruser_disconnects_suddently(state);
+    fn process_pending_disposals(&self, sv: &mut ServerStateInner) {
+        let config = self.config.load();
+        let user_ids = std::mem::take(&mut *self.pending_disposals.lock());
+        for user_id in user_ids {
+            // the user might have been removed already, in which case nothing happens
+            sv.ruser_disconnects_suddently(&config, user_id);
+            sv.user_disconnects_suddently(&config, user_id);
+        }
+    }
+
+    /// Counters that can be read without locking the state.
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    /// Time since the creation of the state, which survives the reloads of the configuration.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Latency of the users, from their last answered ping. None until a user answers one.
+    pub fn ping_latencies(&self) -> Option<PingLatencies> {
+        self.read().ping_latencies()
+    }
+
+    /// Number of users by hostname they connected to (TLS SNI), None counting the plaintext
+    /// connections and the clients that did not send one.
+    pub fn users_per_server_name(&self) -> BTreeMap<Option<String>, usize> {
+        self.read()
+            .users_per_server_name()
+            .into_iter()
+            .map(|(server_name, users)| (server_name.map(str::to_string), users))
+            .collect()
+    }
+
+    /// Lists the channels with their creation metadata and last activity.
+    pub fn channel_snapshots(&self) -> Vec<ChannelSnapshot> {
+        let sv = self.read();
+        sv.channels
+            .iter()
+            .map(|(name, channel)| ChannelSnapshot {
+                name: name.to_string(),
+                creator: channel.creator.clone(),
+                created_at: channel.created_at,
+                last_message_at: channel.last_message_at(),
+                users: channel.users.len(),
+            })
+            .collect()
+    }
+
+    /// Lists the members of the channel, None if the channel does not exist.
+    pub fn channel_members(&self, channel_name: &str) -> Option<Vec<ChannelMember>> {
+        let sv = self.read();
+        let channel = sv.channels.get(BorrowedChannelID::new(channel_name))?;
+        let members = channel
+            .users
+            .iter()
+            .filter_map(|(user_id, user_mode)| {
+                let user = sv.users.get(user_id)?;
+                Some(ChannelMember {
+                    user_id: *user_id,
+                    nickname: user.nickname.clone(),
+                    op: user_mode.is_op(),
+                    voice: user_mode.is_voice(),
+                })
+            })
+            .collect();
+        Some(members)
+    }
+
+    /// Lists the names of the channels joined by the user, None if the user is not registered.
+    pub fn user_channels(&self, user_id: UserID) -> Option<Vec<String>> {
+        let sv = self.read();
+        if !sv.users.contains_key(&user_id) {
+            return None;
+        }
+        let channels = sv
+            .channels
+            .iter()
+            .filter(|(_, channel)| channel.users.contains_key(&user_id))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        Some(channels)
+    }
+
+    /// Topic of the channel, None if the channel does not exist or has no topic.
+    pub fn channel_topic(&self, channel_name: &str) -> Option<Topic> {
+        let sv = self.read();
+        let channel = sv.channels.get(BorrowedChannelID::new(channel_name))?;
+        Some(channel.topic.clone()).filter(|topic| topic.is_valid())
+    }
+
+    /// Periodic housekeeping, to be called regularly (about every minute) by the server.
+    /// Everything that has to happen over time should be done from here instead of using
+    /// dedicated timers.
+    pub fn run_maintenance(&self) {
+        self.write().expire(&self.config.load(), Instant::now());
+        self.metrics
+            .set_ping_latencies(self.read().ping_latencies());
+
+        let traffic = self.metrics.rotate_traffic();
+        log::debug!(
+            "since last maintenance: received {} bytes, sent {} bytes",
+            traffic.bytes_received,
+            traffic.bytes_sent
+        );
+    }
+
+    /// Records the time during which a command held the state lock, and logs it when it exceeds
+    /// the configured threshold. The user is logged by its ID, so that reporting does not take the
+    /// state lock again.
+    pub(crate) fn report_command_duration(&self, user_id: UserID, command: &[u8], held: Duration) {
+        self.metrics.add_command_duration(held);
+
+        let threshold = self.config.load().slow_command_threshold;
+        if threshold.is_some_and(|threshold| held >= threshold) {
+            log::warn!(
+                "slow command {} from {user_id}: held the state lock for {held:?}",
+                String::from_utf8_lossy(command),
+            );
+        }
+    }
+
+    /// Handles the line as if it was received on the connection of the user.
+    /// Meant for fuzzers and tests, the sessions parse the stream themselves.
+    pub fn drive_raw_line(&self, mut user_state: UserState, line: &[u8]) -> UserState {
+        let mut stream_parser = StreamParser::default();
+        stream_parser.feed_from_slice(line);
+        stream_parser.feed_from_slice(b"\r\n");
+
+        let mut iter = stream_parser.consume_iter();
+        while let Some(message) = iter.next() {
+            if let Ok(message) = message {
+                user_state = user_state.handle_message(self, message);
+            }
+        }
+        user_state
+    }
+
+    /// Holds the replies to the command of the user, to be sent with the label once it is
+    /// handled. Returns false if the user did not negotiate labeled-response.
+    pub(crate) fn start_labeled_response(&self, user_id: UserID, label: &str) -> bool {
+        let sv = self.read();
+        if let Some(user) = sv.users.get(&user_id) {
+            user.start_labeled_response(label)
+        } else if let Some(user) = sv.registering_users.get(&user_id) {
+            user.start_labeled_response(label)
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn end_labeled_response(&self, user_id: UserID) {
+        let config = self.config.load();
+        let sv = self.read();
+        let server_name = &config.server_name;
+        if let Some(user) = sv.users.get(&user_id) {
+            user.end_labeled_response(server_name);
+        } else if let Some(user) = sv.registering_users.get(&user_id) {
+            user.end_labeled_response(server_name);
+        }
+    }
+
+    /// Concatenates messages coalesced by a session. If the user supports it, the consecutive
+    /// membership changes are wrapped in a BATCH.
+    pub fn frame_coalesced_messages(
+        &self,
+        user_id: UserID,
+        messages: &[SerializedMessage],
+    ) -> Vec<u8> {
+        let n_membership_changes = messages
+            .iter()
+            .take_while(|m| m.is_membership_change())
+            .count();
+        let use_batch = n_membership_changes > 1
+            && self
+                .read()
+                .users
+                .get(&user_id)
+                .is_some_and(|u| u.capabilities.has(Capability::Batch));
+
+        let mut buf = vec![];
+        if !use_batch {
+            for message in messages {
+                buf.extend_from_slice(message.bytes());
             }
-            UserState::Registered(state) => {
-                self.user_disconnects_suddently(state);
+            return buf;
+        }
+
+        let server_name = self.config.load().server_name.clone();
+        let batch_id = uuid::Uuid::new_v4().simple().to_string();
+        buf.extend_from_slice(
+            format!(":{server_name} BATCH +{batch_id} {BATCH_VENDOR}/membership\r\n").as_bytes(),
+        );
+        let (batched, others) = messages.split_at(n_membership_changes);
+        for message in batched {
+            // joins the tags of the message, such as its time
+            match message.bytes().strip_prefix(b"@") {
+                Some(tagged) => {
+                    buf.extend_from_slice(format!("@batch={batch_id};").as_bytes());
+                    buf.extend_from_slice(tagged);
+                }
+                None => {
+                    buf.extend_from_slice(format!("@batch={batch_id} ").as_bytes());
+                    buf.extend_from_slice(message.bytes());
+                }
             }
-            UserState::Disconnected => {}
+        }
+        buf.extend_from_slice(format!(":{server_name} BATCH -{batch_id}\r\n").as_bytes());
+        for message in others {
+            buf.extend_from_slice(message.bytes());
+        }
+        buf
+    }
+
+    /// Sends a NOTICE from the server to the user, about an event detected by the session
+    /// rather than by the state (such as the throttling of their messages).
+    pub fn notify_user(&self, user_id: UserID, content: &[u8]) {
+        self.read()
+            .notify_user(&self.config.load(), user_id, content);
+    }
+
+    /// Removes the user whose connection is closed. During a storm of disconnections, the
+    /// sessions do not wait for the state lock: the removal is queued and done before the next
+    /// access to the state (at the latest, the maintenance). Disposing of a user twice has no
+    /// effect.
+    pub fn dispose_state(&self, state: UserState) {
+        let user_id = match state {
+            UserState::Registering(state) => state.user_id,
+            UserState::Registered(state) => state.user_id,
+            UserState::Disconnected => return,
+        };
+        self.pending_disposals.lock().push(user_id);
+
+        if let Some(guard) = self.inner.try_write() {
+            let mut sv = StateWriteGuard {
+                guard,
+                metrics: &self.metrics,
+                locked_at: Instant::now(),
+            };
+            self.process_pending_disposals(&mut sv);
         }
     }
 }
@@ -156,13 +498,14 @@ impl ServerStateInner {
         let maybe_user = self
             .users
             .values()
-            .find(|&u| u.nickname.eq_ignore_ascii_case(target))
+            .find(|&u| nicknames_match(&u.nickname, target))
             .map(LookupResult::RegisteredUser);
         maybe_channel.into_iter().chain(maybe_user).next()
     }
 
     fn check_nickname(
         &self,
+        config: &ServerConfig,
         nickname: &str,
         user_id: Option<UserID>,
     ) -> Result<(), ServerStateError> {
@@ -193,6 +536,36 @@ impl ServerStateInner {
             });
         };
 
+        if config.is_reserved_nickname(&cured) {
+            return Err(ServerStateError::ReservedNickname {
+                client: client.to_string(),
+                nickname: nickname.into(),
+            });
+        }
+
+        let ip = user_id.and_then(|user_id| {
+            self.users
+                .get(&user_id)
+                .map(|u| u.connection_info.ip)
+                .or_else(|| {
+                    self.registering_users
+                        .get(&user_id)
+                        .map(|u| u.connection_info.ip)
+                })
+                .flatten()
+        });
+        let now = Instant::now();
+        if self
+            .held_nicknames
+            .iter()
+            .any(|h| h.prevents(&cured, ip, now))
+        {
+            return Err(ServerStateError::NickTemporarilyUnavailable {
+                client: client.to_string(),
+                nickname: nickname.into(),
+            });
+        }
+
         let another_user_has_same_nick = self
             .users
             .values()
@@ -224,62 +597,91 @@ impl ServerStateInner {
 }
 
 impl ServerState {
-    pub fn new_registering_user(&self) -> (UserState, MailboxSink) {
-        let mut sv = self.0.write();
+    pub fn new_registering_user(
+        &self,
+        connection_info: ConnectionInfo,
+    ) -> (UserState, MailboxSink) {
+        let config = self.config.load();
+        let timeout_config = self.get_timeout_config();
+        let mut sv = self.write();
+
+        sv.make_room_for_registering_user(&config, connection_info.ip);
 
         let mailbox_capacity = 128;
-        let (user, rx) = RegisteringUser::new(mailbox_capacity);
+        let (user, rx) = RegisteringUser::new(mailbox_capacity, connection_info);
         let user_id = user.user_id;
-        let state =
-            UserState::Registering(RegisteringState::new(user_id, sv.timeout_config.clone()));
+        let state = UserState::Registering(RegisteringState::new(user_id, timeout_config));
 
         sv.registering_users.insert(user.user_id, user);
 
         (state, rx)
     }
 
+    /// Modifies the config. The modifications are applied atomically, all at once.
+    pub fn update_config(&self, f: impl Fn(&mut ServerConfig)) {
+        self.config.rcu(|config| {
+            let mut config = ServerConfig::clone(config);
+            f(&mut config);
+            config
+        });
+    }
+
     pub fn set_server_name(&self, server_name: &str) {
-        let mut sv = self.0.write();
-        sv.server_name = server_name.to_string();
-        sv.message_context = server_to_client::MessageContext {
-            server_name: server_name.to_string(),
-        };
+        self.update_config(|config| config.set_server_name(server_name));
     }
 
     pub fn set_password(&self, password: Option<&[u8]>) {
-        let mut sv = self.0.write();
-        sv.password = password.map(|s| s.into());
+        self.update_config(|config| config.set_password(password));
     }
 
     pub fn set_motd(&self, motd: Option<Vec<Vec<u8>>>) {
-        let mut sv = self.0.write();
-        sv.motd = motd;
+        self.update_config(|config| config.set_motd(motd.clone()));
     }
 
-    pub fn get_messages_per_second_limit(&self) -> u32 {
-        let sv = self.0.read();
-        sv.messages_per_second_limit
+    /// Updates the reserved nicknames, and renames the users holding one of them.
+    pub fn set_reserved_nicknames(&self, reserved_nicknames: Vec<String>) {
+        self.update_config(|config| config.set_reserved_nicknames(reserved_nicknames.clone()));
+        let mut sv = self.write();
+        sv.reclaim_reserved_nicknames(&self.config.load());
     }
 
-    /// Warning: changing the value on ServerState does not affect existing clients.
-    pub fn set_messages_per_second_limit(&self, max_messages_per_second: u32) {
-        let mut sv = self.0.write();
-        sv.messages_per_second_limit = max_messages_per_second;
+    /// The config is only replaced when modified, so that the sessions can tell when it changed.
+    pub fn get_throttle_config(&self) -> Arc<ThrottleConfig> {
+        Arc::clone(&self.config.load().throttle_config)
     }
 
     pub fn set_default_channel_mode(&self, default_channel_mode: &ChannelMode) {
-        let mut sv = self.0.write();
-        sv.default_channel_mode = default_channel_mode.clone();
+        self.update_config(|config| config.set_default_channel_mode(default_channel_mode));
     }
 
     pub fn get_timeout_config(&self) -> Option<TimeoutConfig> {
-        let sv = self.0.read();
-        sv.timeout_config.clone()
+        self.config.load().timeout_config.clone()
     }
 
     pub fn set_timeout_config(&self, timeout: Option<TimeoutConfig>) {
-        let mut sv = self.0.write();
-        sv.timeout_config = timeout;
+        self.update_config(|config| config.set_timeout_config(timeout.clone()));
+    }
+
+    pub fn get_membership_coalescing(&self) -> Option<Duration> {
+        self.config.load().membership_coalescing
+    }
+
+    pub fn get_unregistered_limits(&self) -> UnregisteredLimits {
+        self.config.load().unregistered_limits
+    }
+
+    pub fn get_lookup_config(&self) -> LookupConfig {
+        self.config.load().lookup_config
+    }
+
+    /// Whether the IP belongs to a trusted gateway, exempt from the limits per IP and from the
+    /// throttling.
+    pub fn is_exempt_ip(&self, ip: IpAddr) -> bool {
+        self.config.load().is_exempt_ip(ip)
+    }
+
+    pub(crate) fn is_utf8_only(&self) -> bool {
+        self.config.load().utf8_only
     }
 }
 
@@ -290,7 +692,8 @@ impl ServerState {
         user_state: RegisteringState,
         error: MessageDecodingError<'_>,
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let user_id = user_state.user_id;
         let Some(user) = sv.registering_users.get(&user_state.user_id) else {
@@ -299,7 +702,7 @@ impl ServerState {
 
         let client = user.maybe_nickname();
         if let Some(err) = ServerStateError::from_decoding_error_with_client(error, client) {
-            sv.send_error(user_id, err);
+            sv.send_error(&config, user_id, err);
         }
 
         UserState::Registering(user_state)
@@ -310,8 +713,9 @@ impl ServerState {
         user_state: RegisteringState,
         password: &[u8],
     ) -> UserState {
+        let config = self.config.load();
         {
-            let mut sv = self.0.write();
+            let mut sv = self.write();
 
             let user_id = user_state.user_id;
             let Some(user) = sv.registering_users.get_mut(&user_id) else {
@@ -320,16 +724,17 @@ impl ServerState {
             user.password = Some(password.into());
         }
 
-        self.check_ruser_registration_state(user_state)
+        self.check_ruser_registration_state(&config, user_state)
     }
 
     pub(crate) fn ruser_uses_nick(&self, user_state: RegisteringState, nick: &str) -> UserState {
+        let config = self.config.load();
         {
-            let mut sv = self.0.write();
+            let mut sv = self.write();
 
             let user_id = user_state.user_id;
-            if let Err(err) = sv.check_nickname(nick, Some(user_id)) {
-                sv.send_error(user_id, err);
+            if let Err(err) = sv.check_nickname(&config, nick, Some(user_id)) {
+                sv.send_error(&config, user_id, err);
                 return UserState::Registering(user_state);
             }
             let Some(user) = sv.registering_users.get_mut(&user_id) else {
@@ -338,7 +743,7 @@ impl ServerState {
             user.nickname = Some(nick.into());
         }
 
-        self.check_ruser_registration_state(user_state)
+        self.check_ruser_registration_state(&config, user_state)
     }
 
     pub(crate) fn ruser_uses_username(
@@ -347,8 +752,9 @@ impl ServerState {
         username: &str,
         realname: &[u8],
     ) -> UserState {
+        let config = self.config.load();
         {
-            let mut sv = self.0.write();
+            let mut sv = self.write();
 
             let Entry::Occupied(mut user) = sv.registering_users.entry(user_state.user_id) else {
                 return UserState::Disconnected;
@@ -358,17 +764,18 @@ impl ServerState {
             user.realname = Some(realname.into());
         }
 
-        self.check_ruser_registration_state(user_state)
+        self.check_ruser_registration_state(&config, user_state)
     }
 
     pub(crate) fn ruser_pings(&self, user_state: RegisteringState, token: &[u8]) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let Some(user) = sv.registering_users.get(&user_state.user_id) else {
             return UserState::Disconnected;
         };
         let message = server_to_client::Message::Pong { token };
-        user.send(&message, &sv.message_context);
+        user.send(&message, &config.message_context);
         UserState::Registering(user_state)
     }
 
@@ -377,13 +784,14 @@ impl ServerState {
         user_state: RegisteringState,
         token: &[u8],
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let Some(user) = sv.registering_users.get(&user_state.user_id) else {
             return UserState::Disconnected;
         };
         let message = server_to_client::Message::Ping { token };
-        user.send(&message, &sv.message_context);
+        user.send(&message, &config.message_context);
         UserState::Registering(user_state)
     }
 
@@ -392,7 +800,8 @@ impl ServerState {
         user_state: RegisteringState,
         command: &str,
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let Some(user) = sv.registering_users.get(&user_state.user_id) else {
             return UserState::Disconnected;
@@ -401,7 +810,7 @@ impl ServerState {
             client: user.maybe_nickname(),
             command: command.to_owned(),
         });
-        user.send(&message, &sv.message_context);
+        user.send(&message, &config.message_context);
         UserState::Registering(user_state)
     }
 
@@ -409,7 +818,8 @@ impl ServerState {
         &self,
         user_state: RegisteringState,
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let Some(user) = sv.registering_users.get(&user_state.user_id) else {
             return UserState::Disconnected;
@@ -417,14 +827,94 @@ impl ServerState {
         let message = server_to_client::Message::Err(ServerStateError::NotRegistered {
             client: user.maybe_nickname(),
         });
-        user.send(&message, &sv.message_context);
+        user.send(&message, &config.message_context);
         UserState::Registering(user_state)
     }
 
-    fn check_ruser_registration_state(&self, user_state: RegisteringState) -> UserState {
-        let mut sv = self.0.write();
+    /// Tells the client about the lookup, its registration then waits for the answer.
+    pub(crate) fn ruser_starts_lookup(&self, user_state: &RegisteringState, lookup: Lookup) {
+        let mut sv = self.write();
+
+        let Some(user) = sv.registering_users.get_mut(&user_state.user_id) else {
+            return;
+        };
+        user.pending_lookups.push(lookup);
+
+        let config = self.config.load();
+        let content = match lookup {
+            Lookup::Hostname => "*** Looking up your hostname...",
+            Lookup::Ident => "*** Checking ident...",
+        };
+        let message = server_to_client::Message::Notice {
+            from_user: &config.server_name,
+            target: &user.maybe_nickname(),
+            content: config.message_context.catalog.translate(content).as_bytes(),
+        };
+        user.send(&message, &config.message_context);
+    }
+
+    /// The answer can come before or after NICK and USER, the registration completes with the
+    /// last of them. A None answer is a failed (or timed out) lookup.
+    pub(crate) fn ruser_finishes_lookup(
+        &self,
+        user_state: RegisteringState,
+        lookup: Lookup,
+        answer: Option<String>,
+    ) -> UserState {
+        let config = self.config.load();
+        {
+            let mut sv = self.write();
+
+            let Some(user) = sv.registering_users.get_mut(&user_state.user_id) else {
+                return UserState::Disconnected;
+            };
+            let Some(index) = user.pending_lookups.iter().position(|l| *l == lookup) else {
+                return UserState::Registering(user_state);
+            };
+            user.pending_lookups.swap_remove(index);
+
+            let content = match lookup {
+                Lookup::Hostname => {
+                    let hostname = answer.filter(|hostname| is_valid_hostname(hostname));
+                    let content = match hostname {
+                        Some(_) => "*** Found your hostname",
+                        None => "*** Couldn't look up your hostname",
+                    };
+                    user.connection_info.resolved_hostname = hostname;
+                    content
+                }
+                Lookup::Ident => {
+                    let ident = answer.filter(|ident| is_valid_ident(ident));
+                    let content = match ident {
+                        Some(_) => "*** Got ident response",
+                        None => "*** No ident response",
+                    };
+                    user.ident_checked = true;
+                    user.connection_info.ident = ident;
+                    content
+                }
+            };
+
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.maybe_nickname(),
+                content: config.message_context.catalog.translate(content).as_bytes(),
+            };
+            user.send(&message, &config.message_context);
+        }
+
+        self.check_ruser_registration_state(&config, user_state)
+    }
+
+    fn check_ruser_registration_state(
+        &self,
+        config: &ServerConfig,
+        user_state: RegisteringState,
+    ) -> UserState {
+        let mut sv = self.write();
 
         let user_id = user_state.user_id;
+        sv.forget_lost_resumed_session(config, user_id);
         let Entry::Occupied(user) = sv.registering_users.entry(user_id) else {
             return UserState::Disconnected;
         };
@@ -433,129 +923,525 @@ impl ServerState {
             return UserState::Registering(user_state);
         }
 
-        let user = user.remove();
+        let mut user = user.remove();
+        if user.ident_checked {
+            let ident = user.connection_info.ident.clone();
+            if let Some(username) = &mut user.username {
+                *username = ident.unwrap_or_else(|| format!("~{username}"));
+            }
+        }
 
         use subtle::ConstantTimeEq;
         let user_password = user.password.as_deref().unwrap_or_default();
-        let sv_password = sv.password.as_deref().unwrap_or_default();
+        let sv_password = config.password.as_deref().unwrap_or_default();
         if user_password.ct_ne(sv_password).into() {
             let message = server_to_client::Message::Err(ServerStateError::PasswdMismatch {
                 client: user.maybe_nickname(),
             });
-            user.send(&message, &sv.message_context);
+            user.send(&message, &config.message_context);
+            return UserState::Disconnected;
+        }
+
+        let now = Instant::now();
+        let kline_target = user.kline_target();
+        if sv
+            .klines
+            .iter()
+            .any(|k| k.is_active(now) && mask_matches(&k.mask, &kline_target))
+        {
+            let message = server_to_client::Message::Err(ServerStateError::YoureBannedCreep {
+                client: user.maybe_nickname(),
+            });
+            user.send(&message, &config.message_context);
             return UserState::Disconnected;
         }
 
-        let user = RegisteredUser::from(user);
-        sv.user_registers(user);
+        let hostname = config.hostname_policy.hostname_for(&user.connection_info);
+        match user.resumes {
+            Some(resumed_id) => sv.user_resumes_session(config, user, resumed_id, &hostname),
+            None => {
+                let user = RegisteredUser::from_registering(user, hostname);
+                sv.user_registers(config, user);
+            }
+        }
         UserState::Registered(RegisteredState::from_registering_state(user_state))
     }
 
+    pub(crate) fn ruser_resumes(&self, user_state: RegisteringState, token: &str) -> UserState {
+        let config = self.config.load();
+        {
+            let mut sv = self.write();
+            let user_id = user_state.user_id;
+            if let Err(err) = sv.ruser_resumes(user_id, token) {
+                sv.send_error(&config, user_id, err);
+            }
+        }
+
+        self.check_ruser_registration_state(&config, user_state)
+    }
+
+    pub(crate) fn user_resumes_too_late(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.send_error(
+            &config,
+            user_state.user_id,
+            ServerStateError::ResumeRegistrationCompleted {},
+        );
+        UserState::Registered(user_state)
+    }
+
     pub(crate) fn ruser_disconnects_voluntarily(
         &self,
         user_state: RegisteringState,
         reason: Option<&[u8]>,
     ) -> UserState {
-        let mut sv = self.0.write();
+        let mut sv = self.write();
 
         let reason = reason.unwrap_or(b"Client Quit");
 
-        let reason = &b"Closing Link: "
-            .iter()
-            .copied()
-            .chain(sv.server_name.as_bytes().iter().copied())
-            .chain(b" (".iter().copied())
-            .chain(reason.iter().copied())
-            .chain(b")".iter().copied())
-            .collect::<Vec<u8>>();
-
         let user_id = user_state.user_id;
         let Entry::Occupied(user) = sv.registering_users.entry(user_id) else {
             return UserState::Disconnected;
         };
 
-        let message = server_to_client::Message::FatalError { reason };
         let user = user.remove();
-        user.send(&message, &sv.message_context);
+        let config = self.config.load();
+        let reason = config
+            .message_context
+            .closing_link(&user.maybe_nickname(), reason);
+        let message = server_to_client::Message::FatalError { reason: &reason };
+        user.send(&message, &config.message_context);
         UserState::Disconnected
     }
 
     pub fn ruser_disconnects_suddently(&self, user_state: RegisteringState) -> UserState {
-        let mut sv = self.0.write();
-
-        let reason = b"connection closed";
-
-        let user_id = user_state.user_id;
-        let Entry::Occupied(user) = sv.registering_users.entry(user_id) else {
-            return UserState::Disconnected;
-        };
-        let message = server_to_client::Message::FatalError { reason };
-        let user = user.remove();
-        user.send(&message, &sv.message_context);
+        let config = self.config.load();
+        let mut sv = self.write();
+        sv.ruser_disconnects_suddently(&config, user_state.user_id);
         UserState::Disconnected
     }
 }
 
-/// Functions for registered users
 impl ServerStateInner {
-    fn send_error(&self, user_id: UserID, error: ServerStateError) {
-        if let Some(user) = self.users.get(&user_id) {
-            user.send(
-                &server_to_client::Message::Err(error),
-                &self.message_context,
-            );
-        } else if let Some(user) = self.registering_users.get(&user_id) {
-            user.send(
-                &server_to_client::Message::Err(error),
-                &self.message_context,
-            );
-        } else {
-            log::error!("user {user_id:?} not found on send_error for {error}");
+    fn ruser_resumes(&mut self, user_id: UserID, token: &str) -> Result<(), ServerStateError> {
+        let Some(user) = self.registering_users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        let resumed = user
+            .capabilities
+            .has(Capability::Resume)
+            .then(|| {
+                self.users
+                    .values()
+                    .find(|u| u.resume_token.as_deref() == Some(token))
+            })
+            .flatten();
+        let Some(resumed) = resumed else {
+            return Err(ServerStateError::ResumeInvalidToken {});
+        };
+        // the session would be exposed to the network
+        if resumed.connection_info.is_tls && !user.connection_info.is_tls {
+            return Err(ServerStateError::ResumeInsecureSession {});
         }
-    }
-}
 
-impl ServerState {
-    pub(crate) fn user_joins_channels(
-        &self,
-        user_state: RegisteredState,
-        channels: &[&str],
-    ) -> UserState {
-        let mut sv = self.0.write();
+        let resumed_id = resumed.user_id;
+        if let Some(user) = self.registering_users.get_mut(&user_id) {
+            user.resumes = Some(resumed_id);
+        }
+        Ok(())
+    }
 
-        let user_id = user_state.user_id;
-        for &channel in channels {
-            if let Err(err) = sv.user_joins_channel(user_id, channel) {
-                sv.send_error(user_id, err);
-            }
+    /// The resumed session might have been closed before the registration completes, in which
+    /// case the registration continues as a new session.
+    fn forget_lost_resumed_session(&mut self, config: &ServerConfig, user_id: UserID) {
+        let Some(user) = self.registering_users.get(&user_id) else {
+            return;
+        };
+        if user.resumes.is_none_or(|id| self.users.contains_key(&id)) {
+            return;
         }
 
-        UserState::Registered(user_state)
+        self.send_error(config, user_id, ServerStateError::ResumeInvalidToken {});
+        if let Some(user) = self.registering_users.get_mut(&user_id) {
+            user.resumes = None;
+        }
     }
-}
 
-impl ServerStateInner {
-    fn user_joins_channel(
+    /// Moves the session to the new connection, which then receives the welcome burst and the
+    /// state of the channels. The users sharing a channel with the session see a RESUMED, or a
+    /// CHGHOST if they do not support the resume capability.
+    fn user_resumes_session(
         &mut self,
-        user_id: UserID,
-        channel_name: &str,
-    ) -> Result<(), ServerStateError> {
-        let Some(user) = self.users.get(&user_id) else {
-            return Ok(()); // internal error
+        config: &ServerConfig,
+        connection: RegisteringUser,
+        resumed_id: UserID,
+        hostname: &str,
+    ) {
+        let Some(mut user) = self.users.remove(&resumed_id) else {
+            return; // internal error
         };
-        validate_channel_name(user, channel_name)?;
 
-        let channel = self
-            .channels
-            .entry(ChannelID(channel_name.to_string()))
-            .or_default();
+        let previous_fullspec = user.fullspec().to_string();
+        let previous_hostname = user.shown_hostname().to_string();
+        let user_id = connection.user_id;
+        let previous_mailbox = user.take_over(connection, hostname);
 
-        if channel.users.contains_key(&user_id) {
-            return Ok(());
+        let reason = config
+            .message_context
+            .closing_link(&user.nickname, b"Connection resumed elsewhere");
+        let message = server_to_client::Message::FatalError { reason: &reason };
+        previous_mailbox.ingest(&message, &config.message_context);
+        drop(previous_mailbox);
+
+        for channel in self.channels.values_mut() {
+            if let Some(user_mode) = channel.users.remove(&resumed_id) {
+                channel.users.insert(user_id, user_mode);
+            }
+            if let Some(expires_at) = channel.invites.remove(&resumed_id) {
+                channel.invites.insert(user_id, expires_at);
+            }
+            let flood_windows = channel.flood_windows.get_mut();
+            if let Some(window) = flood_windows.remove(&resumed_id) {
+                flood_windows.insert(user_id, window);
+            }
+        }
+
+        log::info!(
+            "{} resumed their session from {}",
+            user.nickname,
+            user.connection_info
+                .ip
+                .map_or("an unknown IP".to_string(), |ip| ip.to_string())
+        );
+
+        self.users.insert(user_id, user);
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+
+        let message = server_to_client::Message::ResumeSuccess {
+            nickname: &user.nickname,
+        };
+        user.send(&message, &config.message_context);
+        self.send_welcome_burst(config, user);
+
+        let mut channel_names = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| channel.users.contains_key(&user_id))
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        channel_names.sort_by(|a, b| a.0.cmp(&b.0));
+        for channel_name in channel_names {
+            let Some(channel) = self.channels.get(channel_name) else {
+                continue; // internal error
+            };
+            let message = server_to_client::Message::Join {
+                channel: &channel_name.0,
+                user_fullspec: user.fullspec(),
+            };
+            user.send(&message, &config.message_context);
+            self.send_topic_and_names(config, user, &channel_name.0, channel);
+        }
+
+        let message = server_to_client::Message::Resumed {
+            user_fullspec: &previous_fullspec,
+            hostname,
+        };
+        let host_changed = server_to_client::Message::ChgHost {
+            previous_user_fullspec: &previous_fullspec,
+            username: &user.username,
+            hostname,
+        };
+        for peer_id in self.user_and_peers(user_id) {
+            let Some(peer) = self.users.get(&peer_id).filter(|_| peer_id != user_id) else {
+                continue;
+            };
+            if peer.capabilities.has(Capability::Resume) {
+                peer.send(&message, &config.message_context);
+            } else if peer.capabilities.has(Capability::ChgHost) && hostname != previous_hostname {
+                peer.send(&host_changed, &config.message_context);
+            }
+        }
+    }
+
+    fn ruser_disconnects_suddently(&mut self, config: &ServerConfig, user_id: UserID) {
+        let reason = b"connection closed";
+
+        let Entry::Occupied(user) = self.registering_users.entry(user_id) else {
+            return;
+        };
+        let message = server_to_client::Message::FatalError { reason };
+        let user = user.remove();
+        user.send(&message, &config.message_context);
+    }
+}
+
+impl ServerStateInner {
+    /// Evicts the oldest registering users while the limits would be exceeded by a new one
+    /// connecting from `ip`.
+    fn make_room_for_registering_user(&mut self, config: &ServerConfig, ip: Option<IpAddr>) {
+        let ip = ip.filter(|&ip| !config.is_exempt_ip(ip));
+        if let (Some(limit), Some(ip)) = (config.max_registering_users_per_ip, ip) {
+            let same_ip = |u: &RegisteringUser| u.connection_info.ip == Some(ip);
+            while self
+                .registering_users
+                .values()
+                .filter(|u| same_ip(u))
+                .count()
+                >= limit
+            {
+                if !self.evict_oldest_registering_user(config, same_ip) {
+                    break;
+                }
+            }
+        }
+
+        if let Some(limit) = config.max_registering_users {
+            while self.registering_users.len() >= limit {
+                if !self.evict_oldest_registering_user(config, |_| true) {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn evict_oldest_registering_user(
+        &mut self,
+        config: &ServerConfig,
+        filter: impl Fn(&RegisteringUser) -> bool,
+    ) -> bool {
+        let Some(user_id) = self
+            .registering_users
+            .values()
+            .filter(|u| filter(u))
+            .min_by_key(|u| u.connected_at)
+            .map(|u| u.user_id)
+        else {
+            return false;
+        };
+        let Some(user) = self.registering_users.remove(&user_id) else {
+            return false;
+        };
+
+        let context = &config.message_context;
+        let reason = context.closing_link(
+            &user.maybe_nickname(),
+            context
+                .catalog
+                .translate("Too many unregistered connections")
+                .as_bytes(),
+        );
+        let message = server_to_client::Message::FatalError { reason: &reason };
+        user.send(&message, &config.message_context);
+        true
+    }
+}
+
+/// Functions for registered users
+impl ServerStateInner {
+    /// Server notice sent to all the operators, about events they should know of.
+    fn notify_operators(&self, config: &ServerConfig, content: &[u8]) {
+        let operators = self.users.values().filter(|u| u.is_operator);
+        for operator in operators {
+            self.notify_user(config, operator.user_id, content);
+        }
+    }
+
+    fn notify_user(&self, config: &ServerConfig, user_id: UserID, content: &[u8]) {
+        if let Some(user) = self.users.get(&user_id) {
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.nickname,
+                content,
+            };
+            user.send(&message, &config.message_context);
+        } else if let Some(user) = self.registering_users.get(&user_id) {
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.maybe_nickname(),
+                content,
+            };
+            user.send(&message, &config.message_context);
+        }
+    }
+
+    fn send_error(&self, config: &ServerConfig, user_id: UserID, error: ServerStateError) {
+        if let Some(user) = self.users.get(&user_id) {
+            user.send(
+                &server_to_client::Message::Err(error),
+                &config.message_context,
+            );
+        } else if let Some(user) = self.registering_users.get(&user_id) {
+            user.send(
+                &server_to_client::Message::Err(error),
+                &config.message_context,
+            );
+        } else {
+            log::error!("user {user_id} not found on send_error for {error}");
+        }
+    }
+}
+
+impl ServerState {
+    /// The keys are matched with the channels by position, an empty key standing for no key.
+    pub(crate) fn user_joins_channels(
+        &self,
+        user_state: RegisteredState,
+        channels: &[&str],
+        keys: &[&str],
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        let Some(user) = sv.users.get(&user_id) else {
+            return UserState::Registered(user_state); // internal error
+        };
+        let nickname = user.nickname.clone();
+
+        // the replies of each channel are kept together, and wrapped in a batch when possible
+        let batch = (channels.len() > 1 && user.capabilities.has(Capability::Batch))
+            .then(|| uuid::Uuid::new_v4().simple().to_string());
+        if let Some(batch) = &batch {
+            let message = server_to_client::Message::BatchStart {
+                reference: batch,
+                batch_type: "join",
+            };
+            user.send(&message, &config.message_context);
+            if let Some(user) = sv.users.get_mut(&user_id) {
+                user.set_batch(Some(batch.clone()));
+            }
+        }
+
+        for (i, &channel) in channels.iter().enumerate() {
+            let key = keys.get(i).copied().filter(|key| !key.is_empty());
+            // only the channels actually joined count against the limit, so a failed join does
+            // not prevent the next ones
+            let over_limit = config.max_channels_per_user.is_some_and(|max_channels| {
+                let is_member = sv
+                    .channels
+                    .get(BorrowedChannelID::new(channel))
+                    .is_some_and(|c| c.users.contains_key(&user_id));
+                let joined = sv
+                    .channels
+                    .values()
+                    .filter(|c| c.users.contains_key(&user_id))
+                    .count();
+                !is_member && joined >= max_channels
+            });
+            let result = if over_limit {
+                Err(ServerStateError::TooManyChannels {
+                    client: nickname.clone(),
+                    channel: channel.to_string(),
+                })
+            } else {
+                sv.user_joins_channel(&config, user_id, channel, key, true)
+            };
+            if let Err(err) = result {
+                sv.send_error(&config, user_id, err);
+            }
+        }
+
+        if let Some(batch) = &batch {
+            let Some(user) = sv.users.get_mut(&user_id) else {
+                return UserState::Registered(user_state); // internal error
+            };
+            user.set_batch(None);
+            let message = server_to_client::Message::BatchEnd { reference: batch };
+            user.send(&message, &config.message_context);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    /// When the channel has too many joins (+j), the user is forwarded to its overflow channel,
+    /// unless they were already forwarded.
+    fn user_joins_channel(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        channel_name: &str,
+        key: Option<&str>,
+        may_forward: bool,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+        validate_channel_name(user, channel_name)?;
+
+        let channel = self
+            .channels
+            .entry(ChannelID(channel_name.to_string()))
+            .or_default();
+
+        if channel.users.contains_key(&user_id) {
+            return Ok(());
+        }
+
+        if channel.is_banned(user) {
+            return Err(ServerStateError::BannedFromChan {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        }
+
+        if channel
+            .mode
+            .key()
+            .is_some_and(|channel_key| key != Some(channel_key))
+        {
+            return Err(ServerStateError::BadChannelKey {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        }
+
+        if channel.mode.is_invite_only() {
+            let is_invited = channel
+                .invites
+                .remove(&user_id)
+                .is_some_and(|expires_at| expires_at > Instant::now())
+                || channel.is_invite_exempt(user);
+            if !is_invited {
+                return Err(ServerStateError::InviteOnlyChan {
+                    client: user.nickname.clone(),
+                    channel: channel_name.to_string(),
+                });
+            }
+        }
+
+        if !channel.record_join_for_throttle(user, Instant::now()) {
+            let err = ServerStateError::JoinThrottled {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            };
+            let overflow = channel
+                .mode
+                .join_throttle()
+                .and_then(|join_throttle| join_throttle.overflow.clone());
+            let Some(overflow) = overflow.filter(|_| may_forward) else {
+                return Err(err);
+            };
+            let forward = ServerStateError::LinkChannel {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+                forward: overflow.clone(),
+            };
+            self.send_error(config, user_id, forward);
+            return self
+                .user_joins_channel(config, user_id, &overflow, None, false)
+                .or(Err(err));
         }
 
         let user_mode = if channel.users.is_empty() {
-            channel.mode = self.default_channel_mode.clone();
+            channel.mode = config.mode_of_new_channel(channel_name).clone();
+            channel.creator.clone_from(&user.nickname);
+            channel.created_at = unix_timestamp();
             ChannelUserMode::default().with_op()
         } else {
             ChannelUserMode::default()
@@ -564,30 +1450,49 @@ impl ServerStateInner {
         channel.users.insert(user_id, user_mode);
 
         // notify everyone, including the joiner
-        let mut nicknames = vec![];
         let joiner_spec = &user.fullspec();
         let message = server_to_client::Message::Join {
             channel: channel_name,
             user_fullspec: joiner_spec,
         };
-        for (user_id, user_mode) in &channel.users {
+        for user_id in channel.users.keys() {
             let Some(user) = self.users.get(user_id) else {
                 return Ok(()); // internal error
             };
-            nicknames.push((&user.nickname, user_mode));
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
         }
 
-        // send topic and names to the joiner
+        if let Some(channel) = self.channels.get(BorrowedChannelID::new(channel_name)) {
+            self.send_topic_and_names(config, user, channel_name, channel);
+        }
+        Ok(())
+    }
+
+    /// Replies following a JOIN, sent to the joiner.
+    fn send_topic_and_names(
+        &self,
+        config: &ServerConfig,
+        user: &RegisteredUser,
+        channel_name: &str,
+        channel: &Channel,
+    ) {
         if channel.topic.is_valid() {
             let message = server_to_client::Message::RplTopic {
                 client: &user.nickname,
                 channel: channel_name,
                 topic: Some(&channel.topic),
             };
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
         }
 
+        let nicknames = channel
+            .users
+            .iter()
+            .filter_map(|(user_id, user_mode)| {
+                let user = self.users.get(user_id)?;
+                Some((&user.nickname, user_mode))
+            })
+            .collect::<Vec<_>>();
         let message = server_to_client::Message::Names {
             client: &user.nickname,
             names: &[NamesReply {
@@ -595,10 +1500,9 @@ impl ServerStateInner {
                 channel_mode: &channel.mode,
                 nicknames: &nicknames,
             }],
+            multi_prefix: user.capabilities.has(Capability::MultiPrefix),
         };
-        user.send(&message, &self.message_context);
-
-        Ok(())
+        user.send(&message, &config.message_context);
     }
 }
 
@@ -608,12 +1512,13 @@ impl ServerState {
         user_state: RegisteredState,
         channels: &[&str],
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let user_id = user_state.user_id;
         for &channel in channels {
-            if let Err(err) = sv.user_names_channel(user_id, channel) {
-                sv.send_error(user_id, err);
+            if let Err(err) = sv.user_names_channel(&config, user_id, channel) {
+                sv.send_error(&config, user_id, err);
             }
         }
 
@@ -624,6 +1529,7 @@ impl ServerState {
 impl ServerStateInner {
     fn user_names_channel(
         &self,
+        config: &ServerConfig,
         user_id: UserID,
         channel_name: &str,
     ) -> Result<(), ServerStateError> {
@@ -638,25 +1544,27 @@ impl ServerStateInner {
                 client: &user.nickname,
                 channel: channel_name,
             };
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
             return Ok(());
         };
 
-        if channel.mode.is_secret() && !channel.users.contains_key(&user_id) {
+        if !channel_visible(user, channel) {
             let message = server_to_client::Message::EndOfNames {
                 client: &user.nickname,
                 channel: channel_name,
             };
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
             return Ok(());
         }
 
         let mut nicknames = vec![];
-        for (user_id, user_mode) in &channel.users {
-            let Some(user) = self.users.get(user_id) else {
+        for (member_id, member_mode) in &channel.users {
+            let Some(member) = self.users.get(member_id) else {
                 return Ok(()); // internal error
             };
-            nicknames.push((&user.nickname, user_mode));
+            if can_see_user(user, member) {
+                nicknames.push((&member.nickname, member_mode));
+            }
         }
 
         let message = server_to_client::Message::Names {
@@ -666,8 +1574,9 @@ impl ServerStateInner {
                 channel_mode: &channel.mode,
                 nicknames: &nicknames,
             }],
+            multi_prefix: user.capabilities.has(Capability::MultiPrefix),
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
         Ok(())
     }
 }
@@ -679,12 +1588,20 @@ impl ServerState {
         channels: &[&str],
         reason: Option<&[u8]>,
     ) -> UserState {
-        let mut sv = self.0.write();
+        let config = self.config.load();
+        // a filtered reason is dropped, but the user still leaves
+        let reason = match reason.map(|r| self.apply_spam_filters(user_state.user_id, r)) {
+            None | Some(SpamFilterOutcome::Allowed) => reason,
+            Some(SpamFilterOutcome::Blocked) => None,
+            Some(SpamFilterOutcome::Disconnected) => return UserState::Disconnected,
+        };
+
+        let mut sv = self.write();
 
         let user_id = user_state.user_id;
         for &channel in channels {
-            if let Err(err) = sv.user_leaves_channel(user_id, channel, reason) {
-                sv.send_error(user_id, err)
+            if let Err(err) = sv.user_leaves_channel(&config, user_id, channel, reason) {
+                sv.send_error(&config, user_id, err)
             }
         }
 
@@ -695,6 +1612,7 @@ impl ServerState {
 impl ServerStateInner {
     fn user_leaves_channel(
         &mut self,
+        config: &ServerConfig,
         user_id: UserID,
         channel_name: &str,
         reason: Option<&[u8]>,
@@ -728,7 +1646,7 @@ impl ServerStateInner {
             let Some(user) = self.users.get(user_id) else {
                 return Ok(()); // internal error
             };
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
         }
 
         channel.users.remove(&user_id);
@@ -747,14 +1665,26 @@ impl ServerState {
         user_state: RegisteredState,
         reason: Option<&[u8]>,
     ) -> UserState {
-        let mut sv = self.0.write();
-        sv.user_disconnects_voluntarily(user_state.user_id, reason);
+        let config = self.config.load();
+        let reason = match reason.map(|r| self.apply_spam_filters(user_state.user_id, r)) {
+            None | Some(SpamFilterOutcome::Allowed) => reason,
+            Some(SpamFilterOutcome::Blocked) => None,
+            Some(SpamFilterOutcome::Disconnected) => return UserState::Disconnected,
+        };
+
+        let mut sv = self.write();
+        sv.user_disconnects_voluntarily(&config, user_state.user_id, reason);
         UserState::Disconnected
     }
 }
 
 impl ServerStateInner {
-    fn user_disconnects_voluntarily(&mut self, user_id: UserID, reason: Option<&[u8]>) {
+    fn user_disconnects_voluntarily(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        reason: Option<&[u8]>,
+    ) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
@@ -771,42 +1701,51 @@ impl ServerStateInner {
                     let Some(user) = self.users.get(user_id) else {
                         return; // internal error
                     };
-                    user.send(&message, &self.message_context);
+                    user.send(&message, &config.message_context);
                 }
             }
         }
 
-        let reason = &b"Closing Link: "
-            .iter()
-            .copied()
-            .chain(self.server_name.as_bytes().iter().copied())
-            .chain(b" (".iter().copied())
-            .chain(reason.iter().copied())
-            .chain(b")".iter().copied())
-            .collect::<Vec<u8>>();
-        let message = server_to_client::Message::FatalError { reason };
-        user.send(&message, &self.message_context);
+        let reason = config.message_context.closing_link(&user.nickname, reason);
+        let message = server_to_client::Message::FatalError { reason: &reason };
+        user.send(&message, &config.message_context);
 
         self.channels.retain(|_, channel| !channel.users.is_empty());
+        self.remember_departed_user(user_id);
         self.users.remove(&user_id);
     }
 }
 
 impl ServerState {
     pub fn user_disconnects_suddently(&self, user_state: RegisteredState) -> UserState {
-        let mut sv = self.0.write();
-        sv.user_disconnects_suddently(user_state.user_id);
+        let config = self.config.load();
+        let mut sv = self.write();
+        sv.user_disconnects_suddently(&config, user_state.user_id);
         UserState::Disconnected
     }
 }
 
 impl ServerStateInner {
-    fn user_disconnects_suddently(&mut self, user_id: UserID) {
+    fn user_disconnects_suddently(&mut self, config: &ServerConfig, user_id: UserID) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
         let reason = b"connection closed";
 
+        // without IP, the user could not be told apart from the others when reconnecting
+        let grace_period = config.nickname_grace_period;
+        if let (Some(grace_period), Some(cured_nickname), Some(ip)) = (
+            grace_period,
+            cure_nickname(&user.nickname),
+            user.connection_info.ip,
+        ) {
+            self.held_nicknames.push(HeldNickname {
+                cured_nickname,
+                ip,
+                expires_at: Instant::now() + grace_period,
+            });
+        }
+
         let message = server_to_client::Message::Quit {
             user_fullspec: user.fullspec(),
             reason,
@@ -818,41 +1757,66 @@ impl ServerStateInner {
                     let Some(user) = self.users.get(user_id) else {
                         return; // internal error
                     };
-                    user.send(&message, &self.message_context);
+                    user.send(&message, &config.message_context);
                 }
             }
         }
 
         let message = server_to_client::Message::FatalError { reason };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
 
         self.channels.retain(|_, channel| !channel.users.is_empty());
+        self.remember_departed_user(user_id);
         self.users.remove(&user_id);
     }
 }
 
-impl ServerState {
-    pub(crate) fn user_changes_nick(
+impl ServerStateInner {
+    /// Channels with the +N mode prevent their members from changing nick.
+    fn check_user_can_change_nick(
         &self,
-        user_state: RegisteredState,
+        user_id: UserID,
         new_nick: &str,
-    ) -> UserState {
-        let mut sv = self.0.write();
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
 
-        let user_id = user_state.user_id;
+        if user.nickname == new_nick {
+            return Ok(());
+        }
 
-        if let Err(err) = sv.check_nickname(new_nick, Some(user_id)) {
-            sv.send_error(user_id, err);
-            return UserState::Registered(user_state);
+        for (channel_name, channel) in &self.channels {
+            if channel.users.contains_key(&user_id) {
+                channel.ensure_user_can_change_nick(user, channel_name.as_ref())?;
+            }
         }
 
-        let Some(user) = sv.users.get_mut(&user_id) else {
-            return UserState::Disconnected;
-        };
+        Ok(())
+    }
+}
 
-        if user.nickname == new_nick {
-            return UserState::Registered(user_state);
+impl ServerStateInner {
+    /// The user and the users sharing a channel with them, which are notified of the changes of
+    /// the user (nickname, host, ...).
+    fn user_and_peers(&self, user_id: UserID) -> HashSet<UserID> {
+        let mut users = HashSet::new();
+        users.insert(user_id);
+        for channel in self.channels.values() {
+            if channel.users.contains_key(&user_id) {
+                users.extend(channel.users.keys().copied());
+            }
         }
+        users
+    }
+
+    /// Changes the nickname of the user, and notifies them and the users sharing a channel.
+    fn rename_user(&mut self, config: &ServerConfig, user_id: UserID, new_nick: &str) {
+        self.remember_departed_user(user_id);
+        let users = self.user_and_peers(user_id);
+        let Some(user) = self.users.get_mut(&user_id) else {
+            return; // internal error
+        };
 
         let message = server_to_client::Message::Nick {
             #[allow(clippy::unnecessary_to_owned)]  // we cannot use a reference as we will modify
@@ -864,46 +1828,106 @@ impl ServerState {
 
         user.change_nickname(new_nick);
 
-        let mut users = HashSet::new();
-        users.insert(user_id);
-        for channel in sv.channels.values() {
-            if channel.users.contains_key(&user_id) {
-                for &user_id in channel.users.keys() {
-                    users.insert(user_id);
-                }
-            }
+        for user_id in users {
+            let Some(user) = self.users.get(&user_id) else {
+                continue; // internal error
+            };
+            user.send(&message, &config.message_context);
         }
+    }
+
+    /// Gives a guest nickname to the users holding a reserved nickname.
+    fn reclaim_reserved_nicknames(&mut self, config: &ServerConfig) {
+        let is_reserved = |nickname: &str| {
+            cure_nickname(nickname).is_some_and(|cured| config.is_reserved_nickname(&cured))
+        };
 
+        let users = self
+            .users
+            .values()
+            .filter(|u| is_reserved(&u.nickname))
+            .map(|u| u.user_id)
+            .collect::<Vec<_>>();
         for user_id in users {
-            let Some(user) = sv.users.get(&user_id) else {
-                return UserState::Disconnected; // internal error
-            };
-            user.send(&message, &sv.message_context);
+            self.rename_user(config, user_id, &guest_nickname(user_id));
         }
 
-        UserState::Registered(user_state)
+        for user in self.registering_users.values_mut() {
+            if user.nickname.as_deref().is_some_and(is_reserved) {
+                user.nickname = Some(guest_nickname(user.user_id));
+            }
+        }
     }
+}
 
-    pub(crate) fn user_messages_target(
+/// Nickname given to a user that lost theirs, derived from their unique ID to avoid collisions.
+fn guest_nickname(user_id: UserID) -> String {
+    let suffix = user_id.public_id().chars().take(11).collect::<String>();
+    format!("Guest{suffix}")
+}
+
+impl ServerState {
+    pub(crate) fn user_changes_nick(
         &self,
         user_state: RegisteredState,
-        target: &str,
-        content: &[u8],
+        new_nick: &str,
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let mut sv = self.write();
 
         let user_id = user_state.user_id;
-        if let Err(err) = sv.user_messages_target(user_id, target, content) {
-            sv.send_error(user_id, err);
+
+        if let Err(err) = sv.check_nickname(&config, new_nick, Some(user_id)) {
+            sv.send_error(&config, user_id, err);
+            return UserState::Registered(user_state);
+        }
+
+        if let Err(err) = sv.check_user_can_change_nick(user_id, new_nick) {
+            sv.send_error(&config, user_id, err);
+            return UserState::Registered(user_state);
+        }
+
+        let Some(user) = sv.users.get(&user_id) else {
+            return UserState::Disconnected;
+        };
+
+        if user.nickname == new_nick {
+            return UserState::Registered(user_state);
         }
 
+        sv.rename_user(&config, user_id, new_nick);
+
         UserState::Registered(user_state)
     }
-}
 
-impl ServerStateInner {
-    fn user_messages_target(
+    pub(crate) fn user_messages_target(
+        &self,
+        user_state: RegisteredState,
+        target: &str,
+        content: &[u8],
+    ) -> UserState {
+        let config = self.config.load();
+        match self.apply_spam_filters(user_state.user_id, content) {
+            SpamFilterOutcome::Allowed => {}
+            SpamFilterOutcome::Blocked => return UserState::Registered(user_state),
+            SpamFilterOutcome::Disconnected => return UserState::Disconnected,
+        }
+
+        let sv = self.read();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_messages_target(&config, user_id, target, content) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_messages_target(
         &self,
+        config: &ServerConfig,
         user_id: UserID,
         target: &str,
         content: &[u8],
@@ -918,6 +1942,13 @@ impl ServerStateInner {
             });
         }
 
+        if config.utf8_only && std::str::from_utf8(content).is_err() {
+            return Err(ServerStateError::InvalidUtf8 {
+                command: "PRIVMSG".to_string(),
+                parameter: "text".to_string(),
+            });
+        }
+
         let Some(obj) = self.lookup_target(target) else {
             return Err(ServerStateError::NoSuchNick {
                 client: user.nickname.to_string(),
@@ -925,38 +1956,61 @@ impl ServerStateInner {
             });
         };
 
+        if is_dcc_offer(content) && !self.relays_dcc(config, user, &obj) {
+            return Err(ServerStateError::DccBlocked {
+                target: target.to_string(),
+            });
+        }
+
+        let hooks = &config.message_hooks;
         match obj {
             LookupResult::Channel(channel_name, channel) => {
+                channel.ensure_user_can_send_message(user, target)?;
+                channel.record_message_for_flood_limit(user, target, Instant::now())?;
+                channel.record_activity(unix_timestamp());
+
+                let Some(content) = run_message_hooks(hooks, &user.nickname, target, content)
+                else {
+                    return Err(ServerStateError::CannotSendToChan {
+                        client: user.nickname.clone(),
+                        channel: channel_name.to_string(),
+                    });
+                };
                 let message = server_to_client::Message::PrivMsg {
                     from_user: user.fullspec(),
                     target: channel_name.as_ref(),
-                    content,
+                    content: &content,
                 };
 
-                channel.ensure_user_can_send_message(user, target)?;
-
                 channel
                     .users
                     .keys()
                     .filter(|&uid| *uid != user_id)
                     .flat_map(|u| self.users.get(u))
-                    .for_each(|u| u.send(&message, &self.message_context));
+                    .for_each(|u| u.send(&message, &config.message_context));
             }
             LookupResult::RegisteredUser(target_user) => {
+                let Some(content) = run_message_hooks(hooks, &user.nickname, target, content)
+                else {
+                    return Err(ServerStateError::CannotSendToChan {
+                        client: user.nickname.clone(),
+                        channel: target.to_string(),
+                    });
+                };
                 let message = server_to_client::Message::PrivMsg {
                     from_user: user.fullspec(),
                     target,
-                    content,
+                    content: &content,
                 };
-                target_user.send(&message, &self.message_context);
+                target_user.send(&message, &config.message_context);
 
-                if let Some(away_message) = &target_user.away_message {
+                if let Some(away_message) = target_user.away_message() {
                     let message = server_to_client::Message::RplAway {
                         client: &user.nickname,
                         target_nickname: &target_user.nickname,
                         away_message,
                     };
-                    user.send(&message, &self.message_context);
+                    user.send(&message, &config.message_context);
                 }
             }
         }
@@ -965,6 +2019,35 @@ impl ServerStateInner {
     }
 }
 
+/// CTCP DCC request, such as `\x01DCC SEND file 3232235777 5000 1024\x01`.
+fn is_dcc_offer(content: &[u8]) -> bool {
+    content
+        .get(..5)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"\x01DCC "))
+}
+
+impl ServerStateInner {
+    fn relays_dcc(
+        &self,
+        config: &ServerConfig,
+        user: &RegisteredUser,
+        target: &LookupResult<'_>,
+    ) -> bool {
+        let is_member = |channel: &Channel, user_id| channel.users.contains_key(user_id);
+        match config.dcc_policy {
+            DccPolicy::Allow => true,
+            DccPolicy::CoMembers => match target {
+                LookupResult::Channel(_, channel) => is_member(channel, &user.user_id),
+                LookupResult::RegisteredUser(target_user) => self
+                    .channels
+                    .values()
+                    .any(|c| is_member(c, &user.user_id) && is_member(c, &target_user.user_id)),
+            },
+            DccPolicy::Strip => false,
+        }
+    }
+}
+
 impl ServerState {
     pub(crate) fn user_notices_target(
         &self,
@@ -972,22 +2055,35 @@ impl ServerState {
         target: &str,
         content: &[u8],
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        match self.apply_spam_filters(user_state.user_id, content) {
+            SpamFilterOutcome::Allowed => {}
+            SpamFilterOutcome::Blocked => return UserState::Registered(user_state),
+            SpamFilterOutcome::Disconnected => return UserState::Disconnected,
+        }
+
+        let sv = self.read();
 
         let user_id = user_state.user_id;
-        sv.user_notices_target(user_id, target, content);
+        sv.user_notices_target(&config, user_id, target, content);
 
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_notices_target(&self, user_id: UserID, target: &str, content: &[u8]) {
+    fn user_notices_target(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        target: &str,
+        content: &[u8],
+    ) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
 
-        if content.is_empty() {
+        if content.is_empty() || (config.utf8_only && std::str::from_utf8(content).is_err()) {
             // NOTICE shouldn't receive an error
             return;
         }
@@ -997,17 +2093,31 @@ impl ServerStateInner {
             return;
         };
 
+        if is_dcc_offer(content) && !self.relays_dcc(config, user, &obj) {
+            return;
+        }
+
+        let hooks = &config.message_hooks;
         match obj {
             LookupResult::Channel(channel_name, channel) => {
-                if channel.ensure_user_can_send_message(user, target).is_err() {
+                if channel.ensure_user_can_send_message(user, target).is_err()
+                    || channel
+                        .record_message_for_flood_limit(user, target, Instant::now())
+                        .is_err()
+                {
                     // NOTICE shouldn't receive an error
                     return;
                 }
+                channel.record_activity(unix_timestamp());
 
+                let Some(content) = run_message_hooks(hooks, &user.nickname, target, content)
+                else {
+                    return;
+                };
                 let message = server_to_client::Message::PrivMsg {
                     from_user: user.fullspec(),
                     target: channel_name.as_ref(),
-                    content,
+                    content: &content,
                 };
 
                 channel
@@ -1015,15 +2125,19 @@ impl ServerStateInner {
                     .keys()
                     .filter(|&uid| *uid != user_id)
                     .flat_map(|u| self.users.get(u))
-                    .for_each(|u| u.send(&message, &self.message_context));
+                    .for_each(|u| u.send(&message, &config.message_context));
             }
             LookupResult::RegisteredUser(target_user) => {
+                let Some(content) = run_message_hooks(hooks, &user.nickname, target, content)
+                else {
+                    return;
+                };
                 let message = server_to_client::Message::Notice {
                     from_user: user.fullspec(),
                     target,
-                    content,
+                    content: &content,
                 };
-                target_user.send(&message, &self.message_context);
+                target_user.send(&message, &config.message_context);
             }
         }
     }
@@ -1035,10 +2149,11 @@ impl ServerState {
         user_state: RegisteredState,
         channel_name: &str,
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
         let user_id = user_state.user_id;
-        if let Err(err) = sv.user_asks_channel_mode(user_id, channel_name) {
-            sv.send_error(user_id, err);
+        if let Err(err) = sv.user_asks_channel_mode(&config, user_id, channel_name) {
+            sv.send_error(&config, user_id, err);
         }
         UserState::Registered(user_state)
     }
@@ -1047,6 +2162,7 @@ impl ServerState {
 impl ServerStateInner {
     fn user_asks_channel_mode(
         &self,
+        config: &ServerConfig,
         user_id: UserID,
         channel_name: &str,
     ) -> Result<(), ServerStateError> {
@@ -1063,13 +2179,21 @@ impl ServerStateInner {
             });
         };
 
+        let context = &config.message_context;
         let message = server_to_client::Message::ChannelMode {
             client: &user.nickname,
             channel: channel_name,
             mode: &channel.mode,
+            show_key: channel.users.contains_key(&user_id),
         };
+        user.send(&message, context);
 
-        user.send(&message, &self.message_context);
+        let message = server_to_client::Message::RplCreationTime {
+            client: &user.nickname,
+            channel: channel_name,
+            created_at: channel.created_at,
+        };
+        user.send(&message, context);
         Ok(())
     }
 }
@@ -1082,20 +2206,216 @@ impl ServerState {
         modechar: &str,
         param: Option<&str>,
     ) -> UserState {
-        let mut sv = self.0.write();
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) =
+            sv.user_changes_channel_mode(&config, user_id, channel_name, modechar, param)
+        {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+/// Largest number of list mode changes (+b) broadcast on a line, advertised as MODES.
+pub(crate) const MAX_MODES: usize = 4;
+
+/// Largest number of entries of each list mode (+b, +I) of a channel, advertised as MAXLIST.
+pub(crate) const MAX_LIST_ENTRIES: usize = 100;
+
+impl ServerState {
+    pub(crate) fn user_changes_channel_list_modes(
+        &self,
+        user_state: RegisteredState,
+        channel_name: &str,
+        modechars: &str,
+        masks: &[&str],
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
 
         let user_id = user_state.user_id;
-        if let Err(err) = sv.user_changes_channel_mode(user_id, channel_name, modechar, param) {
-            sv.send_error(user_id, err);
+        if let Err(err) =
+            sv.user_changes_channel_list_modes(&config, user_id, channel_name, modechars, masks)
+        {
+            sv.send_error(&config, user_id, err);
         }
 
         UserState::Registered(user_state)
     }
 }
 
+/// Prefix of the list mode entries that expire, as in `~time:1h:*!*@host`. A duration without
+/// unit is a number of minutes.
+const TIMED_ENTRY_PREFIX: &str = "~time:";
+
+impl ServerStateInner {
+    /// The masks are taken in the order of the modechars, the extra ones are ignored. A mask
+    /// given as the nickname of a user stands for their host.
+    fn user_changes_channel_list_modes(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        channel_name: &str,
+        modechars: &str,
+        masks: &[&str],
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+        validate_channel_name(user, channel_name)?;
+
+        let channel_id = BorrowedChannelID::new(channel_name);
+        let Some(channel) = self.channels.get_mut(channel_id) else {
+            return Err(ServerStateError::NoSuchChannel {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        };
+
+        channel.ensure_user_can_set_channel_mode(user, channel_name)?;
+
+        let now = Instant::now();
+        let mut masks = masks.iter();
+        let mut adding = true;
+        let mut changes: Vec<(bool, char, String)> = vec![];
+        let mut full_list = None;
+        for modechar in modechars.chars() {
+            let list = match modechar {
+                '+' => {
+                    adding = true;
+                    continue;
+                }
+                '-' => {
+                    adding = false;
+                    continue;
+                }
+                'b' => &mut channel.bans,
+                'I' => &mut channel.invite_exemptions,
+                _ => continue,
+            };
+            let Some(&mask) = masks.next() else {
+                break;
+            };
+
+            let (duration, mask) = mask
+                .strip_prefix(TIMED_ENTRY_PREFIX)
+                .and_then(|timed| timed.split_once(':'))
+                .and_then(|(duration, mask)| Some((parse_duration(duration)?, mask)))
+                .map_or((None, mask), |(duration, mask)| (Some(duration), mask));
+            let target = (!mask.contains(['!', '@']))
+                .then(|| {
+                    self.users
+                        .values()
+                        .find(|u| nicknames_match(&u.nickname, mask))
+                })
+                .flatten();
+            // the shown hostname may be shared by everyone (e.g. hidden), unlike the IP
+            let mask = match target {
+                Some(target) => match target.connection_info.ip {
+                    Some(ip) => format!("*!*@{ip}"),
+                    None => format!("{}!*@*", target.nickname),
+                },
+                None => BanMask::normalize(mask),
+            };
+
+            let position = list
+                .iter()
+                .position(|entry| entry.mask.eq_ignore_ascii_case(&mask));
+            match (adding, position) {
+                (true, None) if list.len() >= MAX_LIST_ENTRIES => {
+                    full_list.get_or_insert(modechar);
+                    continue;
+                }
+                (true, None) => list.push(BanMask {
+                    mask: mask.clone(),
+                    set_by: user.nickname.clone(),
+                    set_at: unix_timestamp(),
+                    expires_at: duration.map(|d| now + d),
+                }),
+                (false, Some(position)) => {
+                    list.remove(position);
+                }
+                _ => continue,
+            }
+            changes.push((adding, modechar, mask));
+        }
+        for (adding, modechar, mask) in &changes {
+            let sign = if *adding { '+' } else { '-' };
+            channel.log_action(&user.nickname, format!("MODE {sign}{modechar} {mask}"));
+        }
+
+        let context = &config.message_context;
+        broadcast_list_mode_changes(
+            &self.users,
+            channel,
+            user.fullspec(),
+            channel_name,
+            &changes,
+            context,
+        );
+
+        if let Some(modechar) = full_list {
+            return Err(ServerStateError::BanListFull {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+                modechar,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Sends the changes of the list modes to the members of the channel, by groups of MODES.
+fn broadcast_list_mode_changes(
+    users: &HashMap<UserID, RegisteredUser>,
+    channel: &Channel,
+    from: &str,
+    channel_name: &str,
+    changes: &[(bool, char, String)],
+    context: &MessageContext,
+) {
+    for changes in changes.chunks(MAX_MODES) {
+        let mut modechars = String::new();
+        let mut sign = None;
+        for &(adding, modechar, _) in changes {
+            if sign != Some(adding) {
+                modechars.push(if adding { '+' } else { '-' });
+                sign = Some(adding);
+            }
+            modechars.push(modechar);
+        }
+        let masks = changes
+            .iter()
+            .map(|(_, _, mask)| mask.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let message = server_to_client::Message::Mode {
+            user_fullspec: from,
+            target: channel_name,
+            modechar: &modechars,
+            param: Some(&masks),
+        };
+        for user in channel
+            .users
+            .keys()
+            .filter_map(|user_id| users.get(user_id))
+        {
+            user.send(&message, context);
+        }
+    }
+}
+
+/// Longest channel key (+k).
+const MAX_KEY_LENGTH: usize = 23;
+
 impl ServerStateInner {
     fn user_changes_channel_mode(
         &mut self,
+        config: &ServerConfig,
         user_id: UserID,
         channel_name: &str,
         modechar: &str,
@@ -1114,9 +2434,32 @@ impl ServerStateInner {
             });
         };
 
+        // anyone can see the ban list
+        if matches!(modechar, "b" | "+b") && param.is_none() {
+            let message = server_to_client::Message::RplBanList {
+                client: &user.nickname,
+                channel: channel_name,
+                bans: &channel.bans,
+            };
+            user.send(&message, &config.message_context);
+            return Ok(());
+        }
+
         channel.ensure_user_can_set_channel_mode(user, channel_name)?;
 
+        // but only the channel operators see who bypasses +i
+        if matches!(modechar, "I" | "+I") && param.is_none() {
+            let message = server_to_client::Message::RplInviteExemptionList {
+                client: &user.nickname,
+                channel: channel_name,
+                exemptions: &channel.invite_exemptions,
+            };
+            user.send(&message, &config.message_context);
+            return Ok(());
+        }
+
         let mut new_channel_mode = channel.mode.clone();
+        let mut mode_param = None;
         // TODO handle multiple modechars
         match modechar {
             "+s" => new_channel_mode = new_channel_mode.with_secret(),
@@ -1127,6 +2470,95 @@ impl ServerStateInner {
             "-m" => new_channel_mode = new_channel_mode.without_moderated(),
             "+n" => new_channel_mode = new_channel_mode.with_no_external(),
             "-n" => new_channel_mode = new_channel_mode.without_no_external(),
+            "+i" => new_channel_mode = new_channel_mode.with_invite_only(),
+            "-i" => new_channel_mode = new_channel_mode.without_invite_only(),
+            "+N" => new_channel_mode = new_channel_mode.with_no_nick_change(),
+            "-N" => new_channel_mode = new_channel_mode.without_no_nick_change(),
+            "+H" => new_channel_mode = new_channel_mode.with_action_log(),
+            "-H" => new_channel_mode = new_channel_mode.without_action_log(),
+            "+f" => {
+                let Some(param) = param else {
+                    return Err(ServerStateError::NeedMoreParams {
+                        client: user.nickname.clone(),
+                        command: "MODE".to_string(),
+                    });
+                };
+                let flood_limit = FloodLimit::try_from(param).map_err(|description| {
+                    ServerStateError::InvalidModeParam {
+                        client: user.nickname.clone(),
+                        target: channel_name.to_string(),
+                        modechar: 'f',
+                        param: param.to_string(),
+                        description,
+                    }
+                })?;
+                new_channel_mode = new_channel_mode.with_flood_limit(flood_limit);
+                mode_param = Some(param);
+            }
+            "-f" => new_channel_mode = new_channel_mode.without_flood_limit(),
+            "+j" => {
+                let Some(param) = param else {
+                    return Err(ServerStateError::NeedMoreParams {
+                        client: user.nickname.clone(),
+                        command: "MODE".to_string(),
+                    });
+                };
+                let join_throttle = JoinThrottle::try_from(param)
+                    .and_then(|join_throttle| {
+                        let overflow = join_throttle.overflow.as_deref();
+                        match overflow {
+                            Some(overflow) if overflow.eq_ignore_ascii_case(channel_name) => {
+                                Err("the overflow channel should be another channel".to_string())
+                            }
+                            _ => Ok(join_throttle),
+                        }
+                    })
+                    .map_err(|description| ServerStateError::InvalidModeParam {
+                        client: user.nickname.clone(),
+                        target: channel_name.to_string(),
+                        modechar: 'j',
+                        param: param.to_string(),
+                        description,
+                    })?;
+                new_channel_mode = new_channel_mode.with_join_throttle(join_throttle);
+                mode_param = Some(param);
+            }
+            "-j" => new_channel_mode = new_channel_mode.without_join_throttle(),
+            "+k" => {
+                let Some(param) = param else {
+                    return Err(ServerStateError::NeedMoreParams {
+                        client: user.nickname.clone(),
+                        command: "MODE".to_string(),
+                    });
+                };
+                let description = if param.len() > MAX_KEY_LENGTH {
+                    Some(format!("the key should be at most {MAX_KEY_LENGTH} bytes"))
+                } else if param.contains(|c: char| c == ',' || c.is_whitespace() || c.is_control())
+                {
+                    Some(
+                        "the key should not contain commas, spaces or control characters"
+                            .to_string(),
+                    )
+                } else {
+                    None
+                };
+                if let Some(description) = description {
+                    return Err(ServerStateError::InvalidModeParam {
+                        client: user.nickname.clone(),
+                        target: channel_name.to_string(),
+                        modechar: 'k',
+                        param: param.to_string(),
+                        description,
+                    });
+                }
+                new_channel_mode = new_channel_mode.with_key(param);
+                mode_param = Some(param);
+            }
+            // the key is not needed to remove it, but a parameter is always sent with -k
+            "-k" => {
+                new_channel_mode = new_channel_mode.without_key();
+                mode_param = Some("*");
+            }
             "+o" | "-o" | "+v" | "-v" => {
                 let Some(target) = param else {
                     return Err(ServerStateError::NeedMoreParams {
@@ -1135,7 +2567,11 @@ impl ServerStateInner {
                     });
                 };
 
-                let Some(target_user) = self.users.values().find(|&u| u.nickname == target) else {
+                let Some(target_user) = self
+                    .users
+                    .values()
+                    .find(|&u| nicknames_match(&u.nickname, target))
+                else {
                     return Err(ServerStateError::NoSuchNick {
                         client: user.nickname.clone(),
                         target: target.to_string(),
@@ -1143,6 +2579,8 @@ impl ServerStateInner {
                 };
 
                 let user_id = target_user.user_id;
+                // the nickname as the user chose it, rather than as given
+                let target = target_user.nickname.as_str();
                 let Some(cur_target_mode) = channel.users.get_mut(&user_id) else {
                     return Err(ServerStateError::UserNotInChannel {
                         client: user.nickname.clone(),
@@ -1168,6 +2606,7 @@ impl ServerStateInner {
 
                 if *cur_target_mode != new_target_mode {
                     *cur_target_mode = new_target_mode;
+                    channel.log_action(&user.nickname, format!("MODE {modechar} {target}"));
                     let message = server_to_client::Message::Mode {
                         user_fullspec: user.fullspec(),
                         target: channel_name,
@@ -1178,7 +2617,7 @@ impl ServerStateInner {
                         let Some(user) = self.users.get(user_id) else {
                             return Ok(()); // internal error
                         };
-                        user.send(&message, &self.message_context);
+                        user.send(&message, &config.message_context);
                     }
                 }
             }
@@ -1194,18 +2633,26 @@ impl ServerStateInner {
 
         if new_channel_mode != channel.mode {
             channel.mode = new_channel_mode;
+            if !channel.mode.is_action_log() {
+                channel.action_log.clear();
+            }
+            let action = match mode_param {
+                Some(param) => format!("MODE {modechar} {param}"),
+                None => format!("MODE {modechar}"),
+            };
+            channel.log_action(&user.nickname, action);
 
             let message = server_to_client::Message::Mode {
                 user_fullspec: user.fullspec(),
                 target: channel_name,
                 modechar,
-                param: None,
+                param: mode_param,
             };
             for user_id in channel.users.keys() {
                 let Some(user) = self.users.get(user_id) else {
                     return Ok(()); // internal error
                 };
-                user.send(&message, &self.message_context);
+                user.send(&message, &config.message_context);
             }
         }
 
@@ -1220,11 +2667,12 @@ impl ServerState {
         channel_name: &str,
         content: &[u8],
     ) -> UserState {
-        let mut sv = self.0.write();
+        let config = self.config.load();
+        let mut sv = self.write();
 
         let user_id = user_state.user_id;
-        if let Err(err) = sv.user_sets_topic(user_id, channel_name, content) {
-            sv.send_error(user_id, err);
+        if let Err(err) = sv.user_sets_topic(&config, user_id, channel_name, content) {
+            sv.send_error(&config, user_id, err);
         }
 
         UserState::Registered(user_state)
@@ -1234,6 +2682,7 @@ impl ServerState {
 impl ServerStateInner {
     fn user_sets_topic(
         &mut self,
+        config: &ServerConfig,
         user_id: UserID,
         channel_name: &str,
         content: &[u8],
@@ -1253,11 +2702,10 @@ impl ServerStateInner {
         channel.ensure_user_can_set_topic(user, channel_name)?;
 
         channel.topic.content = content.to_vec();
-        channel.topic.ts = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
+        channel.topic.ts = unix_timestamp();
         channel.topic.from_nickname.clone_from(&user.nickname);
+        let action = format!("TOPIC :{}", String::from_utf8_lossy(content));
+        channel.log_action(&user.nickname, action);
 
         let message = &server_to_client::Message::Topic {
             user_fullspec: user.fullspec(),
@@ -1268,7 +2716,7 @@ impl ServerStateInner {
             .users
             .keys()
             .flat_map(|u| self.users.get(u))
-            .for_each(|u| u.send(message, &self.message_context));
+            .for_each(|u| u.send(message, &config.message_context));
         Ok(())
     }
 }
@@ -1279,11 +2727,12 @@ impl ServerState {
         user_state: RegisteredState,
         channel_name: &str,
     ) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let user_id = user_state.user_id;
-        if let Err(err) = sv.user_wants_topic(user_id, channel_name) {
-            sv.send_error(user_id, err);
+        if let Err(err) = sv.user_wants_topic(&config, user_id, channel_name) {
+            sv.send_error(&config, user_id, err);
         }
 
         UserState::Registered(user_state)
@@ -1293,6 +2742,7 @@ impl ServerState {
 impl ServerStateInner {
     fn user_wants_topic(
         &self,
+        config: &ServerConfig,
         user_id: UserID,
         channel_name: &str,
     ) -> Result<(), ServerStateError> {
@@ -1325,67 +2775,122 @@ impl ServerStateInner {
                 None
             },
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
         Ok(())
     }
 }
 
 impl ServerStateInner {
-    fn user_registers(&mut self, user: RegisteredUser) {
+    fn user_registers(&mut self, config: &ServerConfig, user: RegisteredUser) {
+        self.send_welcome_burst(config, &user);
+        self.users.insert(user.user_id, user);
+    }
+
+    /// Replies sent at the end of the registration, from RPL_WELCOME to the MOTD.
+    fn send_welcome_burst(&self, config: &ServerConfig, user: &RegisteredUser) {
         let message = server_to_client::Message::Welcome {
             nickname: &user.nickname,
             user_fullspec: user.fullspec(),
-            welcome_config: &self.welcome_config,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
+
+        // chirch doesn't like 005, but it's better with it for irctest
+        if config.welcome_config.send_isupport {
+            let reply = config.reply_cache.isupport(
+                config.utf8_only,
+                config.max_channels_per_user,
+                &config.message_context,
+            );
+            let message = server_to_client::Message::Cached {
+                client: &user.nickname,
+                reply: &reply,
+            };
+            user.send(&message, &config.message_context);
+        }
+
+        if config.welcome_config.send_your_id {
+            let message = server_to_client::Message::RplYourId {
+                client: &user.nickname,
+                id: &user.user_id.public_id(),
+            };
+            user.send(&message, &config.message_context);
+        }
 
         let message = server_to_client::Message::LUsers {
             client: &user.nickname,
-            n_operators: 0,
+            n_operators: self
+                .users
+                .values()
+                .filter(|u| operator_visible(user, u))
+                .count(),
             n_unknown_connections: self.registering_users.len(),
             n_channels: self.channels.len(),
             n_clients: self.users.len(),
             n_other_servers: 0,
             extra_info: false,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
 
-        let message = server_to_client::Message::MOTD {
+        let reply = config.reply_cache.motd(
+            user.connection_info.listener.as_deref(),
+            user.connection_info.is_tls,
+            config.motd_for(&user.connection_info),
+            &config.message_context,
+        );
+        let message = server_to_client::Message::Cached {
             client: &user.nickname,
-            motd: self.motd.as_deref(),
+            reply: &reply,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
 
-        self.users.insert(user.user_id, user);
+        if let Some(deadline) = config.oper_deadline_for(&user.connection_info) {
+            let content = config.message_context.catalog.format(
+                "This port is reserved to the operators, use OPER within {seconds} seconds",
+                &[("seconds", &deadline.as_secs().to_string())],
+            );
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.nickname,
+                content: content.as_bytes(),
+            };
+            user.send(&message, &config.message_context);
+        }
+
+        if let Some(token) = &user.resume_token {
+            let message = server_to_client::Message::ResumeToken { token };
+            user.send(&message, &config.message_context);
+        }
     }
 }
 
 impl ServerState {
     pub(crate) fn user_pings(&self, user_state: RegisteredState, token: &[u8]) -> UserState {
-        let sv = self.0.read();
-        sv.user_pings(user_state.user_id, token);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_pings(&config, user_state.user_id, token);
         UserState::Registered(user_state)
     }
 
     pub(crate) fn send_ping_to_user(&self, user_state: RegisteredState, token: &[u8]) -> UserState {
-        let sv = self.0.read();
+        let config = self.config.load();
+        let sv = self.read();
 
         let Some(user) = sv.users.get(&user_state.user_id) else {
             return UserState::Disconnected;
         };
         let message = server_to_client::Message::Ping { token };
-        user.send(&message, &sv.message_context);
+        user.send(&message, &config.message_context);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_pings(&self, user_id: UserID, token: &[u8]) {
+    fn user_pings(&self, config: &ServerConfig, user_id: UserID, token: &[u8]) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
         let message = server_to_client::Message::Pong { token };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
     }
 }
 
@@ -1395,14 +2900,15 @@ impl ServerState {
         user_state: RegisteredState,
         command: &str,
     ) -> UserState {
-        let sv = self.0.read();
-        sv.user_sends_unknown_command(user_state.user_id, command);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_sends_unknown_command(&config, user_state.user_id, command);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_sends_unknown_command(&self, user_id: UserID, command: &str) {
+    fn user_sends_unknown_command(&self, config: &ServerConfig, user_id: UserID, command: &str) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
@@ -1410,97 +2916,217 @@ impl ServerStateInner {
             client: user.nickname.clone(),
             command: command.to_owned(),
         });
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
     }
 }
 
 impl ServerState {
-    pub(crate) fn user_sends_invalid_message(
+    pub(crate) fn user_sends_unsupported_command(
         &self,
         user_state: RegisteredState,
-        error: MessageDecodingError<'_>,
+        command: &str,
     ) -> UserState {
-        let sv = self.0.read();
-        sv.user_sends_invalid_message(user_state.user_id, error);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_sends_unsupported_command(&config, user_state.user_id, command);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_sends_invalid_message(&self, user_id: UserID, error: MessageDecodingError<'_>) {
+    fn user_sends_unsupported_command(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        command: &str,
+    ) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
+
+        let context = &config.message_context;
+        if !context.compat.unsupported_commands {
+            self.user_sends_unknown_command(config, user_id, command);
+            return;
+        }
+
         let client = user.nickname.clone();
-        if let Some(err) = ServerStateError::from_decoding_error_with_client(error, client) {
-            self.send_error(user_id, err);
+        if command.eq_ignore_ascii_case("MAP") {
+            let message = server_to_client::Message::Map { client: &client };
+            user.send(&message, context);
+        } else if command.eq_ignore_ascii_case("USERS") {
+            self.send_error(config, user_id, ServerStateError::UsersDisabled { client });
+        } else {
+            self.send_error(config, user_id, ServerStateError::SummonDisabled { client });
         }
     }
 }
 
 impl ServerState {
-    pub(crate) fn user_wants_motd(&self, user_state: RegisteredState) -> UserState {
-        let sv = self.0.read();
-        sv.user_wants_motd(user_state.user_id);
+    pub(crate) fn user_sends_invalid_message(
+        &self,
+        user_state: RegisteredState,
+        error: MessageDecodingError<'_>,
+    ) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_sends_invalid_message(&config, user_state.user_id, error);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_wants_motd(&self, user_id: UserID) {
+    fn user_sends_invalid_message(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        error: MessageDecodingError<'_>,
+    ) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
-        let message = server_to_client::Message::MOTD {
-            client: &user.nickname,
-            motd: self.motd.as_deref(),
-        };
-        user.send(&message, &self.message_context);
-    }
-
-    fn filter_channel(&self, list_option: &ListOption, channel: &Channel) -> bool {
-        use std::ops::Div;
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
-            .div(60);
-        match list_option.filter {
-            ListFilter::ChannelCreation => match list_option.operation {
-                ListOperation::Inf => false,
-                ListOperation::Sup => false,
-            },
-            ListFilter::TopicUpdate => match list_option.operation {
-                ListOperation::Inf => channel.topic.ts.div(60) - current_time < list_option.number,
-                ListOperation::Sup => channel.topic.ts.div(60) - current_time > list_option.number,
-            },
-            ListFilter::UserNumber => match list_option.operation {
-                ListOperation::Inf => channel.users.len() > list_option.number as usize,
-                ListOperation::Sup => channel.users.len() < list_option.number as usize,
-            },
+        let client = user.nickname.clone();
+        if let Some(err) = ServerStateError::from_decoding_error_with_client(error, client) {
+            self.send_error(config, user_id, err);
         }
     }
 }
 
 impl ServerState {
-    pub(crate) fn user_sends_list_info(
-        &self,
-        user_state: RegisteredState,
-        list_channels: Option<Vec<String>>,
-        list_options: Option<Vec<ListOption>>,
-    ) -> UserState {
-        let sv = self.0.read();
-        sv.user_sends_list_info(user_state.user_id, list_channels, list_options);
+    pub(crate) fn user_wants_motd(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_wants_motd(&config, user_state.user_id);
+        UserState::Registered(user_state)
+    }
+
+    pub(crate) fn user_wants_rules(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_wants_rules(&config, user_state.user_id);
+        UserState::Registered(user_state)
+    }
+
+    pub(crate) fn user_wants_admin_info(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_wants_admin_info(&config, user_state.user_id);
+        UserState::Registered(user_state)
+    }
+
+    pub(crate) fn user_wants_version(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_wants_version(&config, user_state.user_id);
+        UserState::Registered(user_state)
+    }
+
+    pub(crate) fn user_wants_links(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_wants_links(&config, user_state.user_id);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_sends_list_info(
-        &self,
-        user_id: UserID,
-        list_channels: Option<Vec<String>>,
-        list_options: Option<Vec<ListOption>>,
+    fn user_wants_motd(&self, config: &ServerConfig, user_id: UserID) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        let message = server_to_client::Message::MOTD {
+            client: &user.nickname,
+            motd: config.motd_for(&user.connection_info),
+        };
+        user.send(&message, &config.message_context);
+    }
+
+    fn user_wants_rules(&self, config: &ServerConfig, user_id: UserID) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        let message = server_to_client::Message::Rules {
+            client: &user.nickname,
+            rules: config.rules.as_deref(),
+        };
+        user.send(&message, &config.message_context);
+    }
+
+    /// The ISUPPORT tokens are sent again, so that the clients can resync after a config reload.
+    fn user_wants_version(&self, config: &ServerConfig, user_id: UserID) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        let message = server_to_client::Message::Version {
+            client: &user.nickname,
+        };
+        user.send(&message, &config.message_context);
+        let message = server_to_client::Message::ISupport {
+            client: &user.nickname,
+            utf8_only: config.utf8_only,
+            channel_limit: config.max_channels_per_user,
+        };
+        user.send(&message, &config.message_context);
+    }
+
+    fn user_wants_links(&self, config: &ServerConfig, user_id: UserID) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        let message = server_to_client::Message::Links {
+            client: &user.nickname,
+            related_servers: &config.related_servers,
+        };
+        user.send(&message, &config.message_context);
+    }
+
+    fn user_wants_admin_info(&self, config: &ServerConfig, user_id: UserID) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        let message = server_to_client::Message::Admin {
+            client: &user.nickname,
+            admin_info: config.admin_info.as_ref(),
+        };
+        user.send(&message, &config.message_context);
+    }
+
+    /// C and T compare the age in minutes of the channel and of its topic,
+    /// U compares the number of users.
+    fn filter_channel(&self, list_option: &ListOption, channel: &Channel, now: u64) -> bool {
+        let value = match list_option.filter {
+            ListFilter::ChannelCreation => now.saturating_sub(channel.created_at) / 60,
+            ListFilter::TopicUpdate => now.saturating_sub(channel.topic.ts) / 60,
+            ListFilter::UserNumber => channel.users.len() as u64,
+        };
+        match list_option.operation {
+            ListOperation::Inf => value < list_option.number,
+            ListOperation::Sup => value > list_option.number,
+        }
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_sends_list_info(
+        &self,
+        user_state: RegisteredState,
+        list_channels: Option<Vec<String>>,
+        list_options: Option<Vec<ListOption>>,
+    ) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_sends_list_info(&config, user_state.user_id, list_channels, list_options);
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_sends_list_info(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        list_channels: Option<Vec<String>>,
+        list_options: Option<Vec<ListOption>>,
     ) {
         let channels = if let Some(list_channels) = list_channels {
             list_channels
@@ -1518,22 +3144,19 @@ impl ServerStateInner {
                 .collect::<Vec<_>>()
         };
 
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+
+        let now = unix_timestamp();
         let channel_info_list = channels
             .iter()
+            .filter(|(_, channel)| channel_visible(user, channel))
             .filter(|(_, channel)| {
-                !channel.mode.is_secret() || channel.users.contains_key(&user_id)
-            })
-            .filter(|(_, channel)| {
-                let mut is_valid: bool = true;
-                if let Some(ref options) = list_options {
-                    for option in options {
-                        let ok = self.filter_channel(option, channel);
-                        if !ok || !is_valid {
-                            is_valid = false;
-                        }
-                    }
-                }
-                is_valid
+                list_options
+                    .iter()
+                    .flatten()
+                    .all(|option| self.filter_channel(option, channel, now))
             })
             .map(|(channel_name, channel)| ChannelInfo {
                 name: channel_name,
@@ -1542,14 +3165,11 @@ impl ServerStateInner {
             })
             .collect::<Vec<_>>();
 
-        let Some(user) = self.users.get(&user_id) else {
-            return; // internal error
-        };
         let message = server_to_client::Message::List {
             client: &user.nickname,
             infos: &channel_info_list,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
     }
 }
 
@@ -1559,19 +3179,25 @@ impl ServerState {
         user_state: RegisteredState,
         away_message: Option<&[u8]>,
     ) -> UserState {
-        let mut sv = self.0.write();
-        sv.user_indicates_away(user_state.user_id, away_message);
+        let config = self.config.load();
+        let mut sv = self.write();
+        sv.user_indicates_away(&config, user_state.user_id, away_message);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_indicates_away(&mut self, user_id: UserID, away_message: Option<&[u8]>) {
+    fn user_indicates_away(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        away_message: Option<&[u8]>,
+    ) {
         let Some(user) = self.users.get_mut(&user_id) else {
             return;
         };
 
-        user.away_message = away_message.map(|m| m.into());
+        user.set_away(away_message, unix_timestamp());
 
         let message = if user.is_away() {
             server_to_client::Message::NowAway {
@@ -1582,7 +3208,7 @@ impl ServerStateInner {
                 client: &user.nickname,
             }
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
     }
 }
 
@@ -1592,25 +3218,37 @@ impl ServerState {
         user_state: RegisteredState,
         nicknames: &[&str],
     ) -> UserState {
-        let sv = self.0.read();
-        sv.user_asks_userhosts(user_state.user_id, nicknames);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_userhosts(&config, user_state.user_id, nicknames);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_asks_userhosts(&self, user_id: UserID, nicknames: &[&str]) {
+    fn user_asks_userhosts(&self, config: &ServerConfig, user_id: UserID, nicknames: &[&str]) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
         let mut replies = vec![];
-        for nick in nicknames {
-            if let Some(user) = self.users.values().find(|&u| &u.nickname == nick) {
+        for (i, nick) in nicknames.iter().enumerate() {
+            let is_duplicate = nicknames
+                .iter()
+                .take(i)
+                .any(|previous| nicknames_match(previous, nick));
+            if is_duplicate {
+                continue;
+            }
+            let target = self
+                .users
+                .values()
+                .find(|&u| nicknames_match(&u.nickname, nick) && can_see_user(user, u));
+            if let Some(target) = target {
                 let reply = UserhostReply {
-                    nickname: &user.nickname,
-                    is_op: false, // no one is OP for now
-                    is_away: user.is_away(),
-                    hostname: user.shown_hostname(),
+                    nickname: &target.nickname,
+                    is_op: operator_visible(user, target),
+                    is_away: target.is_away(),
+                    hostname: target.shown_hostname(),
                 };
                 replies.push(reply);
             }
@@ -1619,59 +3257,217 @@ impl ServerStateInner {
             client: &user.nickname,
             info: &replies,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_asks_userips(
+        &self,
+        user_state: RegisteredState,
+        nicknames: &[&str],
+    ) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_userips(&config, user_state.user_id, nicknames);
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_asks_userips(&self, config: &ServerConfig, user_id: UserID, nicknames: &[&str]) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        if !user.is_operator {
+            let err = ServerStateError::NoPrivileges {
+                client: user.nickname.clone(),
+            };
+            self.send_error(config, user_id, err);
+            return;
+        }
+
+        let mut targets = vec![];
+        for (i, nick) in nicknames.iter().enumerate() {
+            let is_duplicate = nicknames
+                .iter()
+                .take(i)
+                .any(|previous| nicknames_match(previous, nick));
+            if is_duplicate {
+                continue;
+            }
+            let target = self
+                .users
+                .values()
+                .find(|&u| nicknames_match(&u.nickname, nick) && can_see_user(user, u));
+            if let Some(target) = target {
+                // the usual placeholder when the IP is unknown or hidden by the listener
+                let ip = target
+                    .connection_info
+                    .ip
+                    .map_or("255.255.255.255".to_string(), |ip| ip.to_string());
+                targets.push((target, ip));
+            }
+        }
+        let replies = targets
+            .iter()
+            .map(|(target, ip)| UserhostReply {
+                nickname: &target.nickname,
+                is_op: target.is_operator,
+                is_away: target.is_away(),
+                hostname: ip,
+            })
+            .collect::<Vec<_>>();
+        let message = server_to_client::Message::RplUserip {
+            client: &user.nickname,
+            info: &replies,
+        };
+        user.send(&message, &config.message_context);
     }
 }
 
 impl ServerState {
     pub(crate) fn user_asks_whois(&self, user_state: RegisteredState, nickname: &str) -> UserState {
-        let sv = self.0.read();
-        sv.user_asks_whois(user_state.user_id, nickname);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_whois(&config, user_state.user_id, nickname);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_asks_whois(&self, user_id: UserID, nickname: &str) {
+    fn user_asks_whois(&self, config: &ServerConfig, user_id: UserID, nickname: &str) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
-        let Some(target_user) = self.users.values().find(|&u| u.nickname == nickname) else {
+        let Some(target_user) = self
+            .users
+            .values()
+            .find(|&u| nicknames_match(&u.nickname, nickname) && can_see_user(user, u))
+        else {
             let message = server_to_client::Message::Err(ServerStateError::NoSuchNick {
                 client: user.nickname.to_string(),
                 target: nickname.to_string(),
             });
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
             let message = server_to_client::Message::RplEndOfWhois {
                 client: &user.nickname,
                 target_nickname: nickname,
             };
-            user.send(&message, &self.message_context);
+            user.send(&message, &config.message_context);
             return;
         };
 
         let message = server_to_client::Message::RplWhois {
             client: &user.nickname,
-            target_nickname: nickname,
-            away_message: target_user.away_message.as_deref(),
+            target_nickname: &target_user.nickname,
+            away_message: target_user.away_message(),
+            away_for: target_user
+                .away
+                .as_ref()
+                .map(|away| unix_timestamp().saturating_sub(away.since)),
+            connecting_from: target_user
+                .connection_info
+                .ip
+                .filter(|_| connection_visible(user, target_user)),
+            own_connection: (user.user_id == target_user.user_id).then_some(OwnConnection {
+                port: user.connection_info.port,
+                is_tls: user.connection_info.is_tls,
+                tls_info: user.connection_info.tls_info.as_ref(),
+            }),
+            is_operator: operator_visible(user, target_user),
+            is_bot: target_user.is_bot,
             hostname: target_user.shown_hostname(),
             username: &target_user.username,
             realname: &target_user.realname,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
+    }
+}
+
+/// Number of entries kept for WHOWAS.
+const WHOWAS_HISTORY_LENGTH: usize = 256;
+
+impl ServerStateInner {
+    /// Keeps the user in the WHOWAS history, before they leave or change nickname.
+    fn remember_departed_user(&mut self, user_id: UserID) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+        if self.whowas.len() >= WHOWAS_HISTORY_LENGTH {
+            self.whowas.pop_front();
+        }
+        self.whowas
+            .push_back(WhowasEntry::new(user, unix_timestamp()));
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_asks_whowas(
+        &self,
+        user_state: RegisteredState,
+        nickname: &str,
+        count: Option<usize>,
+    ) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_whowas(&config, user_state.user_id, nickname, count);
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    /// The most recent entries come first.
+    fn user_asks_whowas(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        nickname: &str,
+        count: Option<usize>,
+    ) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+
+        let entries = self
+            .whowas
+            .iter()
+            .rev()
+            .filter(|entry| nicknames_match(&entry.nickname, nickname))
+            .take(count.unwrap_or(usize::MAX))
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            self.send_error(
+                config,
+                user_id,
+                ServerStateError::WasNoSuchNick {
+                    client: user.nickname.clone(),
+                    nickname: nickname.to_string(),
+                },
+            );
+        }
+
+        let message = server_to_client::Message::RplWhowas {
+            client: &user.nickname,
+            nickname,
+            entries: &entries,
+            now: unix_timestamp(),
+        };
+        user.send(&message, &config.message_context);
     }
 }
 
 impl ServerState {
     pub(crate) fn user_asks_who(&self, user_state: RegisteredState, mask: &str) -> UserState {
-        let sv = self.0.read();
-        sv.user_asks_who(user_state.user_id, mask);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_who(&config, user_state.user_id, mask);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_asks_who(&self, user_id: UserID, mask: &str) {
+    fn user_asks_who(&self, config: &ServerConfig, user_id: UserID, mask: &str) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
@@ -1682,48 +3478,59 @@ impl ServerStateInner {
         let mut replies = vec![];
         match result {
             Some(LookupResult::Channel(channel_name, channel)) => {
-                for (user_id, user_mode) in &channel.users {
-                    let Some(user) = self.users.get(user_id) else {
-                        return; // internal error
-                    };
+                if channel_visible(user, channel) {
+                    for (member_id, member_mode) in &channel.users {
+                        let Some(member) = self.users.get(member_id) else {
+                            return; // internal error
+                        };
+                        if !can_see_user(user, member) {
+                            continue;
+                        }
+                        let reply = WhoReply {
+                            channel: Some(channel_name.as_ref()),
+                            channel_user_mode: Some(member_mode),
+                            nickname: &member.nickname,
+                            is_op: operator_visible(user, member),
+                            is_away: member.is_away(),
+                            is_bot: member.is_bot,
+                            hostname: member.shown_hostname(),
+                            username: &member.username,
+                            realname: &member.realname,
+                        };
+                        replies.push(reply);
+                    }
+                }
+            }
+            Some(LookupResult::RegisteredUser(target)) => {
+                if can_see_user(user, target) {
                     let reply = WhoReply {
-                        channel: Some(channel_name.as_ref()),
-                        channel_user_mode: Some(user_mode),
-                        nickname: &user.nickname,
-                        is_op: false,
-                        is_away: user.is_away(),
-                        hostname: user.shown_hostname(),
-                        username: &user.username,
-                        realname: &user.realname,
+                        channel: None,
+                        channel_user_mode: None,
+                        nickname: &target.nickname,
+                        is_op: operator_visible(user, target),
+                        is_away: target.is_away(),
+                        is_bot: target.is_bot,
+                        hostname: target.shown_hostname(),
+                        username: &target.username,
+                        realname: &target.realname,
                     };
                     replies.push(reply);
                 }
             }
-            Some(LookupResult::RegisteredUser(user)) => {
-                let reply = WhoReply {
-                    channel: None,
-                    channel_user_mode: None,
-                    nickname: &user.nickname,
-                    is_op: false,
-                    is_away: user.is_away(),
-                    hostname: user.shown_hostname(),
-                    username: &user.username,
-                    realname: &user.realname,
-                };
-                replies.push(reply);
-            }
             None => {
                 if mask == "*" {
-                    for user in self.users.values().take(10) {
+                    let targets = self.users.values().filter(|&t| can_see_user(user, t));
+                    for target in targets.take(10) {
                         let reply = WhoReply {
                             channel: None,
                             channel_user_mode: None,
-                            nickname: &user.nickname,
-                            is_op: false,
-                            is_away: user.is_away(),
-                            hostname: user.shown_hostname(),
-                            username: &user.username,
-                            realname: &user.realname,
+                            nickname: &target.nickname,
+                            is_op: operator_visible(user, target),
+                            is_away: target.is_away(),
+                            is_bot: target.is_bot,
+                            hostname: target.shown_hostname(),
+                            username: &target.username,
+                            realname: &target.realname,
                         };
                         replies.push(reply);
                     }
@@ -1735,130 +3542,5178 @@ impl ServerStateInner {
             client: &user.nickname,
             mask,
             replies: &replies,
+            multi_prefix: user.capabilities.has(Capability::MultiPrefix),
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
     }
 }
 
 impl ServerState {
     pub(crate) fn user_asks_lusers(&self, user_state: RegisteredState) -> UserState {
-        let sv = self.0.read();
-        sv.user_asks_lusers(user_state.user_id);
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_lusers(&config, user_state.user_id, &self.metrics);
         UserState::Registered(user_state)
     }
 }
 
 impl ServerStateInner {
-    fn user_asks_lusers(&self, user_id: UserID) {
+    fn user_asks_lusers(&self, config: &ServerConfig, user_id: UserID, metrics: &ServerMetrics) {
         let Some(user) = self.users.get(&user_id) else {
             return; // internal error
         };
 
         let message = server_to_client::Message::LUsers {
             client: &user.nickname,
-            n_operators: 0,
-            n_unknown_connections: self.registering_users.len(),
-            n_channels: self.channels.len(),
-            n_clients: self.users.len(),
+            n_operators: self
+                .users
+                .values()
+                .filter(|u| operator_visible(user, u))
+                .count(),
+            n_unknown_connections: metrics.registering_users(),
+            n_channels: metrics.channels(),
+            n_clients: metrics.users(),
             n_other_servers: 0,
-            extra_info: true,
+            extra_info: config.welcome_config.compat.extended_lusers,
         };
-        user.send(&message, &self.message_context);
+        user.send(&message, &config.message_context);
     }
 }
 
-fn validate_channel_name(
-    user: &RegisteredUser,
-    channel_name: &str,
-) -> Result<(), ServerStateError> {
-    if channel_name.is_empty() || !channel_name.starts_with('#') {
-        return Err(ServerStateError::BadChanMask {
-            client: user.nickname.to_string(),
-            channel: channel_name.to_string(),
-        });
+impl ServerState {
+    pub(crate) fn user_answers_ping(
+        &self,
+        user_state: RegisteredState,
+        round_trip: Duration,
+    ) -> UserState {
+        let sv = self.read();
+        if let Some(user) = sv.users.get(&user_state.user_id) {
+            *user.ping_round_trip.lock() = Some(round_trip);
+        }
+        UserState::Registered(user_state)
     }
-    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    #![allow(clippy::panic)] // fine in tests
-    #![allow(clippy::panic_in_result_fn)] // fine in tests
-    #![allow(clippy::indexing_slicing)] // fine in tests
-    use super::*;
-
-    fn new_server_state() -> ServerState {
-        let welcome_config = WelcomeConfig::default();
-        let motd = None;
-        ServerState::new("srv", &welcome_config, motd, None, None)
-    }
-
-    fn r1(user_state: UserState) -> RegisteringState {
-        match user_state {
-            UserState::Registering(r) => r,
-            UserState::Registered(_) => panic!(),
-            UserState::Disconnected => panic!(),
-        }
+impl ServerStateInner {
+    fn ping_latencies(&self) -> Option<PingLatencies> {
+        let round_trips = self
+            .users
+            .values()
+            .filter_map(|user| *user.ping_round_trip.lock())
+            .collect();
+        PingLatencies::from_round_trips(round_trips)
     }
+}
 
-    fn r2(user_state: UserState) -> RegisteredState {
-        match user_state {
-            UserState::Registering(_) => panic!(),
-            UserState::Registered(r) => r,
-            UserState::Disconnected => panic!(),
+impl ServerStateInner {
+    fn users_per_server_name(&self) -> BTreeMap<Option<&str>, usize> {
+        let mut users_per_server_name = BTreeMap::new();
+        for user in self.users.values() {
+            let server_name = user
+                .connection_info
+                .tls_info
+                .as_ref()
+                .and_then(|tls_info| tls_info.server_name.as_deref());
+            *users_per_server_name.entry(server_name).or_default() += 1;
         }
+        users_per_server_name
     }
+}
 
-    fn collect_mail(sink: &mut MailboxSink) -> Vec<Vec<u8>> {
-        let mut messages = vec![];
-        while let Ok(m) = sink.try_recv() {
-            messages.push(m.bytes().to_vec());
-        }
-        messages
+impl ServerState {
+    pub(crate) fn user_asks_stats(&self, user_state: RegisteredState, query: &str) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_asks_stats(
+            &config,
+            user_state.user_id,
+            query,
+            &self.metrics,
+            self.uptime(),
+        );
+        UserState::Registered(user_state)
     }
+}
 
-    #[test]
-    fn test_nick_change_same() {
-        let server_state = new_server_state();
-        let nick1 = "test";
+impl ServerStateInner {
+    fn user_asks_stats(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        query: &str,
+        metrics: &ServerMetrics,
+        uptime: Duration,
+    ) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
 
-        let (mut state1, mut rx1) = server_state.new_registering_user();
-        state1 = server_state.ruser_uses_nick(r1(state1), "jester");
-        state1 = server_state.ruser_uses_username(r1(state1), nick1, nick1.as_bytes());
-        assert!(collect_mail(&mut rx1).len() > 6);
+        let context = &config.message_context;
+        if query == "k" {
+            if !user.is_operator {
+                let err = ServerStateError::NoPrivileges {
+                    client: user.nickname.clone(),
+                };
+                self.send_error(config, user_id, err);
+                return;
+            }
 
-        let (mut state2, _rx2) = server_state.new_registering_user();
-        state2 = server_state.ruser_uses_nick(r1(state2), nick1);
-        server_state.ruser_uses_username(r1(state2), nick1, nick1.as_bytes());
+            let now = Instant::now();
+            let klines = self
+                .klines
+                .iter()
+                .filter(|k| k.is_active(now))
+                .map(|k| KLineInfo {
+                    mask: &k.mask,
+                    reason: &k.reason,
+                    remaining: k.expires_at.map(|e| e.duration_since(now)),
+                })
+                .collect::<Vec<_>>();
+            let message = server_to_client::Message::RplStatsKLines {
+                client: &user.nickname,
+                klines: &klines,
+            };
+            user.send(&message, context);
+        } else if query == "p" {
+            let operators = self
+                .users
+                .values()
+                .filter(|u| operator_visible(user, u))
+                .map(|u| (u.nickname.as_str(), u.username.as_str(), u.shown_hostname()))
+                .collect::<Vec<_>>();
+            let message = server_to_client::Message::RplStatsOperators {
+                client: &user.nickname,
+                operators: &operators,
+            };
+            user.send(&message, context);
+        } else if query == "t" {
+            let message = server_to_client::Message::RplStatsTraffic {
+                client: &user.nickname,
+                plaintext: metrics.plaintext_traffic(),
+                tls: metrics.tls_traffic(),
+                handshake_failures: metrics.handshake_failures(),
+            };
+            user.send(&message, context);
+        } else if query == "d" {
+            if !user.is_operator {
+                let err = ServerStateError::NoPrivileges {
+                    client: user.nickname.clone(),
+                };
+                self.send_error(config, user_id, err);
+                return;
+            }
 
-        server_state.user_changes_nick(r2(state1), nick1);
-        let mails = collect_mail(&mut rx1);
-        assert_eq!(
-            mails[0],
+            let message = server_to_client::Message::RplStatsServerNames {
+                client: &user.nickname,
+                users_per_server_name: &self.users_per_server_name(),
+            };
+            user.send(&message, context);
+        } else if query == "r" {
+            let message = server_to_client::Message::RplStatsLatency {
+                client: &user.nickname,
+                latencies: self.ping_latencies(),
+            };
+            user.send(&message, context);
+        } else if query == "m" {
+            let message = server_to_client::Message::RplStatsCommands {
+                client: &user.nickname,
+                durations: metrics.command_durations(),
+            };
+            user.send(&message, context);
+        } else if query == "u" {
+            let message = server_to_client::Message::RplStatsUptime {
+                client: &user.nickname,
+                uptime,
+                reloads: metrics.reloads(),
+            };
+            user.send(&message, context);
+        }
+
+        let message = server_to_client::Message::RplEndOfStats {
+            client: &user.nickname,
+            query,
+        };
+        user.send(&message, context);
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_asks_oper(
+        &self,
+        user_state: RegisteredState,
+        name: &str,
+        password: &[u8],
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err((err, reason)) = sv.user_asks_oper(&config, user_id, name, password) {
+            sv.send_error(&config, user_id, err);
+            if sv.user_fails_oper(&config, user_id, reason) {
+                return UserState::Disconnected;
+            }
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+/// Failed OPER attempts are counted for this duration, per IP.
+const FAILED_OPER_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Beyond this number of failures from an IP, the OPER attempts are rejected without checking
+/// the password.
+const MAX_FAILED_OPERS_PER_IP: usize = 5;
+/// The connection is closed after this number of failures.
+const MAX_FAILED_OPERS_PER_CONNECTION: u32 = 3;
+
+impl ServerStateInner {
+    /// On failure, also returns the reason given to the operators.
+    fn user_asks_oper(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        name: &str,
+        password: &[u8],
+    ) -> Result<(), (ServerStateError, &'static str)> {
+        let Some(user) = self.users.get_mut(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        let Some(oper_password) = &config.oper_password else {
+            let err = ServerStateError::NoOperHost {
+                client: user.nickname.clone(),
+            };
+            return Err((err, "no operator password"));
+        };
+
+        let host = user.kline_target();
+        if !config.oper_hosts.is_empty()
+            && !config
+                .oper_hosts
+                .iter()
+                .any(|mask| mask_matches(mask, &host))
+        {
+            let err = ServerStateError::NoOperHost {
+                client: user.nickname.clone(),
+            };
+            return Err((err, "host not allowed"));
+        }
+
+        let failures_from_ip = self
+            .failed_opers
+            .iter()
+            .filter(|(ip, _)| Some(*ip) == user.connection_info.ip)
+            .count();
+        if failures_from_ip >= MAX_FAILED_OPERS_PER_IP {
+            let err = ServerStateError::PasswdMismatch {
+                client: user.nickname.clone(),
+            };
+            return Err((err, "too many failures from this IP"));
+        }
+
+        // a wrong name is reported as a wrong password, not to tell which one is wrong
+        if config
+            .oper_name
+            .as_ref()
+            .is_some_and(|oper_name| oper_name != name)
+        {
+            let err = ServerStateError::PasswdMismatch {
+                client: user.nickname.clone(),
+            };
+            return Err((err, "wrong name"));
+        }
+
+        use subtle::ConstantTimeEq;
+        if oper_password.as_slice().ct_ne(password).into() {
+            let err = ServerStateError::PasswdMismatch {
+                client: user.nickname.clone(),
+            };
+            return Err((err, "wrong password"));
+        }
+
+        user.is_operator = true;
+        let message = server_to_client::Message::RplYoureOper {
+            client: &user.nickname,
+        };
+        user.send(&message, &config.message_context);
+        Ok(())
+    }
+
+    /// Records the failure and reports it to the operators.
+    /// Returns true if the user was disconnected for failing too many times.
+    fn user_fails_oper(&mut self, config: &ServerConfig, user_id: UserID, reason: &str) -> bool {
+        let Some(user) = self.users.get_mut(&user_id) else {
+            return false; // internal error
+        };
+        user.failed_oper_attempts += 1;
+        let failed_oper_attempts = user.failed_oper_attempts;
+        if let Some(ip) = user.connection_info.ip {
+            self.failed_opers.push((ip, Instant::now()));
+        }
+
+        log::warn!(
+            "Failed OPER attempt by {} ({}): {reason}",
+            user.nickname,
+            user.kline_target()
+        );
+        let catalog = &config.message_context.catalog;
+        let notice = catalog.format(
+            "Failed OPER attempt by {nick} ({target}): {reason}",
+            &[
+                ("nick", &user.nickname),
+                ("target", &user.kline_target()),
+                ("reason", catalog.translate(reason)),
+            ],
+        );
+        self.notify_operators(config, notice.as_bytes());
+
+        if failed_oper_attempts < MAX_FAILED_OPERS_PER_CONNECTION {
+            return false;
+        }
+        self.user_disconnects_voluntarily(config, user_id, Some(b"Too many failed OPER attempts"));
+        true
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_asks_user_mode(
+        &self,
+        user_state: RegisteredState,
+        target: &str,
+    ) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_asks_user_mode(&config, user_id, target) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_asks_user_mode(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        target: &str,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if user.nickname != target {
+            return Err(ServerStateError::UsersDontMatch {
+                client: user.nickname.clone(),
+            });
+        }
+
+        let message = server_to_client::Message::RplUModeIs {
+            client: &user.nickname,
+            is_operator: user.is_operator,
+            hides_operator: user.hides_operator,
+            is_bot: user.is_bot,
+        };
+        user.send(&message, &config.message_context);
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_changes_user_mode(
+        &self,
+        user_state: RegisteredState,
+        target: &str,
+        modechars: &str,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_changes_user_mode(&config, user_id, target, modechars) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    /// The modechars are applied in order and the effective changes are sent back on one line;
+    /// the first error (unknown modechar, missing privileges) is reported after them.
+    fn user_changes_user_mode(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        target: &str,
+        modechars: &str,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get_mut(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if !nicknames_match(&user.nickname, target) {
+            return Err(ServerStateError::UsersDontMatch {
+                client: user.nickname.clone(),
+            });
+        }
+
+        let mut adding = true;
+        let mut changes = String::new();
+        let mut sign = None;
+        let mut error = None;
+        for modechar in modechars.chars() {
+            let changed = match modechar {
+                '+' => {
+                    adding = true;
+                    continue;
+                }
+                '-' => {
+                    adding = false;
+                    continue;
+                }
+                // the operator status can only be obtained with OPER
+                'o' if adding => false,
+                'o' => {
+                    let changed = user.is_operator;
+                    user.is_operator = false;
+                    user.hides_operator = false;
+                    changed
+                }
+                'H' if !user.is_operator => {
+                    error.get_or_insert(ServerStateError::NoPrivileges {
+                        client: user.nickname.clone(),
+                    });
+                    false
+                }
+                'H' => std::mem::replace(&mut user.hides_operator, adding) != adding,
+                'B' => std::mem::replace(&mut user.is_bot, adding) != adding,
+                // the away status can only be changed with AWAY
+                'a' => false,
+                _ => {
+                    error.get_or_insert(ServerStateError::UModeUnknownFlag {
+                        client: user.nickname.clone(),
+                    });
+                    false
+                }
+            };
+            if changed {
+                if sign != Some(adding) {
+                    changes.push(if adding { '+' } else { '-' });
+                    sign = Some(adding);
+                }
+                changes.push(modechar);
+            }
+        }
+
+        if !changes.is_empty() {
+            let message = server_to_client::Message::Mode {
+                user_fullspec: user.fullspec(),
+                target: &user.nickname,
+                modechar: &changes,
+                param: None,
+            };
+            user.send(&message, &config.message_context);
+        }
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Invitations to a channel are valid for this duration.
+const INVITE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Clients that started to negotiate capabilities (suspending their registration) but did not
+/// send CAP END in time are disconnected by the maintenance.
+const CAP_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl ServerState {
+    pub(crate) fn user_invites(
+        &self,
+        user_state: RegisteredState,
+        nickname: &str,
+        channel_name: &str,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_invites(&config, user_id, nickname, channel_name, Instant::now())
+        {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_invites(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        nickname: &str,
+        channel_name: &str,
+        now: Instant,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        let Some(target_user) = self
+            .users
+            .values()
+            .find(|&u| nicknames_match(&u.nickname, nickname))
+        else {
+            return Err(ServerStateError::NoSuchNick {
+                client: user.nickname.clone(),
+                target: nickname.to_string(),
+            });
+        };
+
+        let channel_id = BorrowedChannelID::new(channel_name);
+        let Some(channel) = self.channels.get_mut(channel_id) else {
+            return Err(ServerStateError::NoSuchChannel {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        };
+
+        let Some(user_mode) = channel.users.get(&user_id) else {
+            return Err(ServerStateError::NotOnChannel {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        };
+
+        if channel.mode.is_invite_only() && !user_mode.is_op() && !user.is_operator {
+            return Err(ServerStateError::ChanOpPrivsNeeded {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        }
+
+        if channel.users.contains_key(&target_user.user_id) {
+            return Err(ServerStateError::UserOnChannel {
+                client: user.nickname.clone(),
+                nickname: nickname.to_string(),
+                channel: channel_name.to_string(),
+            });
+        }
+
+        channel
+            .invites
+            .insert(target_user.user_id, now + INVITE_DURATION);
+
+        let context = &config.message_context;
+        let message = server_to_client::Message::RplInviting {
+            client: &user.nickname,
+            nickname: &target_user.nickname,
+            channel: channel_name,
+        };
+        user.send(&message, context);
+
+        let message = server_to_client::Message::Invite {
+            user_fullspec: user.fullspec(),
+            nickname: &target_user.nickname,
+            channel: channel_name,
+        };
+        target_user.send(&message, context);
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_lists_invites(&self, user_state: RegisteredState) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+        sv.user_lists_invites(&config, user_state.user_id, Instant::now());
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_lists_invites(&self, config: &ServerConfig, user_id: UserID, now: Instant) {
+        let Some(user) = self.users.get(&user_id) else {
+            return; // internal error
+        };
+
+        let channels = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| {
+                channel
+                    .invites
+                    .get(&user_id)
+                    .is_some_and(|&expires_at| expires_at > now)
+            })
+            .map(|(channel_name, _)| channel_name.as_ref())
+            .collect::<Vec<_>>();
+
+        let message = server_to_client::Message::RplInviteList {
+            client: &user.nickname,
+            channels: &channels,
+        };
+        user.send(&message, &config.message_context);
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_sets_kline(
+        &self,
+        user_state: RegisteredState,
+        duration: Option<Duration>,
+        mask: &str,
+        reason: Option<&[u8]>,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) =
+            sv.user_sets_kline(&config, user_id, duration, mask, reason, Instant::now())
+        {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_sets_kline(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        duration: Option<Duration>,
+        mask: &str,
+        reason: Option<&[u8]>,
+        now: Instant,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if !user.is_operator {
+            return Err(ServerStateError::NoPrivileges {
+                client: user.nickname.clone(),
+            });
+        }
+
+        // the operator would be disconnected by their own K-line (e.g. with *@*)
+        let matches_user = mask_matches(mask, &user.kline_target());
+        let template = if matches_user {
+            "K-line not added for {mask}, it matches your own connection"
+        } else {
+            "K-line added for {mask}"
+        };
+
+        let content = config
+            .message_context
+            .catalog
+            .format(template, &[("mask", mask)]);
+        let message = server_to_client::Message::Notice {
+            from_user: &config.server_name,
+            target: &user.nickname,
+            content: content.as_bytes(),
+        };
+        user.send(&message, &config.message_context);
+
+        if !matches_user {
+            self.add_kline(
+                config,
+                KLine {
+                    mask: mask.to_string(),
+                    reason: reason.unwrap_or(b"K-lined").to_vec(),
+                    expires_at: duration.map(|d| now + d),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Stores the K-line, and disconnects the users that are now banned.
+    fn add_kline(&mut self, config: &ServerConfig, kline: KLine) {
+        let banned_user_ids = self
+            .users
+            .values()
+            .filter(|u| mask_matches(&kline.mask, &u.kline_target()))
+            .map(|u| u.user_id)
+            .collect::<Vec<_>>();
+        for banned_user_id in banned_user_ids {
+            self.user_disconnects_voluntarily(config, banned_user_id, Some(&kline.reason));
+        }
+
+        self.klines.retain(|k| k.mask != kline.mask);
+        self.klines.push(kline);
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_removes_kline(&self, user_state: RegisteredState, mask: &str) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_removes_kline(&config, user_id, mask) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_removes_kline(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        mask: &str,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if !user.is_operator {
+            return Err(ServerStateError::NoPrivileges {
+                client: user.nickname.clone(),
+            });
+        }
+
+        let previous_len = self.klines.len();
+        self.klines.retain(|k| k.mask != mask);
+        let template = if self.klines.len() != previous_len {
+            "K-line removed for {mask}"
+        } else {
+            "No K-line for {mask}"
+        };
+
+        let content = config
+            .message_context
+            .catalog
+            .format(template, &[("mask", mask)]);
+        let message = server_to_client::Message::Notice {
+            from_user: &config.server_name,
+            target: &user.nickname,
+            content: content.as_bytes(),
+        };
+        user.send(&message, &config.message_context);
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_manages_spam_filters(
+        &self,
+        user_state: RegisteredState,
+        command: SpamFilterCommand<'_>,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_manages_spam_filters(&config, user_id, command) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_manages_spam_filters(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        command: SpamFilterCommand<'_>,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if !user.is_operator {
+            return Err(ServerStateError::NoPrivileges {
+                client: user.nickname.clone(),
+            });
+        }
+
+        let catalog = &config.message_context.catalog;
+        let contents = match command {
+            SpamFilterCommand::Add {
+                action,
+                pattern,
+                reason,
+            } => match SpamFilter::new(action, pattern, reason) {
+                Ok(spam_filter) => {
+                    self.spam_filters.retain(|f| f.pattern != pattern);
+                    self.spam_filters.push(spam_filter);
+                    vec![catalog.format("Spam filter added for {pattern}", &[("pattern", pattern)])]
+                }
+                Err(err) => vec![catalog.format(
+                    "Invalid spam filter: {error}",
+                    &[("error", &err.to_string())],
+                )],
+            },
+            SpamFilterCommand::Del(pattern) => {
+                let previous_len = self.spam_filters.len();
+                self.spam_filters.retain(|f| f.pattern != pattern);
+                let template = if self.spam_filters.len() != previous_len {
+                    "Spam filter removed for {pattern}"
+                } else {
+                    "No spam filter for {pattern}"
+                };
+                vec![catalog.format(template, &[("pattern", pattern)])]
+            }
+            SpamFilterCommand::List => self
+                .spam_filters
+                .iter()
+                .map(|f| {
+                    catalog.format(
+                        "Spam filter {pattern} ({action}): {reason}",
+                        &[
+                            ("pattern", &f.pattern),
+                            ("action", f.action.name()),
+                            ("reason", &String::from_utf8_lossy(&f.reason)),
+                        ],
+                    )
+                })
+                .chain(std::iter::once(catalog.format("End of spam filters", &[])))
+                .collect(),
+            SpamFilterCommand::Unknown(subcommand) => vec![catalog.format(
+                "Unknown SPAMFILTER subcommand {subcommand} (ADD, DEL or LIST)",
+                &[("subcommand", subcommand)],
+            )],
+        };
+
+        for content in contents {
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.nickname,
+                content: content.as_bytes(),
+            };
+            user.send(&message, &config.message_context);
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of the spam filters on a content sent by a user.
+enum SpamFilterOutcome {
+    Allowed,
+    Blocked,
+    Disconnected,
+}
+
+impl ServerState {
+    /// Applies the spam filters to a content sent by the user. Unless it is allowed, the content
+    /// must not be used.
+    fn apply_spam_filters(&self, user_id: UserID, content: &[u8]) -> SpamFilterOutcome {
+        let config = self.config.load();
+        // matching only needs the read lock, which is all the messages take
+        let matched = self.read().matching_spam_filter(user_id, content);
+        let Some((action, reason)) = matched else {
+            return SpamFilterOutcome::Allowed;
+        };
+
+        let mut sv = self.write();
+        sv.enforce_spam_filter(&config, user_id, action, &reason)
+    }
+}
+
+impl ServerStateInner {
+    /// The operators are not filtered.
+    fn matching_spam_filter(
+        &self,
+        user_id: UserID,
+        content: &[u8],
+    ) -> Option<(SpamFilterAction, Vec<u8>)> {
+        if self.spam_filters.is_empty() {
+            return None;
+        }
+        let user = self.users.get(&user_id)?;
+        if user.is_operator {
+            return None;
+        }
+        self.spam_filters
+            .iter()
+            .find(|f| f.matches(content))
+            .map(|f| (f.action, f.reason.clone()))
+    }
+
+    fn enforce_spam_filter(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        action: SpamFilterAction,
+        reason: &[u8],
+    ) -> SpamFilterOutcome {
+        let Some(user) = self.users.get(&user_id) else {
+            return SpamFilterOutcome::Disconnected; // internal error
+        };
+        log::info!(
+            "spam filter ({}) matched content from {}",
+            action.name(),
+            user.nickname
+        );
+
+        match action {
+            SpamFilterAction::Block => {
+                let content = config.message_context.catalog.format(
+                    "Message blocked by a spam filter ({reason})",
+                    &[("reason", &String::from_utf8_lossy(reason))],
+                );
+                let message = server_to_client::Message::Notice {
+                    from_user: &config.server_name,
+                    target: &user.nickname,
+                    content: content.as_bytes(),
+                };
+                user.send(&message, &config.message_context);
+                SpamFilterOutcome::Blocked
+            }
+            SpamFilterAction::Kill => {
+                self.user_disconnects_voluntarily(config, user_id, Some(reason));
+                SpamFilterOutcome::Disconnected
+            }
+            SpamFilterAction::Kline => {
+                let mask = match user.connection_info.ip {
+                    Some(ip) => format!("*@{ip}"),
+                    None => user.kline_target(),
+                };
+                self.add_kline(
+                    config,
+                    KLine {
+                        mask,
+                        reason: reason.to_vec(),
+                        expires_at: None,
+                    },
+                );
+                SpamFilterOutcome::Disconnected
+            }
+        }
+    }
+}
+
+impl ServerState {
+    pub(crate) fn ruser_negotiates_capabilities(
+        &self,
+        user_state: RegisteringState,
+        subcommand: &str,
+        param: Option<&str>,
+    ) -> UserState {
+        let config = self.config.load();
+        {
+            let mut sv = self.write();
+
+            let user_id = user_state.user_id;
+            let Some(user) = sv.registering_users.get_mut(&user_id) else {
+                return UserState::Disconnected;
+            };
+
+            let client = user.maybe_nickname();
+            let offer = capability_offer(&config, &user.connection_info);
+            match user.capabilities.negotiate(subcommand, param, &offer) {
+                CapResponse::Reply { subcommand, lines } => {
+                    // CAP LS and CAP REQ suspend the registration until CAP END
+                    if matches!(subcommand, "LS" | "ACK" | "NAK") {
+                        user.negotiating_capabilities_since
+                            .get_or_insert(Instant::now());
+                    }
+                    for (i, line) in lines.iter().enumerate() {
+                        let message = server_to_client::Message::Cap {
+                            client: &client,
+                            subcommand,
+                            capabilities: line,
+                            more: i + 1 < lines.len(),
+                        };
+                        user.send(&message, &config.message_context);
+                    }
+                }
+                CapResponse::End => {
+                    user.negotiating_capabilities_since = None;
+                }
+                CapResponse::InvalidCommand => {
+                    let err = ServerStateError::InvalidCapCmd {
+                        client,
+                        subcommand: subcommand.to_string(),
+                    };
+                    sv.send_error(&config, user_id, err);
+                }
+            }
+            if let Some(user) = sv.registering_users.get_mut(&user_id) {
+                user.update_message_tags();
+            }
+        }
+
+        self.check_ruser_registration_state(&config, user_state)
+    }
+
+    pub(crate) fn user_negotiates_capabilities(
+        &self,
+        user_state: RegisteredState,
+        subcommand: &str,
+        param: Option<&str>,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_negotiates_capabilities(&config, user_id, subcommand, param) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_negotiates_capabilities(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        subcommand: &str,
+        param: Option<&str>,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get_mut(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        let offer = capability_offer(config, &user.connection_info);
+        match user.capabilities.negotiate(subcommand, param, &offer) {
+            CapResponse::Reply { subcommand, lines } => {
+                for (i, line) in lines.iter().enumerate() {
+                    let message = server_to_client::Message::Cap {
+                        client: &user.nickname,
+                        subcommand,
+                        capabilities: line,
+                        more: i + 1 < lines.len(),
+                    };
+                    user.send(&message, &config.message_context);
+                }
+                user.update_message_tags();
+            }
+            // the user is already registered
+            CapResponse::End => {}
+            CapResponse::InvalidCommand => {
+                return Err(ServerStateError::InvalidCapCmd {
+                    client: user.nickname.clone(),
+                    subcommand: subcommand.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_resets_channel(
+        &self,
+        user_state: RegisteredState,
+        channel_name: &str,
+        nickname: Option<&str>,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_resets_channel(&config, user_id, channel_name, nickname) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    /// For the hijacked channels and the ones left without channel operator: the modes become
+    /// the ones of a new channel, the lists and the pending invitations are dropped, and the given
+    /// member (the operator by default, if they are a member) becomes the only channel operator.
+    fn user_resets_channel(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        channel_name: &str,
+        nickname: Option<&str>,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if !user.is_operator {
+            return Err(ServerStateError::NoPrivileges {
+                client: user.nickname.clone(),
+            });
+        }
+        validate_channel_name(user, channel_name)?;
+
+        let channel_id = BorrowedChannelID::new(channel_name);
+        let Some(channel) = self.channels.get_mut(channel_id) else {
+            return Err(ServerStateError::NoSuchChannel {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        };
+
+        let new_op = channel
+            .users
+            .keys()
+            .filter_map(|member_id| self.users.get(member_id))
+            .find(|member| nicknames_match(&member.nickname, nickname.unwrap_or(&user.nickname)));
+        if let (Some(nickname), None) = (nickname, new_op) {
+            return Err(ServerStateError::UserNotInChannel {
+                client: user.nickname.clone(),
+                nickname: nickname.to_string(),
+                channel: channel_name.to_string(),
+            });
+        }
+
+        // the action log is kept, to see what led to the reset
+        let new_mode = match channel.mode.is_action_log() {
+            true => config.mode_of_new_channel(channel_name).with_action_log(),
+            false => config.mode_of_new_channel(channel_name).clone(),
+        };
+        let mut changes = channel
+            .mode
+            .changes_to(&new_mode)
+            .into_iter()
+            .map(|(modechar, param)| (modechar, param.unwrap_or_default()))
+            .collect::<Vec<_>>();
+        changes.extend(channel.bans.drain(..).map(|entry| ("-b", entry.mask)));
+        changes.extend(
+            channel
+                .invite_exemptions
+                .drain(..)
+                .map(|entry| ("-I", entry.mask)),
+        );
+        for (member_id, member_mode) in channel.users.iter_mut() {
+            let Some(member) = self.users.get(member_id) else {
+                continue; // internal error
+            };
+            if new_op.is_some_and(|new_op| member.user_id == new_op.user_id) {
+                if !member_mode.is_op() {
+                    *member_mode = member_mode.with_op();
+                    changes.push(("+o", member.nickname.clone()));
+                }
+            } else if member_mode.is_op() {
+                *member_mode = member_mode.without_op();
+                changes.push(("-o", member.nickname.clone()));
+            }
+        }
+        // the new channel operator is announced last
+        changes.sort_by_key(|(modechar, _)| *modechar == "+o");
+        channel.mode = new_mode;
+        channel.invites.clear();
+        for (modechar, param) in &changes {
+            let action = format!("MODE {modechar} {param}");
+            channel.log_action(&user.nickname, action.trim_end().to_string());
+        }
+
+        for (modechar, param) in &changes {
+            let message = server_to_client::Message::Mode {
+                user_fullspec: &config.server_name,
+                target: channel_name,
+                modechar,
+                param: Some(param.as_str()).filter(|param| !param.is_empty()),
+            };
+            for member_id in channel.users.keys() {
+                if let Some(member) = self.users.get(member_id) {
+                    member.send(&message, &config.message_context);
+                }
+            }
+        }
+
+        let notice = match new_op {
+            Some(new_op) => {
+                log::warn!(
+                    "Channel {channel_name} reset by {} ({}), {} is now the channel operator",
+                    user.nickname,
+                    user.kline_target(),
+                    new_op.nickname
+                );
+                config.message_context.catalog.format(
+                    "Channel {channel} reset by {nick}, {op} is now the channel operator",
+                    &[
+                        ("channel", channel_name),
+                        ("nick", &user.nickname),
+                        ("op", &new_op.nickname),
+                    ],
+                )
+            }
+            None => {
+                log::warn!(
+                    "Channel {channel_name} reset by {} ({}), without channel operator",
+                    user.nickname,
+                    user.kline_target(),
+                );
+                config.message_context.catalog.format(
+                    "Channel {channel} reset by {nick}, without channel operator",
+                    &[("channel", channel_name), ("nick", &user.nickname)],
+                )
+            }
+        };
+        self.notify_operators(config, notice.as_bytes());
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_wants_action_log(
+        &self,
+        user_state: RegisteredState,
+        channel_name: &str,
+    ) -> UserState {
+        let config = self.config.load();
+        let sv = self.read();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_wants_action_log(&config, user_id, channel_name) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    /// Lists the recorded actions to the channel operators, oldest first, for instance to know
+    /// what happened before taking over the moderation.
+    fn user_wants_action_log(
+        &self,
+        config: &ServerConfig,
+        user_id: UserID,
+        channel_name: &str,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+        validate_channel_name(user, channel_name)?;
+
+        let channel_id = BorrowedChannelID::new(channel_name);
+        let Some(channel) = self.channels.get(channel_id) else {
+            return Err(ServerStateError::NoSuchChannel {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        };
+        channel.ensure_user_can_set_channel_mode(user, channel_name)?;
+
+        let catalog = &config.message_context.catalog;
+        let contents = if channel.mode.is_action_log() {
+            let now = Instant::now();
+            channel
+                .action_log
+                .iter()
+                .map(|entry| {
+                    let ago = format_duration(now.saturating_duration_since(entry.at));
+                    catalog.format(
+                        "{ago} ago, {nick}: {action}",
+                        &[
+                            ("ago", &ago),
+                            ("nick", &entry.by),
+                            ("action", &entry.action),
+                        ],
+                    )
+                })
+                .chain(std::iter::once(catalog.format(
+                    "End of the action log of {channel}",
+                    &[("channel", channel_name)],
+                )))
+                .collect::<Vec<_>>()
+        } else {
+            vec![catalog.format(
+                "The actions are not recorded on {channel} (mode +H)",
+                &[("channel", channel_name)],
+            )]
+        };
+
+        for content in contents {
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.nickname,
+                content: content.as_bytes(),
+            };
+            user.send(&message, &config.message_context);
+        }
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub(crate) fn user_changes_host(
+        &self,
+        user_state: RegisteredState,
+        nickname: &str,
+        hostname: &str,
+    ) -> UserState {
+        let config = self.config.load();
+        let mut sv = self.write();
+
+        let user_id = user_state.user_id;
+        if let Err(err) = sv.user_changes_host(&config, user_id, nickname, hostname) {
+            sv.send_error(&config, user_id, err);
+        }
+
+        UserState::Registered(user_state)
+    }
+}
+
+impl ServerStateInner {
+    fn user_changes_host(
+        &mut self,
+        config: &ServerConfig,
+        user_id: UserID,
+        nickname: &str,
+        hostname: &str,
+    ) -> Result<(), ServerStateError> {
+        let Some(user) = self.users.get(&user_id) else {
+            return Ok(()); // internal error
+        };
+
+        if !user.is_operator {
+            return Err(ServerStateError::NoPrivileges {
+                client: user.nickname.clone(),
+            });
+        }
+
+        let Some(target) = self
+            .users
+            .values()
+            .find(|u| nicknames_match(&u.nickname, nickname))
+        else {
+            return Err(ServerStateError::NoSuchNick {
+                client: user.nickname.clone(),
+                target: nickname.to_string(),
+            });
+        };
+
+        if !is_valid_hostname(hostname) {
+            let content = config
+                .message_context
+                .catalog
+                .format("Invalid hostname: {hostname}", &[("hostname", hostname)]);
+            let message = server_to_client::Message::Notice {
+                from_user: &config.server_name,
+                target: &user.nickname,
+                content: content.as_bytes(),
+            };
+            user.send(&message, &config.message_context);
+            return Ok(());
+        }
+
+        let content = config.message_context.catalog.format(
+            "Host of {nick} changed to {hostname}",
+            &[("nick", &target.nickname), ("hostname", hostname)],
+        );
+        let message = server_to_client::Message::Notice {
+            from_user: &config.server_name,
+            target: &user.nickname,
+            content: content.as_bytes(),
+        };
+        user.send(&message, &config.message_context);
+
+        let target_id = target.user_id;
+        let previous_user_fullspec = target.fullspec().to_string();
+        let Some(target) = self.users.get_mut(&target_id) else {
+            return Ok(()); // internal error
+        };
+        target.change_hostname(hostname);
+        let Some(target) = self.users.get(&target_id) else {
+            return Ok(()); // internal error
+        };
+
+        let message = server_to_client::Message::RplVisibleHost {
+            client: &target.nickname,
+            hostname,
+        };
+        target.send(&message, &config.message_context);
+
+        let users = self.user_and_peers(target_id);
+
+        let message = server_to_client::Message::ChgHost {
+            previous_user_fullspec: &previous_user_fullspec,
+            username: &target.username,
+            hostname,
+        };
+        for user_id in users {
+            let Some(user) = self.users.get(&user_id) else {
+                continue; // internal error
+            };
+            if user.capabilities.has(Capability::ChgHost) {
+                user.send(&message, &config.message_context);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerStateInner {
+    /// Removes the expired invitations, list mode entries, K-lines and nickname holds, and
+    /// disconnects the clients stuck in the capability negotiation.
+    fn expire(&mut self, config: &ServerConfig, now: Instant) {
+        let users = &self.users;
+        for (channel_id, channel) in self.channels.iter_mut() {
+            channel
+                .invites
+                .retain(|user_id, &mut expires_at| expires_at > now && users.contains_key(user_id));
+
+            let mut changes = vec![];
+            for (modechar, list) in [
+                ('b', &mut channel.bans),
+                ('I', &mut channel.invite_exemptions),
+            ] {
+                list.retain(|entry| {
+                    let is_active = entry.is_active(now);
+                    if !is_active {
+                        changes.push((false, modechar, entry.mask.clone()));
+                    }
+                    is_active
+                });
+            }
+            broadcast_list_mode_changes(
+                users,
+                channel,
+                &config.server_name,
+                &channel_id.0,
+                &changes,
+                &config.message_context,
+            );
+        }
+
+        self.klines.retain(|k| k.is_active(now));
+        self.held_nicknames.retain(|h| h.expires_at > now);
+        self.failed_opers
+            .retain(|&(_, at)| now.duration_since(at) < FAILED_OPER_WINDOW);
+
+        self.registering_users.retain(|_, user| {
+            let negotiation_timed_out = user
+                .negotiating_capabilities_since
+                .is_some_and(|since| now.duration_since(since) > CAP_NEGOTIATION_TIMEOUT);
+            if negotiation_timed_out {
+                let message =
+                    server_to_client::Message::Err(ServerStateError::CapNegotiationTimeout {});
+                user.send(&message, &config.message_context);
+                let context = &config.message_context;
+                let reason = context.closing_link(
+                    &user.maybe_nickname(),
+                    context
+                        .catalog
+                        .translate("Capability negotiation timed out")
+                        .as_bytes(),
+                );
+                let message = server_to_client::Message::FatalError { reason: &reason };
+                user.send(&message, &config.message_context);
+            }
+            !negotiation_timed_out
+        });
+
+        let missed_oper_deadline = self
+            .users
+            .values()
+            .filter(|user| {
+                !user.is_operator
+                    && config
+                        .oper_deadline_for(&user.connection_info)
+                        .is_some_and(|deadline| now.duration_since(user.connected_at) > deadline)
+            })
+            .map(|user| user.user_id)
+            .collect::<Vec<_>>();
+        for user_id in missed_oper_deadline {
+            self.user_disconnects_voluntarily(config, user_id, Some(b"Operator access required"));
+        }
+    }
+}
+
+fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 63
+        && !hostname.starts_with([':', '-'])
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '/'))
+}
+
+/// The ident answers are free text, only the characters of a plain username are accepted.
+fn is_valid_ident(ident: &str) -> bool {
+    !ident.is_empty()
+        && ident.len() <= 16
+        && ident
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+fn validate_channel_name(
+    user: &RegisteredUser,
+    channel_name: &str,
+) -> Result<(), ServerStateError> {
+    if channel_name.is_empty() || !channel_name.starts_with('#') {
+        return Err(ServerStateError::BadChanMask {
+            client: user.nickname.to_string(),
+            channel: channel_name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)] // fine in tests
+    #![allow(clippy::panic_in_result_fn)] // fine in tests
+    #![allow(clippy::indexing_slicing)] // fine in tests
+    use super::*;
+    use crate::{
+        AdminInfo, Catalog, ChannelModeRule, CompatFlags, ContentFilter, ContentFilterAction,
+        HandshakeFailure, HostnamePolicy, RelatedServer, StsPolicy, Templates, TlsInfo, Traffic,
+    };
+
+    fn new_server_state() -> ServerState {
+        let welcome_config = WelcomeConfig::default();
+        let motd = None;
+        ServerState::new("srv", &welcome_config, motd, None, None)
+    }
+
+    fn r1(user_state: UserState) -> RegisteringState {
+        match user_state {
+            UserState::Registering(r) => r,
+            UserState::Registered(_) => panic!(),
+            UserState::Disconnected => panic!(),
+        }
+    }
+
+    fn r2(user_state: UserState) -> RegisteredState {
+        match user_state {
+            UserState::Registering(_) => panic!(),
+            UserState::Registered(r) => r,
+            UserState::Disconnected => panic!(),
+        }
+    }
+
+    fn collect_mail(sink: &mut MailboxSink) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        while let Ok(m) = sink.try_recv() {
+            messages.push(m.bytes().to_vec());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_nick_change_same() {
+        let server_state = new_server_state();
+        let nick1 = "test";
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "jester");
+        state1 = server_state.ruser_uses_username(r1(state1), nick1, nick1.as_bytes());
+        assert!(collect_mail(&mut rx1).len() > 6);
+
+        let (mut state2, _rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), nick1);
+        server_state.ruser_uses_username(r1(state2), nick1, nick1.as_bytes());
+
+        server_state.user_changes_nick(r2(state1), nick1);
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails[0],
             b":srv 433 jester test :Nickname is already in use\r\n"
         );
     }
 
     #[test]
-    fn test_nick_change_homoglyph() {
+    fn test_metrics() {
+        let server_state = new_server_state();
+        let metrics = server_state.metrics();
+
+        let (state1, _rx1) = server_state.new_registering_user(Default::default());
+        let (mut state2, _rx2) = server_state.new_registering_user(Default::default());
+        assert_eq!(metrics.registering_users(), 2);
+        assert_eq!(metrics.users(), 0);
+
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        assert_eq!(metrics.registering_users(), 1);
+        assert_eq!(metrics.users(), 1);
+
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan1", "#chan2"], &[]);
+        assert_eq!(metrics.channels(), 2);
+
+        server_state.dispose_state(state2);
+        server_state.dispose_state(state1);
+        assert_eq!(metrics.registering_users(), 0);
+        assert_eq!(metrics.users(), 0);
+        assert_eq!(metrics.channels(), 0);
+    }
+
+    #[test]
+    fn test_command_durations() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| c.set_slow_command_threshold(Some(Duration::ZERO)));
+        let metrics = server_state.metrics();
+        assert_eq!(metrics.command_durations().count(), 0);
+
+        let (mut state, _rx) = server_state.new_registering_user(Default::default());
+        let before = lock_held_by_thread();
+        for line in [&b"NICK nick"[..], b"USER user 0 * :Real", b"JOIN #chan"] {
+            state = server_state.drive_raw_line(state, line);
+        }
+        let held = lock_held_by_thread() - before;
+
+        let durations = metrics.command_durations();
+        assert_eq!(durations.count(), 3);
+        assert_eq!(
+            durations.counts.len(),
+            crate::COMMAND_DURATION_BUCKETS.len() + 1
+        );
+        assert!(durations.sum <= held + Duration::from_micros(3));
+
+        // the commands of disconnected users are not measured
+        state = state.disconnect(&server_state, b"bye");
+        server_state.drive_raw_line(state, b"PING a");
+        assert_eq!(metrics.command_durations().count(), 3);
+    }
+
+    #[test]
+    fn test_stats_traffic() {
+        let server_state = new_server_state();
+        server_state.metrics().add_traffic(
+            false,
+            Traffic {
+                bytes_received: 10,
+                bytes_sent: 20,
+            },
+        );
+        server_state.metrics().add_traffic(
+            true,
+            Traffic {
+                bytes_received: 1,
+                bytes_sent: 2,
+            },
+        );
+        server_state
+            .metrics()
+            .add_handshake_failure(HandshakeFailure::Timeout);
+        server_state
+            .metrics()
+            .add_handshake_failure(HandshakeFailure::Protocol);
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_asks_stats(r2(state1), "t");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 249 nick1 t :plaintext received 10 bytes, sent 20 bytes\r\n".to_vec(),
+                b":srv 249 nick1 t :tls received 1 bytes, sent 2 bytes\r\n".to_vec(),
+                b":srv 249 nick1 t :failed handshakes: 1 timeout, 0 bad certificate, 1 protocol, \
+                  0 connection\r\n"
+                    .to_vec(),
+                b":srv 219 nick1 t :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+
+        server_state
+            .metrics()
+            .add_command_duration(Duration::from_micros(50));
+        server_state
+            .metrics()
+            .add_command_duration(Duration::from_secs(2));
+        state1 = server_state.user_asks_stats(r2(state1), "m");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 249 nick1 m :2 command(s), holding the state lock for 2.00005s in total\r\n"
+                    .to_vec(),
+                b":srv 249 nick1 m :<=10\xc2\xb5s: 0, <=100\xc2\xb5s: 1, <=1ms: 0, <=10ms: 0, \
+                  <=100ms: 0, <=1s: 0, longer: 1\r\n"
+                    .to_vec(),
+                b":srv 219 nick1 m :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+
+        server_state.metrics().add_reload();
+        state1 = server_state.user_asks_stats(r2(state1), "u");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 242 nick1 :Server Up 0 days 0:00:00\r\n".to_vec(),
+                b":srv 249 nick1 u :1 configuration reload(s)\r\n".to_vec(),
+                b":srv 219 nick1 u :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+
+        server_state.user_asks_stats(r2(state1), "x");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 219 nick1 x :End of /STATS report\r\n".to_vec()]
+        );
+
+        // the maintenance resets the traffic of the period, but not the totals
+        server_state.run_maintenance();
+        assert_eq!(server_state.metrics().rotate_traffic(), Traffic::default());
+        assert_eq!(server_state.metrics().tls_traffic().bytes_sent, 2);
+    }
+
+    #[test]
+    fn test_whowas() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let (mut state2, _rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"Real Name");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.drive_raw_line(state1, b"WHOWAS nick2");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv 406 nick1 nick2 :There was no such nickname\r\n".to_vec(),
+                b":srv 369 nick1 nick2 :End of WHOWAS\r\n".to_vec(),
+            ]
+        );
+
+        // the history is filled by the nick changes and the disconnections
+        state2 = server_state.user_changes_nick(r2(state2), "nick3");
+        state2 = server_state.user_changes_nick(r2(state2), "nick2");
+        server_state.user_disconnects_voluntarily(r2(state2), None);
+        collect_mail(&mut rx1);
+
+        state1 = server_state.drive_raw_line(state1, b"WHOWAS NICK2");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv 314 nick1 nick2 user2 hidden * :Real Name\r\n".to_vec(),
+                b":srv 312 nick1 nick2 srv :left 0 seconds ago\r\n".to_vec(),
+                b":srv 314 nick1 nick2 user2 hidden * :Real Name\r\n".to_vec(),
+                b":srv 312 nick1 nick2 srv :left 0 seconds ago\r\n".to_vec(),
+                b":srv 369 nick1 NICK2 :End of WHOWAS\r\n".to_vec(),
+            ]
+        );
+
+        // the count limits the number of entries, unless it is not positive
+        state1 = server_state.drive_raw_line(state1, b"WHOWAS nick2 1");
+        assert_eq!(collect_mail(&mut rx1).len(), 3);
+        server_state.drive_raw_line(state1, b"WHOWAS nick3 0");
+        assert_eq!(
+            collect_mail(&mut rx1)[0],
+            b":srv 314 nick1 nick3 user2 hidden * :Real Name\r\n"
+        );
+    }
+
+    #[test]
+    fn test_stats_latency() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let (mut state2, _rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_asks_stats(r2(state1), "r");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv 249 nick1 r :no ping answered yet\r\n".to_vec(),
+                b":srv 219 nick1 r :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+
+        // only the last round trip of each user counts
+        state1 = server_state.user_answers_ping(r2(state1), Duration::from_millis(900));
+        state1 = server_state.user_answers_ping(r2(state1), Duration::from_millis(30));
+        state2 = server_state.user_answers_ping(r2(state2), Duration::from_millis(250));
+        state1 = server_state.user_asks_stats(r2(state1), "r");
+        assert_eq!(
+            collect_mail(&mut rx1)[0],
+            b":srv 249 nick1 r :ping round trip of 2 user(s): p50 30ms, p95 250ms\r\n"
+        );
+
+        // the metrics are updated by the maintenance
+        assert_eq!(server_state.metrics().ping_latencies(), None);
+        server_state.run_maintenance();
+        assert_eq!(
+            server_state.metrics().ping_latencies(),
+            server_state.ping_latencies()
+        );
+        assert_eq!(server_state.metrics().ping_latencies().unwrap().users, 2);
+
+        server_state.dispose_state(state2);
+        assert_eq!(
+            server_state.ping_latencies(),
+            Some(PingLatencies {
+                users: 1,
+                p50: Duration::from_millis(30),
+                p95: Duration::from_millis(30),
+            })
+        );
+        server_state.dispose_state(state1);
+        assert_eq!(server_state.ping_latencies(), None);
+    }
+
+    #[test]
+    fn test_stats_server_names() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let connect = |server_name: Option<&str>, nickname: &str| {
+            let connection_info = ConnectionInfo {
+                is_tls: server_name.is_some(),
+                tls_info: server_name.map(|server_name| TlsInfo {
+                    version: "TLSv1_3".to_string(),
+                    cipher: "TLS13_AES_256_GCM_SHA384".to_string(),
+                    server_name: Some(server_name.to_string()),
+                }),
+                ..Default::default()
+            };
+            let (state, rx) = server_state.new_registering_user(connection_info);
+            let state = server_state.ruser_uses_nick(r1(state), nickname);
+            let state = server_state.ruser_uses_username(r1(state), "user", b"user");
+            (state, rx)
+        };
+        let (mut state1, mut rx1) = connect(None, "nick1");
+        let (_state2, _rx2) = connect(Some("irc.example.org"), "nick2");
+        let (_state3, _rx3) = connect(Some("irc.example.org"), "nick3");
+        let (_state4, _rx4) = connect(Some("chat.example.net"), "nick4");
+        collect_mail(&mut rx1);
+
+        assert_eq!(
+            server_state.users_per_server_name(),
+            BTreeMap::from([
+                (None, 1),
+                (Some("chat.example.net".to_string()), 1),
+                (Some("irc.example.org".to_string()), 2),
+            ])
+        );
+
+        state1 = server_state.user_asks_stats(r2(state1), "d");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 481 nick1 :Permission Denied- You're not an IRC operator\r\n".to_vec()]
+        );
+
+        state1 = server_state.drive_raw_line(state1, b"OPER nick1 secret");
+        collect_mail(&mut rx1);
+        server_state.user_asks_stats(r2(state1), "d");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv 249 nick1 d :no TLS server name: 1 user(s)\r\n".to_vec(),
+                b":srv 249 nick1 d :chat.example.net: 1 user(s)\r\n".to_vec(),
+                b":srv 249 nick1 d :irc.example.org: 2 user(s)\r\n".to_vec(),
+                b":srv 219 nick1 d :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oper() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"wrong");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 464 nick1 :Password incorrect\r\n".to_vec()]
+        );
+
+        // the name is checked once configured
+        server_state.update_config(|config| config.set_oper_name(Some("admin")));
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"secret");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 464 nick1 :Password incorrect\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_asks_oper(r2(state1), "admin", b"secret");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 381 nick1 :You are now an IRC operator\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_asks_user_mode(r2(state1), "nick1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv 221 nick1 +o\r\n".to_vec()]);
+
+        // operators can change the mode of channels without being channel operator
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+m", None);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":nick1!user1@hidden MODE #chan +m\r\n".to_vec()]
+        );
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_changes_user_mode(r2(state1), "nick2", "-o");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 502 nick1 :Cant change mode for other users\r\n".to_vec()]
+        );
+
+        // the modechars are applied one by one, the unknown ones are reported
+        state1 = server_state.drive_raw_line(state1, b"MODE NICK1 +Bw-o");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":nick1!user1@hidden MODE nick1 +B-o\r\n".to_vec(),
+                b":srv 501 nick1 :Unknown MODE flag\r\n".to_vec(),
+            ]
+        );
+
+        server_state.user_asks_user_mode(r2(state1), "nick1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv 221 nick1 +B\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_drive_raw_line() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        state1 = server_state.drive_raw_line(state1, b"USER user1 0 * :real name");
+        assert!(matches!(state1, UserState::Registered(_)));
+        collect_mail(&mut rx1);
+
+        // multiple lines and invalid lines
+        state1 = server_state.drive_raw_line(state1, b"JOIN #chan\r\n\x00\r\nPING tok");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails.first().unwrap(),
+            b":nick1!user1@hidden JOIN #chan\r\n"
+        );
+        assert_eq!(mails.last().unwrap(), b":srv PONG srv :tok\r\n");
+
+        state1 = server_state.drive_raw_line(state1, b"QUIT");
+        assert!(!state1.is_alive());
+    }
+
+    #[test]
+    fn test_invite() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+i", None);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 473 nick2 #chan :Cannot join channel (+i)\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_invites(r2(state1), "nick2", "#chan");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv 341 nick1 nick2 #chan\r\n".to_vec()]);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":nick1!user1@hidden INVITE nick2 #chan\r\n".to_vec()]
+        );
+
+        state2 = server_state.user_lists_invites(r2(state2));
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 336 nick2 #chan\r\n".to_vec(),
+                b":srv 337 nick2 :End of /INVITE list\r\n".to_vec(),
+            ]
+        );
+
+        // the invitation is consumed by the join
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails[0], b":nick2!user2@hidden JOIN #chan\r\n");
+        state2 = server_state.user_leaves_channels(r2(state2), &["#chan"], None);
+        collect_mail(&mut rx2);
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 473 nick2 #chan :Cannot join channel (+i)\r\n".to_vec()]
+        );
+
+        // the invitations expire (the nickname is casefolded)
+        server_state.user_invites(r2(state1), "NICK2", "#chan");
+        assert_eq!(
+            collect_mail(&mut rx1).last().unwrap(),
+            b":srv 341 nick1 nick2 #chan\r\n"
+        );
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + INVITE_DURATION + Duration::from_secs(1),
+        );
+        collect_mail(&mut rx2);
+        server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 473 nick2 #chan :Cannot join channel (+i)\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_kline() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+        let connection_info = |ip: &str| ConnectionInfo {
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(connection_info("10.0.0.2"));
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state1 = server_state.user_sets_kline(r2(state1), None, "*@10.0.0.2", None);
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 481 nick1 :Permission Denied- You're not an IRC operator\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"secret");
+        collect_mail(&mut rx1);
+
+        // the masks matching the operator are refused
+        state1 = server_state.user_sets_kline(r2(state1), None, "*@*", None);
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv NOTICE nick1 :K-line not added for *@*, it matches your own connection\r\n"
+                    .to_vec()
+            ]
+        );
+        assert!(collect_mail(&mut rx2).is_empty());
+
+        let duration = Some(Duration::from_secs(86400));
+        state1 = server_state.user_sets_kline(r2(state1), duration, "*@10.0.0.2", Some(b"spam"));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails.last().unwrap(),
+            b":srv NOTICE nick1 :K-line added for *@10.0.0.2\r\n"
+        );
+
+        // the user is disconnected
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv ERROR :Closing Link: srv (spam)\r\n".to_vec()]
+        );
+        server_state.dispose_state(state2);
+
+        // and cannot reconnect
+        let (mut state2, mut rx2) = server_state.new_registering_user(connection_info("10.0.0.2"));
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        assert!(!state2.is_alive());
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 465 nick2 :You are banned from this server\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_asks_stats(r2(state1), "k");
+        let mails = collect_mail(&mut rx1);
+        assert!(mails[0].starts_with(b":srv 216 nick1 K *@10.0.0.2 :spam (expires in "));
+        assert_eq!(mails[1], b":srv 219 nick1 k :End of /STATS report\r\n");
+
+        // the K-line expires
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(2 * 86400),
+        );
+        server_state.user_asks_stats(r2(state1), "k");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 219 nick1 k :End of /STATS report\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_nick_change_homoglyph() {
+        let server_state = new_server_state();
+        let nick1 = "test";
+        let nick2 = "tėst";
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "jester");
+        state1 = server_state.ruser_uses_username(r1(state1), nick1, nick1.as_bytes());
+        assert!(collect_mail(&mut rx1).len() > 6);
+
+        let (mut state2, _rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), nick1);
+        server_state.ruser_uses_username(r1(state2), nick1, nick1.as_bytes());
+
+        server_state.user_changes_nick(r2(state1), nick2);
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails[0],
+            b":srv 433 jester t\xC4\x97st :Nickname is already in use\r\n"
+        );
+    }
+
+    #[test]
+    fn test_cap_negotiation() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_negotiates_capabilities(r1(state1), "LS", Some("302"));
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.ruser_negotiates_capabilities(r1(state1), "REQ", Some("chghost"));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv CAP * LS :batch chghost labeled-response multi-prefix draft/resume-0.5 server-time\r\n"
+                    .to_vec(),
+                b":srv CAP nick1 ACK :chghost\r\n".to_vec(),
+            ]
+        );
+
+        // the registration completes on CAP END
+        state1 = server_state.ruser_negotiates_capabilities(r1(state1), "END", None);
+        assert!(collect_mail(&mut rx1)[0].starts_with(b":srv 001 nick1 "));
+
+        server_state.user_negotiates_capabilities(r2(state1), "LIST", None);
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv CAP nick1 LIST :chghost\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_resetchan() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+
+        // nick1 took over the channel
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+i", None);
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+f", Some("2:10"));
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "-n", None);
+        server_state.drive_raw_line(state1, b"MODE #chan +bI a *!*@trusted");
+        collect_mail(&mut rx1);
+
+        state2 = server_state.drive_raw_line(state2, b"RESETCHAN #chan");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails.last().unwrap(),
+            b":srv 481 nick2 :Permission Denied- You're not an IRC operator\r\n"
+        );
+
+        state2 = server_state.user_asks_oper(r2(state2), "nick2", b"secret");
+        state2 = server_state.drive_raw_line(state2, b"RESETCHAN #chan nick3");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails.last().unwrap(),
+            b":srv 441 nick2 nick3 #chan :They aren't on that channel\r\n"
+        );
+
+        state2 = server_state.drive_raw_line(state2, b"RESETCHAN #chan");
+        let expected = vec![
+            b":srv MODE #chan +n\r\n".to_vec(),
+            b":srv MODE #chan -i\r\n".to_vec(),
+            b":srv MODE #chan -f\r\n".to_vec(),
+            b":srv MODE #chan -b a!*@*\r\n".to_vec(),
+            b":srv MODE #chan -I *!*@trusted\r\n".to_vec(),
+            b":srv MODE #chan -o nick1\r\n".to_vec(),
+            b":srv MODE #chan +o nick2\r\n".to_vec(),
+        ];
+        assert_eq!(collect_mail(&mut rx1), expected);
+        let mut mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails.pop().unwrap(),
+            b":srv NOTICE nick2 :Channel #chan reset by nick2, nick2 is now the channel operator\r\n"
+        );
+        assert_eq!(mails, expected);
+
+        // an operator outside of the channel resets it, with or without giving it to a member
+        state2 = server_state.drive_raw_line(state2, b"PART #chan");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+        state2 = server_state.drive_raw_line(state2, b"RESETCHAN #chan NICK1");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv MODE #chan +o nick1\r\n".to_vec()]
+        );
+        collect_mail(&mut rx2);
+        server_state.drive_raw_line(state2, b"RESETCHAN #chan");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv MODE #chan -o nick1\r\n".to_vec()]
+        );
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![
+                b":srv NOTICE nick2 :Channel #chan reset by nick2, without channel operator\r\n"
+                    .to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_bans() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(ConnectionInfo {
+            ip: Some("192.0.2.7".parse().unwrap()),
+            ..Default::default()
+        });
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        // only the channel operators can change the list
+        state2 = server_state.drive_raw_line(state2, b"MODE #chan +b nick1");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 482 nick2 #chan :You're not channel operator\r\n".to_vec()]
+        );
+
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +b *@192.0.2.*");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +b Nick3");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +b nick3!*@*");
+        let expected = vec![
+            b":nick1!user1@hidden MODE #chan +b *!*@192.0.2.*\r\n".to_vec(),
+            b":nick1!user1@hidden MODE #chan +b Nick3!*@*\r\n".to_vec(),
+        ];
+        assert_eq!(collect_mail(&mut rx1), expected);
+        assert_eq!(collect_mail(&mut rx2), expected);
+
+        state2 = server_state.drive_raw_line(state2, b"MODE #chan b");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails.len(), 3);
+        assert!(mails[0].starts_with(b":srv 367 nick2 #chan *!*@192.0.2.* nick1 "));
+        assert!(mails[1].starts_with(b":srv 367 nick2 #chan Nick3!*@* nick1 "));
+        assert_eq!(
+            mails[2],
+            b":srv 368 nick2 #chan :End of channel ban list\r\n"
+        );
+
+        // the bans apply when joining, matched against the IP as well
+        state2 = server_state.user_leaves_channels(r2(state2), &["#chan"], None);
+        collect_mail(&mut rx2);
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 474 nick2 #chan :Cannot join channel (+b)\r\n".to_vec()]
+        );
+
+        server_state.drive_raw_line(state1, b"MODE #chan -b *@192.0.2.*");
+        collect_mail(&mut rx1);
+        server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        assert!(collect_mail(&mut rx2)[0].starts_with(b":nick2!user2@hidden JOIN #chan"));
+    }
+
+    #[test]
+    fn test_channel_ban_changes() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, _rx2) = server_state.new_registering_user(ConnectionInfo {
+            ip: Some("192.0.2.7".parse().unwrap()),
+            ..Default::default()
+        });
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        let (mut state3, _rx3) = server_state.new_registering_user(Default::default());
+        state3 = server_state.ruser_uses_nick(r1(state3), "nick3");
+        server_state.ruser_uses_username(r1(state3), "user3", b"user3");
+        collect_mail(&mut rx1);
+
+        // the nickname of a user is resolved to their IP, not to the shown host
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +b NICK2");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":nick1!user1@hidden MODE #chan +b *!*@192.0.2.7\r\n".to_vec()]
+        );
+        // or to their nickname, when the IP is unknown
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +b-b NICK3 nick3!*@*");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":nick1!user1@hidden MODE #chan +b-b nick3!*@* nick3!*@*\r\n".to_vec()]
+        );
+
+        // the changes are grouped by MODES, without the ones changing nothing
+        state1 =
+            server_state.drive_raw_line(state1, b"MODE #chan +bbb-bbb a b c *!*@192.0.2.7 d e");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":nick1!user1@hidden MODE #chan +bbb-b a!*@* b!*@* c!*@* *!*@192.0.2.7\r\n"
+                    .to_vec(),
+            ]
+        );
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan -bbb+bbb a b c d e f");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":nick1!user1@hidden MODE #chan -bbb+b a!*@* b!*@* c!*@* d!*@*\r\n".to_vec(),
+                b":nick1!user1@hidden MODE #chan +bb e!*@* f!*@*\r\n".to_vec(),
+            ]
+        );
+
+        // the extra masks are ignored
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan -b d e");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":nick1!user1@hidden MODE #chan -b d!*@*\r\n".to_vec()]
+        );
+
+        // the lists are capped at MAXLIST
+        for i in 2..MAX_LIST_ENTRIES {
+            let line = format!("MODE #chan +b mask{i}");
+            state1 = server_state.drive_raw_line(state1, line.as_bytes());
+        }
+        collect_mail(&mut rx1);
+        server_state.drive_raw_line(state1, b"MODE #chan +bbI x y *!*@trusted");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":nick1!user1@hidden MODE #chan +I *!*@trusted\r\n".to_vec(),
+                b":srv 478 nick1 #chan b :Channel list is full\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_invite_exemptions() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +i");
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(ConnectionInfo {
+            ip: Some("192.0.2.7".parse().unwrap()),
+            ..Default::default()
+        });
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +Ib *@192.0.2.* nick3");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":nick1!user1@hidden MODE #chan +Ib *!*@192.0.2.* nick3!*@*\r\n".to_vec()]
+        );
+
+        // the exempted users join without invitation, but the bans still apply
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        assert!(collect_mail(&mut rx2)[0].starts_with(b":nick2!user2@hidden JOIN #chan"));
+        collect_mail(&mut rx1);
+
+        // only the channel operators see the list
+        state2 = server_state.drive_raw_line(state2, b"MODE #chan I");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 482 nick2 #chan :You're not channel operator\r\n".to_vec()]
+        );
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan I");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails.len(), 2);
+        assert!(mails[0].starts_with(b":srv 346 nick1 #chan *!*@192.0.2.* nick1 "));
+        assert_eq!(
+            mails[1],
+            b":srv 347 nick1 #chan :End of channel invite exception list\r\n"
+        );
+
+        // the timed entries are removed once expired
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan -I+I *@192.0.2.* ~time:1h:nick2");
+        let expected =
+            vec![b":nick1!user1@hidden MODE #chan -I+I *!*@192.0.2.* *!*@192.0.2.7\r\n".to_vec()];
+        assert_eq!(collect_mail(&mut rx1), expected);
+        assert_eq!(collect_mail(&mut rx2), expected);
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(30 * 60),
+        );
+        assert!(collect_mail(&mut rx1).is_empty());
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(3601),
+        );
+        let expected = vec![b":srv MODE #chan -I *!*@192.0.2.7\r\n".to_vec()];
+        assert_eq!(collect_mail(&mut rx1), expected);
+        assert_eq!(collect_mail(&mut rx2), expected);
+        server_state.drive_raw_line(state1, b"MODE #chan I");
+        assert_eq!(collect_mail(&mut rx1).len(), 1);
+
+        state2 = server_state.user_leaves_channels(r2(state2), &["#chan"], None);
+        collect_mail(&mut rx2);
+        server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 473 nick2 #chan :Cannot join channel (+i)\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_channel_key() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +k a,b");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 696 nick1 #chan k a,b :the key should not contain commas, spaces or control characters\r\n".to_vec()]
+        );
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +k secret");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":nick1!user1@hidden MODE #chan +k secret\r\n".to_vec()]
+        );
+
+        // the key is only shown to the members
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan");
+        assert_eq!(
+            collect_mail(&mut rx1)[0],
+            b":srv 324 nick1 #chan +nk secret\r\n"
+        );
+        state2 = server_state.drive_raw_line(state2, b"MODE #chan");
+        assert_eq!(collect_mail(&mut rx2)[0], b":srv 324 nick2 #chan +nk *\r\n");
+
+        state2 = server_state.drive_raw_line(state2, b"JOIN #chan");
+        state2 = server_state.drive_raw_line(state2, b"JOIN #chan wrong");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![
+                b":srv 475 nick2 #chan :Cannot join channel (+k)\r\n".to_vec(),
+                b":srv 475 nick2 #chan :Cannot join channel (+k)\r\n".to_vec(),
+            ]
+        );
+
+        // the keys are matched with the channels by position
+        state2 = server_state.drive_raw_line(state2, b"JOIN #other,#chan x,secret");
+        let mails = collect_mail(&mut rx2);
+        assert!(mails[0].starts_with(b":nick2!user2@hidden JOIN #other"));
+        assert!(mails
+            .iter()
+            .any(|mail| mail.starts_with(b":nick2!user2@hidden JOIN #chan")));
+
+        // the key is not needed to remove it
+        server_state.drive_raw_line(state1, b"MODE #chan -k");
+        assert_eq!(
+            collect_mail(&mut rx1).last().unwrap(),
+            b":nick1!user1@hidden MODE #chan -k *\r\n"
+        );
+        collect_mail(&mut rx2);
+        server_state.drive_raw_line(state2, b"MODE #chan");
+        assert_eq!(collect_mail(&mut rx2)[0], b":srv 324 nick2 #chan +n\r\n");
+    }
+
+    #[test]
+    fn test_chghost() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_negotiates_capabilities(r1(state2), "REQ", Some("chghost"));
+        state2 = server_state.ruser_negotiates_capabilities(r1(state2), "END", None);
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+
+        let (mut state3, mut rx3) = server_state.new_registering_user(Default::default());
+        state3 = server_state.ruser_uses_nick(r1(state3), "nick3");
+        state3 = server_state.ruser_uses_username(r1(state3), "user3", b"user3");
+        server_state.user_joins_channels(r2(state3), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+        collect_mail(&mut rx3);
+
+        state1 = server_state.user_changes_host(r2(state1), "nick3", "vanity.host");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 481 nick1 :Permission Denied- You're not an IRC operator\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"secret");
+        collect_mail(&mut rx1);
+        state1 = server_state.user_changes_host(r2(state1), "nick3", "vanity.host");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv NOTICE nick1 :Host of nick3 changed to vanity.host\r\n".to_vec()]
+        );
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":nick3!user3@hidden CHGHOST user3 vanity.host\r\n".to_vec()]
+        );
+        let mails = collect_mail(&mut rx3);
+        assert_eq!(
+            mails,
+            vec![b":srv 396 nick3 vanity.host :is now your displayed host\r\n".to_vec()]
+        );
+
+        server_state.user_asks_whois(r2(state2), "nick3");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails[0],
+            b":srv 311 nick2 nick3 user3 vanity.host * :user3\r\n"
+        );
+
+        server_state.user_changes_host(r2(state1), "nick3", "bad host");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv NOTICE nick1 :Invalid hostname: bad host\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let server_state = new_server_state();
+        let token_of = |mails: &[Vec<u8>]| {
+            mails
+                .iter()
+                .find_map(|m| m.strip_prefix(b":srv RESUME TOKEN "))
+                .map(|token| String::from_utf8_lossy(token).trim_end().to_string())
+                .unwrap()
+        };
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(ConnectionInfo {
+            is_tls: true,
+            ..Default::default()
+        });
+        state1 = server_state.drive_raw_line(state1, b"CAP REQ draft/resume-0.5");
+        state1 = server_state.drive_raw_line(state1, b"CAP END");
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        let token = token_of(&collect_mail(&mut rx1));
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.drive_raw_line(state2, b"CAP REQ :chghost draft/resume-0.5");
+        state2 = server_state.drive_raw_line(state2, b"CAP END");
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        let (mut state3, mut rx3) = server_state.new_registering_user(Default::default());
+        state3 = server_state.drive_raw_line(state3, b"CAP REQ chghost");
+        state3 = server_state.drive_raw_line(state3, b"CAP END");
+        state3 = server_state.ruser_uses_nick(r1(state3), "nick3");
+        state3 = server_state.ruser_uses_username(r1(state3), "user3", b"user3");
+        state3 = server_state.user_joins_channels(r2(state3), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+        collect_mail(&mut rx3);
+
+        // the token has to be known, and the session cannot move out of TLS
+        let (mut state4, mut rx4) = server_state.new_registering_user(Default::default());
+        state4 = server_state.drive_raw_line(state4, b"CAP REQ draft/resume-0.5");
+        state4 = server_state.drive_raw_line(state4, b"RESUME abc");
+        state4 = server_state.drive_raw_line(state4, format!("RESUME {token}").as_bytes());
+        let mails = collect_mail(&mut rx4);
+        assert_eq!(
+            mails[1..],
+            [
+                b":srv FAIL RESUME INVALID_TOKEN :Cannot resume connection, token is not valid\r\n"
+                    .to_vec(),
+                b":srv FAIL RESUME INSECURE_SESSION :Cannot resume connection, old and new clients \
+                  must use TLS\r\n"
+                    .to_vec(),
+            ]
+        );
+        server_state.ruser_disconnects_suddently(r1(state4));
+
+        let (mut state5, mut rx5) = server_state.new_registering_user(ConnectionInfo {
+            is_tls: true,
+            ip: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        });
+        state5 = server_state.drive_raw_line(state5, b"CAP REQ draft/resume-0.5");
+        state5 = server_state.drive_raw_line(state5, format!("RESUME {token}").as_bytes());
+        state5 = server_state.drive_raw_line(state5, b"NICK nick1");
+        state5 = server_state.drive_raw_line(state5, b"USER user1 0 * :user1");
+        state5 = server_state.drive_raw_line(state5, b"CAP END");
+        let mails = collect_mail(&mut rx5);
+        let registration = mails
+            .iter()
+            .position(|m| m == b":srv RESUME SUCCESS nick1\r\n")
+            .unwrap();
+        assert!(mails[registration + 1].starts_with(b":srv 001 nick1 "));
+        assert_ne!(token_of(&mails), token);
+        assert!(mails.contains(&b":nick1!user1@hidden JOIN #chan\r\n".to_vec()));
+        let names = mails
+            .iter()
+            .find(|m| m.starts_with(b":srv 353 nick1 = #chan :"))
+            .unwrap();
+        assert!(String::from_utf8_lossy(names).contains("@nick1"));
+
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv ERROR :Closing Link: srv (Connection resumed elsewhere)\r\n".to_vec()]
+        );
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":nick1!user1@hidden RESUMED hidden\r\n".to_vec()]
+        );
+        assert!(collect_mail(&mut rx3).is_empty());
+
+        // the previous connection closing does not end the session, which stays operator of the
+        // channel
+        server_state.dispose_state(state1);
+        server_state.user_messages_target(r2(state3), "nick1", b"hello");
+        assert_eq!(
+            collect_mail(&mut rx5),
+            vec![b":nick3!user3@hidden PRIVMSG nick1 :hello\r\n".to_vec()]
+        );
+        server_state.user_changes_channel_mode(r2(state5), "#chan", "+t", None);
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":nick1!user1@hidden MODE #chan +t\r\n".to_vec()]
+        );
+
+        server_state.drive_raw_line(state2, b"RESUME abc");
+        assert_eq!(
+            collect_mail(&mut rx2).last().unwrap(),
+            b":srv FAIL RESUME REGISTRATION_IS_COMPLETED :Cannot resume connection, connection \
+              registration has already been completed\r\n"
+        );
+    }
+
+    #[test]
+    fn test_cap_sts() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| {
+            config.set_sts_policy(Some(StsPolicy {
+                port: 6697,
+                duration: Duration::from_secs(86400),
+            }))
+        });
+
+        let (state1, mut rx1) = server_state.new_registering_user(Default::default());
+        server_state.ruser_negotiates_capabilities(r1(state1), "LS", Some("302"));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv CAP * LS :batch chghost labeled-response multi-prefix draft/resume-0.5 server-time sts=port=6697\r\n"
+                    .to_vec()
+            ]
+        );
+
+        let connection_info = ConnectionInfo {
+            is_tls: true,
+            ..Default::default()
+        };
+        let (state2, mut rx2) = server_state.new_registering_user(connection_info);
+        server_state.ruser_negotiates_capabilities(r1(state2), "LS", Some("302"));
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv CAP * LS :batch chghost labeled-response multi-prefix draft/resume-0.5 server-time sts=duration=86400\r\n"
+                    .to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dcc_policy() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| c.set_dcc_policy(DccPolicy::CoMembers));
+
+        let mut users = vec![];
+        for nickname in ["nick1", "nick2", "nick3"] {
+            let (state, rx) = server_state.new_registering_user(Default::default());
+            let state = server_state.ruser_uses_nick(r1(state), nickname);
+            let state = server_state.ruser_uses_username(r1(state), "user", b"user");
+            users.push((state, rx));
+        }
+        let (mut state3, mut rx3) = users.pop().unwrap();
+        let (mut state2, mut rx2) = users.pop().unwrap();
+        let (mut state1, mut rx1) = users.pop().unwrap();
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+        collect_mail(&mut rx3);
+
+        let offer = b"\x01DCC SEND file.txt 3232235777 5000 1024\x01";
+        state1 = server_state.user_messages_target(r2(state1), "nick2", offer);
+        assert_eq!(collect_mail(&mut rx2).len(), 1);
+
+        // the other CTCP requests are not concerned
+        state1 = server_state.user_messages_target(r2(state1), "nick3", b"\x01VERSION\x01");
+        assert_eq!(collect_mail(&mut rx3).len(), 1);
+
+        server_state.user_messages_target(r2(state1), "nick3", offer);
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv FAIL PRIVMSG DCC_BLOCKED :DCC offers to nick3 are not relayed by this \
+                   server\r\n"
+                    .to_vec()
+            ]
+        );
+        state3 = server_state.user_notices_target(r2(state3), "nick1", offer);
+        assert!(collect_mail(&mut rx1).is_empty());
+        assert!(collect_mail(&mut rx3).is_empty());
+
+        server_state.update_config(|c| c.set_dcc_policy(DccPolicy::Strip));
+        state2 = server_state.user_notices_target(r2(state2), "nick1", offer);
+        assert!(collect_mail(&mut rx1).is_empty());
+        server_state.user_messages_target(r2(state2), "#chan", offer);
+        assert!(collect_mail(&mut rx1).is_empty());
+        assert_eq!(collect_mail(&mut rx2).len(), 1);
+
+        server_state.update_config(|c| c.set_dcc_policy(DccPolicy::Allow));
+        server_state.user_messages_target(r2(state3), "nick1", offer);
+        assert_eq!(collect_mail(&mut rx1).len(), 1);
+    }
+
+    #[test]
+    fn test_content_filter() {
+        let server_state = new_server_state();
+        let censor = ContentFilter::new(ContentFilterAction::Censor)
+            .with_word("heck")
+            .unwrap();
+        let block = ContentFilter::new(ContentFilterAction::Block)
+            .with_regex("spam")
+            .unwrap()
+            .for_channels(vec!["#chan".to_string()]);
+        server_state.update_config(|config| {
+            config.set_message_hooks(vec![Arc::new(censor.clone()), Arc::new(block.clone())])
+        });
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state1 = server_state.user_messages_target(r2(state1), "#chan", b"oh heck");
+        assert!(collect_mail(&mut rx1).is_empty());
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":nick1!user1@hidden PRIVMSG #chan :oh ***\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_messages_target(r2(state1), "#chan", b"some spam");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 404 nick1 #chan :Cannot send to channel\r\n".to_vec()]
+        );
+        assert!(collect_mail(&mut rx2).is_empty());
+
+        // the block filter is restricted to #chan
+        server_state.user_messages_target(r2(state1), "nick2", b"some spam");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":nick1!user1@hidden PRIVMSG nick2 :some spam\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_no_nick_change() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+N", None);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state2 = server_state.user_changes_nick(r2(state2), "nick3");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 447 nick2 :Cannot change nickname while on #chan (+N)\r\n".to_vec()]
+        );
+
+        // channel operators are not affected
+        state1 = server_state.user_changes_nick(r2(state1), "nick4");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":nick1!user1@hidden NICK :nick4\r\n".to_vec()]);
+
+        server_state.user_changes_channel_mode(r2(state1), "#chan", "-N", None);
+        collect_mail(&mut rx2);
+        server_state.user_changes_nick(r2(state2), "nick3");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails, vec![b":nick2!user2@hidden NICK :nick3\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_mode_minus_v_removes_voice() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, _rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        let is_voiced = |nickname: &str| {
+            let members = server_state.channel_members("#chan").unwrap();
+            members.iter().any(|m| m.nickname == nickname && m.voice)
+        };
+
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +v nick2");
+        assert!(is_voiced("nick2"));
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan -v nick2");
+        assert!(!is_voiced("nick2"));
+
+        server_state.drive_raw_line(state1, b"NAMES #chan");
+        let mails = collect_mail(&mut rx1);
+        let names = mails
+            .iter()
+            .find(|m| m.starts_with(b":srv 353 nick1 = #chan :"))
+            .unwrap();
+        assert!(!names.windows(6).any(|w| w == b"+nick2"));
+    }
+
+    #[test]
+    fn test_channel_action_log() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+
+        // nothing is recorded without +H
+        state1 = server_state.drive_raw_line(state1, b"TOPIC #chan :before");
+        collect_mail(&mut rx1);
+        state1 = server_state.drive_raw_line(state1, b"ACTIONLOG #chan");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv NOTICE nick1 :The actions are not recorded on #chan (mode +H)\r\n".to_vec()
+            ]
+        );
+
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +H");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +o nick2");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +k secret");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +bI nick2 *!*@trusted");
+        state1 = server_state.drive_raw_line(state1, b"TOPIC #chan :after");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan");
+        let mails = collect_mail(&mut rx1);
+        assert!(mails
+            .iter()
+            .any(|m| m.starts_with(b":srv 324 nick1 #chan +nHk ")));
+
+        // the log is shown to the channel operators only
+        collect_mail(&mut rx2);
+        state2 = server_state.drive_raw_line(state2, b"MODE #chan -o nick2");
+        server_state.drive_raw_line(state2, b"ACTIONLOG #chan");
+        assert_eq!(
+            collect_mail(&mut rx2)[1..],
+            vec![b":srv 482 nick2 #chan :You're not channel operator\r\n".to_vec()]
+        );
+        state1 = server_state.drive_raw_line(state1, b"ACTIONLOG #chan");
+        assert_eq!(
+            collect_mail(&mut rx1)[1..],
+            vec![
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +H\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +o nick2\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +k secret\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +b nick2!*@*\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +I *!*@trusted\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: TOPIC :after\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick2: MODE -o nick2\r\n".to_vec(),
+                b":srv NOTICE nick1 :End of the action log of #chan\r\n".to_vec(),
+            ]
+        );
+
+        // only the most recent actions are kept
+        for i in 0..60 {
+            let line = format!("TOPIC #chan :topic {i}");
+            state1 = server_state.drive_raw_line(state1, line.as_bytes());
+        }
+        collect_mail(&mut rx1);
+        state1 = server_state.drive_raw_line(state1, b"ACTIONLOG #chan");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails.len(), 51);
+        assert_eq!(
+            mails[0],
+            b":srv NOTICE nick1 :0s ago, nick1: TOPIC :topic 10\r\n".to_vec()
+        );
+
+        // the log is forgotten with -H
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan -H");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +H");
+        collect_mail(&mut rx1);
+        state1 = server_state.drive_raw_line(state1, b"ACTIONLOG #chan");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +H\r\n".to_vec(),
+                b":srv NOTICE nick1 :End of the action log of #chan\r\n".to_vec(),
+            ]
+        );
+
+        // but not with RESETCHAN, which keeps +H
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"secret");
+        state1 = server_state.drive_raw_line(state1, b"RESETCHAN #chan");
+        collect_mail(&mut rx1);
+        state1 = server_state.drive_raw_line(state1, b"ACTIONLOG #chan");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv NOTICE nick1 :0s ago, nick1: MODE +H\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE -k *\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE -b nick2!*@*\r\n".to_vec(),
+                b":srv NOTICE nick1 :0s ago, nick1: MODE -I *!*@trusted\r\n".to_vec(),
+                b":srv NOTICE nick1 :End of the action log of #chan\r\n".to_vec(),
+            ]
+        );
+        server_state.drive_raw_line(state1, b"MODE #chan");
+        assert_eq!(collect_mail(&mut rx1)[0], b":srv 324 nick1 #chan +nH\r\n");
+    }
+
+    #[test]
+    fn test_batched_replies() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"CAP REQ batch");
+        state1 = server_state.drive_raw_line(state1, b"CAP END");
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx2);
+
+        // each reply is in its own batch, identified by its reference
+        let batches = |mails: Vec<Vec<u8>>| {
+            let lines = mails
+                .into_iter()
+                .map(|m| String::from_utf8(m).unwrap().trim_end().to_string())
+                .collect::<Vec<_>>();
+            let mut batched = vec![];
+            let mut current: Option<(String, Vec<String>)> = None;
+            for line in lines {
+                if let Some(start) = line.strip_prefix(":srv BATCH +") {
+                    let (id, batch_type) = start.split_once(' ').unwrap();
+                    current = Some((id.to_string(), vec![batch_type.to_string()]));
+                } else if let Some(id) = line.strip_prefix(":srv BATCH -") {
+                    let (current_id, batch) = current.take().unwrap();
+                    assert_eq!(id, current_id);
+                    batched.push(batch);
+                } else {
+                    let (id, batch) = current.as_mut().expect("message out of a batch");
+                    let tag = format!("@batch={id} ");
+                    batch.push(line.strip_prefix(&tag).unwrap().to_string());
+                }
+            }
+            assert!(current.is_none());
+            batched
+        };
+
+        state1 = server_state.drive_raw_line(state1, b"JOIN #chan");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails[0], b":nick1!user1@hidden JOIN #chan\r\n");
+        assert_eq!(
+            batches(mails[1..].to_vec()),
+            vec![vec![
+                "kidanger.github.io/names",
+                ":srv 353 nick1 = #chan :@nick1",
+                ":srv 366 nick1 #chan :End of NAMES list",
+            ]]
+        );
+
+        state1 = server_state.drive_raw_line(state1, b"WHO #chan");
+        state1 = server_state.drive_raw_line(state1, b"LIST");
+        assert_eq!(
+            batches(collect_mail(&mut rx1)),
+            vec![
+                vec![
+                    "kidanger.github.io/who",
+                    ":srv 352 nick1 #chan user1 hidden srv nick1 H@ :0 user1",
+                    ":srv 315 nick1 #chan :End of WHO list",
+                ],
+                vec![
+                    "kidanger.github.io/list",
+                    ":srv 322 nick1 #chan 1 :",
+                    ":srv 323 nick1 :End of LIST",
+                ]
+            ]
+        );
+
+        // a lone message is not wrapped
+        server_state.drive_raw_line(state1, b"WHO #nobody");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 315 nick1 #nobody :End of WHO list\r\n".to_vec()]
+        );
+
+        // nor are the replies to the clients without the capability
+        server_state.drive_raw_line(state2, b"WHO #chan");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![
+                b":srv 352 nick2 #chan user1 hidden srv nick1 H@ :0 user1\r\n".to_vec(),
+                b":srv 315 nick2 #chan :End of WHO list\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_prefix() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"CAP REQ multi-prefix");
+        state1 = server_state.drive_raw_line(state1, b"CAP END");
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +v nick1");
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        // sorted, as are the nicknames of RPL_NAMREPLY
+        let names_and_who = |mails: Vec<Vec<u8>>| {
+            let mut replies = mails
+                .into_iter()
+                .filter(|m| m.starts_with(b":srv 353 ") || m.starts_with(b":srv 352 "))
+                .map(|m| {
+                    let line = String::from_utf8(m).unwrap().trim_end().to_string();
+                    match line.split_once(" = #chan :") {
+                        Some((start, names)) if line.starts_with(":srv 353 ") => {
+                            let mut names = names.split(' ').collect::<Vec<_>>();
+                            names.sort_by_key(|name| name.trim_start_matches(['@', '+']));
+                            format!("{start} = #chan :{}", names.join(" "))
+                        }
+                        _ => line,
+                    }
+                })
+                .collect::<Vec<_>>();
+            replies.sort();
+            replies
+        };
+
+        // all the prefixes with the capability, the highest one otherwise
+        state1 = server_state.drive_raw_line(state1, b"NAMES #chan");
+        state1 = server_state.drive_raw_line(state1, b"WHO #chan");
+        assert_eq!(
+            names_and_who(collect_mail(&mut rx1)),
+            vec![
+                ":srv 352 nick1 #chan user1 hidden srv nick1 H@+ :0 user1",
+                ":srv 352 nick1 #chan user2 hidden srv nick2 H :0 user2",
+                ":srv 353 nick1 = #chan :@+nick1 nick2",
+            ]
+        );
+        state2 = server_state.drive_raw_line(state2, b"NAMES #chan");
+        server_state.drive_raw_line(state2, b"WHO #chan");
+        assert_eq!(
+            names_and_who(collect_mail(&mut rx2)),
+            vec![
+                ":srv 352 nick2 #chan user1 hidden srv nick1 H@ :0 user1",
+                ":srv 352 nick2 #chan user2 hidden srv nick2 H :0 user2",
+                ":srv 353 nick2 = #chan :@nick1 nick2",
+            ]
+        );
+
+        // the voice can be taken back
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan -v nick1");
+        server_state.drive_raw_line(state1, b"NAMES #chan");
+        assert_eq!(
+            names_and_who(collect_mail(&mut rx1)),
+            vec![":srv 353 nick1 = #chan :@nick1 nick2"]
+        );
+    }
+
+    #[test]
+    fn test_secret_channel_visibility() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        server_state.user_changes_channel_mode(r2(state1), "#chan", "+s", None);
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state2 = server_state.user_asks_who(r2(state2), "#chan");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 315 nick2 #chan :End of WHO list\r\n".to_vec()]
+        );
+
+        state2 = server_state.user_names_channels(r2(state2), &["#chan"]);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 366 nick2 #chan :End of NAMES list\r\n".to_vec()]
+        );
+
+        server_state.user_sends_list_info(r2(state2), None, None);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails, vec![b":srv 323 nick2 :End of LIST\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_frame_coalesced_messages() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_negotiates_capabilities(r1(state1), "REQ", Some("batch"));
+        state1 = server_state.ruser_negotiates_capabilities(r1(state1), "END", None);
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+
+        for i in 2..4 {
+            let (mut state, _rx) = server_state.new_registering_user(Default::default());
+            state = server_state.ruser_uses_nick(r1(state), &format!("nick{i}"));
+            state = server_state.ruser_uses_username(r1(state), "user", b"user");
+            server_state.user_joins_channels(r2(state), &["#chan"], &[]);
+        }
+
+        let mut messages = vec![];
+        while let Ok(m) = rx1.try_recv() {
+            assert!(m.is_membership_change());
+            messages.push(m);
+        }
+        let bytes = server_state.frame_coalesced_messages(state1.user_id().unwrap(), &messages);
+        let bytes = String::from_utf8(bytes).unwrap();
+        let lines = bytes.split_terminator("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        let batch_id = lines[0]
+            .strip_prefix(":srv BATCH +")
+            .and_then(|l| l.strip_suffix(" kidanger.github.io/membership"))
+            .unwrap();
+        assert_eq!(
+            lines[1],
+            format!("@batch={batch_id} :nick2!user@hidden JOIN #chan")
+        );
+        assert_eq!(
+            lines[2],
+            format!("@batch={batch_id} :nick3!user@hidden JOIN #chan")
+        );
+        assert_eq!(lines[3], format!(":srv BATCH -{batch_id}"));
+    }
+
+    #[test]
+    fn test_server_time() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"CAP REQ :batch server-time");
+        state1 = server_state.drive_raw_line(state1, b"CAP END");
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails[0], b":srv CAP * ACK :batch server-time\r\n".to_vec());
+        assert!(mails[1..].iter().all(|m| m.starts_with(b"@time=")));
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx2);
+
+        // the other clients are not concerned
+        state2 = server_state.user_messages_target(r2(state2), "nick1", b"hello");
+        let mails = collect_mail(&mut rx1);
+        let (time, message) = std::str::from_utf8(&mails[0])
+            .unwrap()
+            .strip_prefix("@time=")
+            .and_then(|m| m.split_once(' '))
+            .unwrap();
+        assert_eq!(message, ":nick2!user2@hidden PRIVMSG nick1 :hello\r\n");
+        assert_eq!(time.len(), "2011-10-19T16:40:51.620Z".len());
+        assert!(time.ends_with('Z'));
+        state1 = server_state.user_messages_target(r2(state1), "nick2", b"hi");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":nick1!user1@hidden PRIVMSG nick2 :hi\r\n".to_vec()]
+        );
+
+        // the tags are joined in the batches of coalesced messages
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        server_state.user_leaves_channels(r2(state2), &["#chan"], None);
+        let mut messages = vec![];
+        while let Ok(m) = rx1.try_recv() {
+            messages.push(m);
+        }
+        let bytes = server_state.frame_coalesced_messages(state1.user_id().unwrap(), &messages);
+        let bytes = String::from_utf8(bytes).unwrap();
+        let lines = bytes.split_terminator("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("@batch="));
+        assert!(lines[1].contains(";time="));
+        assert!(lines[1].ends_with(" :nick2!user2@hidden JOIN #chan"));
+
+        // the capability can be disabled after the registration
+        state1 = server_state.drive_raw_line(state1, b"CAP REQ -server-time");
+        assert!(collect_mail(&mut rx1)[0].ends_with(b" :srv CAP nick1 ACK :-server-time\r\n"));
+        server_state.drive_raw_line(state1, b"PING token");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv PONG srv :token\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_labeled_response() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"CAP REQ :batch labeled-response");
+        state1 = server_state.drive_raw_line(state1, b"CAP END");
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+
+        // no reply: a bare ACK
+        state1 = server_state.drive_raw_line(state1, b"@label=a PONG srv");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b"@label=a :srv ACK\r\n".to_vec()]
+        );
+
+        // a single reply carries the label
+        state1 = server_state.drive_raw_line(state1, b"@label=b PING token");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b"@label=b :srv PONG srv :token\r\n".to_vec()]
+        );
+
+        // several replies are batched, with the batch of the WHO reply nested
+        state1 = server_state.drive_raw_line(state1, b"@label=c WHO #chan");
+        let mails = collect_mail(&mut rx1);
+        let mails = mails
+            .iter()
+            .map(|m| std::str::from_utf8(m).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(mails.len(), 6);
+        let id = mails[0]
+            .strip_prefix("@label=c :srv BATCH +")
+            .and_then(|m| m.strip_suffix(" labeled-response\r\n"))
+            .unwrap();
+        let who_id = mails[1]
+            .strip_prefix(&format!("@batch={id} :srv BATCH +"))
+            .and_then(|m| m.strip_suffix(" kidanger.github.io/who\r\n"))
+            .unwrap();
+        assert!(mails[2].starts_with(&format!("@batch={who_id} :srv 352 nick1 #chan ")));
+        assert!(mails[3].starts_with(&format!("@batch={who_id} :srv 315 nick1 #chan ")));
+        assert_eq!(mails[4], format!("@batch={id} :srv BATCH -{who_id}\r\n"));
+        assert_eq!(mails[5], format!(":srv BATCH -{id}\r\n"));
+
+        // messages without label are not held
+        server_state.drive_raw_line(state1, b"PING token");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv PONG srv :token\r\n".to_vec()]
+        );
+
+        // without the batch capability, only the responses of several replies lose the label
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.drive_raw_line(state2, b"CAP REQ :labeled-response");
+        state2 = server_state.drive_raw_line(state2, b"CAP END");
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx2);
+        state2 = server_state.drive_raw_line(state2, b"@label=d PING token");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b"@label=d :srv PONG srv :token\r\n".to_vec()]
+        );
+        state2 = server_state.drive_raw_line(state2, b"@label=e PONG srv");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b"@label=e :srv ACK\r\n".to_vec()]
+        );
+        server_state.drive_raw_line(state2, b"@label=f WHO #chan");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails.len(), 3);
+        assert!(mails
+            .iter()
+            .all(|m| m.starts_with(b":srv 352 ") || m.starts_with(b":srv 315 ")));
+    }
+
+    #[test]
+    fn test_your_id() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        let state1 = r2(server_state.ruser_uses_username(r1(state1), "user1", b"user1"));
+
+        let expected = format!(
+            ":srv 042 nick1 {} :your unique ID\r\n",
+            state1.user_id.public_id()
+        );
+        let mails = collect_mail(&mut rx1);
+        assert!(mails.contains(&expected.into_bytes()));
+    }
+
+    #[test]
+    fn test_stats_operators() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"secret");
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state2 = server_state.user_asks_stats(r2(state2), "p");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 249 nick2 p :nick1 (user1@hidden)\r\n".to_vec(),
+                b":srv 249 nick2 p :1 operator(s) online\r\n".to_vec(),
+                b":srv 219 nick2 p :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+
+        state1 = server_state.user_changes_user_mode(r2(state1), "nick1", "+H");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":nick1!user1@hidden MODE nick1 +H\r\n".to_vec()]
+        );
+
+        state2 = server_state.user_asks_stats(r2(state2), "p");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 249 nick2 p :0 operator(s) online\r\n".to_vec(),
+                b":srv 219 nick2 p :End of /STATS report\r\n".to_vec(),
+            ]
+        );
+
+        // only operators can hide their status
+        server_state.user_changes_user_mode(r2(state2), "nick2", "+H");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 481 nick2 :Permission Denied- You're not an IRC operator\r\n".to_vec()]
+        );
+
+        server_state.user_asks_user_mode(r2(state1), "nick1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv 221 nick1 +oH\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_rules_and_admin() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_wants_rules(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 434 nick1 :RULES File is missing\r\n".to_vec()]
+        );
+
+        state1 = server_state.user_wants_admin_info(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":srv 423 nick1 srv :No administrative info available\r\n".to_vec()]
+        );
+
+        server_state.update_config(|config| {
+            config.set_rules(Some(vec![b"be nice".to_vec()]));
+            config.set_admin_info(Some(AdminInfo {
+                location: "Somewhere".to_string(),
+                email: "admin@example.com".to_string(),
+            }));
+        });
+
+        state1 = server_state.user_wants_rules(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 308 nick1 :- srv Server Rules - \r\n".to_vec(),
+                b":srv 232 nick1 :- be nice\r\n".to_vec(),
+                b":srv 309 nick1 :End of RULES command\r\n".to_vec(),
+            ]
+        );
+
+        server_state.user_wants_admin_info(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 256 nick1 srv :Administrative info\r\n".to_vec(),
+                b":srv 257 nick1 :Somewhere\r\n".to_vec(),
+                b":srv 259 nick1 :admin@example.com\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_motd_overrides() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| {
+            config.set_motd(Some(vec![b"default".to_vec()]));
+            config.set_tls_motd(Some(vec![b"tls".to_vec()]));
+            config.set_listener_motds(HashMap::from([(
+                "onion".to_string(),
+                vec![b"onion".to_vec()],
+            )]));
+        });
+
+        let motd_of = |connection_info: ConnectionInfo| {
+            let (mut state, mut rx) = server_state.new_registering_user(connection_info);
+            state = server_state.ruser_uses_nick(r1(state), "nick");
+            state = server_state.ruser_uses_username(r1(state), "user", b"user");
+            collect_mail(&mut rx);
+            state = server_state.user_wants_motd(r2(state));
+            server_state.dispose_state(state);
+            collect_mail(&mut rx)
+        };
+        let motd_line = |line: &str| format!(":srv 372 nick :- {line}\r\n").into_bytes();
+
+        let mails = motd_of(Default::default());
+        assert!(mails.contains(&motd_line("default")));
+
+        let mails = motd_of(ConnectionInfo {
+            is_tls: true,
+            ..Default::default()
+        });
+        assert!(mails.contains(&motd_line("tls")));
+
+        let mails = motd_of(ConnectionInfo {
+            is_tls: true,
+            listener: Some("onion".to_string()),
+            ..Default::default()
+        });
+        assert!(mails.contains(&motd_line("onion")));
+
+        let mails = motd_of(ConnectionInfo {
+            listener: Some("other".to_string()),
+            ..Default::default()
+        });
+        assert!(mails.contains(&motd_line("default")));
+    }
+
+    #[test]
+    fn test_reserved_nicknames() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nickserv");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let state1 = r2(state1);
+        collect_mail(&mut rx1);
+
+        server_state.set_reserved_nicknames(vec!["NickServ".to_string()]);
+        let guest = guest_nickname(state1.user_id);
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![format!(":nickserv!user1@hidden NICK :{guest}\r\n").into_bytes()]
+        );
+
+        let state1 = server_state.user_changes_nick(state1, "NICKSERV");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![format!(":srv 432 {guest} NICKSERV :Nickname is reserved\r\n").into_bytes()]
+        );
+
+        let (state2, mut rx2) = server_state.new_registering_user(Default::default());
+        server_state.ruser_uses_nick(r1(state2), "nickserv");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv 432 * nickserv :Nickname is reserved\r\n".to_vec()]
+        );
+
+        server_state.user_changes_nick(r2(state1), "nick1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![format!(":{guest}!user1@hidden NICK :nick1\r\n").into_bytes()]
+        );
+    }
+
+    #[test]
+    fn test_bot_mode() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let mails = collect_mail(&mut rx1);
+        assert!(mails.contains(
+            &b":srv 005 nick1 BOT=B CASEMAPPING=rfc7613 CHANMODES=Ib,k,fj,HNimnst ELIST=CTU INVEX MAXLIST=bI:100 MODES=4 :are supported by this server\r\n".to_vec()
+        ));
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "bot");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx2);
+
+        state2 = server_state.user_changes_user_mode(r2(state2), "bot", "+B");
+        state2 = server_state.user_asks_user_mode(r2(state2), "bot");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![
+                b":bot!user2@hidden MODE bot +B\r\n".to_vec(),
+                b":srv 221 bot +B\r\n".to_vec(),
+            ]
+        );
+
+        state1 = server_state.user_asks_who(r2(state1), "bot");
+        state1 = server_state.user_asks_whois(r2(state1), "bot");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 352 nick1 * user2 hidden srv bot HB :0 user2\r\n".to_vec(),
+                b":srv 315 nick1 bot :End of WHO list\r\n".to_vec(),
+                b":srv 311 nick1 bot user2 hidden * :user2\r\n".to_vec(),
+                b":srv 335 nick1 bot :is a bot\r\n".to_vec(),
+                b":srv 318 nick1 bot :End of /WHOIS list\r\n".to_vec(),
+            ]
+        );
+
+        server_state.user_changes_user_mode(r2(state2), "bot", "-B");
+        server_state.user_asks_who(r2(state1), "bot");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails[0],
+            b":srv 352 nick1 * user2 hidden srv bot H :0 user2\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_join_throttle() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +j 1:60:#chan");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +j 1:60:#overflow");
+        state1 = server_state.drive_raw_line(state1, b"MODE #chan +f 5:10");
+        state1 = server_state.user_asks_channel_mode(r2(state1), "#chan");
+        let mut mails = collect_mail(&mut rx1);
+        assert!(mails.pop().unwrap().starts_with(b":srv 329 nick1 #chan "));
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 696 nick1 #chan j 1:60:#chan :the overflow channel should be another \
+                  channel\r\n"
+                    .to_vec(),
+                b":nick1!user1@hidden MODE #chan +j 1:60:#overflow\r\n".to_vec(),
+                b":nick1!user1@hidden MODE #chan +f 5:10\r\n".to_vec(),
+                b":srv 324 nick1 #chan +nfj 5:10 1:60:#overflow\r\n".to_vec(),
+            ]
+        );
+
+        let join = |nick: &str| {
+            let (mut state, mut rx) = server_state.new_registering_user(Default::default());
+            state = server_state.ruser_uses_nick(r1(state), nick);
+            state = server_state.ruser_uses_username(r1(state), nick, nick.as_bytes());
+            collect_mail(&mut rx);
+            server_state.user_joins_channels(r2(state), &["#chan"], &[]);
+            collect_mail(&mut rx)
+        };
+
+        // the first join of the window is accepted, the next one is forwarded
+        assert_eq!(join("nick2")[0], b":nick2!nick2@hidden JOIN #chan\r\n");
+        let mails = join("nick3");
+        assert_eq!(
+            mails[..2],
+            [
+                b":srv 470 nick3 #chan #overflow :Forwarding to another channel\r\n".to_vec(),
+                b":nick3!nick3@hidden JOIN #overflow\r\n".to_vec(),
+            ]
+        );
+
+        server_state.drive_raw_line(state1, b"MODE #chan +j 1:60");
+        collect_mail(&mut rx1);
+        assert_eq!(
+            join("nick4"),
+            vec![
+                b":srv 437 nick4 #chan :Cannot join channel (+j, too many joins), try again later\r\n"
+                    .to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flood_limit() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+f", Some("2"));
+        state1 = server_state.user_changes_channel_mode(r2(state1), "#chan", "+f", Some("2:60"));
+        state1 = server_state.user_asks_channel_mode(r2(state1), "#chan");
+        let mut mails = collect_mail(&mut rx1);
+        assert!(mails.pop().unwrap().starts_with(b":srv 329 nick1 #chan "));
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 696 nick1 #chan f 2 :flood limit should be <lines>:<seconds>, not '2'\r\n"
+                    .to_vec(),
+                b":nick1!user1@hidden MODE #chan +f 2:60\r\n".to_vec(),
+                b":srv 324 nick1 #chan +nf 2:60\r\n".to_vec(),
+            ]
+        );
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        for _ in 0..3 {
+            state2 = server_state.user_messages_target(r2(state2), "#chan", b"spam");
+        }
+        assert_eq!(collect_mail(&mut rx1).len(), 2);
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 404 nick2 #chan :Cannot send to channel (+f, too many messages)\r\n"
+                    .to_vec()
+            ]
+        );
+
+        // channel operators are not limited
+        for _ in 0..3 {
+            state1 = server_state.user_messages_target(r2(state1), "#chan", b"hello");
+        }
+        assert_eq!(collect_mail(&mut rx2).len(), 3);
+
+        server_state.user_changes_channel_mode(r2(state1), "#chan", "-f", None);
+        collect_mail(&mut rx2);
+        server_state.user_messages_target(r2(state2), "#chan", b"hello");
+        assert_eq!(collect_mail(&mut rx1).len(), 2);
+    }
+
+    #[test]
+    fn test_spam_filters() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_asks_oper(r2(state1), "nick1", b"secret");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+
+        let add = |state, action, pattern| {
+            server_state.user_manages_spam_filters(
+                r2(state),
+                SpamFilterCommand::Add {
+                    action,
+                    pattern,
+                    reason: Some(b"no spam"),
+                },
+            )
+        };
+        state1 = add(state1, "block", "*free money*");
+        state1 = add(state1, "kill", "/^kill me$/");
+        state1 = add(state1, "ban", "*");
+        state1 = server_state.user_manages_spam_filters(r2(state1), SpamFilterCommand::List);
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails[mails.len() - 6..],
+            vec![
+                b":srv NOTICE nick1 :Spam filter added for *free money*\r\n".to_vec(),
+                b":srv NOTICE nick1 :Spam filter added for /^kill me$/\r\n".to_vec(),
+                b":srv NOTICE nick1 :Invalid spam filter: unknown action 'ban' (block, kill or kline)\r\n".to_vec(),
+                b":srv NOTICE nick1 :Spam filter *free money* (block): no spam\r\n".to_vec(),
+                b":srv NOTICE nick1 :Spam filter /^kill me$/ (kill): no spam\r\n".to_vec(),
+                b":srv NOTICE nick1 :End of spam filters\r\n".to_vec(),
+            ]
+        );
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        state2 = server_state.user_joins_channels(r2(state2), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        // the operators are not filtered
+        state1 = server_state.user_messages_target(r2(state1), "#chan", b"free money");
+        assert_eq!(collect_mail(&mut rx2).len(), 1);
+
+        state2 = server_state.user_messages_target(r2(state2), "#chan", b"get free money");
+        assert!(collect_mail(&mut rx1).is_empty());
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(
+            mails,
+            vec![b":srv NOTICE nick2 :Message blocked by a spam filter (no spam)\r\n".to_vec()]
+        );
+
+        state2 = server_state.user_messages_target(r2(state2), "#chan", b"kill me");
+        assert!(matches!(state2, UserState::Disconnected));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![b":nick2!user2@hidden QUIT :no spam\r\n".to_vec()]
+        );
+
+        state1 = server_state
+            .user_manages_spam_filters(r2(state1), SpamFilterCommand::Del("/^kill me$/"));
+        server_state.user_manages_spam_filters(r2(state1), SpamFilterCommand::Del("/^kill me$/"));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv NOTICE nick1 :Spam filter removed for /^kill me$/\r\n".to_vec(),
+                b":srv NOTICE nick1 :No spam filter for /^kill me$/\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_notify_user() {
+        let server_state = new_server_state();
+
+        let (state1, mut rx1) = server_state.new_registering_user(Default::default());
+        server_state.notify_user(state1.user_id().unwrap(), b"slow down");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv NOTICE * :slow down\r\n".to_vec()]);
+
+        let state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        let state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+        server_state.notify_user(state1.user_id().unwrap(), b"slow down");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails, vec![b":srv NOTICE nick1 :slow down\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_links() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.user_wants_links(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 364 nick1 srv srv :0 srv\r\n".to_vec(),
+                b":srv 365 nick1 * :End of /LINKS list\r\n".to_vec(),
+            ]
+        );
+
+        server_state.update_config(|config| {
+            config.set_related_servers(vec![RelatedServer {
+                name: "other".to_string(),
+                description: "Other server".to_string(),
+                address: "irc.example.com:6697".to_string(),
+            }])
+        });
+        server_state.user_wants_links(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 364 nick1 srv srv :0 srv\r\n".to_vec(),
+                b":srv 364 nick1 other srv :1 Other server (irc.example.com:6697)\r\n".to_vec(),
+                b":srv 365 nick1 * :End of /LINKS list\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        server_state.user_wants_version(r2(state1));
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                format!(
+                    ":srv 351 nick1 cirque-{} srv :\r\n",
+                    env!("CARGO_PKG_VERSION")
+                )
+                .into_bytes(),
+                b":srv 005 nick1 BOT=B CASEMAPPING=rfc7613 CHANMODES=Ib,k,fj,HNimnst ELIST=CTU INVEX MAXLIST=bI:100 MODES=4 :are supported by this server\r\n"
+                    .to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_mode_rules() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| {
+            config.set_default_channel_mode(&ChannelMode::try_from("nt").unwrap());
+            config.set_channel_mode_rules(vec![
+                ChannelModeRule {
+                    mask: "#help-*".to_string(),
+                    mode: ChannelMode::try_from("mt").unwrap(),
+                },
+                ChannelModeRule {
+                    mask: "#*".to_string(),
+                    mode: ChannelMode::try_from("s").unwrap(),
+                },
+            ]);
+        });
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#HELP-rust", "#chan"], &[]);
+        collect_mail(&mut rx1);
+
+        // the first matching rule wins
+        for (channel, mode) in [("#HELP-rust", "+nmt"), ("#chan", "+ns")] {
+            state1 = server_state.user_asks_channel_mode(r2(state1), channel);
+            let mails = collect_mail(&mut rx1);
+            let expected = format!(":srv 324 nick1 {channel} {mode}\r\n");
+            assert_eq!(mails[0], expected.as_bytes());
+        }
+
+        // without a matching rule, the default mode is used
+        server_state.update_config(|config| config.set_channel_mode_rules(vec![]));
+        state1 = server_state.user_joins_channels(r2(state1), &["#other"], &[]);
+        collect_mail(&mut rx1);
+        server_state.user_asks_channel_mode(r2(state1), "#other");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(mails[0], b":srv 324 nick1 #other +nt\r\n");
+    }
+
+    #[test]
+    fn test_channel_metadata() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        state1 = server_state.user_joins_channels(r2(state1), &["#chan"], &[]);
+        collect_mail(&mut rx1);
+
+        let snapshots = server_state.channel_snapshots();
+        assert_eq!(snapshots.len(), 1);
+        let snapshot = &snapshots[0];
+        assert_eq!(snapshot.name, "#chan");
+        assert_eq!(snapshot.creator, "nick1");
+        assert!(snapshot.created_at > 0);
+        assert_eq!(snapshot.last_message_at, None);
+        assert_eq!(snapshot.users, 1);
+
+        state1 = server_state.user_asks_channel_mode(r2(state1), "#chan");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 324 nick1 #chan +n\r\n".to_vec(),
+                format!(":srv 329 nick1 #chan {}\r\n", snapshot.created_at).into_bytes(),
+            ]
+        );
+
+        state1 = server_state.user_messages_target(r2(state1), "#chan", b"hello");
+        let snapshots = server_state.channel_snapshots();
+        assert!(snapshots[0].last_message_at >= Some(snapshot.created_at));
+
+        let list = |state: UserState, conditions: &[u8]| {
+            let message = [b"LIST ".as_slice(), conditions].concat();
+            server_state.drive_raw_line(state, &message)
+        };
+        state1 = list(state1, b"C<5");
+        state1 = list(state1, b"C>5");
+        state1 = list(state1, b"#chan C<5,>0");
+        list(state1, b"<1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 322 nick1 #chan 1 :\r\n".to_vec(),
+                b":srv 323 nick1 :End of LIST\r\n".to_vec(),
+                b":srv 323 nick1 :End of LIST\r\n".to_vec(),
+                b":srv 322 nick1 #chan 1 :\r\n".to_vec(),
+                b":srv 323 nick1 :End of LIST\r\n".to_vec(),
+                b":srv 323 nick1 :End of LIST\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"NICK caf\xe9");
+        state1 = server_state.drive_raw_line(state1, b"USER user1 0 * :R\xe9al name");
+        state1 = server_state.drive_raw_line(state1, b"JOIN #caf\xe9");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.drive_raw_line(state1, b"TOPIC #caf\xe9 :\xe9t\xe9");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![":café!user1@hidden TOPIC #café :été\r\n"
+                .as_bytes()
+                .to_vec()]
+        );
+
+        server_state.update_config(|c| c.set_utf8_only(true));
+        state1 = server_state.drive_raw_line(state1, b"NICK n\xff");
+        state1 = server_state.drive_raw_line(state1, b"JOIN #ok,#n\xff");
+        state1 = server_state.drive_raw_line(state1, b"PRIVMSG #chan :\xff");
+        server_state.drive_raw_line(state1, b"NOTICE #chan :\xff");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv FAIL NICK INVALID_UTF8 :Invalid UTF-8 in the nickname\r\n".to_vec(),
+                b":srv FAIL JOIN INVALID_UTF8 :Invalid UTF-8 in the channel\r\n".to_vec(),
+                b":srv FAIL PRIVMSG INVALID_UTF8 :Invalid UTF-8 in the text\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_need_more_params() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+
+        for line in [
+            "USERHOST",
+            "WHOIS",
+            "MODE",
+            "STATS",
+            "OPER name",
+            "CHGHOST nick1",
+            "JOIN",
+            "TOPIC",
+        ] {
+            state1 = server_state.drive_raw_line(state1, line.as_bytes());
+        }
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 461 nick1 USERHOST :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 WHOIS :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 MODE :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 STATS :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 OPER :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 CHGHOST :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 JOIN :Not enough parameters\r\n".to_vec(),
+                b":srv 461 nick1 TOPIC :Not enough parameters\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commands_before_registration() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        for line in ["JOIN", "LIST", "PRIVMSG nick :hello", "FOO", "PING tok"] {
+            state1 = server_state.drive_raw_line(state1, line.as_bytes());
+        }
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 451 * :You have not registered\r\n".to_vec(),
+                b":srv 451 * :You have not registered\r\n".to_vec(),
+                b":srv 451 * :You have not registered\r\n".to_vec(),
+                b":srv 421 * FOO :Unknown command\r\n".to_vec(),
+                b":srv PONG srv :tok\r\n".to_vec(),
+            ]
+        );
+        assert!(matches!(state1, UserState::Registering(_)));
+    }
+
+    #[test]
+    fn test_max_registering_users() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| c.set_max_registering_users(Some(3), Some(2)));
+
+        let connection_info = |ip: &str| ConnectionInfo {
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+        let (_, mut rx1) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        let (_, mut rx2) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        let (_, mut rx3) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        let evicted =
+            b":srv ERROR :Closing Link: srv (Too many unregistered connections)\r\n".to_vec();
+        assert_eq!(collect_mail(&mut rx1), vec![evicted.clone()]);
+        assert!(collect_mail(&mut rx2).is_empty());
+        assert_eq!(server_state.metrics().registering_users(), 2);
+
+        let (_, mut rx4) = server_state.new_registering_user(connection_info("10.0.0.2"));
+        let (_, mut rx5) = server_state.new_registering_user(connection_info("10.0.0.3"));
+        assert_eq!(collect_mail(&mut rx2), vec![evicted]);
+        assert!(collect_mail(&mut rx3).is_empty());
+        assert!(collect_mail(&mut rx4).is_empty());
+        assert!(collect_mail(&mut rx5).is_empty());
+        assert_eq!(server_state.metrics().registering_users(), 3);
+    }
+
+    #[test]
+    fn test_exempt_ips() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| {
+            c.set_max_registering_users(None, Some(1));
+            c.set_exempt_ips(vec!["10.0.0.0/24".parse().unwrap()]);
+        });
+        assert!(server_state.is_exempt_ip("10.0.0.7".parse().unwrap()));
+        assert!(!server_state.is_exempt_ip("10.0.1.7".parse().unwrap()));
+
+        let connection_info = |ip: &str| ConnectionInfo {
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+        let (_, mut rx1) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        let (_, mut rx2) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        assert!(collect_mail(&mut rx1).is_empty());
+        assert!(collect_mail(&mut rx2).is_empty());
+
+        let (_, mut rx3) = server_state.new_registering_user(connection_info("10.0.1.1"));
+        let (_, mut rx4) = server_state.new_registering_user(connection_info("10.0.1.1"));
+        assert_eq!(collect_mail(&mut rx3).len(), 1);
+        assert!(collect_mail(&mut rx4).is_empty());
+        assert_eq!(server_state.metrics().registering_users(), 3);
+    }
+
+    #[test]
+    fn test_nickname_grace_period() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| c.set_nickname_grace_period(Some(Duration::from_secs(60))));
+
+        let connection_info = |ip: &str| ConnectionInfo {
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+        let (mut state1, _rx1) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        server_state.user_disconnects_suddently(r2(state1));
+
+        let (state2, mut rx2) = server_state.new_registering_user(connection_info("10.0.0.2"));
+        let state2 = server_state.ruser_uses_nick(r1(state2), "Nick1");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 437 * Nick1 :Nick/channel is temporarily unavailable\r\n".to_vec()]
+        );
+
+        let (state3, mut rx3) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        let state3 = server_state.ruser_uses_nick(r1(state3), "nick1");
+        assert!(collect_mail(&mut rx3).is_empty());
+        server_state.ruser_disconnects_suddently(r1(state3));
+
+        // the hold is gone once expired
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(61),
+        );
+        server_state.ruser_uses_nick(r1(state2), "nick1");
+        assert!(collect_mail(&mut rx2).is_empty());
+
+        // quitting does not hold the nickname
+        let (state4, _rx4) = server_state.new_registering_user(connection_info("10.0.0.4"));
+        let state4 = server_state.ruser_uses_nick(r1(state4), "nick4");
+        let state4 = server_state.ruser_uses_username(r1(state4), "user4", b"user4");
+        server_state.user_disconnects_voluntarily(r2(state4), None);
+        let (state5, mut rx5) = server_state.new_registering_user(connection_info("10.0.0.5"));
+        let state5 = server_state.ruser_uses_nick(r1(state5), "nick4");
+        assert!(collect_mail(&mut rx5).is_empty());
+
+        // neither does losing a connection without IP
+        let (state6, _rx6) = server_state.new_registering_user(Default::default());
+        let state6 = server_state.ruser_uses_nick(r1(state6), "nick6");
+        let state6 = server_state.ruser_uses_username(r1(state6), "user6", b"user6");
+        server_state.user_disconnects_suddently(r2(state6));
+        server_state.ruser_uses_nick(r1(state5), "nick6");
+        assert!(collect_mail(&mut rx5).is_empty());
+    }
+    #[test]
+    fn test_away_status() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        state2 = server_state.user_indicates_away(r2(state2), Some(b"lunch"));
+        let user_id2 = state2.user_id().unwrap();
+        let set_since = |since| {
+            if let Some(user) = server_state.write().users.get_mut(&user_id2) {
+                user.away.as_mut().unwrap().since = since;
+            }
+        };
+        set_since(unix_timestamp() - 30);
+        // a new message keeps the time since when the user is away
+        state2 = server_state.user_indicates_away(r2(state2), Some(b"dinner"));
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 306 nick2 :You have been marked as being away\r\n".to_vec(); 2]
+        );
+
+        state1 = server_state.user_asks_who(r2(state1), "nick2");
+        state1 = server_state.user_asks_whois(r2(state1), "nick2");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails[..2],
+            [
+                b":srv 352 nick1 * user2 hidden srv nick2 G :0 user2\r\n".to_vec(),
+                b":srv 315 nick1 nick2 :End of WHO list\r\n".to_vec(),
+            ]
+        );
+        let away = String::from_utf8(mails[2].clone()).unwrap();
+        assert!(away.starts_with(":srv 301 nick1 nick2 :dinner (away for 3"));
+        assert_eq!(mails[3], b":srv 311 nick1 nick2 user2 hidden * :user2\r\n");
+
+        server_state.user_indicates_away(r2(state2), None);
+        state1 = server_state.user_asks_who(r2(state1), "nick2");
+        server_state.user_asks_whois(r2(state1), "nick2");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":srv 352 nick1 * user2 hidden srv nick2 H :0 user2\r\n".to_vec(),
+                b":srv 315 nick1 nick2 :End of WHO list\r\n".to_vec(),
+                b":srv 311 nick1 nick2 user2 hidden * :user2\r\n".to_vec(),
+                b":srv 318 nick1 nick2 :End of /WHOIS list\r\n".to_vec(),
+            ]
+        );
+    }
+    #[test]
+    fn test_registration_orderings() {
         let server_state = new_server_state();
-        let nick1 = "test";
-        let nick2 = "tėst";
+        server_state.set_password(Some(b"pw"));
 
-        let (mut state1, mut rx1) = server_state.new_registering_user();
-        state1 = server_state.ruser_uses_nick(r1(state1), "jester");
-        state1 = server_state.ruser_uses_username(r1(state1), nick1, nick1.as_bytes());
-        assert!(collect_mail(&mut rx1).len() > 6);
+        let register = |lines: &[&[u8]]| {
+            let (mut state, mut rx) = server_state.new_registering_user(Default::default());
+            for line in lines {
+                state = server_state.drive_raw_line(state, line);
+            }
+            (state, collect_mail(&mut rx))
+        };
 
-        let (mut state2, _rx2) = server_state.new_registering_user();
-        state2 = server_state.ruser_uses_nick(r1(state2), nick1);
-        server_state.ruser_uses_username(r1(state2), nick1, nick1.as_bytes());
+        // the password is only checked once the capability negotiation ends
+        let (state, _) = register(&[b"CAP LS 302", b"NICK nick1", b"USER u 0 * :r", b"PASS pw"]);
+        assert!(matches!(state, UserState::Registering(_)));
+        let state = server_state.drive_raw_line(state, b"CAP END");
+        assert!(matches!(state, UserState::Registered(_)));
+
+        let (state, _) = register(&[b"USER u 0 * :r", b"PASS pw", b"NICK nick2"]);
+        assert!(matches!(state, UserState::Registered(_)));
+
+        let (state, mails) = register(&[
+            b"NICK nick3",
+            b"CAP LS",
+            b"USER u 0 * :r",
+            b"PASS wrong",
+            b"CAP END",
+        ]);
+        assert!(matches!(state, UserState::Disconnected));
+        assert_eq!(
+            mails.last().unwrap(),
+            b":srv 464 nick3 :Password incorrect\r\n"
+        );
 
-        server_state.user_changes_nick(r2(state1), nick2);
+        // there is no SASL, AUTHENTICATE does not interrupt the registration
+        let (state, mails) = register(&[b"CAP LS 302", b"AUTHENTICATE PLAIN", b"NICK nick4"]);
+        assert_eq!(
+            mails.last().unwrap(),
+            b":srv 421 * AUTHENTICATE :Unknown command\r\n"
+        );
+        let state = server_state.drive_raw_line(state, b"USER u 0 * :r");
+        let state = server_state.drive_raw_line(state, b"PASS pw");
+        let state = server_state.drive_raw_line(state, b"CAP END");
+        assert!(matches!(state, UserState::Registered(_)));
+    }
+
+    #[test]
+    fn test_cap_negotiation_timeout() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.drive_raw_line(state1, b"CAP LS 302");
+        state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        server_state.drive_raw_line(state1, b"USER u 0 * :r");
+        let (state2, mut rx2) = server_state.new_registering_user(Default::default());
+        server_state.drive_raw_line(state2, b"NICK nick2");
+        collect_mail(&mut rx1);
+
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(30),
+        );
+        assert!(collect_mail(&mut rx1).is_empty());
+
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(61),
+        );
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv FAIL CAP TIMEOUT :Capability negotiation was not ended in time\r\n".to_vec(),
+                b":srv ERROR :Closing Link: srv (Capability negotiation timed out)\r\n".to_vec(),
+            ]
+        );
+        assert!(collect_mail(&mut rx2).is_empty());
+        assert_eq!(server_state.metrics().registering_users(), 1);
+    }
+    #[test]
+    fn test_userhost() {
+        let server_state = new_server_state();
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let (state2, _rx2) = server_state.new_registering_user(Default::default());
+        let state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+
+        state1 = server_state.drive_raw_line(state1, b"USERHOST nick2 NICK2 nick1 nick2 nobody");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 302 nick1 :nick2=+hidden nick1=+hidden\r\n".to_vec()]
+        );
+
+        state1 = server_state.drive_raw_line(state1, b"USERHOST a b c d e f");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 407 nick1 f :Too many targets (at most 5)\r\n".to_vec()]
+        );
+
+        // the replies do not fit in a single message with a long server name
+        let server_name = "s".repeat(480);
+        server_state.set_server_name(&server_name);
+        server_state.drive_raw_line(state1, b"USERHOST nick1 nick2 nick1");
+        let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                format!(":{server_name} 302 nick1 :nick1=+hidden\r\n").into_bytes(),
+                format!(":{server_name} 302 nick1 :nick2=+hidden\r\n").into_bytes(),
+            ]
+        );
+    }
+    #[test]
+    fn test_whois_host() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let connection_info = |ip: &str| ConnectionInfo {
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+        let (mut state1, mut rx1) = server_state.new_registering_user(connection_info("10.0.0.1"));
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        let (mut state2, mut rx2) = server_state.new_registering_user(connection_info("10.0.0.2"));
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        // users see their own IP
+        server_state.user_asks_whois(r2(state1), "nick1");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![
+                b":srv 311 nick1 nick1 user1 hidden * :user1\r\n".to_vec(),
+                b":srv 378 nick1 nick1 :is connecting from user1@10.0.0.1 10.0.0.1\r\n".to_vec(),
+                b":srv 318 nick1 nick1 :End of /WHOIS list\r\n".to_vec(),
+            ]
+        );
+
+        // but not the one of the others, unless they are operators
+        state2 = server_state.user_asks_whois(r2(state2), "nick1");
+        assert_eq!(collect_mail(&mut rx2).len(), 2);
+
+        state2 = server_state.user_asks_oper(r2(state2), "nick2", b"secret");
+        collect_mail(&mut rx2);
+        server_state.user_asks_whois(r2(state2), "nick1");
+        assert!(collect_mail(&mut rx2).contains(
+            &b":srv 378 nick2 nick1 :is connecting from user1@10.0.0.1 10.0.0.1\r\n".to_vec()
+        ));
+    }
+
+    #[test]
+    fn test_own_connection_and_userip() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let connection_info = ConnectionInfo {
+            ip: Some("10.0.0.1".parse().unwrap()),
+            port: Some(51234),
+            is_tls: true,
+            tls_info: Some(TlsInfo {
+                version: "TLSv1_3".to_string(),
+                cipher: "TLS13_AES_256_GCM_SHA384".to_string(),
+                server_name: None,
+            }),
+            ..Default::default()
+        };
+        let (state1, mut rx1) = server_state.new_registering_user(connection_info);
+        let state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        let state1 = server_state.drive_raw_line(state1, b"USER user1 0 * :real");
+        let (state2, mut rx2) = server_state.new_registering_user(Default::default());
+        let state2 = server_state.drive_raw_line(state2, b"NICK nick2");
+        let mut state2 = server_state.drive_raw_line(state2, b"USER user2 0 * :real");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        // the port and the TLS parameters are only shown to the user themselves
+        let state1 = server_state.drive_raw_line(state1, b"WHOIS nick1");
+        let mails = collect_mail(&mut rx1);
+        assert!(mails.contains(
+            &b":srv 378 nick1 nick1 :is connecting from user1@10.0.0.1 10.0.0.1 port 51234\r\n"
+                .to_vec()
+        ));
+        assert!(mails.contains(
+            &b":srv 671 nick1 nick1 :is using a secure connection \
+               (TLSv1_3, TLS13_AES_256_GCM_SHA384)\r\n"
+                .to_vec()
+        ));
+
+        state2 = server_state.drive_raw_line(state2, b"USERIP nick1");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 481 nick2 :Permission Denied- You're not an IRC operator\r\n".to_vec()]
+        );
+
+        state2 = server_state.drive_raw_line(state2, b"OPER nick2 secret");
+        collect_mail(&mut rx2);
+        state2 = server_state.drive_raw_line(state2, b"WHOIS nick1");
+        let mails = collect_mail(&mut rx2);
+        assert!(mails.contains(
+            &b":srv 378 nick2 nick1 :is connecting from user1@10.0.0.1 10.0.0.1\r\n".to_vec()
+        ));
+        assert!(!mails.iter().any(|m| m.starts_with(b":srv 671")));
+
+        server_state.drive_raw_line(state2, b"USERIP nick1 nick2 nobody");
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":srv 340 nick2 :nick1=+10.0.0.1 nick2*=+255.255.255.255\r\n".to_vec()]
+        );
+        server_state.dispose_state(state1);
+    }
+
+    #[test]
+    fn test_embedder_accessors() {
+        let server_state = new_server_state();
+
+        let (state1, _rx1) = server_state.new_registering_user(Default::default());
+        let state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        let state1 = server_state.drive_raw_line(state1, b"USER user 0 * :real");
+        let (state2, _rx2) = server_state.new_registering_user(Default::default());
+        let state2 = server_state.drive_raw_line(state2, b"NICK nick2");
+        let state2 = server_state.drive_raw_line(state2, b"USER user 0 * :real");
+        let user1 = state1.user_id().unwrap();
+        let user2 = state2.user_id().unwrap();
+
+        assert_eq!(server_state.channel_members("#chan"), None);
+        assert_eq!(server_state.user_channels(user1), Some(vec![]));
+
+        let state1 = server_state.drive_raw_line(state1, b"JOIN #chan,#other");
+        let state2 = server_state.drive_raw_line(state2, b"JOIN #CHAN");
+        assert_eq!(server_state.channel_topic("#chan").map(|t| t.content), None);
+        server_state.drive_raw_line(state1, b"TOPIC #chan :the topic");
+
+        let mut members = server_state.channel_members("#Chan").unwrap();
+        members.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+        assert_eq!(
+            members,
+            vec![
+                ChannelMember {
+                    user_id: user1,
+                    nickname: "nick1".into(),
+                    op: true,
+                    voice: false,
+                },
+                ChannelMember {
+                    user_id: user2,
+                    nickname: "nick2".into(),
+                    op: false,
+                    voice: false,
+                },
+            ]
+        );
+
+        let mut channels = server_state.user_channels(user1).unwrap();
+        channels.sort();
+        assert_eq!(channels, vec!["#chan", "#other"]);
+
+        let topic = server_state.channel_topic("#chan").unwrap();
+        assert_eq!(topic.content, b"the topic");
+        assert_eq!(topic.from_nickname, "nick1");
+
+        server_state.dispose_state(state2);
+        assert_eq!(server_state.user_channels(user2), None);
+        assert_eq!(server_state.channel_members("#chan").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_join_many_channels() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| c.set_max_channels_per_user(Some(2)));
+
+        let (mut state1, mut rx1) = server_state.new_registering_user(Default::default());
+        state1 = server_state.ruser_uses_nick(r1(state1), "nick1");
+        state1 = server_state.ruser_uses_username(r1(state1), "user1", b"user1");
+        collect_mail(&mut rx1);
+        state1 = server_state.drive_raw_line(state1, b"JOIN #a,#b,#c,#A");
+        server_state.drive_raw_line(state1, b"JOIN #d");
         let mails = collect_mail(&mut rx1);
+        assert_eq!(
+            mails,
+            vec![
+                b":nick1!user1@hidden JOIN #a\r\n".to_vec(),
+                b":srv 353 nick1 = #a :@nick1\r\n".to_vec(),
+                b":srv 366 nick1 #a :End of NAMES list\r\n".to_vec(),
+                b":nick1!user1@hidden JOIN #b\r\n".to_vec(),
+                b":srv 353 nick1 = #b :@nick1\r\n".to_vec(),
+                b":srv 366 nick1 #b :End of NAMES list\r\n".to_vec(),
+                b":srv 405 nick1 #c :You have joined too many channels\r\n".to_vec(),
+                b":srv 405 nick1 #d :You have joined too many channels\r\n".to_vec(),
+            ]
+        );
+
+        let (mut state2, mut rx2) = server_state.new_registering_user(Default::default());
+        state2 = server_state.ruser_negotiates_capabilities(r1(state2), "REQ", Some("batch"));
+        state2 = server_state.ruser_negotiates_capabilities(r1(state2), "END", None);
+        state2 = server_state.ruser_uses_nick(r1(state2), "nick2");
+        state2 = server_state.ruser_uses_username(r1(state2), "user2", b"user2");
+        collect_mail(&mut rx2);
+        state2 = server_state.drive_raw_line(state2, b"JOIN #x,y,#z");
+        let mails = collect_mail(&mut rx2);
+        let lines = mails
+            .iter()
+            .map(|m| String::from_utf8_lossy(m).into_owned())
+            .collect::<Vec<_>>();
+        let batch_id = lines[0]
+            .strip_prefix(":srv BATCH +")
+            .and_then(|l| l.strip_suffix(" kidanger.github.io/join\r\n"))
+            .unwrap();
+        let tag = format!("@batch={batch_id}");
+        // the NAMES reply is in a nested batch
+        let names_id = lines[2]
+            .strip_prefix(&format!("{tag} :srv BATCH +"))
+            .and_then(|l| l.strip_suffix(" kidanger.github.io/names\r\n"))
+            .unwrap();
+        let names_tag = format!("@batch={names_id}");
+        // the failed join of y does not count against the limit
+        let z_names_id = lines[8]
+            .strip_prefix(&format!("{tag} :srv BATCH +"))
+            .and_then(|l| l.strip_suffix(" kidanger.github.io/names\r\n"))
+            .unwrap();
+        let z_names_tag = format!("@batch={z_names_id}");
+        assert_eq!(
+            lines[1..],
+            [
+                format!("{tag} :nick2!user2@hidden JOIN #x\r\n"),
+                format!("{tag} :srv BATCH +{names_id} kidanger.github.io/names\r\n"),
+                format!("{names_tag} :srv 353 nick2 = #x :@nick2\r\n"),
+                format!("{names_tag} :srv 366 nick2 #x :End of NAMES list\r\n"),
+                format!("{tag} :srv BATCH -{names_id}\r\n"),
+                format!("{tag} :srv 476 nick2 y :Bad Channel Mask\r\n"),
+                format!("{tag} :nick2!user2@hidden JOIN #z\r\n"),
+                format!("{tag} :srv BATCH +{z_names_id} kidanger.github.io/names\r\n"),
+                format!("{z_names_tag} :srv 353 nick2 = #z :@nick2\r\n"),
+                format!("{z_names_tag} :srv 366 nick2 #z :End of NAMES list\r\n"),
+                format!("{tag} :srv BATCH -{z_names_id}\r\n"),
+                format!(":srv BATCH -{batch_id}\r\n"),
+            ]
+        );
+
+        // a single channel is not wrapped, and the messages after the batch are not tagged
+        state2 = server_state.drive_raw_line(state2, b"PART #x");
+        server_state.drive_raw_line(state2, b"JOIN #w");
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails[0], b":nick2!user2@hidden PART #x\r\n");
+        assert_eq!(mails[1], b":nick2!user2@hidden JOIN #w\r\n");
+        assert!(server_state.channel_members("#w").is_some());
+    }
+
+    #[test]
+    fn test_queued_disposal() {
+        let server_state = new_server_state();
+        server_state.update_config(|config| config.set_oper_password(Some(b"secret")));
+
+        let connection_info = ConnectionInfo {
+            ip: Some("192.0.2.7".parse().unwrap()),
+            ..Default::default()
+        };
+        let (state1, _rx1) = server_state.new_registering_user(connection_info);
+        let state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        let state1 = server_state.drive_raw_line(state1, b"USER user 0 * :real");
+        let state1 = server_state.drive_raw_line(state1, b"JOIN #chan");
+        let (state2, mut rx2) = server_state.new_registering_user(Default::default());
+        let state2 = server_state.drive_raw_line(state2, b"NICK nick2");
+        let state2 = server_state.drive_raw_line(state2, b"USER user 0 * :real");
+        let state2 = server_state.drive_raw_line(state2, b"JOIN #chan");
+        let (state3, _rx3) = server_state.new_registering_user(Default::default());
+        let state3 = server_state.drive_raw_line(state3, b"NICK nick3");
+        let state3 = server_state.drive_raw_line(state3, b"USER user 0 * :real");
+        let state3 = server_state.drive_raw_line(state3, b"JOIN #chan");
+        let (state4, mut rx4) = server_state.new_registering_user(Default::default());
+        let user1 = state1.user_id().unwrap();
+        let user3 = state3.user_id().unwrap();
+        collect_mail(&mut rx2);
+
+        // the state is busy, the disposals are queued
+        let guard = server_state.read();
+        server_state.dispose_state(state3);
+        server_state.dispose_state(state4);
+        assert!(guard.users.contains_key(&user3));
+        drop(guard);
+        assert!(collect_mail(&mut rx2).is_empty());
+
+        // and processed before the next read
+        let members = server_state.channel_members("#chan").unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(server_state.user_channels(user3), None);
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![b":nick3!user@hidden QUIT :connection closed\r\n".to_vec()]
+        );
+        assert_eq!(
+            collect_mail(&mut rx4),
+            vec![b":srv ERROR :connection closed\r\n".to_vec()]
+        );
+
+        // disposing of a user already removed (here by a K-line) has no effect
+        let state2 = server_state.drive_raw_line(state2, b"OPER nick2 secret");
+        server_state.drive_raw_line(state2, b"KLINE *@192.0.2.7 :bye");
+        collect_mail(&mut rx2);
+        assert_eq!(server_state.user_channels(user1), None);
+        server_state.dispose_state(state1);
+        server_state.run_maintenance();
+        assert!(collect_mail(&mut rx2).is_empty());
+        assert_eq!(server_state.channel_members("#chan").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_oper_only_listener() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| {
+            c.set_oper_password(Some(b"secret"));
+            c.set_oper_only_listeners([("admin".to_string(), Duration::from_secs(30))].into());
+        });
+
+        let admin = ConnectionInfo {
+            listener: Some("admin".to_string()),
+            ..Default::default()
+        };
+        let (state1, mut rx1) = server_state.new_registering_user(admin.clone());
+        let state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        let state1 = server_state.drive_raw_line(state1, b"USER user 0 * :real");
+        let (state2, mut rx2) = server_state.new_registering_user(admin);
+        let state2 = server_state.drive_raw_line(state2, b"NICK nick2");
+        let state2 = server_state.drive_raw_line(state2, b"USER user 0 * :real");
+        let (state3, mut rx3) = server_state.new_registering_user(Default::default());
+        let state3 = server_state.drive_raw_line(state3, b"NICK nick3");
+        let state3 = server_state.drive_raw_line(state3, b"USER user 0 * :real");
+        assert!(collect_mail(&mut rx1).contains(
+            &b":srv NOTICE nick1 :This port is reserved to the operators, use OPER within 30 seconds\r\n"
+                .to_vec()
+        ));
+        assert!(!collect_mail(&mut rx3)
+            .iter()
+            .any(|m| m.starts_with(b":srv NOTICE")));
+
+        let state2 = server_state.drive_raw_line(state2, b"OPER nick2 secret");
+        collect_mail(&mut rx2);
+
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(20),
+        );
+        assert!(collect_mail(&mut rx1).is_empty());
+
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(31),
+        );
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv ERROR :Closing Link: srv (Operator access required)\r\n".to_vec()]
+        );
+        assert!(server_state
+            .user_channels(state1.user_id().unwrap())
+            .is_none());
+        assert!(server_state
+            .user_channels(state2.user_id().unwrap())
+            .is_some());
+        assert!(server_state
+            .user_channels(state3.user_id().unwrap())
+            .is_some());
+    }
+
+    #[test]
+    fn test_oper_hardening() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| {
+            c.set_oper_password(Some(b"secret"));
+            c.set_oper_hosts(vec!["*@10.0.0.*".to_string()]);
+        });
+        let connect = |nickname: &str, ip: &str| {
+            let connection_info = ConnectionInfo {
+                ip: Some(ip.parse().unwrap()),
+                ..Default::default()
+            };
+            let (state, mut rx) = server_state.new_registering_user(connection_info);
+            let state = server_state.drive_raw_line(state, format!("NICK {nickname}").as_bytes());
+            let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+            collect_mail(&mut rx);
+            (state, rx)
+        };
+
+        let (oper, mut oper_rx) = connect("oper", "10.0.0.1");
+        let _oper = server_state.drive_raw_line(oper, b"OPER oper secret");
+        assert_eq!(
+            collect_mail(&mut oper_rx),
+            vec![b":srv 381 oper :You are now an IRC operator\r\n".to_vec()]
+        );
+
+        // the host must match
+        let (state1, mut rx1) = connect("nick1", "192.168.0.2");
+        server_state.drive_raw_line(state1, b"OPER nick1 secret");
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 491 nick1 :No O-lines for your host\r\n".to_vec()]
+        );
+        assert_eq!(
+            collect_mail(&mut oper_rx),
+            vec![b":srv NOTICE oper :Failed OPER attempt by nick1 (user@192.168.0.2): host not allowed\r\n".to_vec()]
+        );
+
+        // a connection can only fail a few times
+        let (mut state2, mut rx2) = connect("nick2", "10.0.0.2");
+        for _ in 0..3 {
+            state2 = server_state.drive_raw_line(state2, b"OPER nick2 wrong");
+        }
+        assert!(!state2.is_alive());
+        let mails = collect_mail(&mut rx2);
+        assert_eq!(mails.len(), 4);
+        assert_eq!(
+            mails[3],
+            b":srv ERROR :Closing Link: srv (Too many failed OPER attempts)\r\n"
+        );
+        assert_eq!(collect_mail(&mut oper_rx).len(), 3);
+
+        // and an IP a few more times, even with several connections
+        let (mut state3, mut rx3) = connect("nick3", "10.0.0.2");
+        state3 = server_state.drive_raw_line(state3, b"OPER nick3 wrong");
+        state3 = server_state.drive_raw_line(state3, b"OPER nick3 wrong");
+        collect_mail(&mut rx3);
+        state3 = server_state.drive_raw_line(state3, b"QUIT");
+        let (mut state4, mut rx4) = connect("nick4", "10.0.0.2");
+        state4 = server_state.drive_raw_line(state4, b"OPER nick4 secret");
+        assert_eq!(
+            collect_mail(&mut rx4),
+            vec![b":srv 464 nick4 :Password incorrect\r\n".to_vec()]
+        );
+        assert!(collect_mail(&mut oper_rx)
+            .last()
+            .unwrap()
+            .ends_with(b": too many failures from this IP\r\n"));
+
+        server_state.write().expire(
+            &server_state.config.load(),
+            Instant::now() + Duration::from_secs(11 * 60),
+        );
+        server_state.drive_raw_line(state4, b"OPER nick4 secret");
+        assert_eq!(
+            collect_mail(&mut rx4),
+            vec![b":srv 381 nick4 :You are now an IRC operator\r\n".to_vec()]
+        );
+        assert!(!state3.is_alive());
+    }
+
+    #[test]
+    fn test_catalog() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| {
+            c.set_catalog(Catalog::new(
+                "fr",
+                [
+                    (
+                        "Welcome to the Internet Relay Network {fullspec}".to_string(),
+                        "Bienvenue {fullspec}".to_string(),
+                    ),
+                    (
+                        "Unknown command".to_string(),
+                        "Commande inconnue".to_string(),
+                    ),
+                ]
+                .into(),
+            ))
+        });
+
+        let (state, mut rx) = server_state.new_registering_user(Default::default());
+        let state = server_state.drive_raw_line(state, b"NICK nick1");
+        let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+        let mails = collect_mail(&mut rx);
+        assert_eq!(mails[0], b":srv 001 nick1 :Bienvenue nick1!user@hidden\r\n");
+        assert_eq!(
+            mails[1],
+            b":srv 002 nick1 :Your host is 'srv', running cirque.\r\n"
+        );
+
+        server_state.drive_raw_line(state, b"FOO");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv 421 nick1 FOO :Commande inconnue\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_templates() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| {
+            c.set_network_name(Some("Net"));
+            c.set_templates(Templates {
+                welcome: "Welcome to {network}, {nick} ({fullspec})".to_string(),
+                closing_link: "Bye {nick}: {reason}".to_string(),
+                ..Default::default()
+            });
+        });
+
+        let (state, mut rx) = server_state.new_registering_user(Default::default());
+        let state = server_state.drive_raw_line(state, b"NICK nick1");
+        let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+        let mails = collect_mail(&mut rx);
         assert_eq!(
             mails[0],
-            b":srv 433 jester t\xC4\x97st :Nickname is already in use\r\n"
+            b":srv 001 nick1 :Welcome to Net, nick1 (nick1!user@hidden)\r\n"
+        );
+        assert_eq!(mails[3], b":srv 004 nick1 srv 0 a a\r\n");
+
+        server_state.drive_raw_line(state, b"QUIT :see you");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv ERROR :Bye nick1: see you\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_compat_flags() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| {
+            c.set_compat_flags(CompatFlags {
+                list_start: true,
+                topic_who_time: false,
+                extended_lusers: false,
+                unsupported_commands: false,
+            })
+        });
+
+        let (state, mut rx) = server_state.new_registering_user(Default::default());
+        let state = server_state.drive_raw_line(state, b"NICK nick1");
+        let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+        let state = server_state.drive_raw_line(state, b"JOIN #chan");
+        let state = server_state.drive_raw_line(state, b"TOPIC #chan :hello");
+        collect_mail(&mut rx);
+
+        let state = server_state.drive_raw_line(state, b"TOPIC #chan");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv 332 nick1 #chan :hello\r\n".to_vec()]
+        );
+
+        let state = server_state.drive_raw_line(state, b"LIST");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![
+                b":srv 321 nick1 Channel :Users  Name\r\n".to_vec(),
+                b":srv 322 nick1 #chan 1 :hello\r\n".to_vec(),
+                b":srv 323 nick1 :End of LIST\r\n".to_vec(),
+            ]
+        );
+
+        let state = server_state.drive_raw_line(state, b"LUSERS");
+        let mails = collect_mail(&mut rx);
+        assert_eq!(mails.len(), 5);
+        assert!(mails[4].starts_with(b":srv 255 "));
+
+        server_state.drive_raw_line(state, b"MAP");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv 421 nick1 MAP :Unknown command\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_commands() {
+        let server_state = new_server_state();
+
+        let (state, mut rx) = server_state.new_registering_user(Default::default());
+        let state = server_state.drive_raw_line(state, b"NICK nick1");
+        let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+        collect_mail(&mut rx);
+
+        let state = server_state.drive_raw_line(state, b"MAP");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![
+                b":srv 015 nick1 :srv\r\n".to_vec(),
+                b":srv 017 nick1 :End of /MAP\r\n".to_vec(),
+            ]
+        );
+
+        let state = server_state.drive_raw_line(state, b"users");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv 446 nick1 :USERS has been disabled\r\n".to_vec()]
+        );
+
+        server_state.drive_raw_line(state, b"SUMMON nick2");
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv 445 nick1 :SUMMON has been disabled\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_hostname_policy() {
+        let server_state = new_server_state();
+        let mut counter = 0;
+        let mut userhost_of = |policy: HostnamePolicy, connection_info: ConnectionInfo| {
+            server_state.update_config(|c| c.set_hostname_policy(policy.clone()));
+            counter += 1;
+            let nickname = format!("nick{counter}");
+            let (state, mut rx) = server_state.new_registering_user(connection_info);
+            let state = server_state.drive_raw_line(state, format!("NICK {nickname}").as_bytes());
+            let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+            collect_mail(&mut rx);
+            server_state.drive_raw_line(state, format!("USERHOST {nickname}").as_bytes());
+            let mails = collect_mail(&mut rx);
+            let prefix = format!(":srv 302 {nickname} :{nickname}=+");
+            let reply = String::from_utf8(mails[0].clone()).unwrap();
+            reply.strip_prefix(&prefix).unwrap().trim_end().to_string()
+        };
+        let connection_info = |ip: &str| ConnectionInfo {
+            ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            userhost_of(HostnamePolicy::Hidden, connection_info("10.0.0.1")),
+            "hidden"
+        );
+        assert_eq!(
+            userhost_of(HostnamePolicy::Ip, connection_info("10.0.0.1")),
+            "10.0.0.1"
+        );
+        assert_eq!(
+            userhost_of(HostnamePolicy::Ip, connection_info("::1")),
+            "0::1"
+        );
+        assert_eq!(
+            userhost_of(HostnamePolicy::Ip, Default::default()),
+            "hidden"
+        );
+
+        let resolved = ConnectionInfo {
+            resolved_hostname: Some("host.example.com".to_string()),
+            ..connection_info("10.0.0.1")
+        };
+        assert_eq!(
+            userhost_of(HostnamePolicy::Resolved, resolved),
+            "host.example.com"
+        );
+        assert_eq!(
+            userhost_of(HostnamePolicy::Resolved, connection_info("10.0.0.1")),
+            "10.0.0.1"
+        );
+
+        let cloaked = HostnamePolicy::Cloaked {
+            key: "secret".to_string(),
+        };
+        let cloak1 = userhost_of(cloaked.clone(), connection_info("10.0.0.1"));
+        let cloak2 = userhost_of(cloaked.clone(), connection_info("10.0.0.2"));
+        // the cloaks must not change with the version of the server
+        assert_eq!(cloak1, "eb5a0e55d511c2fe.cloak");
+        assert_ne!(cloak1, cloak2);
+        assert_eq!(cloak1, userhost_of(cloaked, connection_info("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_lookups_during_registration() {
+        let server_state = new_server_state();
+        server_state.update_config(|c| c.set_hostname_policy(HostnamePolicy::Resolved));
+        let connection_info = ConnectionInfo {
+            ip: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+
+        // the lookups finish after NICK and USER
+        let (state, mut rx) = server_state.new_registering_user(connection_info.clone());
+        state.start_lookup(&server_state, Lookup::Hostname);
+        state.start_lookup(&server_state, Lookup::Ident);
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![
+                b":srv NOTICE * :*** Looking up your hostname...\r\n".to_vec(),
+                b":srv NOTICE * :*** Checking ident...\r\n".to_vec(),
+            ]
+        );
+        let state = server_state.drive_raw_line(state, b"NICK nick1");
+        let state = server_state.drive_raw_line(state, b"USER user 0 * :real");
+        assert!(collect_mail(&mut rx).is_empty());
+        let state = state.finish_lookup(
+            &server_state,
+            Lookup::Hostname,
+            Some("host.example.com".to_string()),
+        );
+        assert_eq!(
+            collect_mail(&mut rx),
+            vec![b":srv NOTICE nick1 :*** Found your hostname\r\n".to_vec()]
+        );
+        let state = r2(state.finish_lookup(&server_state, Lookup::Ident, None));
+        let mails = collect_mail(&mut rx);
+        assert_eq!(mails[0], b":srv NOTICE nick1 :*** No ident response\r\n");
+        assert!(mails[1].starts_with(b":srv 001 nick1 "));
+        assert!(mails[1].ends_with(b" nick1!~user@host.example.com\r\n"));
+        server_state.drive_raw_line(UserState::Registered(state), b"QUIT");
+
+        // the lookups finish before NICK and USER, with invalid answers being ignored
+        let (state, mut rx) = server_state.new_registering_user(connection_info);
+        state.start_lookup(&server_state, Lookup::Hostname);
+        state.start_lookup(&server_state, Lookup::Ident);
+        let state = state.finish_lookup(&server_state, Lookup::Ident, Some("id".to_string()));
+        let state = state.finish_lookup(
+            &server_state,
+            Lookup::Hostname,
+            Some("bad host".to_string()),
+        );
+        // a lookup is only answered once
+        let state = state.finish_lookup(&server_state, Lookup::Ident, None);
+        let state = server_state.drive_raw_line(state, b"NICK nick2");
+        r2(server_state.drive_raw_line(state, b"USER user 0 * :real"));
+        let mails = collect_mail(&mut rx);
+        assert_eq!(mails[2], b":srv NOTICE * :*** Got ident response\r\n");
+        assert_eq!(
+            mails[3],
+            b":srv NOTICE * :*** Couldn't look up your hostname\r\n"
+        );
+        assert!(mails[4].ends_with(b" nick2!id@10.0.0.1\r\n"));
+    }
+
+    #[test]
+    fn test_nickname_lookups_are_cured() {
+        let server_state = new_server_state();
+
+        let (state1, mut rx1) = server_state.new_registering_user(Default::default());
+        let state1 = server_state.drive_raw_line(state1, b"NICK nick1");
+        let state1 = server_state.drive_raw_line(state1, b"USER user1 0 * :real");
+        let state1 = server_state.drive_raw_line(state1, b"JOIN #chan");
+        let (state2, mut rx2) = server_state.new_registering_user(Default::default());
+        let state2 = server_state.drive_raw_line(state2, b"NICK nick2");
+        let state2 = server_state.drive_raw_line(state2, b"USER user2 0 * :real");
+        let state2 = server_state.drive_raw_line(state2, b"JOIN #chan");
+        collect_mail(&mut rx1);
+        collect_mail(&mut rx2);
+
+        // the nicknames are found as the nickname registry compares them
+        let confusable = "NICK\u{ff12}";
+        let state1 =
+            server_state.drive_raw_line(state1, format!("PRIVMSG {confusable} :hello").as_bytes());
+        assert_eq!(
+            collect_mail(&mut rx2),
+            vec![format!(":nick1!user1@hidden PRIVMSG {confusable} :hello\r\n").into_bytes()]
+        );
+
+        let state1 = server_state.drive_raw_line(state1, format!("WHOIS {confusable}").as_bytes());
+        let mails = collect_mail(&mut rx1);
+        assert!(mails[0].starts_with(b":srv 311 nick1 nick2 "));
+
+        let state1 =
+            server_state.drive_raw_line(state1, format!("USERHOST {confusable}").as_bytes());
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":srv 302 nick1 :nick2=+hidden\r\n".to_vec()]
+        );
+
+        let state1 =
+            server_state.drive_raw_line(state1, format!("MODE #chan +v {confusable}").as_bytes());
+        assert_eq!(
+            collect_mail(&mut rx1),
+            vec![b":nick1!user1@hidden MODE #chan +v nick2\r\n".to_vec()]
         );
+
+        server_state.drive_raw_line(state2, b"QUIT :bye");
+        collect_mail(&mut rx1);
+        server_state.drive_raw_line(state1, format!("WHOWAS {confusable}").as_bytes());
+        let mails = collect_mail(&mut rx1);
+        assert!(mails[0].starts_with(b":srv 314 nick1 nick2 user2 "));
     }
 }
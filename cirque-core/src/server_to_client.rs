@@ -1,7 +1,16 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
 use crate::{
+    catalog::Catalog,
+    config::{AdminInfo, RelatedServer},
     message_writer::MessageWriter,
-    types::{ChannelMode, ChannelUserMode, Topic},
-    WelcomeConfig,
+    metrics::{HandshakeFailures, Histogram, PingLatencies, Traffic, COMMAND_DURATION_BUCKETS},
+    reply_cache::CachedReply,
+    server_state::{MAX_LIST_ENTRIES, MAX_MODES},
+    templates::{TemplateVariables, Templates},
+    types::{BanMask, ChannelMode, ChannelUserMode, CompatFlags, TlsInfo, Topic, WhowasEntry},
 };
 
 #[derive(Debug, Clone)]
@@ -19,6 +28,14 @@ pub(crate) struct UserhostReply<'a> {
     pub(crate) hostname: &'a str,
 }
 
+/// Details of the connection of the user, in the reply to their own WHOIS.
+#[derive(Debug, Clone)]
+pub(crate) struct OwnConnection<'a> {
+    pub(crate) port: Option<u16>,
+    pub(crate) is_tls: bool,
+    pub(crate) tls_info: Option<&'a TlsInfo>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct WhoReply<'a> {
     pub(crate) channel: Option<&'a str>,
@@ -26,11 +43,20 @@ pub(crate) struct WhoReply<'a> {
     pub(crate) nickname: &'a str,
     pub(crate) is_op: bool,
     pub(crate) is_away: bool,
+    pub(crate) is_bot: bool,
     pub(crate) hostname: &'a str,
     pub(crate) username: &'a str,
     pub(crate) realname: &'a [u8],
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct KLineInfo<'a> {
+    pub(crate) mask: &'a str,
+    pub(crate) reason: &'a [u8],
+    /// None if the K-line is permanent
+    pub(crate) remaining: Option<Duration>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct NamesReply<'a> {
     pub(crate) channel_name: &'a str,
@@ -43,7 +69,14 @@ pub(crate) enum Message<'a> {
     Welcome {
         nickname: &'a str,
         user_fullspec: &'a str,
-        welcome_config: &'a WelcomeConfig,
+    },
+    ISupport {
+        client: &'a str,
+        utf8_only: bool,
+        channel_limit: Option<usize>,
+    },
+    Version {
+        client: &'a str,
     },
     Join {
         channel: &'a str,
@@ -57,6 +90,8 @@ pub(crate) enum Message<'a> {
         client: &'a str,
         //names: Vec<(&'a str, &'a ChannelMode, Vec<(String, ChannelUserMode)>)>,
         names: &'a [NamesReply<'a>],
+        /// whether the client has the multi-prefix capability
+        multi_prefix: bool,
     },
     /// only used on NAMES command when the channel is invalid or does not exist
     EndOfNames {
@@ -92,6 +127,14 @@ pub(crate) enum Message<'a> {
         client: &'a str,
         channel: &'a str,
         mode: &'a ChannelMode,
+        /// the key is only shown to the members of the channel
+        show_key: bool,
+    },
+    /// only as a reply to AskChannelMode, after ChannelMode
+    RplCreationTime {
+        client: &'a str,
+        channel: &'a str,
+        created_at: u64,
     },
     PrivMsg {
         from_user: &'a str,
@@ -103,6 +146,22 @@ pub(crate) enum Message<'a> {
         target: &'a str,
         content: &'a [u8],
     },
+    Rules {
+        client: &'a str,
+        rules: Option<&'a [Vec<u8>]>,
+    },
+    Admin {
+        client: &'a str,
+        admin_info: Option<&'a AdminInfo>,
+    },
+    Links {
+        client: &'a str,
+        related_servers: &'a [RelatedServer],
+    },
+    /// reply to MAP, which only shows this server
+    Map {
+        client: &'a str,
+    },
     #[allow(clippy::upper_case_acronyms)]
     MOTD {
         client: &'a str,
@@ -115,7 +174,7 @@ pub(crate) enum Message<'a> {
         n_channels: usize,
         n_clients: usize,
         n_other_servers: usize,
-        // this is mostly because some clients don't like extended lusers info (chirc testsuite)
+        /// RPL_LOCALUSERS and RPL_GLOBALUSERS
         extra_info: bool,
     },
     Part {
@@ -143,10 +202,23 @@ pub(crate) enum Message<'a> {
         client: &'a str,
         info: &'a [UserhostReply<'a>],
     },
+    /// same format as RplUserhost, with the IPs as hostnames
+    RplUserip {
+        client: &'a str,
+        info: &'a [UserhostReply<'a>],
+    },
     RplWhois {
         client: &'a str,
         target_nickname: &'a str,
         away_message: Option<&'a [u8]>,
+        /// in seconds, appended to the away message
+        away_for: Option<u64>,
+        /// only when the requester is allowed to see it
+        connecting_from: Option<std::net::IpAddr>,
+        /// only when the requester is the target
+        own_connection: Option<OwnConnection<'a>>,
+        is_operator: bool,
+        is_bot: bool,
         hostname: &'a str,
         username: &'a str,
         realname: &'a [u8],
@@ -156,10 +228,133 @@ pub(crate) enum Message<'a> {
         client: &'a str,
         target_nickname: &'a str,
     },
+    /// entries of the nickname history, followed by RPL_ENDOFWHOWAS
+    RplWhowas {
+        client: &'a str,
+        nickname: &'a str,
+        entries: &'a [&'a WhowasEntry],
+        /// unix timestamp, in seconds
+        now: u64,
+    },
     Who {
         client: &'a str,
         mask: &'a str,
         replies: &'a [WhoReply<'a>],
+        /// whether the client has the multi-prefix capability
+        multi_prefix: bool,
+    },
+    RplYoureOper {
+        client: &'a str,
+    },
+    RplInviting {
+        client: &'a str,
+        nickname: &'a str,
+        channel: &'a str,
+    },
+    Invite {
+        user_fullspec: &'a str,
+        nickname: &'a str,
+        channel: &'a str,
+    },
+    /// reply to INVITE without parameters
+    RplBanList {
+        client: &'a str,
+        channel: &'a str,
+        bans: &'a [BanMask],
+    },
+    RplInviteExemptionList {
+        client: &'a str,
+        channel: &'a str,
+        exemptions: &'a [BanMask],
+    },
+    RplInviteList {
+        client: &'a str,
+        channels: &'a [&'a str],
+    },
+    /// reply to STATS k
+    RplStatsKLines {
+        client: &'a str,
+        klines: &'a [KLineInfo<'a>],
+    },
+    /// reply to MODE on the user itself
+    RplUModeIs {
+        client: &'a str,
+        is_operator: bool,
+        hides_operator: bool,
+        is_bot: bool,
+    },
+    /// reply to STATS p
+    RplStatsOperators {
+        client: &'a str,
+        operators: &'a [(&'a str, &'a str, &'a str)],
+    },
+    /// reply to STATS t
+    RplStatsTraffic {
+        client: &'a str,
+        plaintext: Traffic,
+        tls: Traffic,
+        handshake_failures: HandshakeFailures,
+    },
+    /// reply to STATS d
+    RplStatsServerNames {
+        client: &'a str,
+        users_per_server_name: &'a BTreeMap<Option<&'a str>, usize>,
+    },
+    /// reply to STATS r (round trips)
+    RplStatsLatency {
+        client: &'a str,
+        latencies: Option<PingLatencies>,
+    },
+    /// reply to STATS m
+    RplStatsCommands {
+        client: &'a str,
+        durations: Histogram,
+    },
+    /// reply to STATS u
+    RplStatsUptime {
+        client: &'a str,
+        uptime: Duration,
+        reloads: usize,
+    },
+    /// reply serialized beforehand, see ReplyCache
+    Cached {
+        client: &'a str,
+        reply: &'a CachedReply,
+    },
+    RplEndOfStats {
+        client: &'a str,
+        query: &'a str,
+    },
+    RplYourId {
+        client: &'a str,
+        id: &'a str,
+    },
+    Cap {
+        client: &'a str,
+        subcommand: &'a str,
+        capabilities: &'a str,
+        /// more lines of the same reply follow (CAP 302)
+        more: bool,
+    },
+    /// sent to the clients with the chghost capability
+    ChgHost {
+        previous_user_fullspec: &'a str,
+        username: &'a str,
+        hostname: &'a str,
+    },
+    RplVisibleHost {
+        client: &'a str,
+        hostname: &'a str,
+    },
+    ResumeToken {
+        token: &'a str,
+    },
+    ResumeSuccess {
+        nickname: &'a str,
+    },
+    Resumed {
+        user_fullspec: &'a str,
+        hostname: &'a str,
     },
     Quit {
         user_fullspec: &'a str,
@@ -168,11 +363,44 @@ pub(crate) enum Message<'a> {
     FatalError {
         reason: &'a [u8],
     },
+    /// sent to the clients with the batch capability
+    BatchStart {
+        reference: &'a str,
+        /// name of the type within the [`BATCH_VENDOR`] namespace
+        batch_type: &'a str,
+    },
+    BatchEnd {
+        reference: &'a str,
+    },
     Err(crate::error::ServerStateError),
 }
 
+#[derive(Debug, Clone)]
 pub(crate) struct MessageContext {
     pub(crate) server_name: String,
+    pub(crate) network_name: Option<String>,
+    pub(crate) catalog: Arc<Catalog>,
+    pub(crate) templates: Arc<Templates>,
+    pub(crate) compat: CompatFlags,
+}
+
+impl MessageContext {
+    pub(crate) fn template_variables<'a>(&'a self, nick: &'a str) -> TemplateVariables<'a> {
+        TemplateVariables {
+            server: &self.server_name,
+            network: self.network_name.as_deref().unwrap_or(&self.server_name),
+            nick,
+        }
+    }
+
+    /// Reason of the ERROR sent before closing the connection of a client.
+    pub(crate) fn closing_link(&self, nick: &str, reason: &[u8]) -> Vec<u8> {
+        self.template_variables(nick).render_closing_link(
+            &self.catalog,
+            &self.templates.closing_link,
+            reason,
+        )
+    }
 }
 
 impl Message<'_> {
@@ -186,60 +414,62 @@ impl Message<'_> {
             Message::Welcome {
                 nickname,
                 user_fullspec,
-                welcome_config,
             } => {
-                message!(
-                    stream,
-                    b":",
-                    sv,
-                    b" 001 ",
-                    nickname,
-                    b" :Welcome to the Internet Relay Network ",
-                    user_fullspec
-                );
+                let (catalog, templates) = (&context.catalog, &context.templates);
+                let variables = context.template_variables(nickname);
+                let welcome =
+                    variables.render(catalog, &templates.welcome, &[("fullspec", user_fullspec)]);
+                message!(stream, b":", sv, b" 001 ", nickname, b" :", &welcome);
 
-                message! {
-                    stream,
-                    b":",
-                    sv,
-                    b" 002 ",
-                    nickname,
-                    b" :Your host is '",
-                    sv,
-                    b"', running cirque."
-                };
+                let your_host = variables.render(catalog, &templates.your_host, &[]);
+                message!(stream, b":", sv, b" 002 ", nickname, b" :", &your_host);
 
-                message! {
-                    stream,
+                let created = variables.render(catalog, &templates.created, &[]);
+                message!(stream, b":", sv, b" 003 ", nickname, b" :", &created);
+
+                let my_info = variables.render(catalog, &templates.my_info, &[]);
+                message!(stream, b":", sv, b" 004 ", nickname, b" ", &my_info);
+            }
+            Message::ISupport {
+                client,
+                utf8_only,
+                channel_limit,
+            } => {
+                let mut m = stream.new_message()?;
+                message_push!(
+                    m,
                     b":",
                     sv,
-                    b" 003 ",
-                    nickname,
-                    b" :This server was created <datetime>."
-                };
-
-                message! {
+                    b" 005 ",
+                    client,
+                    b" BOT=B CASEMAPPING=rfc7613 CHANMODES=Ib,k,fj,HNimnst ELIST=CTU INVEX",
+                    b" MAXLIST=bI:",
+                    &MAX_LIST_ENTRIES.to_string(),
+                    b" MODES=",
+                    &MAX_MODES.to_string()
+                );
+                if let Some(channel_limit) = channel_limit {
+                    message_push!(m, b" CHANLIMIT=#:", &channel_limit.to_string());
+                }
+                if *utf8_only {
+                    m = m.write(b" UTF8ONLY");
+                }
+                m = m.write(b" :are supported by this server");
+                m.validate();
+            }
+            Message::Version { client } => {
+                message!(
                     stream,
                     b":",
                     sv,
-                    b" 004 ",
-                    nickname,
+                    b" 351 ",
+                    client,
+                    b" cirque-",
+                    &env!("CARGO_PKG_VERSION"),
                     b" ",
                     sv,
-                    b" 0 a a"
-                };
-
-                // chirch doesn't like 005, but it's better with it for irctest
-                if welcome_config.send_isupport {
-                    message! {
-                        stream,
-                        b":",
-                        sv,
-                        b" 005 ",
-                        nickname,
-                        b" CASEMAPPING=rfc7613 :are supported by this server"
-                    };
-                }
+                    b" :"
+                );
             }
             Message::Join {
                 channel,
@@ -253,7 +483,11 @@ impl Message<'_> {
             } => {
                 message!(stream, b":", previous_user_fullspec, b" NICK :", nickname);
             }
-            Message::Names { names, client } => {
+            Message::Names {
+                names,
+                client,
+                multi_prefix,
+            } => {
                 for NamesReply {
                     channel_name,
                     channel_mode,
@@ -275,11 +509,7 @@ impl Message<'_> {
                         b" :"
                     );
                     for (i, (nick, user_mode)) in nicknames.iter().enumerate() {
-                        if user_mode.is_op() {
-                            m = m.write(b"@");
-                        } else if user_mode.is_voice() {
-                            m = m.write(b"+");
-                        }
+                        m = m.write(&user_mode.prefixes(*multi_prefix));
                         m = m.write(nick);
                         if i != nicknames.len() - 1 {
                             m = m.write(b" ")
@@ -329,8 +559,7 @@ impl Message<'_> {
                         &topic.content
                     );
 
-                    // irctest requires the RPL_TOPICWHOTIME, but chirch doesn't want it
-                    if true {
+                    if context.compat.topic_who_time {
                         message!(
                             stream,
                             b":",
@@ -396,6 +625,7 @@ impl Message<'_> {
                 client,
                 channel,
                 mode,
+                show_key,
             } => {
                 let mut m = stream.new_message()?;
                 message_push!(m, b":", sv, b" 324 ", client, b" ", channel, b" +");
@@ -411,8 +641,51 @@ impl Message<'_> {
                 if mode.is_topic_protected() {
                     m = m.write(b"t");
                 }
+                if mode.is_invite_only() {
+                    m = m.write(b"i");
+                }
+                if mode.is_no_nick_change() {
+                    m = m.write(b"N");
+                }
+                if mode.is_action_log() {
+                    m = m.write(b"H");
+                }
+                // the parameters follow the letters, in the same order
+                let mut params = vec![];
+                if let Some(flood_limit) = mode.flood_limit() {
+                    m = m.write(b"f");
+                    params.push(flood_limit.to_string());
+                }
+                if let Some(join_throttle) = mode.join_throttle() {
+                    m = m.write(b"j");
+                    params.push(join_throttle.to_string());
+                }
+                if let Some(key) = mode.key() {
+                    m = m.write(b"k");
+                    params.push(if *show_key { key } else { "*" }.to_string());
+                }
+                for param in &params {
+                    message_push!(m, b" ", param);
+                }
                 m.validate();
             }
+            Message::RplCreationTime {
+                client,
+                channel,
+                created_at,
+            } => {
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 329 ",
+                    client,
+                    b" ",
+                    channel,
+                    b" ",
+                    &created_at.to_string()
+                );
+            }
             Message::PrivMsg {
                 from_user,
                 target,
@@ -435,6 +708,115 @@ impl Message<'_> {
             } => {
                 message!(stream, b":", from_user, b" NOTICE ", target, b" :", content);
             }
+            Message::Rules { client, rules } => match rules {
+                Some(rules) => {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 308 ",
+                        client,
+                        b" :- ",
+                        sv,
+                        b" Server Rules - "
+                    );
+
+                    for line in *rules {
+                        message!(stream, b":", sv, b" 232 ", client, b" :- ", line);
+                    }
+
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 309 ",
+                        client,
+                        b" :End of RULES command"
+                    );
+                }
+                None => {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 434 ",
+                        client,
+                        b" :RULES File is missing"
+                    );
+                }
+            },
+            Message::Admin { client, admin_info } => match admin_info {
+                Some(admin_info) => {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 256 ",
+                        client,
+                        b" ",
+                        sv,
+                        b" :Administrative info"
+                    );
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 257 ",
+                        client,
+                        b" :",
+                        &admin_info.location
+                    );
+                    message!(stream, b":", sv, b" 259 ", client, b" :", &admin_info.email);
+                }
+                None => {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 423 ",
+                        client,
+                        b" ",
+                        sv,
+                        b" :No administrative info available"
+                    );
+                }
+            },
+            Message::Map { client } => {
+                message!(stream, b":", sv, b" 015 ", client, b" :", sv);
+                message!(stream, b":", sv, b" 017 ", client, b" :End of /MAP");
+            }
+            Message::Links {
+                client,
+                related_servers,
+            } => {
+                message!(stream, b":", sv, b" 364 ", client, b" ", sv, b" ", sv, b" :0 ", sv);
+                for server in *related_servers {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 364 ",
+                        client,
+                        b" ",
+                        &server.name,
+                        b" ",
+                        sv,
+                        b" :1 ",
+                        &server.description,
+                        b" (",
+                        &server.address,
+                        b")"
+                    );
+                }
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 365 ",
+                    client,
+                    b" * :End of /LINKS list"
+                );
+            }
             Message::MOTD { client, motd } => match motd {
                 Some(motd) => {
                     message!(
@@ -568,8 +950,7 @@ impl Message<'_> {
                 m.validate();
             }
             Message::List { client, infos } => {
-                // chirc test suite doesn't like 321
-                if false {
+                if context.compat.list_start {
                     message!(stream, b":", sv, b" 321 ", client, b" Channel :Users  Name");
                 }
 
@@ -627,35 +1008,43 @@ impl Message<'_> {
                     away_message
                 );
             }
-            Message::RplUserhost { client, info } => {
+            Message::RplUserhost { client, info } | Message::RplUserip { client, info } => {
+                let numeric = match self {
+                    Message::RplUserip { .. } => b" 340 ",
+                    _ => b" 302 ",
+                };
+                // the replies are split over several messages if they do not fit in one
                 let mut m = stream.new_message()?;
-                message_push!(m, b":", sv, b" 302 ", client, b" :");
-                for (
-                    i,
-                    UserhostReply {
-                        nickname,
-                        is_op,
-                        is_away,
-                        hostname,
-                    },
-                ) in info.iter().enumerate()
+                message_push!(m, b":", sv, numeric, client, b" :");
+                let mut is_first = true;
+                for UserhostReply {
+                    nickname,
+                    is_op,
+                    is_away,
+                    hostname,
+                } in *info
                 {
-                    message_push!(m, nickname);
-                    if *is_op {
-                        message_push!(m, b"*");
-                    }
-                    message_push!(
-                        m,
-                        b"=",
+                    let reply = [
+                        nickname.as_bytes(),
+                        if *is_op { b"*" } else { b"" },
                         match is_away {
-                            true => b"-",
-                            false => b"+",
+                            true => b"=-",
+                            false => b"=+",
                         },
-                        hostname
-                    );
-                    if i != info.len() - 1 {
+                        hostname.as_bytes(),
+                    ]
+                    .concat();
+                    if !is_first && !m.has_room_for(1 + reply.len()) {
+                        m.validate();
+                        m = stream.new_message()?;
+                        message_push!(m, b":", sv, numeric, client, b" :");
+                        is_first = true;
+                    }
+                    if !is_first {
                         message_push!(m, b" ");
                     }
+                    message_push!(m, &reply);
+                    is_first = false;
                 }
                 m.validate();
             }
@@ -663,11 +1052,20 @@ impl Message<'_> {
                 client,
                 target_nickname,
                 away_message,
+                away_for,
+                connecting_from,
+                own_connection,
+                is_operator,
+                is_bot,
                 hostname,
                 username,
                 realname,
             } => {
                 if let Some(away_message) = away_message {
+                    // no numeric is assigned to the away duration, it is told in the message
+                    let away_for = away_for
+                        .map(|away_for| format!(" (away for {away_for} seconds)"))
+                        .unwrap_or_default();
                     message!(
                         stream,
                         b":",
@@ -677,7 +1075,8 @@ impl Message<'_> {
                         b" ",
                         target_nickname,
                         b" :",
-                        away_message
+                        away_message,
+                        &away_for
                     );
                 }
 
@@ -697,28 +1096,97 @@ impl Message<'_> {
                     realname
                 );
 
-                // don't send RPL_WHOISCHANNELS, for privacy reasons
-                // (also because the implementation is not done)
-                if false {
+                if *is_operator {
                     message!(
                         stream,
                         b":",
                         sv,
-                        b" 319 ",
+                        b" 313 ",
                         client,
                         b" ",
                         target_nickname,
-                        b" :" // list of channels would go here
+                        b" :is an IRC operator"
                     );
                 }
 
-                message!(
-                    stream,
-                    b":",
-                    sv,
-                    b" 318 ",
-                    client,
-                    b" ",
+                if *is_bot {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 335 ",
+                        client,
+                        b" ",
+                        target_nickname,
+                        b" :is a bot"
+                    );
+                }
+
+                if let Some(ip) = connecting_from {
+                    let ip = ip.to_string();
+                    let port = own_connection
+                        .as_ref()
+                        .and_then(|c| c.port)
+                        .map(|port| format!(" port {port}"))
+                        .unwrap_or_default();
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 378 ",
+                        client,
+                        b" ",
+                        target_nickname,
+                        b" :is connecting from ",
+                        username,
+                        b"@",
+                        &ip,
+                        b" ",
+                        &ip,
+                        &port
+                    );
+                }
+
+                if let Some(own_connection) = own_connection.as_ref().filter(|c| c.is_tls) {
+                    let parameters = own_connection
+                        .tls_info
+                        .map(|tls| format!(" ({}, {})", tls.version, tls.cipher))
+                        .unwrap_or_default();
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 671 ",
+                        client,
+                        b" ",
+                        target_nickname,
+                        b" :is using a secure connection",
+                        &parameters
+                    );
+                }
+
+                // don't send RPL_WHOISCHANNELS, for privacy reasons
+                // (also because the implementation is not done)
+                if false {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 319 ",
+                        client,
+                        b" ",
+                        target_nickname,
+                        b" :" // list of channels would go here
+                    );
+                }
+
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 318 ",
+                    client,
+                    b" ",
                     target_nickname,
                     b" :End of /WHOIS list"
                 );
@@ -738,10 +1206,60 @@ impl Message<'_> {
                     b" :End of /WHOIS list"
                 );
             }
+            Message::RplWhowas {
+                client,
+                nickname,
+                entries,
+                now,
+            } => {
+                for entry in *entries {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 314 ",
+                        client,
+                        b" ",
+                        &entry.nickname,
+                        b" ",
+                        &entry.username,
+                        b" ",
+                        &entry.hostname,
+                        b" * :",
+                        &entry.realname
+                    );
+                    let departed_for = now.saturating_sub(entry.departed_at);
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 312 ",
+                        client,
+                        b" ",
+                        &entry.nickname,
+                        b" ",
+                        sv,
+                        b" :left ",
+                        &departed_for.to_string(),
+                        b" seconds ago"
+                    );
+                }
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 369 ",
+                    client,
+                    b" ",
+                    nickname,
+                    b" :End of WHOWAS"
+                );
+            }
             Message::Who {
                 client,
                 mask,
                 replies,
+                multi_prefix,
             } => {
                 for WhoReply {
                     channel,
@@ -749,6 +1267,7 @@ impl Message<'_> {
                     nickname,
                     is_op,
                     is_away,
+                    is_bot,
                     hostname,
                     username,
                     realname,
@@ -777,12 +1296,11 @@ impl Message<'_> {
                     if *is_op {
                         message_push!(m, b"*");
                     }
+                    if *is_bot {
+                        message_push!(m, b"B");
+                    }
                     if let Some(channel_user_mode) = channel_user_mode {
-                        if channel_user_mode.is_op() {
-                            message_push!(m, b"@");
-                        } else if channel_user_mode.is_voice() {
-                            message_push!(m, b"+");
-                        }
+                        message_push!(m, &channel_user_mode.prefixes(*multi_prefix));
                     }
                     message_push!(m, b" :0 ", realname);
                     m.validate();
@@ -798,6 +1316,350 @@ impl Message<'_> {
                     b" :End of WHO list"
                 );
             }
+            Message::RplYoureOper { client } => {
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 381 ",
+                    client,
+                    b" :You are now an IRC operator"
+                );
+            }
+            Message::RplInviting {
+                client,
+                nickname,
+                channel,
+            } => {
+                message!(stream, b":", sv, b" 341 ", client, b" ", nickname, b" ", channel);
+            }
+            Message::Invite {
+                user_fullspec,
+                nickname,
+                channel,
+            } => {
+                message!(
+                    stream,
+                    b":",
+                    user_fullspec,
+                    b" INVITE ",
+                    nickname,
+                    b" ",
+                    channel
+                );
+            }
+            Message::RplBanList {
+                client,
+                channel,
+                bans,
+            } => {
+                for ban in *bans {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 367 ",
+                        client,
+                        b" ",
+                        channel,
+                        b" ",
+                        &ban.mask,
+                        b" ",
+                        &ban.set_by,
+                        b" ",
+                        &ban.set_at.to_string()
+                    );
+                }
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 368 ",
+                    client,
+                    b" ",
+                    channel,
+                    b" :End of channel ban list"
+                );
+            }
+            Message::RplInviteExemptionList {
+                client,
+                channel,
+                exemptions,
+            } => {
+                for exemption in *exemptions {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 346 ",
+                        client,
+                        b" ",
+                        channel,
+                        b" ",
+                        &exemption.mask,
+                        b" ",
+                        &exemption.set_by,
+                        b" ",
+                        &exemption.set_at.to_string()
+                    );
+                }
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 347 ",
+                    client,
+                    b" ",
+                    channel,
+                    b" :End of channel invite exception list"
+                );
+            }
+            Message::RplInviteList { client, channels } => {
+                for channel in *channels {
+                    message!(stream, b":", sv, b" 336 ", client, b" ", channel);
+                }
+                message!(stream, b":", sv, b" 337 ", client, b" :End of /INVITE list");
+            }
+            Message::RplStatsKLines { client, klines } => {
+                for kline in *klines {
+                    let remaining = match kline.remaining {
+                        Some(remaining) => format!("expires in {}", format_duration(remaining)),
+                        None => "permanent".to_string(),
+                    };
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 216 ",
+                        client,
+                        b" K ",
+                        &kline.mask,
+                        b" :",
+                        &kline.reason,
+                        b" (",
+                        &remaining,
+                        b")"
+                    );
+                }
+            }
+            Message::RplUModeIs {
+                client,
+                is_operator,
+                hides_operator,
+                is_bot,
+            } => {
+                let mut m = stream.new_message()?;
+                message_push!(m, b":", sv, b" 221 ", client, b" +");
+                if *is_operator {
+                    m = m.write(b"o");
+                }
+                if *hides_operator {
+                    m = m.write(b"H");
+                }
+                if *is_bot {
+                    m = m.write(b"B");
+                }
+                m.validate();
+            }
+            Message::RplStatsOperators { client, operators } => {
+                for (nickname, username, hostname) in *operators {
+                    message!(
+                        stream, b":", sv, b" 249 ", client, b" p :", nickname, b" (", username,
+                        b"@", hostname, b")"
+                    );
+                }
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 249 ",
+                    client,
+                    b" p :",
+                    &operators.len().to_string(),
+                    b" operator(s) online"
+                );
+            }
+            Message::RplStatsTraffic {
+                client,
+                plaintext,
+                tls,
+                handshake_failures,
+            } => {
+                for (name, traffic) in [("plaintext", plaintext), ("tls", tls)] {
+                    message!(
+                        stream,
+                        b":",
+                        sv,
+                        b" 249 ",
+                        client,
+                        b" t :",
+                        &name,
+                        b" received ",
+                        &traffic.bytes_received.to_string(),
+                        b" bytes, sent ",
+                        &traffic.bytes_sent.to_string(),
+                        b" bytes"
+                    );
+                }
+                let HandshakeFailures {
+                    timeout,
+                    bad_certificate,
+                    protocol,
+                    connection,
+                } = handshake_failures;
+                let failures = format!(
+                    "failed handshakes: {timeout} timeout, {bad_certificate} bad certificate, \
+                     {protocol} protocol, {connection} connection"
+                );
+                message!(stream, b":", sv, b" 249 ", client, b" t :", &failures);
+            }
+            Message::RplStatsServerNames {
+                client,
+                users_per_server_name,
+            } => {
+                for (server_name, users) in *users_per_server_name {
+                    let line = format!(
+                        "{}: {users} user(s)",
+                        server_name.unwrap_or("no TLS server name")
+                    );
+                    message!(stream, b":", sv, b" 249 ", client, b" d :", &line);
+                }
+            }
+            Message::RplStatsLatency { client, latencies } => {
+                let latencies = match latencies {
+                    Some(PingLatencies { users, p50, p95 }) => format!(
+                        "ping round trip of {users} user(s): p50 {}ms, p95 {}ms",
+                        p50.as_millis(),
+                        p95.as_millis()
+                    ),
+                    None => "no ping answered yet".to_string(),
+                };
+                message!(stream, b":", sv, b" 249 ", client, b" r :", &latencies);
+            }
+            Message::RplStatsCommands { client, durations } => {
+                let total = format!(
+                    "{} command(s), holding the state lock for {:?} in total",
+                    durations.count(),
+                    durations.sum
+                );
+                message!(stream, b":", sv, b" 249 ", client, b" m :", &total);
+                let buckets = COMMAND_DURATION_BUCKETS
+                    .iter()
+                    .map(|bound| format!("<={bound:?}"))
+                    .chain(["longer".to_string()])
+                    .zip(&durations.counts)
+                    .map(|(bucket, count)| format!("{bucket}: {count}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                message!(stream, b":", sv, b" 249 ", client, b" m :", &buckets);
+            }
+            Message::RplStatsUptime {
+                client,
+                uptime,
+                reloads,
+            } => {
+                let secs = uptime.as_secs();
+                let uptime = format!(
+                    "Server Up {} days {}:{:02}:{:02}",
+                    secs / (24 * 60 * 60),
+                    secs / (60 * 60) % 24,
+                    secs / 60 % 60,
+                    secs % 60
+                );
+                message!(stream, b":", sv, b" 242 ", client, b" :", &uptime);
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 249 ",
+                    client,
+                    b" u :",
+                    &reloads.to_string(),
+                    b" configuration reload(s)"
+                );
+            }
+            Message::RplEndOfStats { client, query } => {
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 219 ",
+                    client,
+                    b" ",
+                    query,
+                    b" :End of /STATS report"
+                );
+            }
+            Message::RplYourId { client, id } => {
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 042 ",
+                    client,
+                    b" ",
+                    id,
+                    b" :your unique ID"
+                );
+            }
+            Message::Cap {
+                client,
+                subcommand,
+                capabilities,
+                more,
+            } => {
+                let continuation: &[u8] = if *more { b" * :" } else { b" :" };
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" CAP ",
+                    client,
+                    b" ",
+                    subcommand,
+                    &continuation,
+                    capabilities
+                );
+            }
+            Message::ChgHost {
+                previous_user_fullspec,
+                username,
+                hostname,
+            } => {
+                message!(
+                    stream,
+                    b":",
+                    previous_user_fullspec,
+                    b" CHGHOST ",
+                    username,
+                    b" ",
+                    hostname
+                );
+            }
+            Message::RplVisibleHost { client, hostname } => {
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" 396 ",
+                    client,
+                    b" ",
+                    hostname,
+                    b" :is now your displayed host"
+                );
+            }
+            Message::ResumeToken { token } => {
+                message!(stream, b":", sv, b" RESUME TOKEN ", token);
+            }
+            Message::ResumeSuccess { nickname } => {
+                message!(stream, b":", sv, b" RESUME SUCCESS ", nickname);
+            }
+            Message::Resumed {
+                user_fullspec,
+                hostname,
+            } => {
+                message!(stream, b":", user_fullspec, b" RESUMED ", hostname);
+            }
             Message::Quit {
                 user_fullspec,
                 reason,
@@ -807,10 +1669,34 @@ impl Message<'_> {
             Message::FatalError { reason } => {
                 message!(stream, b":", sv, b" ERROR :", reason);
             }
+            Message::BatchStart {
+                reference,
+                batch_type,
+            } => {
+                message!(
+                    stream,
+                    b":",
+                    sv,
+                    b" BATCH +",
+                    reference,
+                    b" ",
+                    &BATCH_VENDOR,
+                    b"/",
+                    batch_type
+                );
+            }
+            Message::BatchEnd { reference } => {
+                message!(stream, b":", sv, b" BATCH -", reference);
+            }
+            Message::Cached { client, reply } => {
+                for (prefix, suffix) in &reply.lines {
+                    message!(stream, prefix, client, suffix);
+                }
+            }
             Message::Err(err) => {
                 let mut m = stream.new_message()?;
                 message_push!(m, b":", sv, b" ");
-                err.write_to(m).validate();
+                err.write_to(m, &context.catalog).validate();
             }
         }
 
@@ -826,4 +1712,60 @@ impl Message<'_> {
                 | Message::Part { .. }
         )
     }
+
+    pub(crate) fn is_membership_change(&self) -> bool {
+        matches!(
+            self,
+            Message::Join { .. } | Message::Part { .. } | Message::Quit { .. }
+        )
+    }
+
+    /// Type of the batch wrapping the replies made of several messages, for the clients with the
+    /// batch capability.
+    pub(crate) fn batch_type(&self) -> Option<&'static str> {
+        match self {
+            Message::Names { names, .. } if !names.is_empty() => Some("names"),
+            Message::Who { replies, .. } if !replies.is_empty() => Some("who"),
+            Message::List { infos, .. } if !infos.is_empty() => Some("list"),
+            _ => None,
+        }
+    }
+}
+
+/// Vendor namespace of the batch types specific to cirque, as the types which are not
+/// standardized must be prefixed with a domain owned by the software.
+pub(crate) const BATCH_VENDOR: &str = "kidanger.github.io";
+
+/// Formats durations like "1d2h30m", rounded down to the second.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    let mut out = String::new();
+    for (unit, unit_secs) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+        if secs >= unit_secs {
+            out.push_str(&format!("{}{unit}", secs / unit_secs));
+            secs %= unit_secs;
+        }
+    }
+    if secs > 0 || out.is_empty() {
+        out.push_str(&format!("{secs}s"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::format_duration;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_millis(45_900)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(5 * 60)), "5m");
+        assert_eq!(
+            format_duration(Duration::from_secs(86400 + 2 * 3600 + 3)),
+            "1d2h3s"
+        );
+    }
 }
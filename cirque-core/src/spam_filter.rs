@@ -0,0 +1,99 @@
+use crate::mask::mask_matches;
+
+/// What happens to a user whose message matches a spam filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpamFilterAction {
+    /// the content is dropped
+    Block,
+    /// the user is disconnected
+    Kill,
+    /// the user is disconnected and K-lined
+    Kline,
+}
+
+impl SpamFilterAction {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SpamFilterAction::Block => "block",
+            SpamFilterAction::Kill => "kill",
+            SpamFilterAction::Kline => "kline",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            SpamFilterAction::Block,
+            SpamFilterAction::Kill,
+            SpamFilterAction::Kline,
+        ]
+        .into_iter()
+        .find(|a| a.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Glob(String),
+    Regex(regex::bytes::Regex),
+}
+
+/// Pattern installed at runtime by an operator with SPAMFILTER, applied to the content of
+/// PRIVMSG and NOTICE, and to the PART and QUIT reasons.
+#[derive(Debug)]
+pub(crate) struct SpamFilter {
+    /// as given by the operator, also used to remove the filter
+    pub(crate) pattern: String,
+    matcher: Matcher,
+    pub(crate) action: SpamFilterAction,
+    pub(crate) reason: Vec<u8>,
+}
+
+impl SpamFilter {
+    /// A pattern between slashes is a regex, otherwise it is a glob (ignoring the ASCII case)
+    /// matched against the whole content.
+    pub(crate) fn new(action: &str, pattern: &str, reason: Option<&[u8]>) -> Result<Self, String> {
+        let action = SpamFilterAction::from_name(action)
+            .ok_or_else(|| format!("unknown action '{action}' (block, kill or kline)"))?;
+        let matcher = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            Some(regex) => regex::bytes::Regex::new(regex)
+                .map(Matcher::Regex)
+                .map_err(|err| format!("invalid regex: {err}"))?,
+            None => Matcher::Glob(pattern.to_string()),
+        };
+        Ok(Self {
+            pattern: pattern.to_string(),
+            matcher,
+            action,
+            reason: reason.unwrap_or(b"Spam").to_vec(),
+        })
+    }
+
+    pub(crate) fn matches(&self, content: &[u8]) -> bool {
+        match &self.matcher {
+            Matcher::Glob(glob) => mask_matches(glob, &String::from_utf8_lossy(content)),
+            Matcher::Regex(regex) => regex.is_match(content),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)] // fine in tests
+    use super::*;
+
+    #[test]
+    fn test_spam_filter() {
+        let filter = SpamFilter::new("block", "*free money*", None).unwrap();
+        assert!(filter.matches(b"get FREE MONEY here"));
+        assert!(!filter.matches(b"money for free"));
+        assert_eq!(filter.reason, b"Spam");
+
+        let filter = SpamFilter::new("KLINE", "/^buy .* now$/", Some(b"ads")).unwrap();
+        assert_eq!(filter.action, SpamFilterAction::Kline);
+        assert!(filter.matches(b"buy this now"));
+        assert!(!filter.matches(b"please buy this now"));
+
+        assert!(SpamFilter::new("ban", "*", None).is_err());
+        assert!(SpamFilter::new("block", "/(/", None).is_err());
+    }
+}
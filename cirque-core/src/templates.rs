@@ -0,0 +1,98 @@
+use crate::catalog::{substitute, Catalog};
+
+/// Texts of the welcome burst (001 to 004) and of the ERROR sent when closing a connection.
+///
+/// The templates can use the variables `{server}`, `{network}`, `{nick}` and `{version}`, as well
+/// as `{fullspec}` (nick!user@host) for `welcome` and `{reason}` for `closing_link`. They are
+/// translated by the catalog before the variables are replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Templates {
+    pub welcome: String,
+    pub your_host: String,
+    pub created: String,
+    /// parameters of the 004 numeric, the first ones should stay the server name and the version
+    pub my_info: String,
+    pub closing_link: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            welcome: "Welcome to the Internet Relay Network {fullspec}".to_string(),
+            your_host: "Your host is '{server}', running cirque.".to_string(),
+            created: "This server was created <datetime>.".to_string(),
+            my_info: "{server} 0 a a".to_string(),
+            closing_link: "Closing Link: {server} ({reason})".to_string(),
+        }
+    }
+}
+
+/// Values of the variables common to all the templates.
+pub(crate) struct TemplateVariables<'a> {
+    pub(crate) server: &'a str,
+    pub(crate) network: &'a str,
+    pub(crate) nick: &'a str,
+}
+
+impl TemplateVariables<'_> {
+    pub(crate) fn render(
+        &self,
+        catalog: &Catalog,
+        template: &str,
+        extra: &[(&str, &str)],
+    ) -> String {
+        let mut variables = vec![
+            ("server", self.server),
+            ("network", self.network),
+            ("nick", self.nick),
+            ("version", env!("CARGO_PKG_VERSION")),
+        ];
+        variables.extend_from_slice(extra);
+        substitute(catalog.translate(template), &variables).into_owned()
+    }
+
+    /// The reason is inserted as is, since it might come from the client and not be valid UTF-8.
+    pub(crate) fn render_closing_link(
+        &self,
+        catalog: &Catalog,
+        template: &str,
+        reason: &[u8],
+    ) -> Vec<u8> {
+        let template = catalog.translate(template);
+        let mut out = Vec::with_capacity(template.len() + reason.len());
+        for (i, part) in template.split("{reason}").enumerate() {
+            if i > 0 {
+                out.extend_from_slice(reason);
+            }
+            out.extend_from_slice(self.render(&Catalog::default(), part, &[]).as_bytes());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let variables = TemplateVariables {
+            server: "srv",
+            network: "Net",
+            nick: "{reason}",
+        };
+        let catalog = Catalog::default();
+        assert_eq!(
+            variables.render(&catalog, "Welcome to {network}, {nick}{}", &[]),
+            "Welcome to Net, {reason}{}"
+        );
+        assert_eq!(
+            variables.render_closing_link(
+                &catalog,
+                "Bye {nick} ({reason}) from {server}",
+                b"gone \xff"
+            ),
+            b"Bye {reason} (gone \xff) from srv"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+/// Classes of commands, which can weigh differently in the throttling of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandClass {
+    /// PRIVMSG and NOTICE
+    Message,
+    /// commands changing channels, such as JOIN, PART, TOPIC or MODE
+    Channel,
+    /// commands asking information, such as WHO, WHOIS, LIST or NAMES
+    Query,
+    Other,
+}
+
+impl CommandClass {
+    pub fn of(command: &[u8]) -> Self {
+        const MESSAGE: &[&[u8]] = &[b"PRIVMSG", b"NOTICE"];
+        const CHANNEL: &[&[u8]] = &[b"JOIN", b"PART", b"TOPIC", b"MODE", b"INVITE", b"KICK"];
+        const QUERY: &[&[u8]] = &[
+            b"WHO",
+            b"WHOIS",
+            b"WHOWAS",
+            b"LIST",
+            b"NAMES",
+            b"USERHOST",
+            b"USERIP",
+            b"LUSERS",
+            b"STATS",
+            b"MOTD",
+            b"RULES",
+            b"ADMIN",
+            b"LINKS",
+            b"VERSION",
+        ];
+        let is_in = |commands: &[&[u8]]| commands.iter().any(|c| c.eq_ignore_ascii_case(command));
+        if is_in(MESSAGE) {
+            CommandClass::Message
+        } else if is_in(CHANNEL) {
+            CommandClass::Channel
+        } else if is_in(QUERY) {
+            CommandClass::Query
+        } else {
+            CommandClass::Other
+        }
+    }
+}
+
+/// What happens to a client sending commands faster than allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThrottleAction {
+    /// the commands are processed later
+    #[default]
+    Delay,
+    /// the client is disconnected
+    Disconnect,
+}
+
+/// Limits the rate of the commands of each client.
+///
+/// Each command costs the penalty of its class (1 by default), and a client can spend `rate`
+/// per second, with up to `burst` at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    pub rate: u32,
+    pub burst: u32,
+    pub penalties: HashMap<CommandClass, u32>,
+    pub action: ThrottleAction,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            rate: 10,
+            burst: 1,
+            penalties: HashMap::new(),
+            action: ThrottleAction::Delay,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    pub fn penalty(&self, command: &[u8]) -> u32 {
+        self.penalties
+            .get(&CommandClass::of(command))
+            .copied()
+            .unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_penalty() {
+        let config = ThrottleConfig {
+            penalties: [(CommandClass::Query, 3), (CommandClass::Message, 0)].into(),
+            ..Default::default()
+        };
+        assert_eq!(config.penalty(b"whois"), 3);
+        assert_eq!(config.penalty(b"PRIVMSG"), 0);
+        assert_eq!(config.penalty(b"JOIN"), 1);
+        assert_eq!(config.penalty(b"PING"), 1);
+        assert_eq!(CommandClass::of(b"Topic"), CommandClass::Channel);
+    }
+}
@@ -3,7 +3,21 @@ use std::time::{Duration, Instant};
 #[derive(Debug, Clone)]
 pub struct TimeoutConfig {
     pub base_timeout: Duration,
+    /// timeout of the pings following an important message sent to the client
     pub reduced_timeout: Duration,
+    /// number of pings with the reduced timeout after an important message, 0 disables the
+    /// reduction so that the pings keep the same cadence
+    pub reduced_pings: u8,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            base_timeout: Duration::from_secs(60),
+            reduced_timeout: Duration::from_secs(10),
+            reduced_pings: 10,
+        }
+    }
 }
 
 impl TimeoutConfig {
@@ -14,6 +28,15 @@ impl TimeoutConfig {
             self.base_timeout
         }
     }
+
+    /// The timeouts have to be checked at a fraction of this duration.
+    pub fn shortest_timeout(&self) -> Duration {
+        if self.reduced_pings == 0 {
+            self.base_timeout
+        } else {
+            self.base_timeout.min(self.reduced_timeout)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -73,23 +96,31 @@ impl PingState {
         });
     }
 
-    pub(crate) fn on_receive_pong(&mut self, token: Vec<u8>) {
+    /// Returns the round trip time when the pong answers the last ping sent.
+    pub(crate) fn on_receive_pong(&mut self, token: Vec<u8>, now: Instant) -> Option<Duration> {
         let new = Some(Pong { token });
         if self.last_received == new {
             // the user sent pong multiple times, we return early to avoid decreasing the timeout
             // tokens
-            return;
+            return None;
         }
 
         self.last_received = new;
 
         // decrease the timeout tokens and maybe get back to the normal timeout
         self.timeout_reduction_tokens = self.timeout_reduction_tokens.saturating_sub(1);
+
+        match (&self.last_sent, &self.last_received) {
+            (Some(ping), Some(pong)) if ping.token == pong.token => Some(now - ping.at),
+            _ => None,
+        }
     }
 
     pub(crate) fn aggressively_reduce_timeout(&mut self) {
-        // timeout reduction lasts for 10 pings (at reduced_timeout rate)
-        self.timeout_reduction_tokens = 10;
+        // timeout reduction lasts for a number of pings (at reduced_timeout rate)
+        if let Some(timeout_config) = &self.timeout_config {
+            self.timeout_reduction_tokens = timeout_config.reduced_pings;
+        }
     }
 
     pub(crate) fn check_status(&self, now: Instant) -> PingStatus {
@@ -165,6 +196,7 @@ mod tests {
         let timeout_config = TimeoutConfig {
             base_timeout: Duration::from_secs(10),
             reduced_timeout: Duration::from_secs(2),
+            reduced_pings: 10,
         };
         let now = Instant::now();
         let mut state = PingState::new(now, Some(timeout_config));
@@ -189,6 +221,7 @@ mod tests {
         let timeout_config = TimeoutConfig {
             base_timeout: Duration::from_secs(10),
             reduced_timeout: Duration::from_secs(2),
+            reduced_pings: 10,
         };
         let now = Instant::now();
         let mut state = PingState::new(now, Some(timeout_config.clone()));
@@ -202,7 +235,11 @@ mod tests {
         let now = now + Duration::from_secs(2);
         assert_eq!(state.check_status(now), PingStatus::AllGood);
         let now = now + Duration::from_secs(7);
-        state.on_receive_pong(b"token".to_vec());
+        assert_eq!(
+            state.on_receive_pong(b"token".to_vec(), now),
+            Some(Duration::from_secs(9))
+        );
+        assert_eq!(state.on_receive_pong(b"token".to_vec(), now), None);
         assert_eq!(state.check_status(now), PingStatus::AllGood);
         let now = now + Duration::from_secs(2);
         assert_eq!(state.check_status(now), PingStatus::NeedToSend);
@@ -213,7 +250,10 @@ mod tests {
         // the ping was not yet with a reduced timeout
         let now = now + Duration::from_secs(8);
         assert_eq!(state.check_status(now), PingStatus::AllGood);
-        state.on_receive_pong(b"token2".to_vec());
+        assert_eq!(
+            state.on_receive_pong(b"token2".to_vec(), now),
+            Some(Duration::from_secs(8))
+        );
         let now = now + Duration::from_secs(1);
         assert_eq!(state.check_status(now), PingStatus::AllGood);
 
@@ -229,4 +269,29 @@ mod tests {
             PingStatus::Timeout(Duration::from_secs(3))
         );
     }
+
+    #[test]
+    fn reduction_disabled() {
+        let timeout_config = TimeoutConfig {
+            base_timeout: Duration::from_secs(10),
+            reduced_timeout: Duration::from_secs(2),
+            reduced_pings: 0,
+        };
+        assert_eq!(timeout_config.shortest_timeout(), Duration::from_secs(10));
+        let now = Instant::now();
+        let mut state = PingState::new(now, Some(timeout_config));
+        state.aggressively_reduce_timeout();
+
+        // the pings keep the base timeout
+        let now = now + Duration::from_secs(11);
+        assert_eq!(state.check_status(now), PingStatus::NeedToSend);
+        state.on_send_ping(b"token", now);
+        let now = now + Duration::from_secs(9);
+        assert_eq!(state.check_status(now), PingStatus::AllGood);
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            state.check_status(now),
+            PingStatus::Timeout(Duration::from_secs(10))
+        );
+    }
 }
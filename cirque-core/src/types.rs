@@ -1,7 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
 
 use crate::{
+    capabilities::{Capabilities, Capability},
     error::ServerStateError,
+    mask::mask_matches,
     message_writer::{Mailbox, MailboxSink},
     server_to_client::{self, MessageContext},
 };
@@ -13,6 +20,54 @@ impl UserID {
     pub(crate) fn generate() -> Self {
         UserID(uuid::Uuid::new_v4())
     }
+
+    /// Identifier given to the client (numeric 042) and shown in the logs.
+    /// Unlike the nickname, it does not change during the session.
+    pub fn public_id(&self) -> String {
+        self.0.simple().to_string()
+    }
+}
+
+impl std::fmt::Display for UserID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.simple())
+    }
+}
+
+/// Parameters negotiated by a TLS connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher: String,
+    /// hostname asked by the client with SNI, when serving several domains
+    pub server_name: Option<String>,
+}
+
+/// Information about the connection of a user, given by the server when the user connects.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    pub ip: Option<IpAddr>,
+    /// port of the peer, only shown to the user themselves
+    pub port: Option<u16>,
+    pub is_tls: bool,
+    /// only shown to the user themselves, None if the transport does not tell
+    pub tls_info: Option<TlsInfo>,
+    /// name of the listener that accepted the connection, if it has one
+    pub listener: Option<String>,
+    /// reverse DNS name of the peer, if the embedder resolved it
+    pub resolved_hostname: Option<String>,
+    /// username given by the ident server of the peer (RFC 1413), if it was asked
+    pub ident: Option<String>,
+}
+
+/// Lookup about the peer done by the session during the registration, which waits for its
+/// answer (or its failure).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    /// reverse DNS name of the IP
+    Hostname,
+    /// username given by the ident server of the peer
+    Ident,
 }
 
 #[derive(Debug)]
@@ -21,9 +76,25 @@ pub struct RegisteredUser {
     pub(crate) nickname: String,
     pub(crate) username: String,
     pub(crate) realname: Vec<u8>,
-    pub(crate) away_message: Option<Vec<u8>>,
+    pub(crate) away: Option<AwayStatus>,
+    pub(crate) is_operator: bool,
+    /// the connection is closed after too many failures
+    pub(crate) failed_oper_attempts: u32,
+    /// user mode +H: the operator status is only shown to other operators
+    pub(crate) hides_operator: bool,
+    /// user mode +B, set by the clients identifying as bots
+    pub(crate) is_bot: bool,
+    pub(crate) connection_info: ConnectionInfo,
+    pub(crate) capabilities: Capabilities,
+    pub(crate) connected_at: Instant,
+    /// given to the clients with the resume capability, so that they can take over this session
+    /// from another connection
+    pub(crate) resume_token: Option<String>,
+    /// round trip time of the last ping sent by the server and answered, updated under the read
+    /// lock of the state as the pongs are frequent
+    pub(crate) ping_round_trip: Mutex<Option<Duration>>,
     fullspec: String,
-    hostname: &'static str,
+    hostname: String,
     mailbox: Mailbox,
 }
 
@@ -32,8 +103,28 @@ impl RegisteredUser {
         self.mailbox.ingest(message, context);
     }
 
+    /// Tags the messages sent to the user with the batch reference, until it is unset.
+    pub(crate) fn set_batch(&mut self, batch: Option<String>) {
+        self.mailbox.set_batch(batch);
+    }
+
+    /// To be called after the capabilities changed.
+    pub(crate) fn update_message_tags(&mut self) {
+        self.mailbox.set_capabilities(&self.capabilities);
+    }
+
+    /// Holds the replies until the end of the command, if the client can receive them labeled.
+    /// Returns whether they are held.
+    pub(crate) fn start_labeled_response(&self, label: &str) -> bool {
+        self.mailbox.start_labeled_response(label)
+    }
+
+    pub(crate) fn end_labeled_response(&self, server_name: &str) {
+        self.mailbox.end_labeled_response(server_name);
+    }
+
     pub(crate) fn shown_hostname(&self) -> &str {
-        self.hostname
+        &self.hostname
     }
 
     pub(crate) fn fullspec(&self) -> &str {
@@ -41,13 +132,64 @@ impl RegisteredUser {
     }
 
     pub fn is_away(&self) -> bool {
-        self.away_message.is_some()
+        self.away.is_some()
+    }
+
+    pub(crate) fn away_message(&self) -> Option<&[u8]> {
+        self.away.as_ref().map(|away| away.message.as_slice())
+    }
+
+    /// Changing the away message keeps the time since when the user is away.
+    /// Returns whether the user changed from present to away or the reverse.
+    pub(crate) fn set_away(&mut self, message: Option<&[u8]>, now: u64) -> bool {
+        let was_away = self.is_away();
+        self.away = message.map(|message| AwayStatus {
+            message: message.to_vec(),
+            since: self.away.as_ref().map_or(now, |away| away.since),
+        });
+        was_away != self.is_away()
+    }
+
+    /// The K-lines are matched against this string.
+    pub(crate) fn kline_target(&self) -> String {
+        kline_target(&self.username, &self.connection_info)
     }
 
     pub(crate) fn change_nickname(&mut self, new_nick: &str) {
         self.nickname = new_nick.to_string();
         self.fullspec = format!("{}!{}@{}", self.nickname, self.username, self.hostname);
     }
+
+    pub(crate) fn change_hostname(&mut self, new_hostname: &str) {
+        self.hostname = new_hostname.to_string();
+        self.fullspec = format!("{}!{}@{}", self.nickname, self.username, self.hostname);
+    }
+
+    /// Moves the session to the connection that resumed it, which gets a new resume token.
+    /// Returns the mailbox of the previous connection.
+    pub(crate) fn take_over(&mut self, connection: RegisteringUser, hostname: &str) -> Mailbox {
+        self.user_id = connection.user_id;
+        self.connection_info = connection.connection_info;
+        self.resume_token = connection
+            .capabilities
+            .has(Capability::Resume)
+            .then(new_resume_token);
+        self.capabilities = connection.capabilities;
+        *self.ping_round_trip.get_mut() = None;
+        self.change_hostname(hostname);
+        std::mem::replace(&mut self.mailbox, connection.mailbox)
+    }
+}
+
+fn new_resume_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+#[derive(Debug)]
+pub(crate) struct AwayStatus {
+    pub(crate) message: Vec<u8>,
+    /// unix timestamp
+    pub(crate) since: u64,
 }
 
 #[derive(Debug)]
@@ -57,11 +199,27 @@ pub(crate) struct RegisteringUser {
     pub(crate) username: Option<String>,
     pub(crate) realname: Option<Vec<u8>>,
     pub(crate) password: Option<Vec<u8>>,
+    pub(crate) connection_info: ConnectionInfo,
+    pub(crate) capabilities: Capabilities,
+    /// the registration is suspended while the client negotiates its capabilities, for a limited
+    /// time
+    pub(crate) negotiating_capabilities_since: Option<Instant>,
+    /// the registration is also suspended until the lookups are answered
+    pub(crate) pending_lookups: Vec<Lookup>,
+    /// whether the username is replaced by the ident answer, or marked with a `~` without one
+    pub(crate) ident_checked: bool,
+    /// session taken over once registered, the nickname is then the one of the session
+    pub(crate) resumes: Option<UserID>,
+    /// the oldest registering users are evicted first when there are too many
+    pub(crate) connected_at: Instant,
     mailbox: Mailbox,
 }
 
 impl RegisteringUser {
-    pub(crate) fn new(mailbox_capacity: usize) -> (Self, MailboxSink) {
+    pub(crate) fn new(
+        mailbox_capacity: usize,
+        connection_info: ConnectionInfo,
+    ) -> (Self, MailboxSink) {
         let user_id = UserID::generate();
         let (mailbox, mailbox_sink) = Mailbox::new(mailbox_capacity);
         let user = Self {
@@ -70,6 +228,13 @@ impl RegisteringUser {
             username: None,
             realname: None,
             password: None,
+            connection_info,
+            capabilities: Default::default(),
+            negotiating_capabilities_since: None,
+            pending_lookups: vec![],
+            ident_checked: false,
+            resumes: None,
+            connected_at: Instant::now(),
             mailbox,
         };
         (user, mailbox_sink)
@@ -79,17 +244,44 @@ impl RegisteringUser {
         self.mailbox.ingest(message, context);
     }
 
+    /// To be called after the capabilities changed.
+    pub(crate) fn update_message_tags(&mut self) {
+        self.mailbox.set_capabilities(&self.capabilities);
+    }
+
+    /// Holds the replies until the end of the command, if the client can receive them labeled.
+    /// Returns whether they are held.
+    pub(crate) fn start_labeled_response(&self, label: &str) -> bool {
+        self.mailbox.start_labeled_response(label)
+    }
+
+    pub(crate) fn end_labeled_response(&self, server_name: &str) {
+        self.mailbox.end_labeled_response(server_name);
+    }
+
     pub(crate) fn maybe_nickname(&self) -> String {
         self.nickname.clone().unwrap_or("*".to_string())
     }
 
     pub(crate) fn is_ready(&self) -> bool {
-        self.nickname.is_some() && self.username.is_some()
+        (self.nickname.is_some() || self.resumes.is_some())
+            && self.username.is_some()
+            && self.negotiating_capabilities_since.is_none()
+            && self.pending_lookups.is_empty()
+    }
+
+    /// The K-lines are matched against this string.
+    pub(crate) fn kline_target(&self) -> String {
+        kline_target(
+            self.username.as_deref().unwrap_or_default(),
+            &self.connection_info,
+        )
     }
 }
 
-impl From<RegisteringUser> for RegisteredUser {
-    fn from(value: RegisteringUser) -> Self {
+impl RegisteredUser {
+    /// The hostname is the one shown to the other users, see `HostnamePolicy`.
+    pub(crate) fn from_registering(value: RegisteringUser, hostname: String) -> Self {
         // we assert that the registration is valid, so the unwraps are fine
         assert!(value.is_ready());
 
@@ -97,16 +289,28 @@ impl From<RegisteringUser> for RegisteredUser {
         let nickname = value.nickname.unwrap();
         #[allow(clippy::unwrap_used)]
         let username = value.username.unwrap();
-        let hostname = "hidden";
 
         let fullspec = format!("{}!{}@{}", nickname, username, hostname);
+        let resume_token = value
+            .capabilities
+            .has(Capability::Resume)
+            .then(new_resume_token);
 
         Self {
             user_id: value.user_id,
             nickname,
             username,
             realname: value.realname.unwrap_or_default(),
-            away_message: None,
+            away: None,
+            is_operator: false,
+            failed_oper_attempts: 0,
+            hides_operator: false,
+            is_bot: false,
+            connection_info: value.connection_info,
+            capabilities: value.capabilities,
+            connected_at: value.connected_at,
+            resume_token,
+            ping_round_trip: Mutex::new(None),
             fullspec,
             hostname,
             mailbox: value.mailbox,
@@ -157,7 +361,7 @@ impl ChannelUserMode {
 
     pub(crate) fn without_voice(&self) -> Self {
         Self {
-            voice: true,
+            voice: false,
             ..self.clone()
         }
     }
@@ -169,6 +373,17 @@ impl ChannelUserMode {
     pub(crate) fn is_voice(&self) -> bool {
         self.voice
     }
+
+    /// Shown before the nickname in NAMES and WHO: the highest prefix, or all of them for the
+    /// clients with the multi-prefix capability.
+    pub(crate) fn prefixes(&self, multi_prefix: bool) -> &'static str {
+        match (self.op, self.voice) {
+            (true, true) if multi_prefix => "@+",
+            (true, _) => "@",
+            (false, true) => "+",
+            (false, false) => "",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -177,6 +392,103 @@ pub struct ChannelMode {
     topic_protected: bool,
     moderated: bool,
     no_external: bool,
+    invite_only: bool,
+    no_nick_change: bool,
+    /// the actions of the channel operators are recorded (+H)
+    action_log: bool,
+    flood_limit: Option<FloodLimit>,
+    join_throttle: Option<JoinThrottle>,
+    key: Option<String>,
+}
+
+/// Parameter of the channel mode +f: a member can send at most `lines` messages
+/// during any period of `seconds`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FloodLimit {
+    pub lines: u32,
+    pub seconds: u32,
+}
+
+impl FloodLimit {
+    fn period(&self) -> Duration {
+        Duration::from_secs(self.seconds.into())
+    }
+}
+
+impl TryFrom<&str> for FloodLimit {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let error = || format!("flood limit should be <lines>:<seconds>, not '{value}'");
+        let (lines, seconds) = value.split_once(':').ok_or_else(error)?;
+        let lines = lines.parse::<u32>().map_err(|_| error())?;
+        let seconds = seconds.parse::<u32>().map_err(|_| error())?;
+        if !(1..=100).contains(&lines) || !(1..=3600).contains(&seconds) {
+            return Err(error());
+        }
+        Ok(Self { lines, seconds })
+    }
+}
+
+impl std::fmt::Display for FloodLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.lines, self.seconds)
+    }
+}
+
+/// Parameter of the channel mode +j: at most `joins` users can join during any period of
+/// `seconds`. The users over the limit are sent to the `overflow` channel when there is one.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JoinThrottle {
+    pub joins: u32,
+    pub seconds: u32,
+    pub overflow: Option<String>,
+}
+
+impl JoinThrottle {
+    fn period(&self) -> Duration {
+        Duration::from_secs(self.seconds.into())
+    }
+}
+
+impl TryFrom<&str> for JoinThrottle {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let error = || {
+            format!("join throttle should be <joins>:<seconds>[:<overflow channel>], not '{value}'")
+        };
+        let mut parts = value.splitn(3, ':');
+        let joins = parts.next().ok_or_else(error)?;
+        let seconds = parts.next().ok_or_else(error)?;
+        let joins = joins.parse::<u32>().map_err(|_| error())?;
+        let seconds = seconds.parse::<u32>().map_err(|_| error())?;
+        if !(1..=100).contains(&joins) || !(1..=3600).contains(&seconds) {
+            return Err(error());
+        }
+        let overflow = parts.next().map(str::to_string);
+        if overflow
+            .as_deref()
+            .is_some_and(|overflow| overflow.len() < 2 || !overflow.starts_with('#'))
+        {
+            return Err(error());
+        }
+        Ok(Self {
+            joins,
+            seconds,
+            overflow,
+        })
+    }
+}
+
+impl std::fmt::Display for JoinThrottle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.joins, self.seconds)?;
+        if let Some(overflow) = &self.overflow {
+            write!(f, ":{overflow}")?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for ChannelMode {
@@ -186,6 +498,12 @@ impl Default for ChannelMode {
             topic_protected: Default::default(),
             moderated: Default::default(),
             no_external: true,
+            invite_only: Default::default(),
+            no_nick_change: Default::default(),
+            action_log: Default::default(),
+            flood_limit: None,
+            join_throttle: None,
+            key: None,
         }
     }
 }
@@ -199,6 +517,9 @@ impl TryFrom<&str> for ChannelMode {
             't' => Ok(mode.with_topic_protected()),
             'm' => Ok(mode.with_moderated()),
             'n' => Ok(mode.with_no_external()),
+            'i' => Ok(mode.with_invite_only()),
+            'N' => Ok(mode.with_no_nick_change()),
+            'H' => Ok(mode.with_action_log()),
             c => Err(format!("unknown channel modechar '{c}'")),
         })
     }
@@ -276,6 +597,155 @@ impl ChannelMode {
             ..self.clone()
         }
     }
+
+    pub fn is_invite_only(&self) -> bool {
+        self.invite_only
+    }
+
+    pub(crate) fn with_invite_only(&self) -> Self {
+        Self {
+            invite_only: true,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn without_invite_only(&self) -> Self {
+        Self {
+            invite_only: false,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_no_nick_change(&self) -> bool {
+        self.no_nick_change
+    }
+
+    pub(crate) fn with_no_nick_change(&self) -> Self {
+        Self {
+            no_nick_change: true,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn without_no_nick_change(&self) -> Self {
+        Self {
+            no_nick_change: false,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_action_log(&self) -> bool {
+        self.action_log
+    }
+
+    pub(crate) fn with_action_log(&self) -> Self {
+        Self {
+            action_log: true,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn without_action_log(&self) -> Self {
+        Self {
+            action_log: false,
+            ..self.clone()
+        }
+    }
+
+    pub fn flood_limit(&self) -> Option<FloodLimit> {
+        self.flood_limit
+    }
+
+    pub(crate) fn with_flood_limit(&self, flood_limit: FloodLimit) -> Self {
+        Self {
+            flood_limit: Some(flood_limit),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn without_flood_limit(&self) -> Self {
+        Self {
+            flood_limit: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn join_throttle(&self) -> Option<&JoinThrottle> {
+        self.join_throttle.as_ref()
+    }
+
+    pub(crate) fn with_join_throttle(&self, join_throttle: JoinThrottle) -> Self {
+        Self {
+            join_throttle: Some(join_throttle),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn without_join_throttle(&self) -> Self {
+        Self {
+            join_throttle: None,
+            ..self.clone()
+        }
+    }
+
+    /// Key (+k) to give in the JOIN.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    pub(crate) fn with_key(&self, key: &str) -> Self {
+        Self {
+            key: Some(key.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn without_key(&self) -> Self {
+        Self {
+            key: None,
+            ..self.clone()
+        }
+    }
+
+    /// Mode changes (such as `+t` or `-f`), with their parameter, turning this mode into the
+    /// other one.
+    pub(crate) fn changes_to(&self, other: &ChannelMode) -> Vec<(&'static str, Option<String>)> {
+        let flags = [
+            (self.secret, other.secret, "+s", "-s"),
+            (self.topic_protected, other.topic_protected, "+t", "-t"),
+            (self.moderated, other.moderated, "+m", "-m"),
+            (self.no_external, other.no_external, "+n", "-n"),
+            (self.invite_only, other.invite_only, "+i", "-i"),
+            (self.no_nick_change, other.no_nick_change, "+N", "-N"),
+            (self.action_log, other.action_log, "+H", "-H"),
+        ];
+        let mut changes = flags
+            .into_iter()
+            .filter(|(from, to, _, _)| from != to)
+            .map(|(_, to, set, unset)| (if to { set } else { unset }, None))
+            .collect::<Vec<_>>();
+        match other.flood_limit {
+            Some(limit) if self.flood_limit != Some(limit) => {
+                changes.push(("+f", Some(limit.to_string())));
+            }
+            None if self.flood_limit.is_some() => changes.push(("-f", None)),
+            _ => {}
+        }
+        match &other.join_throttle {
+            Some(throttle) if self.join_throttle.as_ref() != Some(throttle) => {
+                changes.push(("+j", Some(throttle.to_string())));
+            }
+            None if self.join_throttle.is_some() => changes.push(("-j", None)),
+            _ => {}
+        }
+        match &other.key {
+            Some(key) if self.key.as_ref() != Some(key) => changes.push(("+k", Some(key.clone()))),
+            // the parameter is expected by the clients, even if the key is not needed
+            None if self.key.is_some() => changes.push(("-k", Some("*".to_string()))),
+            _ => {}
+        }
+        changes
+    }
 }
 
 #[derive(Debug, Default)]
@@ -283,9 +753,112 @@ pub(crate) struct Channel {
     pub(crate) topic: Topic,
     pub(crate) users: HashMap<UserID, ChannelUserMode>,
     pub(crate) mode: ChannelMode,
+    /// pending invitations, with their expiration
+    pub(crate) invites: HashMap<UserID, Instant>,
+    /// the users matching one of these cannot join the channel
+    pub(crate) bans: Vec<BanMask>,
+    /// the users matching one of these can join the channel without invitation when it is +i
+    pub(crate) invite_exemptions: Vec<BanMask>,
+    /// recent joins, for the +j mode
+    pub(crate) recent_joins: VecDeque<Instant>,
+    /// recent actions of the channel operators, for the +H mode
+    pub(crate) action_log: VecDeque<ChannelAction>,
+    /// recent messages of each member, for the +f mode (the messages are sent with a read lock)
+    pub(crate) flood_windows: Mutex<HashMap<UserID, VecDeque<Instant>>>,
+    /// nickname of the first member, at the time they joined
+    pub(crate) creator: String,
+    /// unix timestamp, in seconds
+    pub(crate) created_at: u64,
+    /// unix timestamp of the last PRIVMSG or NOTICE to the channel, 0 if there was none
+    last_message_at: AtomicU64,
+}
+
+/// Number of actions kept in the log of a channel (+H), the oldest ones are forgotten first.
+const ACTION_LOG_LENGTH: usize = 50;
+
+/// Entry of the action log of a channel (mode +H).
+#[derive(Debug, Clone)]
+pub(crate) struct ChannelAction {
+    pub(crate) at: Instant,
+    /// nickname of the channel operator, or of the IRC operator for RESETCHAN
+    pub(crate) by: String,
+    /// command as it was relayed to the members, such as `MODE +o nick` or `TOPIC :text`
+    pub(crate) action: String,
+}
+
+/// Entry of the ban list (mode +b) or of the invite-exemption list (mode +I) of a channel.
+#[derive(Debug, Clone)]
+pub(crate) struct BanMask {
+    /// nick!user@host, with wildcards
+    pub(crate) mask: String,
+    pub(crate) set_by: String,
+    /// unix timestamp, in seconds
+    pub(crate) set_at: u64,
+    /// None if the entry is permanent
+    pub(crate) expires_at: Option<Instant>,
+}
+
+impl BanMask {
+    /// Completes the masks given as `nick`, `nick!user` or `user@host` into `nick!user@host`.
+    pub(crate) fn normalize(mask: &str) -> String {
+        let (nick_user, host) = mask.split_once('@').unwrap_or((mask, "*"));
+        let (nick, user) = match nick_user.split_once('!') {
+            Some((nick, user)) => (nick, user),
+            None if mask.contains('@') => ("*", nick_user),
+            None => (nick_user, "*"),
+        };
+        let or_any = |part: &str| if part.is_empty() { "*" } else { part }.to_string();
+        format!("{}!{}@{}", or_any(nick), or_any(user), or_any(host))
+    }
+
+    pub(crate) fn is_active(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
 }
 
 impl Channel {
+    /// Records the action if the channel has the mode +H.
+    pub(crate) fn log_action(&mut self, by: &str, action: String) {
+        if !self.mode.is_action_log() {
+            return;
+        }
+        if self.action_log.len() >= ACTION_LOG_LENGTH {
+            self.action_log.pop_front();
+        }
+        self.action_log.push_back(ChannelAction {
+            at: Instant::now(),
+            by: by.to_string(),
+            action,
+        });
+    }
+
+    pub(crate) fn is_banned(&self, user: &RegisteredUser) -> bool {
+        Self::any_mask_matches(&self.bans, user)
+    }
+
+    pub(crate) fn is_invite_exempt(&self, user: &RegisteredUser) -> bool {
+        Self::any_mask_matches(&self.invite_exemptions, user)
+    }
+
+    /// The masks are matched against the host shown to the other users, and against the IP. The
+    /// expired entries not removed yet are skipped.
+    fn any_mask_matches(masks: &[BanMask], user: &RegisteredUser) -> bool {
+        let now = Instant::now();
+        let with_ip = user
+            .connection_info
+            .ip
+            .map(|ip| format!("{}!{}@{ip}", user.nickname, user.username));
+        masks
+            .iter()
+            .filter(|entry| entry.is_active(now))
+            .any(|entry| {
+                mask_matches(&entry.mask, user.fullspec())
+                    || with_ip
+                        .as_deref()
+                        .is_some_and(|with_ip| mask_matches(&entry.mask, with_ip))
+            })
+    }
+
     pub(crate) fn ensure_user_can_set_topic(
         &self,
         user: &RegisteredUser,
@@ -301,7 +874,7 @@ impl Channel {
                 channel: channel_name.into(),
             })?;
 
-        if !user_mode.is_op() && self.mode.is_topic_protected() {
+        if !user_mode.is_op() && !user.is_operator && self.mode.is_topic_protected() {
             return Err(ServerStateError::ChanOpPrivsNeeded {
                 client: user.nickname.clone(),
                 channel: channel_name.to_string(),
@@ -318,6 +891,11 @@ impl Channel {
     ) -> Result<(), ServerStateError> {
         let user_id = &user.user_id;
 
+        // IRC operators have the channel operator privileges on all channels
+        if user.is_operator {
+            return Ok(());
+        }
+
         let user_mode = self
             .users
             .get(user_id)
@@ -336,6 +914,26 @@ impl Channel {
         Ok(())
     }
 
+    pub(crate) fn ensure_user_can_change_nick(
+        &self,
+        user: &RegisteredUser,
+        channel_name: &str,
+    ) -> Result<(), ServerStateError> {
+        if !self.mode.is_no_nick_change() || user.is_operator {
+            return Ok(());
+        }
+
+        let is_op = self.users.get(&user.user_id).is_some_and(|m| m.is_op());
+        if !is_op {
+            return Err(ServerStateError::NoNickChange {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn ensure_user_can_send_message(
         &self,
         user: &RegisteredUser,
@@ -371,17 +969,204 @@ impl Channel {
 
         Ok(())
     }
+
+    /// Records a message of the user for the +f mode, and fails if it goes over the limit.
+    /// The channel operators are not limited.
+    pub(crate) fn record_message_for_flood_limit(
+        &self,
+        user: &RegisteredUser,
+        channel_name: &str,
+        now: Instant,
+    ) -> Result<(), ServerStateError> {
+        let Some(flood_limit) = self.mode.flood_limit() else {
+            return Ok(());
+        };
+        if self.users.get(&user.user_id).is_some_and(|m| m.is_op()) {
+            return Ok(());
+        }
+
+        let mut flood_windows = self.flood_windows.lock();
+        flood_windows.retain(|_, window| {
+            while window
+                .front()
+                .is_some_and(|&t| now.duration_since(t) >= flood_limit.period())
+            {
+                window.pop_front();
+            }
+            !window.is_empty()
+        });
+
+        let window = flood_windows.entry(user.user_id).or_default();
+        if window.len() >= flood_limit.lines as usize {
+            return Err(ServerStateError::FloodLimited {
+                client: user.nickname.clone(),
+                channel: channel_name.to_string(),
+            });
+        }
+        window.push_back(now);
+        Ok(())
+    }
+
+    /// Records a join for the +j mode, and returns false if it goes over the limit.
+    /// The IRC operators are not limited.
+    pub(crate) fn record_join_for_throttle(&mut self, user: &RegisteredUser, now: Instant) -> bool {
+        let Some(join_throttle) = self.mode.join_throttle() else {
+            return true;
+        };
+
+        while self
+            .recent_joins
+            .front()
+            .is_some_and(|&t| now.duration_since(t) >= join_throttle.period())
+        {
+            self.recent_joins.pop_front();
+        }
+        if user.is_operator {
+            return true;
+        }
+        if self.recent_joins.len() >= join_throttle.joins as usize {
+            return false;
+        }
+        self.recent_joins.push_back(now);
+        true
+    }
+
+    pub(crate) fn record_activity(&self, timestamp: u64) {
+        self.last_message_at.store(timestamp, Ordering::Relaxed);
+    }
+
+    pub(crate) fn last_message_at(&self) -> Option<u64> {
+        Some(self.last_message_at.load(Ordering::Relaxed)).filter(|&ts| ts > 0)
+    }
+}
+
+/// Public view of a channel, for the administration of the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelSnapshot {
+    pub name: String,
+    pub creator: String,
+    /// unix timestamp, in seconds
+    pub created_at: u64,
+    /// unix timestamp, in seconds
+    pub last_message_at: Option<u64>,
+    pub users: usize,
+}
+
+/// Member of a channel, as seen by the embedder of the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMember {
+    pub user_id: UserID,
+    pub nickname: String,
+    pub op: bool,
+    pub voice: bool,
+}
+
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct KLine {
+    pub(crate) mask: String,
+    pub(crate) reason: Vec<u8>,
+    /// None if the K-line is permanent
+    pub(crate) expires_at: Option<Instant>,
+}
+
+impl KLine {
+    pub(crate) fn is_active(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// User who left the server or changed nickname, as shown by WHOWAS.
+#[derive(Debug, Clone)]
+pub(crate) struct WhowasEntry {
+    pub(crate) nickname: String,
+    pub(crate) username: String,
+    pub(crate) hostname: String,
+    pub(crate) realname: Vec<u8>,
+    /// unix timestamp, in seconds
+    pub(crate) departed_at: u64,
+}
+
+impl WhowasEntry {
+    pub(crate) fn new(user: &RegisteredUser, departed_at: u64) -> Self {
+        Self {
+            nickname: user.nickname.clone(),
+            username: user.username.clone(),
+            hostname: user.shown_hostname().to_string(),
+            realname: user.realname.clone(),
+            departed_at,
+        }
+    }
+}
+
+/// Nickname of a user whose connection was lost, which only their IP can take until it expires.
+#[derive(Debug, Clone)]
+pub(crate) struct HeldNickname {
+    pub(crate) cured_nickname: String,
+    pub(crate) ip: IpAddr,
+    pub(crate) expires_at: Instant,
+}
+
+impl HeldNickname {
+    pub(crate) fn prevents(&self, cured_nickname: &str, ip: Option<IpAddr>, now: Instant) -> bool {
+        self.expires_at > now
+            && self.cured_nickname.eq_ignore_ascii_case(cured_nickname)
+            && ip != Some(self.ip)
+    }
+}
+
+fn kline_target(username: &str, connection_info: &ConnectionInfo) -> String {
+    match connection_info.ip {
+        Some(ip) => format!("{username}@{ip}"),
+        None => format!("{username}@unknown"),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct WelcomeConfig {
     pub send_isupport: bool,
+    /// send the numeric 042 (RPL_YOURID) at registration
+    pub send_your_id: bool,
+    pub compat: CompatFlags,
 }
 
 impl Default for WelcomeConfig {
     fn default() -> Self {
         Self {
             send_isupport: true,
+            send_your_id: true,
+            compat: Default::default(),
+        }
+    }
+}
+
+/// Replies that some clients or test suites do not expect (the chirc test suite in particular).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatFlags {
+    /// send RPL_LISTSTART (321) before the replies to LIST
+    pub list_start: bool,
+    /// send RPL_TOPICWHOTIME (333) after RPL_TOPIC, irctest requires it
+    pub topic_who_time: bool,
+    /// send RPL_LOCALUSERS and RPL_GLOBALUSERS (265, 266) in reply to LUSERS
+    pub extended_lusers: bool,
+    /// answer MAP with this server alone, and USERS and SUMMON with ERR_USERSDISABLED and
+    /// ERR_SUMMONDISABLED (446, 445), instead of ERR_UNKNOWNCOMMAND (421)
+    pub unsupported_commands: bool,
+}
+
+impl Default for CompatFlags {
+    fn default() -> Self {
+        Self {
+            list_start: false,
+            topic_who_time: true,
+            extended_lusers: true,
+            unsupported_commands: true,
         }
     }
 }
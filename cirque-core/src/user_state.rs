@@ -1,8 +1,8 @@
 use std::time::Instant;
 
-use crate::server_state::ServerState;
+use crate::server_state::{lock_held_by_thread, ServerState};
 use crate::timeout::{PingState, PingStatus};
-use crate::types::UserID;
+use crate::types::{Lookup, UserID};
 use crate::{client_to_server, TimeoutConfig};
 
 #[derive(Debug)]
@@ -24,8 +24,24 @@ impl RegisteringState {
         server_state: &ServerState,
         message: cirque_parser::Message<'_>,
     ) -> UserState {
+        if !client_to_server::is_allowed_before_registration(message.command()) {
+            return server_state.ruser_sends_command_but_is_not_registered(self);
+        }
+
         let message = match client_to_server::Message::try_from(message) {
             Ok(message) => message,
+            Err(client_to_server::MessageDecodingError::InvalidUtf8 {
+                command,
+                parameters,
+                ..
+            }) if !server_state.is_utf8_only() => {
+                let parameters = client_to_server::transliterate_parameters(&parameters);
+                let message = cirque_parser::Message::new(
+                    command.as_bytes(),
+                    parameters.iter().map(Vec::as_slice).collect(),
+                );
+                return self.handle_message(server_state, message);
+            }
             Err(error) => {
                 return server_state.ruser_sends_invalid_message(self, error);
             }
@@ -44,19 +60,20 @@ impl RegisteringState {
             }
             client_to_server::Message::Ping(token) => server_state.ruser_pings(self, token),
             client_to_server::Message::Pong(token) => {
-                self.ping_state.on_receive_pong(token.to_vec());
+                self.ping_state
+                    .on_receive_pong(token.to_vec(), Instant::now());
                 UserState::Registering(self)
             }
+            client_to_server::Message::Cap(subcommand, param) => {
+                server_state.ruser_negotiates_capabilities(self, subcommand, param)
+            }
+            client_to_server::Message::Resume(token) => server_state.ruser_resumes(self, token),
             client_to_server::Message::Unknown(command) => {
                 server_state.ruser_sends_unknown_command(self, command)
             }
-            client_to_server::Message::PrivMsg(_, _) => {
-                // some valid commands should return ErrNotRegistered when not registered
-                server_state.ruser_sends_command_but_is_not_registered(self)
-            }
             _ => {
-                // valid commands should not return replies
-                UserState::Registering(self)
+                // filtered by is_allowed_before_registration
+                server_state.ruser_sends_command_but_is_not_registered(self)
             }
         }
     }
@@ -83,14 +100,26 @@ impl RegisteredState {
     ) -> UserState {
         let message = match client_to_server::Message::try_from(message) {
             Ok(message) => message,
+            Err(client_to_server::MessageDecodingError::InvalidUtf8 {
+                command,
+                parameters,
+                ..
+            }) if !server_state.is_utf8_only() => {
+                let parameters = client_to_server::transliterate_parameters(&parameters);
+                let message = cirque_parser::Message::new(
+                    command.as_bytes(),
+                    parameters.iter().map(Vec::as_slice).collect(),
+                );
+                return self.handle_message(server_state, message);
+            }
             Err(error) => {
                 return server_state.user_sends_invalid_message(self, error);
             }
         };
 
         match message {
-            client_to_server::Message::Join(channels) => {
-                server_state.user_joins_channels(self, &channels)
+            client_to_server::Message::Join(channels, keys) => {
+                server_state.user_joins_channels(self, &channels, &keys)
             }
             client_to_server::Message::Names(channels) => {
                 server_state.user_names_channels(self, &channels)
@@ -105,10 +134,24 @@ impl RegisteredState {
             client_to_server::Message::ChangeModeChannel(channel, modechar, param) => {
                 server_state.user_changes_channel_mode(self, channel, modechar, param)
             }
+            client_to_server::Message::ChangeListModesChannel(channel, modechars, masks) => {
+                server_state.user_changes_channel_list_modes(self, channel, modechars, &masks)
+            }
+            client_to_server::Message::AskModeUser(target) => {
+                server_state.user_asks_user_mode(self, target)
+            }
+            client_to_server::Message::ChangeModeUser(target, modechar) => {
+                server_state.user_changes_user_mode(self, target, modechar)
+            }
             client_to_server::Message::Ping(token) => server_state.user_pings(self, token),
             client_to_server::Message::Pong(token) => {
-                self.ping_state.on_receive_pong(token.to_vec());
-                UserState::Registered(self)
+                match self
+                    .ping_state
+                    .on_receive_pong(token.to_vec(), Instant::now())
+                {
+                    Some(round_trip) => server_state.user_answers_ping(self, round_trip),
+                    None => UserState::Registered(self),
+                }
             }
             client_to_server::Message::Quit(reason) => {
                 server_state.user_disconnects_voluntarily(self, reason)
@@ -126,17 +169,58 @@ impl RegisteredState {
                 server_state.user_wants_topic(self, target)
             }
             client_to_server::Message::MOTD() => server_state.user_wants_motd(self),
+            client_to_server::Message::Rules() => server_state.user_wants_rules(self),
+            client_to_server::Message::Admin() => server_state.user_wants_admin_info(self),
+            client_to_server::Message::Links() => server_state.user_wants_links(self),
+            client_to_server::Message::Unsupported(command) => {
+                server_state.user_sends_unsupported_command(self, command)
+            }
+            client_to_server::Message::Version() => server_state.user_wants_version(self),
             client_to_server::Message::Away(away_message) => {
                 server_state.user_indicates_away(self, away_message)
             }
             client_to_server::Message::Userhost(nicknames) => {
                 server_state.user_asks_userhosts(self, &nicknames)
             }
+            client_to_server::Message::Userip(nicknames) => {
+                server_state.user_asks_userips(self, &nicknames)
+            }
             client_to_server::Message::Whois(nickname) => {
                 server_state.user_asks_whois(self, nickname)
             }
+            client_to_server::Message::Whowas(nickname, count) => {
+                server_state.user_asks_whowas(self, nickname, count)
+            }
             client_to_server::Message::Who(mask) => server_state.user_asks_who(self, mask),
             client_to_server::Message::Lusers() => server_state.user_asks_lusers(self),
+            client_to_server::Message::Stats(query) => server_state.user_asks_stats(self, query),
+            client_to_server::Message::Oper(name, password) => {
+                server_state.user_asks_oper(self, name, password)
+            }
+            client_to_server::Message::Invite(nickname, channel) => {
+                server_state.user_invites(self, nickname, channel)
+            }
+            client_to_server::Message::ListInvites() => server_state.user_lists_invites(self),
+            client_to_server::Message::Kline(duration, mask, reason) => {
+                server_state.user_sets_kline(self, duration, mask, reason)
+            }
+            client_to_server::Message::Unkline(mask) => server_state.user_removes_kline(self, mask),
+            client_to_server::Message::SpamFilter(command) => {
+                server_state.user_manages_spam_filters(self, command)
+            }
+            client_to_server::Message::Cap(subcommand, param) => {
+                server_state.user_negotiates_capabilities(self, subcommand, param)
+            }
+            client_to_server::Message::ChgHost(nickname, hostname) => {
+                server_state.user_changes_host(self, nickname, hostname)
+            }
+            client_to_server::Message::ResetChan(channel, nickname) => {
+                server_state.user_resets_channel(self, channel, nickname)
+            }
+            client_to_server::Message::ActionLog(channel) => {
+                server_state.user_wants_action_log(self, channel)
+            }
+            client_to_server::Message::Resume(_) => server_state.user_resumes_too_late(self),
             client_to_server::Message::Unknown(command) => {
                 server_state.user_sends_unknown_command(self, command)
             }
@@ -159,6 +243,19 @@ pub enum UserState {
 }
 
 impl UserState {
+    /// The ID is kept during the registration, None once disconnected.
+    pub fn user_id(&self) -> Option<UserID> {
+        match self {
+            Self::Registering(state) => Some(state.user_id),
+            Self::Registered(state) => Some(state.user_id),
+            Self::Disconnected => None,
+        }
+    }
+
+    pub fn is_registering(&self) -> bool {
+        matches!(self, Self::Registering(_))
+    }
+
     pub fn is_alive(&self) -> bool {
         match self {
             Self::Registering(_) => true,
@@ -172,10 +269,65 @@ impl UserState {
         server_state: &ServerState,
         message: cirque_parser::Message<'_>,
     ) -> Self {
-        match self {
+        let Some(user_id) = self.user_id() else {
+            return self;
+        };
+        let command = message.command();
+        let is_labeled = message
+            .tags()
+            .and_then(client_to_server::label_tag)
+            .is_some_and(|label| server_state.start_labeled_response(user_id, label));
+        let held_before = lock_held_by_thread();
+
+        let state = match self {
             Self::Registering(session_state) => session_state.handle_message(server_state, message),
             Self::Registered(session_state) => session_state.handle_message(server_state, message),
             Self::Disconnected => self,
+        };
+
+        let held = lock_held_by_thread().saturating_sub(held_before);
+        server_state.report_command_duration(user_id, command, held);
+        if is_labeled {
+            server_state.end_labeled_response(user_id);
+        }
+        state
+    }
+
+    /// Closes the connection of the user as if they had quit, for a reason detected by the
+    /// session (such as a flood).
+    pub fn disconnect(self, server_state: &ServerState, reason: &[u8]) -> Self {
+        match self {
+            UserState::Registering(state) => {
+                server_state.ruser_disconnects_voluntarily(state, Some(reason))
+            }
+            UserState::Registered(state) => {
+                server_state.user_disconnects_voluntarily(state, Some(reason))
+            }
+            UserState::Disconnected => self,
+        }
+    }
+
+    /// Notifies the client that the lookup started. Its registration is held until
+    /// finish_lookup is called with the answer.
+    pub fn start_lookup(&self, server_state: &ServerState, lookup: Lookup) {
+        if let UserState::Registering(state) = self {
+            server_state.ruser_starts_lookup(state, lookup);
+        }
+    }
+
+    /// Records the answer of the lookup (None if it failed), and completes the registration if
+    /// the client already sent NICK and USER.
+    pub fn finish_lookup(
+        self,
+        server_state: &ServerState,
+        lookup: Lookup,
+        answer: Option<String>,
+    ) -> Self {
+        match self {
+            UserState::Registering(state) => {
+                server_state.ruser_finishes_lookup(state, lookup, answer)
+            }
+            UserState::Registered(_) | UserState::Disconnected => self,
         }
     }
 
@@ -204,16 +356,7 @@ impl UserState {
             PingStatus::AllGood => self,
             PingStatus::Timeout(duration) => {
                 let reason = format!("Timeout ({:.2}s)", duration.as_secs_f32());
-                let reason = reason.as_bytes();
-                match self {
-                    UserState::Registering(state) => {
-                        server_state.ruser_disconnects_voluntarily(state, Some(reason))
-                    }
-                    UserState::Registered(state) => {
-                        server_state.user_disconnects_voluntarily(state, Some(reason))
-                    }
-                    UserState::Disconnected => self,
-                }
+                self.disconnect(server_state, reason.as_bytes())
             }
             PingStatus::NeedToSend => {
                 let token = uuid::Uuid::new_v4().to_string();
@@ -0,0 +1,32 @@
+//! What a user is allowed to know about the other users and the channels.
+//!
+//! WHO, WHOIS, USERHOST, NAMES, LIST and LUSERS go through these functions, so that a new mode
+//! hiding users or channels only needs to be handled here.
+
+use crate::types::{Channel, RegisteredUser};
+
+/// Whether the viewer can see the existence, the topic and the members of the channel.
+pub(crate) fn channel_visible(viewer: &RegisteredUser, channel: &Channel) -> bool {
+    !channel.mode.is_secret() || channel.users.contains_key(&viewer.user_id)
+}
+
+/// Whether the viewer can see the target in listings and queries.
+///
+/// There is no user mode hiding users yet, so everyone is visible.
+pub(crate) fn can_see_user(_viewer: &RegisteredUser, _target: &RegisteredUser) -> bool {
+    true
+}
+
+/// Whether the viewer can see where the target connects from (their IP). Only the users
+/// themselves and the operators can, the others only see the displayed host.
+pub(crate) fn connection_visible(viewer: &RegisteredUser, target: &RegisteredUser) -> bool {
+    viewer.user_id == target.user_id || viewer.is_operator
+}
+
+/// Whether the viewer can see that the target is an IRC operator.
+/// Hidden operators (user mode +H) are only visible to the other operators.
+pub(crate) fn operator_visible(viewer: &RegisteredUser, target: &RegisteredUser) -> bool {
+    target.is_operator
+        && (!target.hides_operator || viewer.is_operator)
+        && can_see_user(viewer, target)
+}
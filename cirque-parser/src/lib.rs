@@ -1,24 +1,67 @@
-/// Note: Source (used for server-to-server or server-to-client communications) is not handled.
+/// Note: the tags and the source are exposed as raw bytes, they are not interpreted.
 use smallvec::SmallVec;
 
 mod parser;
+#[cfg(any(test, feature = "proptest"))]
+pub mod proptest_helpers;
+mod serializer;
 mod stream;
 
+pub use crate::stream::{parse_bytes_for_fuzzing, parse_bytes_in_chunks_for_fuzzing};
 pub use crate::stream::{LendingIterator, ParsingError, StreamParser};
 
 pub type Command = [u8];
-pub type Parameters<'a> = SmallVec<[&'a [u8]; 15]>;
+/// Maximum number of parameters in a message, the trailing one included.
+/// Longer messages are rejected by the parser, so the parameters never spill to the heap.
+pub const MAX_PARAMETERS: usize = 15;
+
+pub type Parameters<'a> = SmallVec<[&'a [u8]; MAX_PARAMETERS]>;
 
 ///
 /// See: https://modern.ircdocs.horse/#client-to-server-protocol-structure
 ///
 #[derive(Debug)]
 pub struct Message<'m> {
+    tags: Option<&'m [u8]>,
+    source: Option<&'m [u8]>,
     command: &'m Command,
     parameters: Parameters<'m>,
 }
 
 impl<'m> Message<'m> {
+    pub fn new(command: &'m Command, parameters: Parameters<'m>) -> Self {
+        Self {
+            tags: None,
+            source: None,
+            command,
+            parameters,
+        }
+    }
+
+    pub fn with_tags(self, tags: &'m [u8]) -> Self {
+        Self {
+            tags: Some(tags),
+            ..self
+        }
+    }
+
+    pub fn with_source(self, source: &'m [u8]) -> Self {
+        Self {
+            source: Some(source),
+            ..self
+        }
+    }
+
+    /// Raw tags, without the leading '@'.
+    pub fn tags(&self) -> Option<&'m [u8]> {
+        self.tags
+    }
+
+    /// Raw source, without the leading ':'.
+    pub fn source(&self) -> Option<&'m [u8]> {
+        self.source
+    }
+
     pub fn command(&self) -> &'m Command {
         self.command
     }
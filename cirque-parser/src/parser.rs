@@ -5,13 +5,13 @@ use nom::{
         complete::{char, space0},
         is_alphabetic, is_digit,
     },
-    combinator::{peek, rest},
-    multi::many0,
-    sequence::preceded,
+    combinator::{opt, peek, rest},
+    multi::{many0, many1},
+    sequence::{preceded, terminated},
     IResult,
 };
 
-use crate::{Command, Message, Parameters};
+use crate::{Command, Message, Parameters, MAX_PARAMETERS};
 
 // command ::= letter* / 3digit
 fn parse_command(buf: &[u8]) -> IResult<&[u8], &Command> {
@@ -34,6 +34,18 @@ fn parse_parameters(mut buf: &[u8]) -> IResult<&[u8], Parameters<'_>> {
         let (buf_, _spaces) = take_while(is_space)(buf)?;
         buf = buf_;
 
+        // trailing spaces do not make an empty parameter
+        if buf.is_empty() {
+            break;
+        }
+
+        if params.len() == MAX_PARAMETERS {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                buf,
+                nom::error::ErrorKind::TooLarge,
+            )));
+        }
+
         buf = if peek(tag::<_, _, nom::error::Error<&[u8]>>(b":"))(buf).is_ok() {
             let (buf_, rest) = preceded(tag(b":"), rest)(buf)?;
             params.push(rest);
@@ -48,15 +60,30 @@ fn parse_parameters(mut buf: &[u8]) -> IResult<&[u8], Parameters<'_>> {
     Ok((buf, params))
 }
 
-// message ::= ['@' <tags> SPACE] <command> <parameters> <crlf>
+// tags and source are kept as raw bytes, they are not interpreted yet
+fn parse_prefixed(prefix: char) -> impl FnMut(&[u8]) -> IResult<&[u8], Option<&[u8]>> {
+    move |buf| {
+        let is_space = |c: u8| -> bool { c == b' ' };
+        opt(terminated(
+            preceded(char(prefix), take_till(is_space)),
+            many1(char(' ')),
+        ))(buf)
+    }
+}
+
+// message ::= ['@' <tags> SPACE] [':' <source> SPACE] <command> <parameters> <crlf>
 pub fn parse_message(buf: &[u8]) -> IResult<&[u8], Message<'_>> {
     let space = &char(' ');
     let (buf, _) = space0(buf)?;
+    let (buf, tags) = parse_prefixed('@')(buf)?;
+    let (buf, source) = parse_prefixed(':')(buf)?;
     let (buf, command) = parse_command(buf)?;
     let (buf, parameters) = preceded(many0(space), parse_parameters)(buf)?;
     Ok((
         buf,
         Message {
+            tags,
+            source,
             command,
             parameters,
         },
@@ -169,6 +196,20 @@ mod tests {
             assert!(buf.is_empty());
         }
 
+        #[test]
+        fn trailing_spaces() {
+            let (buf, params) = all_consuming(parse_parameters)(b"#chan  ").unwrap();
+            assert_eq!(params[0], b"#chan");
+            assert_eq!(params.len(), 1);
+            assert!(buf.is_empty());
+
+            let (buf, params) = all_consuming(parse_parameters)(b"#chan : ").unwrap();
+            assert_eq!(params[0], b"#chan");
+            assert_eq!(params[1], b" ");
+            assert_eq!(params.len(), 2);
+            assert!(buf.is_empty());
+        }
+
         #[test]
         fn ex6() {
             let (buf, params) = all_consuming(parse_parameters)(b"#chan ::-)").unwrap();
@@ -177,6 +218,49 @@ mod tests {
             assert_eq!(params.len(), 2);
             assert!(buf.is_empty());
         }
+
+        #[test]
+        fn max_parameters() {
+            let line = b"a ".repeat(MAX_PARAMETERS);
+            let (buf, params) = all_consuming(parse_parameters)(&line).unwrap();
+            assert_eq!(params.len(), MAX_PARAMETERS);
+            assert!(!params.spilled());
+            assert!(buf.is_empty());
+
+            let mut line = b"a ".repeat(MAX_PARAMETERS - 1);
+            line.extend_from_slice(b":trailing with spaces");
+            let (buf, params) = all_consuming(parse_parameters)(&line).unwrap();
+            assert_eq!(params.len(), MAX_PARAMETERS);
+            assert_eq!(params[MAX_PARAMETERS - 1], b"trailing with spaces");
+            assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn fail_too_many_parameters() {
+            let line = b"a ".repeat(MAX_PARAMETERS + 1);
+            let result = all_consuming(parse_parameters)(&line);
+            assert!(matches!(result, Err(nom::Err::Failure(_))));
+
+            let mut line = b"a ".repeat(MAX_PARAMETERS);
+            line.extend_from_slice(b":trailing");
+            let result = all_consuming(parse_parameters)(&line);
+            assert!(matches!(result, Err(nom::Err::Failure(_))));
+
+            // a full line of one-letter parameters
+            let line = b"a ".repeat(256);
+            let result = all_consuming(parse_parameters)(&line);
+            assert!(matches!(result, Err(nom::Err::Failure(_))));
+        }
+
+        #[test]
+        fn many_spaces_are_not_parameters() {
+            let mut line = b" ".repeat(400);
+            line.extend_from_slice(b"a");
+            line.extend_from_slice(&b" ".repeat(100));
+            let (buf, params) = all_consuming(parse_parameters)(&line).unwrap();
+            assert_eq!(params.len(), 1);
+            assert!(buf.is_empty());
+        }
     }
 
     mod message {
@@ -199,5 +283,40 @@ mod tests {
             assert_eq!(message.command(), b"CAP");
             assert!(buf.is_empty());
         }
+
+        #[test]
+        fn tags_and_source() {
+            let (buf, message) =
+                all_consuming(parse_message)(b"@id=123;+draft/x :nick!user@host PRIVMSG #a :b")
+                    .unwrap();
+            assert_eq!(message.tags(), Some(&b"id=123;+draft/x"[..]));
+            assert_eq!(message.source(), Some(&b"nick!user@host"[..]));
+            assert_eq!(message.command(), b"PRIVMSG");
+            assert_eq!(message.parameters().len(), 2);
+            assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn source_only() {
+            let (buf, message) = all_consuming(parse_message)(b":srv  PING :tok").unwrap();
+            assert_eq!(message.tags(), None);
+            assert_eq!(message.source(), Some(&b"srv"[..]));
+            assert_eq!(message.command(), b"PING");
+            assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn fail_tags_without_command() {
+            let result = all_consuming(parse_message)(b"@id=123");
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn fail_too_many_parameters() {
+            let mut line = b"@id=1 :nick PRIVMSG".to_vec();
+            line.extend_from_slice(&b" #a".repeat(MAX_PARAMETERS + 1));
+            let result = all_consuming(parse_message)(&line);
+            assert!(result.is_err());
+        }
     }
 }
@@ -0,0 +1,120 @@
+//! Strategies generating valid messages, to check the parser against the serializer.
+//! Available in other crates with the `proptest` feature.
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::{LendingIterator, Message, StreamParser};
+
+/// Owned version of a Message, as generated by the strategies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageParts {
+    pub tags: Option<Vec<u8>>,
+    pub source: Option<Vec<u8>>,
+    pub command: Vec<u8>,
+    pub parameters: Vec<Vec<u8>>,
+}
+
+impl MessageParts {
+    pub fn from_message(message: &Message<'_>) -> Self {
+        Self {
+            tags: message.tags().map(|t| t.to_vec()),
+            source: message.source().map(|s| s.to_vec()),
+            command: message.command().to_vec(),
+            parameters: message.parameters().iter().map(|p| p.to_vec()).collect(),
+        }
+    }
+
+    pub fn as_message(&self) -> Message<'_> {
+        let parameters = self.parameters.iter().map(|p| p.as_slice()).collect();
+        let mut message = Message::new(&self.command, parameters);
+        if let Some(tags) = &self.tags {
+            message = message.with_tags(tags);
+        }
+        if let Some(source) = &self.source {
+            message = message.with_source(source);
+        }
+        message
+    }
+}
+
+/// Any byte that can appear in a line.
+fn arb_line_byte() -> impl Strategy<Value = u8> {
+    any::<u8>().prop_filter("no end of line", |&c| c != b'\r' && c != b'\n')
+}
+
+/// Any byte that can appear in a word (tags, source and middle parameters).
+fn arb_word_byte() -> impl Strategy<Value = u8> {
+    arb_line_byte().prop_filter("no space", |&c| c != b' ')
+}
+
+fn arb_word(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(arb_word_byte(), 1..=max_len)
+}
+
+pub fn arb_command() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        vec(prop_oneof![b'a'..=b'z', b'A'..=b'Z'], 1..=12),
+        vec(b'0'..=b'9', 3),
+    ]
+}
+
+pub fn arb_middle_parameter() -> impl Strategy<Value = Vec<u8>> {
+    arb_word(20).prop_filter("no leading colon", |p| !p.starts_with(b":"))
+}
+
+/// The last parameter can be empty, contain spaces (including trailing ones) or start with ':'.
+pub fn arb_last_parameter() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        arb_middle_parameter(),
+        vec(arb_line_byte(), 0..=40),
+        vec(Just(b' '), 1..=3),
+        Just(b":".to_vec()),
+    ]
+}
+
+/// Between 0 and 15 parameters.
+pub fn arb_parameters() -> impl Strategy<Value = Vec<Vec<u8>>> {
+    (vec(arb_middle_parameter(), 0..=14), arb_last_parameter()).prop_flat_map(|(middles, last)| {
+        let with_last = {
+            let mut params = middles.clone();
+            params.push(last);
+            params
+        };
+        prop_oneof![Just(middles), Just(with_last)]
+    })
+}
+
+pub fn arb_message() -> impl Strategy<Value = MessageParts> {
+    (
+        proptest::option::of(arb_word(50)),
+        proptest::option::of(arb_word(30)),
+        arb_command(),
+        arb_parameters(),
+    )
+        .prop_map(|(tags, source, command, parameters)| MessageParts {
+            tags,
+            source,
+            command,
+            parameters,
+        })
+}
+
+/// Serializes the message, parses it back and checks that nothing changed.
+pub fn check_roundtrip(parts: &MessageParts) -> Result<(), TestCaseError> {
+    let bytes = parts.as_message().to_bytes();
+
+    let mut stream_parser = StreamParser::default();
+    stream_parser.feed_from_slice(&bytes);
+    let mut iter = stream_parser.consume_iter();
+
+    let parsed = match iter.next() {
+        Some(Ok(message)) => MessageParts::from_message(&message),
+        Some(Err(err)) => return Err(TestCaseError::fail(err.to_string())),
+        None => return Err(TestCaseError::fail("no message parsed")),
+    };
+    prop_assert_eq!(&parsed, parts);
+
+    prop_assert!(iter.next().is_none());
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use crate::Message;
+
+impl Message<'_> {
+    /// Writes the message as a line terminated by CRLF.
+    ///
+    /// Only the last parameter can be empty, contain spaces or start with ':'.
+    /// The other parts of the message are written as is, it is the responsibility of the caller to
+    /// ensure that they are valid.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        if let Some(tags) = self.tags {
+            out.push(b'@');
+            out.extend_from_slice(tags);
+            out.push(b' ');
+        }
+        if let Some(source) = self.source {
+            out.push(b':');
+            out.extend_from_slice(source);
+            out.push(b' ');
+        }
+        out.extend_from_slice(self.command);
+
+        if let Some((last, middles)) = self.parameters.split_last() {
+            for param in middles {
+                out.push(b' ');
+                out.extend_from_slice(param);
+            }
+
+            out.push(b' ');
+            let needs_colon = last.is_empty() || last.starts_with(b":") || last.contains(&b' ');
+            if needs_colon {
+                out.push(b':');
+            }
+            out.extend_from_slice(last);
+        }
+
+        out.extend_from_slice(b"\r\n");
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write_to(&mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use smallvec::smallvec;
+
+    use crate::proptest_helpers::{arb_message, check_roundtrip};
+    use crate::Message;
+
+    #[test]
+    fn simple() {
+        let message = Message::new(b"PRIVMSG", smallvec![&b"#chan"[..], &b"hello world"[..]]);
+        assert_eq!(message.to_bytes(), b"PRIVMSG #chan :hello world\r\n");
+
+        let message = Message::new(b"PING", smallvec![&b"token"[..]]);
+        assert_eq!(message.to_bytes(), b"PING token\r\n");
+
+        let message = Message::new(b"QUIT", smallvec![]);
+        assert_eq!(message.to_bytes(), b"QUIT\r\n");
+    }
+
+    #[test]
+    fn tags_and_source() {
+        let message = Message::new(b"TOPIC", smallvec![&b"#chan"[..], &b""[..]])
+            .with_tags(b"time=2024-01-01T00:00:00.000Z")
+            .with_source(b"nick!user@host");
+        assert_eq!(
+            message.to_bytes(),
+            b"@time=2024-01-01T00:00:00.000Z :nick!user@host TOPIC #chan :\r\n"
+        );
+    }
+
+    #[test]
+    fn colon_in_last_parameter() {
+        let message = Message::new(b"PRIVMSG", smallvec![&b"#chan"[..], &b":-)"[..]]);
+        assert_eq!(message.to_bytes(), b"PRIVMSG #chan ::-)\r\n");
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(parts in arb_message()) {
+            check_roundtrip(&parts)?;
+        }
+    }
+}
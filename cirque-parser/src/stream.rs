@@ -34,8 +34,14 @@ impl StreamParser {
         self.buffer.extend_from_slice(buf);
     }
 
+    /// Whether the buffer holds the beginning of a message, once the complete ones are consumed.
+    pub fn has_partial_message(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
     pub fn consume_iter(&mut self) -> MessageIterator<'_> {
-        if self.buffer.is_full() && !self.buffer.contains(&b'\n') {
+        // the lines can also end with a single '\r'
+        if self.buffer.is_full() && !self.buffer.iter().any(|&c| is_end_of_message(c)) {
             log::warn!("buffer full without valid message, resetting");
             self.buffer.clear();
         }
@@ -43,6 +49,57 @@ impl StreamParser {
             stream_parser: self,
         }
     }
+
+    /// Same as `consume_iter`, for the callers that prefer a callback to a lending iterator.
+    /// The messages still borrow the buffer, they are only valid during the call.
+    pub fn for_each_message(&mut self, mut f: impl FnMut(Result<Message<'_>, ParsingError>)) {
+        let mut iter = self.consume_iter();
+        while let Some(message) = iter.next() {
+            f(message);
+        }
+    }
+}
+
+/// Entry point for the fuzzers: feeds the bytes to a StreamParser in small chunks, as it would
+/// happen with a network stream, and parses all the lines.
+/// Returns the number of valid messages.
+pub fn parse_bytes_for_fuzzing(bytes: &[u8]) -> usize {
+    let mut stream_parser = StreamParser::default();
+    let mut count = 0;
+    for chunk in bytes.chunks(1024) {
+        stream_parser.feed_from_slice(chunk);
+        stream_parser.for_each_message(|message| {
+            if message.is_ok() {
+                count += 1;
+            }
+        });
+    }
+    count
+}
+
+/// Entry point for the fuzzers: the first byte gives the size of the chunks (down to one byte at
+/// a time), and the lines are read through the BufMut interface, whose buffer does not grow and
+/// wraps around.
+/// Returns the number of valid messages.
+pub fn parse_bytes_in_chunks_for_fuzzing(bytes: &[u8]) -> usize {
+    let Some((&chunk_size, bytes)) = bytes.split_first() else {
+        return 0;
+    };
+    let mut stream_parser = StreamParser::default();
+    let mut count = 0;
+    for chunk in bytes.chunks(usize::from(chunk_size).max(1)) {
+        let mut chunk = chunk;
+        while !chunk.is_empty() {
+            let n = chunk
+                .len()
+                .min(bytes::BufMut::remaining_mut(&stream_parser));
+            let (now, later) = chunk.split_at(n);
+            bytes::BufMut::put_slice(&mut stream_parser, now);
+            chunk = later;
+            stream_parser.for_each_message(|message| count += usize::from(message.is_ok()));
+        }
+    }
+    count
 }
 
 unsafe impl bytes::BufMut for StreamParser {
@@ -72,8 +129,11 @@ fn is_end_of_message(c: u8) -> bool {
     c == b'\r' || c == b'\n'
 }
 
+/// Longest line given to the parser, including the tags.
+const MAX_LINE_LENGTH: usize = 512;
+
 fn consume_line(buf: &mut SliceRingBuffer<u8>) -> Option<&[u8]> {
-    assert!(buf.capacity() > 512);
+    assert!(buf.capacity() > MAX_LINE_LENGTH);
 
     #[allow(clippy::indexing_slicing)]
     while !buf.is_empty() && is_end_of_message(buf[0]) {
@@ -99,12 +159,6 @@ fn consume_line(buf: &mut SliceRingBuffer<u8>) -> Option<&[u8]> {
         buf.move_head_unchecked(line_length as isize);
     }
 
-    // restrict the message length to 512 characters
-    // this is not strictly necessary but might prevent some abuse
-    // the head of the buffer was still moved by the correct amont (till the end of line),
-    // so this truncates the line but keep the next message clean
-    let line_length = line_length.min(512);
-
     // retrieve the line from the pointer
     // SAFETY:
     // - the data is aligned because it is u8, and of size `cur` since it was part of the buffer
@@ -112,7 +166,26 @@ fn consume_line(buf: &mut SliceRingBuffer<u8>) -> Option<&[u8]> {
     // - the data won't be mutated as it is used only by MessageIterator which hold the mutable
     //   reference to StreamParser, hence the owned buffer can't be mutated
     let line = unsafe { std::slice::from_raw_parts(line_start_ptr, line_length) };
-    Some(line)
+
+    // restrict the message length to 512 characters
+    // this is not strictly necessary but might prevent some abuse
+    // the head of the buffer was still moved by the correct amont (till the end of line),
+    // so this truncates the line but keep the next message clean
+    Some(truncate_line(line))
+}
+
+/// Cuts the line to MAX_LINE_LENGTH bytes, before the UTF-8 character that would be split.
+fn truncate_line(line: &[u8]) -> &[u8] {
+    if line.len() <= MAX_LINE_LENGTH {
+        return line;
+    }
+    let is_continuation = |i: usize| line.get(i).is_some_and(|&c| c & 0b1100_0000 == 0b1000_0000);
+    // a UTF-8 character is at most 4 bytes long, so at most 3 bytes are dropped
+    let mut end = MAX_LINE_LENGTH;
+    while end > MAX_LINE_LENGTH - 3 && is_continuation(end) {
+        end -= 1;
+    }
+    line.get(..end).unwrap_or(line)
 }
 
 pub struct MessageIterator<'a> {
@@ -203,6 +276,26 @@ mod tests {
         assert_eq!(iter.count(), 2);
     }
 
+    #[test]
+    fn test_for_each_message() {
+        let mut sp = StreamParser::default();
+        sp.feed_from_slice(b"CMD a\n:\nPING :b c\nPA");
+        let mut messages = vec![];
+        sp.for_each_message(|message| {
+            let message = message.map(|m| (m.command().to_vec(), m.parameters().len()));
+            messages.push(message.map_err(|_| ()));
+        });
+        assert_eq!(
+            messages,
+            vec![Ok((b"CMD".to_vec(), 1)), Err(()), Ok((b"PING".to_vec(), 1))]
+        );
+
+        sp.feed_from_slice(b"RT\n");
+        let mut commands = vec![];
+        sp.for_each_message(|message| commands.push(message.unwrap().command().to_vec()));
+        assert_eq!(commands, vec![b"PART".to_vec()]);
+    }
+
     #[test]
     fn test_one_plus_one() {
         let mut sp = StreamParser::default();
@@ -287,4 +380,135 @@ mod tests {
         let a = iter.next().unwrap().unwrap();
         assert_eq!(a.command(), b"aa");
     }
+
+    #[test]
+    fn test_parse_bytes_for_fuzzing() {
+        assert_eq!(super::parse_bytes_for_fuzzing(b""), 0);
+        assert_eq!(super::parse_bytes_for_fuzzing(b"NICK a\r\nUSER"), 1);
+        assert_eq!(
+            super::parse_bytes_for_fuzzing(b"NICK a\r\n\x00\r\nJOIN #b\n"),
+            2
+        );
+
+        // lines longer than a chunk
+        let mut bytes = vec![b'A'; 3000];
+        bytes.extend_from_slice(b"\r\nPING a\r\n");
+        assert_eq!(super::parse_bytes_for_fuzzing(&bytes), 2);
+    }
+
+    #[test]
+    fn test_too_many_parameters() {
+        let mut sp = StreamParser::default();
+        let mut line = b"PRIVMSG".to_vec();
+        line.extend_from_slice(&b" a".repeat(200));
+        line.extend_from_slice(b"\r\nPING a\r\n");
+        sp.feed_from_slice(&line);
+
+        let mut iter = sp.consume_iter();
+        assert!(iter.next().unwrap().is_err());
+        assert_eq!(iter.next().unwrap().unwrap().command(), b"PING");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_only_carriage_returns() {
+        let mut sp = StreamParser::default();
+        sp.feed_from_slice(&[b'\r'; 5000]);
+        assert_eq!(sp.consume_iter().count(), 0);
+        assert!(sp.buffer.is_empty());
+
+        sp.feed_from_slice(b"PING a\r");
+        assert_eq!(sp.consume_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_full_buffer_with_carriage_returns() {
+        let sp = StreamParser::default();
+        let mut writer = sp.writer();
+        writer.write_all(b"PING a\r").unwrap();
+        for _ in 0..4089 {
+            writer.write_all(b"0").unwrap();
+        }
+        let mut sp = writer.into_inner();
+        assert!(sp.buffer.is_full());
+
+        // the line ending with '\r' is still parsed
+        let mut iter = sp.consume_iter();
+        assert_eq!(iter.next().unwrap().unwrap().command(), b"PING");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_one_byte_at_a_time() {
+        let mut sp = StreamParser::default();
+        let mut commands = vec![];
+        for &byte in b"NICK a\r\nUSER a 0 * :b\rPING c\n\n" {
+            sp.put_u8(byte);
+            sp.for_each_message(|message| commands.push(message.unwrap().command().to_vec()));
+        }
+        assert_eq!(
+            commands,
+            vec![b"NICK".to_vec(), b"USER".to_vec(), b"PING".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_max_line_at_wrap_point() {
+        let mut sp = StreamParser::default();
+        // move the head of the buffer close to its end, so that the next line wraps around
+        let filler = [b'A'; 4000];
+        sp.put_slice(&filler);
+        sp.put_slice(b"\n");
+        assert_eq!(sp.consume_iter().count(), 1);
+
+        let mut line = b"PRIVMSG #a :".to_vec();
+        line.resize(512, b'b');
+        line.extend_from_slice(b"\r\nPING a\r\n");
+        sp.put_slice(&line);
+
+        let mut iter = sp.consume_iter();
+        {
+            let message = iter.next().unwrap().unwrap();
+            assert_eq!(message.parameters().last().unwrap().len(), 500);
+        }
+        assert_eq!(iter.next().unwrap().unwrap().command(), b"PING");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_truncate_partial_utf8() {
+        let mut sp = StreamParser::default();
+        let mut line = b"PRIVMSG #a :".to_vec();
+        line.resize(510, b'b');
+        // a 4-byte character across the 512 bytes limit
+        line.extend_from_slice("🦀é".as_bytes());
+        line.extend_from_slice(b"\r\n");
+        sp.feed_from_slice(&line);
+
+        let mut iter = sp.consume_iter();
+        let message = iter.next().unwrap().unwrap();
+        let text = message.parameters().last().unwrap();
+        assert_eq!(text.len(), 498);
+        assert!(std::str::from_utf8(text).is_ok());
+
+        // invalid UTF-8 is cut at 512 bytes regardless
+        assert_eq!(super::truncate_line(&[0x80; 600]).len(), 509);
+        assert_eq!(super::truncate_line(&[b'a'; 600]).len(), 512);
+    }
+
+    #[test]
+    fn test_parse_bytes_in_chunks_for_fuzzing() {
+        assert_eq!(super::parse_bytes_in_chunks_for_fuzzing(b""), 0);
+        assert_eq!(
+            super::parse_bytes_in_chunks_for_fuzzing(b"\x01NICK a\r\nUSER"),
+            1
+        );
+
+        // more bytes than the buffer can hold
+        let mut bytes = vec![0xff];
+        for _ in 0..1000 {
+            bytes.extend_from_slice(b"PING a\r\n");
+        }
+        assert_eq!(super::parse_bytes_in_chunks_for_fuzzing(&bytes), 1000);
+    }
 }
@@ -1,11 +1,17 @@
 mod connection_validator;
 mod listener;
+mod lookup;
 mod message_throttler;
 mod server;
 mod session;
+mod shutdown;
 mod stream;
 
 pub use connection_validator::{AcceptAll, ConnectionLimiter, ConnectionValidator};
+pub use listener::ListenerProfile;
 pub use listener::TCPListener;
 pub use listener::TLSListener;
+pub use listener::{ConnectingStream, Listener};
 pub use server::run_server;
+pub use shutdown::ShutdownToken;
+pub use stream::Stream;
@@ -3,28 +3,66 @@ use std::net::SocketAddr;
 use crate::stream::Stream;
 
 pub use tcp::TCPListener;
+pub(crate) use tls::handshake_failure;
 pub use tls::TLSListener;
 
+/// Set of options applied to the connections accepted by a listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListenerProfile {
+    #[default]
+    Default,
+    /// For onion services: the peer address (always the local Tor daemon) is ignored,
+    /// and the clients are limited to fewer messages per second.
+    Tor,
+}
+
+impl ListenerProfile {
+    pub(crate) fn hides_peer_address(self) -> bool {
+        self == ListenerProfile::Tor
+    }
+
+    pub(crate) fn messages_per_second_limit(self, limit: u32) -> u32 {
+        match self {
+            ListenerProfile::Default => limit,
+            ListenerProfile::Tor => (limit / 2).max(1),
+        }
+    }
+}
+
+/// Connection accepted by a listener, before its handshake (such as the TLS one).
 pub trait ConnectingStream {
     type Stream: Stream;
 
+    /// Called in the task of the connection, after the connection was validated.
     fn handshake(self) -> impl std::future::Future<Output = std::io::Result<Self::Stream>> + Send;
 
+    /// Address used to limit the connections per IP and shown to the operators, unless the
+    /// profile of the listener hides it.
     fn peer_addr(&self) -> SocketAddr;
 }
 
+/// Source of connections given to run_server.
 pub trait Listener {
     type ConnectingStream: ConnectingStream + Send + 'static;
 
     fn accept(
         &self,
     ) -> impl std::future::Future<Output = std::io::Result<Self::ConnectingStream>> + Send;
+
+    /// Name given to the listener in the config, used to customize the connections it accepts.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    fn profile(&self) -> ListenerProfile {
+        ListenerProfile::Default
+    }
 }
 
 mod tcp {
     use tokio::net::TcpListener;
 
-    use super::{ConnectingStream, Listener};
+    use super::{ConnectingStream, Listener, ListenerProfile};
 
     /// Bind a TCP socket from the std:: to be blocking (this function is not async),
     /// then convert to a tokio:: listener for future use.
@@ -54,6 +92,8 @@ mod tcp {
 
     pub struct TCPListener {
         listener: TcpListener,
+        name: Option<String>,
+        profile: ListenerProfile,
     }
 
     impl TCPListener {
@@ -62,7 +102,22 @@ mod tcp {
             let listener = bind_tcp_socket(&addr)?;
 
             log::info!("listening on {addr} (TCP without TLS)");
-            Ok(Self { listener })
+            Ok(Self {
+                listener,
+                name: None,
+                profile: ListenerProfile::Default,
+            })
+        }
+
+        pub fn with_name(self, name: &str) -> Self {
+            Self {
+                name: Some(name.to_string()),
+                ..self
+            }
+        }
+
+        pub fn with_profile(self, profile: ListenerProfile) -> Self {
+            Self { profile, ..self }
         }
     }
 
@@ -75,10 +130,21 @@ mod tcp {
 
             Ok(TCPConnectingStream { stream, peer_addr })
         }
+
+        fn name(&self) -> Option<&str> {
+            self.name.as_deref()
+        }
+
+        fn profile(&self) -> ListenerProfile {
+            self.profile
+        }
     }
 }
 
 mod tls {
+    use std::time::Duration;
+
+    use cirque_core::HandshakeFailure;
     use tokio::net::TcpListener;
 
     use tokio_rustls::{
@@ -90,20 +156,31 @@ mod tls {
     };
 
     use super::tcp::bind_tcp_socket;
-    use super::{ConnectingStream, Listener};
+    use super::{ConnectingStream, Listener, ListenerProfile};
+
+    /// Time given to the clients to complete the TLS handshake, so that the half-open handshakes
+    /// do not keep their task and socket forever.
+    const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
     pub struct TLSConnectingStream {
         stream: tokio::net::TcpStream,
         peer_addr: std::net::SocketAddr,
         acceptor: TlsAcceptor,
+        handshake_timeout: Duration,
     }
 
     impl ConnectingStream for TLSConnectingStream {
         type Stream = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
 
         async fn handshake(self) -> std::io::Result<Self::Stream> {
-            let stream = self.acceptor.accept(self.stream).await?;
-            Ok(stream)
+            let handshake = self.acceptor.accept(self.stream);
+            match tokio::time::timeout(self.handshake_timeout, handshake).await {
+                Ok(stream) => stream,
+                Err(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "TLS handshake not completed in time",
+                )),
+            }
         }
 
         fn peer_addr(&self) -> std::net::SocketAddr {
@@ -114,6 +191,9 @@ mod tls {
     pub struct TLSListener {
         listener: TcpListener,
         acceptor: TlsAcceptor,
+        name: Option<String>,
+        profile: ListenerProfile,
+        handshake_timeout: Duration,
     }
 
     impl TLSListener {
@@ -132,7 +212,31 @@ mod tls {
             let acceptor = TlsAcceptor::from(std::sync::Arc::new(config));
 
             log::info!("listening on {addr} (TCP with TLS)");
-            Ok(Self { listener, acceptor })
+            Ok(Self {
+                listener,
+                acceptor,
+                name: None,
+                profile: ListenerProfile::Default,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            })
+        }
+
+        pub fn with_name(self, name: &str) -> Self {
+            Self {
+                name: Some(name.to_string()),
+                ..self
+            }
+        }
+
+        pub fn with_profile(self, profile: ListenerProfile) -> Self {
+            Self { profile, ..self }
+        }
+
+        pub fn with_handshake_timeout(self, handshake_timeout: Duration) -> Self {
+            Self {
+                handshake_timeout,
+                ..self
+            }
         }
     }
 
@@ -146,7 +250,89 @@ mod tls {
                 stream,
                 peer_addr,
                 acceptor: self.acceptor.clone(),
+                handshake_timeout: self.handshake_timeout,
             })
         }
+
+        fn name(&self) -> Option<&str> {
+            self.name.as_deref()
+        }
+
+        fn profile(&self) -> ListenerProfile {
+            self.profile
+        }
+    }
+
+    /// Category of the error returned by a handshake, for the metrics.
+    pub(crate) fn handshake_failure(err: &std::io::Error) -> HandshakeFailure {
+        use rustls::AlertDescription;
+
+        let tls_error = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>());
+        match (err.kind(), tls_error) {
+            (std::io::ErrorKind::TimedOut, _) => HandshakeFailure::Timeout,
+            (_, Some(rustls::Error::InvalidCertificate(_))) => HandshakeFailure::BadCertificate,
+            (
+                _,
+                Some(rustls::Error::AlertReceived(
+                    AlertDescription::BadCertificate
+                    | AlertDescription::UnsupportedCertificate
+                    | AlertDescription::CertificateRevoked
+                    | AlertDescription::CertificateExpired
+                    | AlertDescription::CertificateUnknown
+                    | AlertDescription::UnknownCA,
+                )),
+            ) => HandshakeFailure::BadCertificate,
+            (_, Some(_)) => HandshakeFailure::Protocol,
+            (
+                std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe,
+                None,
+            ) => HandshakeFailure::Connection,
+            (_, None) => HandshakeFailure::Protocol,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_handshake_failure() {
+            let io_error = |kind, error: rustls::Error| std::io::Error::new(kind, error);
+            let invalid_data = std::io::ErrorKind::InvalidData;
+            assert_eq!(
+                handshake_failure(&std::io::ErrorKind::TimedOut.into()),
+                HandshakeFailure::Timeout
+            );
+            assert_eq!(
+                handshake_failure(&std::io::ErrorKind::UnexpectedEof.into()),
+                HandshakeFailure::Connection
+            );
+            assert_eq!(
+                handshake_failure(&io_error(
+                    invalid_data,
+                    rustls::Error::AlertReceived(rustls::AlertDescription::UnknownCA)
+                )),
+                HandshakeFailure::BadCertificate
+            );
+            assert_eq!(
+                handshake_failure(&io_error(
+                    invalid_data,
+                    rustls::Error::AlertReceived(rustls::AlertDescription::ProtocolVersion)
+                )),
+                HandshakeFailure::Protocol
+            );
+            assert_eq!(
+                handshake_failure(&io_error(
+                    invalid_data,
+                    rustls::Error::InvalidMessage(rustls::InvalidMessage::InvalidContentType)
+                )),
+                HandshakeFailure::Protocol
+            );
+        }
     }
 }
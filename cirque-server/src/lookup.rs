@@ -0,0 +1,132 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use cirque_core::{Lookup, LookupConfig, ServerState, UserState};
+
+const IDENT_PORT: u16 = 113;
+
+/// RFC 1413 limits the responses to 1000 characters.
+const MAX_IDENT_RESPONSE_LENGTH: u64 = 1000;
+
+/// The DNS lookups block a thread of the blocking pool until they complete, even after their
+/// timeout. Beyond this number of lookups in progress, the new ones wait for a slot (and usually
+/// time out), so that a burst of connections cannot fill the pool.
+const MAX_CONCURRENT_DNS_LOOKUPS: usize = 32;
+
+static DNS_LOOKUPS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_DNS_LOOKUPS);
+
+/// Lookups of a registering client, whose answers are given to UserState::finish_lookup.
+pub(crate) type Lookups = JoinSet<(Lookup, Option<String>)>;
+
+/// Starts the enabled lookups about the peer, and tells the client about them. The lookups are
+/// aborted when the set is dropped.
+pub(crate) fn start_lookups(
+    state: &UserState,
+    server_state: &ServerState,
+    config: LookupConfig,
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+) -> Lookups {
+    let mut lookups = JoinSet::new();
+    if config.resolve_hostname {
+        state.start_lookup(server_state, Lookup::Hostname);
+        let answer = resolve_hostname(peer_addr.ip());
+        lookups.spawn(with_timeout(config.timeout, Lookup::Hostname, answer));
+    }
+    if let (true, Some(local_addr)) = (config.check_ident, local_addr) {
+        state.start_lookup(server_state, Lookup::Ident);
+        let answer = query_ident(peer_addr, local_addr);
+        lookups.spawn(with_timeout(config.timeout, Lookup::Ident, answer));
+    }
+    lookups
+}
+
+async fn with_timeout(
+    timeout: Duration,
+    lookup: Lookup,
+    answer: impl Future<Output = Option<String>>,
+) -> (Lookup, Option<String>) {
+    let answer = tokio::time::timeout(timeout, answer).await.ok().flatten();
+    (lookup, answer)
+}
+
+/// The reverse DNS name is only kept if it resolves back to the IP, otherwise the owner of the
+/// reverse zone could claim any name.
+async fn resolve_hostname(ip: IpAddr) -> Option<String> {
+    let ip = ip.to_canonical();
+    let permit = DNS_LOOKUPS.acquire().await.ok()?;
+    let lookup = tokio::task::spawn_blocking(move || {
+        // the slot is released when the lookup completes, not when it times out
+        let _permit = permit;
+        let hostname = dns_lookup::lookup_addr(&ip).ok()?;
+        let confirmed = dns_lookup::lookup_host(&hostname)
+            .ok()?
+            .into_iter()
+            .any(|resolved| resolved.to_canonical() == ip);
+        confirmed.then_some(hostname)
+    });
+    lookup.await.ok().flatten()
+}
+
+async fn query_ident(peer_addr: SocketAddr, local_addr: SocketAddr) -> Option<String> {
+    let mut stream = TcpStream::connect((peer_addr.ip(), IDENT_PORT))
+        .await
+        .ok()?;
+    let query = format!("{}, {}\r\n", peer_addr.port(), local_addr.port());
+    stream.write_all(query.as_bytes()).await.ok()?;
+
+    let mut response = String::new();
+    BufReader::new(stream.take(MAX_IDENT_RESPONSE_LENGTH))
+        .read_line(&mut response)
+        .await
+        .ok()?;
+    parse_ident_response(&response, peer_addr.port(), local_addr.port())
+}
+
+/// Parses `<port-on-server>, <port-on-client> : USERID : <opsys> : <user-id>`, the ports being
+/// the ones of the query.
+fn parse_ident_response(response: &str, peer_port: u16, local_port: u16) -> Option<String> {
+    let mut fields = response.trim_end().splitn(4, ':');
+    let (ports, kind, _opsys, user_id) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+
+    let (queried_peer_port, queried_local_port) = ports.split_once(',')?;
+    let same_ports = queried_peer_port.trim().parse() == Ok(peer_port)
+        && queried_local_port.trim().parse() == Ok(local_port);
+    if !same_ports || kind.trim() != "USERID" {
+        return None;
+    }
+    Some(user_id.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ident_response() {
+        let parse = |response| parse_ident_response(response, 6191, 23);
+        assert_eq!(
+            parse("6191, 23 : USERID : UNIX : stjohns\r\n"),
+            Some("stjohns".to_string())
+        );
+        assert_eq!(
+            parse("6191,23:USERID:OTHER,UTF-8:stjohns"),
+            Some("stjohns".to_string())
+        );
+        assert_eq!(parse("6191, 23 : ERROR : NO-USER\r\n"), None);
+        assert_eq!(parse("6192, 23 : USERID : UNIX : stjohns\r\n"), None);
+        assert_eq!(parse("6191, 23 : USERID : UNIX"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -1,25 +1,161 @@
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use cirque_core::{ThrottleAction, ThrottleConfig};
+
+use crate::listener::ListenerProfile;
+
+pub(crate) enum Throttling {
+    Allowed,
+    /// the client has been throttled for about a second
+    SlowedDown,
+    /// the client should be disconnected
+    Exceeded,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct MessageThrottler {
-    last_timestamp: Instant,
-    threshold: Duration,
+    config: Arc<ThrottleConfig>,
+    profile: ListenerProfile,
+    /// time needed to earn the right to send one command
+    interval: Duration,
+    /// when all the previous commands will have been paid back (GCRA)
+    paid_at: Instant,
+    /// the short bursts (such as the registration) are not worth reporting
+    max_consecutive_delays: u32,
+    consecutive_delays: u32,
 }
 
 impl MessageThrottler {
-    pub(crate) fn new(max_messages_per_second: u32) -> Self {
+    pub(crate) fn new(config: Arc<ThrottleConfig>, profile: ListenerProfile) -> Self {
+        let rate = Self::rate(&config, profile);
         Self {
-            last_timestamp: Instant::now(),
-            threshold: Duration::from_secs(1) / max_messages_per_second,
+            config,
+            profile,
+            interval: Duration::from_secs(1) / rate,
+            paid_at: Instant::now(),
+            max_consecutive_delays: rate,
+            consecutive_delays: 0,
+        }
+    }
+
+    fn rate(config: &ThrottleConfig, profile: ListenerProfile) -> u32 {
+        profile.messages_per_second_limit(config.rate).max(1)
+    }
+
+    /// Switches to the config if it is not the current one (after a reload). The commands
+    /// already sent still have to be paid back, at the new rate.
+    pub(crate) fn update_config(&mut self, config: &Arc<ThrottleConfig>) {
+        if Arc::ptr_eq(&self.config, config) {
+            return;
+        }
+        let rate = Self::rate(config, self.profile);
+        self.config = Arc::clone(config);
+        self.interval = Duration::from_secs(1) / rate;
+        self.max_consecutive_delays = rate;
+    }
+
+    /// Returns how long the command should be delayed, or None if the client should be
+    /// disconnected instead.
+    fn delay_for(&mut self, command: &[u8], now: Instant) -> Option<Duration> {
+        let cost = self.interval * self.config.penalty(command);
+        let allowance = self.interval * self.config.burst.max(1);
+
+        let paid_at = self.paid_at.max(now) + cost;
+        let delay = paid_at.saturating_duration_since(now + allowance);
+        if !delay.is_zero() && self.config.action == ThrottleAction::Disconnect {
+            return None;
+        }
+        self.paid_at = paid_at;
+        Some(delay)
+    }
+
+    pub(crate) async fn maybe_slow_down(&mut self, command: &[u8]) -> Throttling {
+        let Some(delay) = self.delay_for(command, Instant::now()) else {
+            return Throttling::Exceeded;
+        };
+        if delay.is_zero() {
+            self.consecutive_delays = 0;
+            return Throttling::Allowed;
+        }
+
+        tokio::time::sleep(delay).await;
+        self.consecutive_delays += 1;
+        if self.consecutive_delays >= self.max_consecutive_delays {
+            Throttling::SlowedDown
+        } else {
+            Throttling::Allowed
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_for() {
+        let config = ThrottleConfig {
+            rate: 10,
+            burst: 3,
+            penalties: [(cirque_core::CommandClass::Query, 2)].into(),
+            action: ThrottleAction::Delay,
+        };
+        let mut throttler =
+            MessageThrottler::new(Arc::new(config.clone()), ListenerProfile::Default);
+        let now = Instant::now();
+        let ms = Duration::from_millis;
 
-    pub(crate) async fn maybe_slow_down(&mut self) {
-        let elapsed = self.last_timestamp.elapsed();
-        if elapsed < self.threshold {
-            let delay = self.threshold - elapsed;
-            tokio::time::sleep(delay).await;
+        // the burst is free, then the commands are spaced by the rate
+        for _ in 0..3 {
+            assert_eq!(throttler.delay_for(b"PRIVMSG", now), Some(Duration::ZERO));
         }
-        self.last_timestamp = Instant::now();
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now), Some(ms(100)));
+        assert_eq!(throttler.delay_for(b"WHO", now), Some(ms(300)));
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now + ms(800)), Some(ms(0)));
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now + ms(2000)), Some(ms(0)));
+
+        let mut throttler = MessageThrottler::new(
+            Arc::new(ThrottleConfig {
+                action: ThrottleAction::Disconnect,
+                ..config
+            }),
+            ListenerProfile::Default,
+        );
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(throttler.delay_for(b"PRIVMSG", now), Some(Duration::ZERO));
+        }
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now), None);
+        assert_eq!(
+            throttler.delay_for(b"PRIVMSG", now + ms(100)),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_update_config() {
+        let config = Arc::new(ThrottleConfig {
+            rate: 10,
+            ..Default::default()
+        });
+        let mut throttler = MessageThrottler::new(Arc::clone(&config), ListenerProfile::Tor);
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now), Some(ms(0)));
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now), Some(ms(200)));
+
+        // the same config does not reset anything
+        throttler.update_config(&config);
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now), Some(ms(400)));
+
+        // a reloaded config applies from the next command, still halved for Tor
+        throttler.update_config(&Arc::new(ThrottleConfig {
+            rate: 100,
+            ..Default::default()
+        }));
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now + ms(600)), Some(ms(0)));
+        assert_eq!(throttler.delay_for(b"PRIVMSG", now + ms(600)), Some(ms(20)));
     }
 }
@@ -1,32 +1,94 @@
-use cirque_core::ServerState;
+use std::time::Duration;
+
+use cirque_core::{ConnectionInfo, ServerState};
+use tokio::task::JoinSet;
 
 use crate::connection_validator::ConnectionValidator;
+use crate::listener::handshake_failure;
 use crate::listener::ConnectingStream;
 use crate::listener::Listener;
+use crate::listener::ListenerProfile;
 use crate::session::run_session;
+use crate::shutdown::ShutdownToken;
+use crate::stream::Stream;
+
+/// Period between two calls to ServerState::run_maintenance.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
 
-async fn handle_client(server_state: ServerState, connecting_stream: impl ConnectingStream) {
+/// Time given to the handshakes in progress to complete once the server is shut down, as the
+/// handshake timeout of the listener is optional.
+const HANDSHAKE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+async fn handle_client(
+    server_state: ServerState,
+    connecting_stream: impl ConnectingStream,
+    listener_name: Option<String>,
+    profile: ListenerProfile,
+) {
+    let peer_addr = Some(connecting_stream.peer_addr()).filter(|_| !profile.hides_peer_address());
     let stream = connecting_stream.handshake().await;
 
     let stream = match stream {
         Ok(stream) => stream,
         Err(err) => {
-            log::error!("error during connection handshake with error: {err:#}");
+            let failure = handshake_failure(&err);
+            log::error!("error during connection handshake ({failure:?}) with error: {err:#}");
+            server_state.metrics().add_handshake_failure(failure);
             return;
         }
     };
 
-    run_session(stream, server_state).await;
+    let connection_info = ConnectionInfo {
+        ip: peer_addr.map(|addr| addr.ip()),
+        port: peer_addr.map(|addr| addr.port()),
+        is_tls: stream.is_tls(),
+        tls_info: stream.tls_info(),
+        listener: listener_name,
+        resolved_hostname: None,
+        ident: None,
+    };
+
+    // the session outlives the server, the clients stay connected when the listener is replaced
+    tokio::spawn(run_session(stream, server_state, connection_info, profile));
 }
 
+/// Accepts the connections of the listener until the shutdown token is cancelled. The listener
+/// is then dropped, and the function returns once the handshakes in progress are completed (the
+/// ones still running after a grace period are aborted); the sessions already started are left
+/// running. `on_ready` is called when the server starts
+/// accepting the connections.
 pub async fn run_server(
     listener: impl Listener,
     server_state: ServerState,
     mut connection_validator: impl ConnectionValidator + Send,
-) -> ! {
+    shutdown: ShutdownToken,
+    on_ready: impl FnOnce(),
+) {
+    let mut maintenance_timer = tokio::time::interval(MAINTENANCE_INTERVAL);
+    let mut handshakes = JoinSet::new();
+    on_ready();
+
     loop {
-        let conn = listener.accept().await;
-        let conn = conn.and_then(|c| connection_validator.validate(c.peer_addr()).map(|_| c));
+        let conn = tokio::select! {
+            conn = listener.accept() => conn,
+            _ = maintenance_timer.tick() => {
+                server_state.run_maintenance();
+                continue;
+            }
+            _ = shutdown.cancelled() => break,
+        };
+        while handshakes.try_join_next().is_some() {}
+        // the gateways are trusted, except behind a listener hiding the peer address, where all
+        // the connections seem to come from the same place
+        let profile = listener.profile();
+        let conn = conn.and_then(|c| {
+            let is_exempt =
+                !profile.hides_peer_address() && server_state.is_exempt_ip(c.peer_addr().ip());
+            if !is_exempt {
+                connection_validator.validate(c.peer_addr())?;
+            }
+            Ok(c)
+        });
 
         let conn = match conn {
             Ok(connecting_stream) => connecting_stream,
@@ -36,6 +98,26 @@ pub async fn run_server(
             }
         };
 
-        tokio::spawn(handle_client(server_state.clone(), conn));
+        let listener_name = listener.name().map(|name| name.to_string());
+        handshakes.spawn(handle_client(
+            server_state.clone(),
+            conn,
+            listener_name,
+            profile,
+        ));
+    }
+
+    drop(listener);
+    log::debug!("draining {} handshake(s) before shutdown", handshakes.len());
+    let drained = async { while handshakes.join_next().await.is_some() {} };
+    if tokio::time::timeout(HANDSHAKE_GRACE_PERIOD, drained)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "aborting {} handshake(s) not completed in time",
+            handshakes.len()
+        );
+        handshakes.shutdown().await;
     }
 }
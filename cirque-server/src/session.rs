@@ -1,28 +1,196 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
 
-use cirque_core::ServerState;
+use cirque_core::{ConnectionInfo, MailboxSink, SerializedMessage, ServerState, Traffic, UserID};
 use cirque_parser::{LendingIterator, StreamParser};
 
-use crate::message_throttler::MessageThrottler;
+use crate::listener::ListenerProfile;
+use crate::lookup::start_lookups;
+use crate::message_throttler::{MessageThrottler, Throttling};
 use crate::stream::Stream;
 
-pub(crate) async fn run_session(mut stream: impl Stream, server_state: ServerState) {
+/// Minimum period between two notices of the same kind about the limits hit by a client,
+/// so that the notices do not add to the flood.
+const OVERLOAD_NOTICE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct OverloadNotice {
+    last_sent: Option<tokio::time::Instant>,
+}
+
+impl OverloadNotice {
+    fn is_due(&mut self) -> bool {
+        let now = tokio::time::Instant::now();
+        let is_due = self
+            .last_sent
+            .is_none_or(|last_sent| now.duration_since(last_sent) >= OVERLOAD_NOTICE_INTERVAL);
+        if is_due {
+            self.last_sent = Some(now);
+        }
+        is_due
+    }
+}
+
+/// Time given to the writer to send the remaining messages once the session is over (after a QUIT
+/// for example), after which the connection is closed even if the client did not read them.
+const CLOSING_FLUSH_DEADLINE: Duration = Duration::from_secs(2);
+
+/// Upper bound on the number of messages written together.
+const MAX_COALESCED_MESSAGES: usize = 100;
+
+/// Collects the membership changes received during the window following the first one.
+/// The first other message stops the collection, and is returned last.
+async fn coalesce_membership_changes(
+    first: SerializedMessage,
+    rx: &mut MailboxSink,
+    window: Duration,
+) -> Vec<SerializedMessage> {
+    let deadline = tokio::time::Instant::now() + window;
+    let mut messages = vec![first];
+    while messages.len() < MAX_COALESCED_MESSAGES {
+        let Ok(Some(msg)) = tokio::time::timeout_at(deadline, rx.recv()).await else {
+            break;
+        };
+        let is_membership_change = msg.is_membership_change();
+        messages.push(msg);
+        if !is_membership_change {
+            break;
+        }
+    }
+    messages
+}
+
+/// Writes the messages of the mailbox to the client, until the mailbox is closed or the client
+/// cannot be written to. It runs in its own task, so that a client that does not read does not
+/// block the processing of its commands.
+async fn run_writer(
+    mut writer: impl AsyncWrite + Unpin,
+    mut rx: MailboxSink,
+    server_state: ServerState,
+    user_id: UserID,
+    is_tls: bool,
+    important_message_sent: Arc<Notify>,
+) {
+    let membership_coalescing = server_state.get_membership_coalescing();
+    let mut drop_notice = OverloadNotice::default();
+
+    while let Some(msg) = rx.recv().await {
+        let is_important = msg.is_important();
+        let coalesced;
+        let bytes = match membership_coalescing {
+            Some(window) if msg.is_membership_change() => {
+                let messages = coalesce_membership_changes(msg, &mut rx, window).await;
+                coalesced = server_state.frame_coalesced_messages(user_id, &messages);
+                &coalesced
+            }
+            _ => msg.bytes(),
+        };
+        if writer.write_all(bytes).await.is_err() {
+            break;
+        }
+        let traffic = Traffic {
+            bytes_sent: bytes.len() as u64,
+            ..Default::default()
+        };
+        server_state.metrics().add_traffic(is_tls, traffic);
+        if is_important {
+            important_message_sent.notify_one();
+        }
+        let dropped = rx.take_dropped_count();
+        if dropped > 0 && drop_notice.is_due() {
+            let content =
+                format!("{dropped} messages to you were dropped, your connection cannot keep up");
+            server_state.notify_user(user_id, content.as_bytes());
+        }
+    }
+    // the mailbox sender was closed, probably because the RegisteringUser/RegisteredUser was
+    // dropped (after a QUIT for example), and the remaining messages were written
+    let _ = writer.shutdown().await;
+}
+
+/// Resolves at the deadline, or never without one.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub(crate) async fn run_session(
+    stream: impl Stream,
+    server_state: ServerState,
+    connection_info: ConnectionInfo,
+    profile: ListenerProfile,
+) {
     let mut stream_parser = StreamParser::default();
-    let mut message_throttler = MessageThrottler::new(server_state.get_messages_per_second_limit());
+    let mut message_throttler = MessageThrottler::new(server_state.get_throttle_config(), profile);
 
     let timeout = server_state
         .get_timeout_config()
-        .map(|config| config.reduced_timeout)
+        .map(|config| config.shortest_timeout())
         .unwrap_or_else(|| Duration::from_secs(99999));
     let mut timer = tokio::time::interval(timeout.div_f32(4.));
 
-    let (mut state, mut rx) = server_state.new_registering_user();
+    // until the registration is complete, the bytes received are counted and the messages have
+    // to be completed in time, so that a client cannot pin the buffers by trickling bytes
+    let unregistered_limits = server_state.get_unregistered_limits();
+    let mut unregistered_bytes: u64 = 0;
+    let mut partial_message_deadline = None;
+    let registration_deadline = unregistered_limits
+        .registration_deadline
+        .map(|deadline| tokio::time::Instant::now() + deadline);
+
+    let peer_addr = connection_info
+        .ip
+        .zip(connection_info.port)
+        .map(SocketAddr::from);
+    // a trusted gateway relays the messages of many people, it is not throttled
+    let is_exempt = connection_info
+        .ip
+        .is_some_and(|ip| server_state.is_exempt_ip(ip));
+    let (mut state, rx) = server_state.new_registering_user(connection_info);
+    let Some(user_id) = state.user_id() else {
+        return;
+    };
+
+    // the registration completes once the lookups are answered, or timed out
+    let mut lookups = match peer_addr {
+        Some(peer_addr) => start_lookups(
+            &state,
+            &server_state,
+            server_state.get_lookup_config(),
+            peer_addr,
+            stream.local_addr(),
+        ),
+        None => Default::default(),
+    };
+
+    // the received traffic is accumulated locally and reported to the metrics at each tick,
+    // the writer reports the sent traffic itself
+    let is_tls = stream.is_tls();
+    let mut traffic = Traffic::default();
+
+    let (mut reader, writer) = tokio::io::split(stream);
+    let important_message_sent = Arc::new(Notify::new());
+    let mut writer_task = tokio::spawn(run_writer(
+        writer,
+        rx,
+        server_state.clone(),
+        user_id,
+        is_tls,
+        Arc::clone(&important_message_sent),
+    ));
+    let mut writer_finished = false;
+
+    let mut throttle_notice = OverloadNotice::default();
 
     while state.is_alive() {
         tokio::select! {
-            result = stream.read_buf(&mut stream_parser) => {
+            result = reader.read_buf(&mut stream_parser) => {
                 let Ok(received) = result else {
                     break;
                 };
@@ -30,9 +198,16 @@ pub(crate) async fn run_session(mut stream: impl Stream, server_state: ServerSta
                 if received == 0 {
                     break;
                 }
+                traffic.bytes_received += received as u64;
+
+                if state.is_registering() {
+                    unregistered_bytes += received as u64;
+                }
 
+                let mut completed_message = false;
                 let mut iter = stream_parser.consume_iter();
                 while let Some(message) = iter.next() {
+                    completed_message = true;
                     let message = match message {
                         Ok(m) => m,
                         Err(err) => {
@@ -41,43 +216,197 @@ pub(crate) async fn run_session(mut stream: impl Stream, server_state: ServerSta
                         }
                     };
 
+                    let command = message.command();
                     state = state.handle_message(&server_state, message);
-                    message_throttler.maybe_slow_down().await;
-                }
-            },
-            msg = rx.recv() => {
-                if let Some(msg) = msg {
-                    if stream.write_all(msg.bytes()).await.is_err() {
-                        break;
+                    message_throttler.update_config(&server_state.get_throttle_config());
+                    if is_exempt {
+                        continue;
                     }
-                    if msg.is_important() {
-                        state.aggressively_reduce_timeout();
+                    match message_throttler.maybe_slow_down(command).await {
+                        Throttling::Allowed => {}
+                        Throttling::SlowedDown => {
+                            if throttle_notice.is_due() {
+                                server_state.notify_user(
+                                    user_id,
+                                    b"You are sending messages too fast, they are being delayed",
+                                );
+                            }
+                        }
+                        Throttling::Exceeded => {
+                            state = state.disconnect(&server_state, b"Excess Flood");
+                            break;
+                        }
                     }
-                } else {
-                    // mailbox sender was closed, probably because of
-                    // RegisteringUser/RegisteredUser was dropped.
+                }
+
+                // checked after the messages, so that the bytes sent along with the registration
+                // are not held against the client
+                let over_quota = unregistered_limits
+                    .max_bytes
+                    .is_some_and(|max_bytes| unregistered_bytes > max_bytes);
+                if state.is_registering() && over_quota {
+                    state = state.disconnect(&server_state, b"Too much data before registration");
                     break;
                 }
+
+                partial_message_deadline = match unregistered_limits.partial_message_deadline {
+                    Some(deadline)
+                        if state.is_registering() && stream_parser.has_partial_message() =>
+                    {
+                        partial_message_deadline
+                            .filter(|_| !completed_message)
+                            .or_else(|| Some(tokio::time::Instant::now() + deadline))
+                    }
+                    _ => None,
+                };
+            },
+            Some(lookup) = lookups.join_next() => {
+                if let Ok((lookup, answer)) = lookup {
+                    state = state.finish_lookup(&server_state, lookup, answer);
+                }
+            }
+            _ = sleep_until(partial_message_deadline) => {
+                state = state.disconnect(&server_state, b"Message not completed in time");
+            }
+            _ = sleep_until(registration_deadline.filter(|_| state.is_registering())) => {
+                state = state.disconnect(&server_state, b"Registration not completed in time");
+            }
+            _ = important_message_sent.notified() => {
+                state.aggressively_reduce_timeout();
+            }
+            _ = &mut writer_task => {
+                // the mailbox was closed or the client cannot be written to anymore
+                writer_finished = true;
+                break;
             }
             _ = timer.tick() => {
                 state = state.check_timeout(&server_state);
+                server_state
+                    .metrics()
+                    .add_traffic(is_tls, std::mem::take(&mut traffic));
             }
         }
     }
 
+    // closes the mailbox, the writer then sends the remaining messages
+    // (in case the client asked a QUIT for example)
     server_state.dispose_state(state);
-    // close the mailbox, we don't want to receive any more messages at this point
-    rx.close();
-
-    // handle the disconnection gracefully by sending remaining
-    // messages (in case the client asked a QUIT for example)
-    let buf = {
-        let mut buf = std::io::Cursor::new(Vec::<u8>::new());
-        while let Ok(msg) = rx.try_recv() {
-            let _ = std::io::Write::write_all(&mut buf, msg.bytes());
+    server_state.metrics().add_traffic(is_tls, traffic);
+
+    // don't hang on the client just for theses, the connection is closed when the writer is
+    // dropped
+    if !writer_finished
+        && tokio::time::timeout(CLOSING_FLUSH_DEADLINE, &mut writer_task)
+            .await
+            .is_err()
+    {
+        log::debug!("closing the connection of {user_id} before sending the last messages");
+        writer_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cirque_core::WelcomeConfig;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_closing_flush_deadline() {
+        let server_state = ServerState::new("srv", &WelcomeConfig::default(), None, None, None);
+        let (mut client, server) = tokio::io::duplex(64);
+        let session = tokio::spawn(run_session(
+            server,
+            server_state,
+            ConnectionInfo::default(),
+            ListenerProfile::Default,
+        ));
+
+        // the client quits right after registering, without reading the replies
+        client
+            .write_all(b"NICK nick\r\nUSER user 0 * :Real\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        let started_at = tokio::time::Instant::now();
+        session.await.unwrap();
+        assert!(started_at.elapsed() <= CLOSING_FLUSH_DEADLINE + Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unregistered_limits() {
+        let server_state = ServerState::new("srv", &WelcomeConfig::default(), None, None, None);
+        server_state.update_config(|c| {
+            c.set_unregistered_limits(cirque_core::UnregisteredLimits {
+                max_bytes: Some(100),
+                partial_message_deadline: Some(Duration::from_secs(10)),
+                registration_deadline: Some(Duration::from_secs(60)),
+            })
+        });
+        let run = |server| {
+            tokio::spawn(run_session(
+                server,
+                server_state.clone(),
+                ConnectionInfo::default(),
+                ListenerProfile::Default,
+            ))
+        };
+
+        // a message trickled byte by byte
+        let (mut client, server) = tokio::io::duplex(1024);
+        let session = run(server);
+        let started_at = tokio::time::Instant::now();
+        let trickle = tokio::spawn(async move {
+            for &byte in b"NICK nickname" {
+                if client.write_all(&[byte]).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+            client
+        });
+        session.await.unwrap();
+        let elapsed = started_at.elapsed();
+        assert!(elapsed >= Duration::from_secs(10) && elapsed < Duration::from_secs(11));
+        let mut client = trickle.await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.ends_with(b"(Message not completed in time)\r\n"));
+
+        // complete messages, but too many bytes before the registration
+        let (mut client, server) = tokio::io::duplex(4096);
+        let session = run(server);
+        for _ in 0..20 {
+            if client.write_all(b"CAP LS\r\n").await.is_err() {
+                break;
+            }
         }
-        buf.into_inner()
-    };
-    // try to send the messages, but don't hang on the client just for theses
-    let _ = tokio::time::timeout(Duration::from_secs(10), stream.write_all(&buf)).await;
+        session.await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.ends_with(b"(Too much data before registration)\r\n"));
+
+        // complete messages, but no registration
+        let (mut client, server) = tokio::io::duplex(4096);
+        let session = run(server);
+        let started_at = tokio::time::Instant::now();
+        client.write_all(b"NICK nick\r\n").await.unwrap();
+        session.await.unwrap();
+        assert!(started_at.elapsed() < Duration::from_secs(61));
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.ends_with(b"(Registration not completed in time)\r\n"));
+
+        // the limits no longer apply once registered
+        let (mut client, server) = tokio::io::duplex(4096);
+        let session = run(server);
+        client
+            .write_all(b"NICK nick\r\nUSER user 0 * :Real\r\n")
+            .await
+            .unwrap();
+        client.write_all(&b"PING a\r\n".repeat(20)).await.unwrap();
+        client.write_all(b"PRIV").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(!session.is_finished());
+        session.abort();
+    }
 }
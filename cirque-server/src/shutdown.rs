@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Asks the servers given a clone of the token to stop accepting connections, see `run_server`.
+#[derive(Debug, Clone)]
+pub struct ShutdownToken {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Completes once the token is cancelled, immediately if it already is.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+        // the sender lives as long as self, so waiting cannot fail
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_token() {
+        let token = ShutdownToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        let waiting = tokio::spawn(async move { clone.cancelled().await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        token.cancel();
+        assert!(waiting.await.is_ok());
+
+        assert!(token.is_cancelled());
+        // already cancelled
+        token.cancelled().await;
+    }
+}
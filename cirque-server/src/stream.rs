@@ -1,7 +1,69 @@
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::net::SocketAddr;
+
+use cirque_core::TlsInfo;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::TcpStream;
 
-pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+/// Established connection with a client, over which the IRC lines are exchanged.
+///
+/// Implement it (with ConnectingStream and Listener) to serve clients over another transport
+/// than the provided TCP and TLS listeners.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Whether the transport is encrypted, which selects the STS policy advertised to the client
+    /// and the MOTD, and the traffic counters.
+    fn is_tls(&self) -> bool;
+
+    /// Version and cipher of the TLS session, shown to the client in the reply to its own WHOIS,
+    /// and the hostname it asked for, by which the users are counted.
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+
+    /// Local end of the connection, needed to query the ident server of the peer.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+impl Stream for tokio_rustls::server::TlsStream<TcpStream> {
+    fn is_tls(&self) -> bool {
+        true
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        let (_, connection) = self.get_ref();
+        let version = connection.protocol_version()?;
+        let cipher = connection.negotiated_cipher_suite()?.suite();
+        Some(TlsInfo {
+            version: version
+                .as_str()
+                .map_or(format!("{version:?}"), str::to_string),
+            cipher: cipher
+                .as_str()
+                .map_or(format!("{cipher:?}"), str::to_string),
+            server_name: connection.server_name().map(str::to_ascii_lowercase),
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        let (stream, _) = self.get_ref();
+        stream.local_addr().ok()
+    }
+}
 
-impl Stream for TcpStream {}
-impl Stream for tokio_rustls::server::TlsStream<TcpStream> {}
+/// In-memory transport, for tests and embedding.
+impl Stream for DuplexStream {
+    fn is_tls(&self) -> bool {
+        false
+    }
+}
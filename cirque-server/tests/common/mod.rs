@@ -0,0 +1,298 @@
+#![allow(dead_code)] // not all the tests use all the helpers
+
+// Harness running the server on the in-memory transport, with clients exchanging lines with it.
+
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+
+use cirque_core::{ServerState, ThrottleConfig, WelcomeConfig};
+use cirque_server::{run_server, AcceptAll, ConnectingStream, Listener, ShutdownToken};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct MemoryConnectingStream {
+    stream: DuplexStream,
+    peer_addr: SocketAddr,
+    /// the handshake completes when the sender is used or dropped
+    handshake: Option<oneshot::Receiver<()>>,
+}
+
+impl ConnectingStream for MemoryConnectingStream {
+    type Stream = DuplexStream;
+
+    async fn handshake(self) -> std::io::Result<Self::Stream> {
+        if let Some(handshake) = self.handshake {
+            let _ = handshake.await;
+        }
+        Ok(self.stream)
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+struct MemoryListener {
+    connections: Mutex<mpsc::UnboundedReceiver<MemoryConnectingStream>>,
+}
+
+impl Listener for MemoryListener {
+    type ConnectingStream = MemoryConnectingStream;
+
+    async fn accept(&self) -> std::io::Result<Self::ConnectingStream> {
+        match self.connections.lock().await.recv().await {
+            Some(connection) => Ok(connection),
+            None => std::future::pending().await,
+        }
+    }
+}
+
+pub struct TestServer {
+    connections: mpsc::UnboundedSender<MemoryConnectingStream>,
+    next_port: u16,
+    shutdown: ShutdownToken,
+    server: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Returns once the server accepts the connections.
+    pub async fn start(membership_coalescing: Option<Duration>) -> Self {
+        let server_state = ServerState::new("srv", &WelcomeConfig::default(), None, None, None);
+        server_state.update_config(|config| {
+            config.set_membership_coalescing(membership_coalescing);
+            config.set_throttle_config(ThrottleConfig {
+                rate: 10000,
+                burst: 10000,
+                ..Default::default()
+            });
+        });
+
+        let (connections, receiver) = mpsc::unbounded_channel();
+        let listener = MemoryListener {
+            connections: Mutex::new(receiver),
+        };
+        let shutdown = ShutdownToken::new();
+        let (ready, is_ready) = oneshot::channel();
+        let server = tokio::spawn(run_server(
+            listener,
+            server_state,
+            AcceptAll {},
+            shutdown.clone(),
+            move || ready.send(()).unwrap(),
+        ));
+        is_ready.await.unwrap();
+        Self {
+            connections,
+            next_port: 1000,
+            shutdown,
+            server,
+        }
+    }
+
+    /// Asks the server to stop accepting the connections, see `stopped`.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Waits for run_server to return.
+    pub async fn stopped(&mut self) {
+        let stopped = tokio::time::timeout(READ_TIMEOUT, &mut self.server);
+        stopped.await.expect("server not stopped in time").unwrap();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.server.is_finished()
+    }
+
+    /// Whether the listener is still there to take the new connections.
+    pub fn is_listening(&self) -> bool {
+        !self.connections.is_closed()
+    }
+
+    fn open(&mut self, nickname: &str, handshake: Option<oneshot::Receiver<()>>) -> Client {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        self.next_port += 1;
+        let connection = MemoryConnectingStream {
+            stream: server,
+            peer_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, self.next_port)),
+            handshake,
+        };
+        self.connections.send(connection).unwrap();
+
+        let (reader, writer) = tokio::io::split(client);
+        Client {
+            nickname: nickname.to_string(),
+            reader: BufReader::new(reader),
+            writer,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub async fn connect(&mut self, nickname: &str, capabilities: &[&str]) -> Client {
+        let mut client = self.open(nickname, None);
+        client.register(capabilities).await;
+        client
+    }
+
+    /// Opens a connection whose handshake is completed by the returned sender, to be registered
+    /// afterwards.
+    pub fn connect_with_handshake(&mut self, nickname: &str) -> (oneshot::Sender<()>, Client) {
+        let (complete, handshake) = oneshot::channel();
+        (complete, self.open(nickname, Some(handshake)))
+    }
+}
+
+pub struct Client {
+    pub nickname: String,
+    reader: BufReader<ReadHalf<DuplexStream>>,
+    writer: WriteHalf<DuplexStream>,
+    /// lines read while waiting for a labeled response, returned by the next reads
+    pending: VecDeque<String>,
+}
+
+impl Client {
+    pub async fn register(&mut self, capabilities: &[&str]) {
+        for capability in capabilities {
+            self.send(&format!("CAP REQ :{capability}")).await;
+        }
+        let nickname = self.nickname.clone();
+        self.send(&format!("NICK {nickname}")).await;
+        self.send(&format!("USER {nickname} 0 * :{nickname}")).await;
+        if !capabilities.is_empty() {
+            self.send("CAP END").await;
+        }
+        // end of the MOTD, or no MOTD
+        self.read_until(|line| line.contains(" 422 ")).await;
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        self.writer
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await
+            .unwrap();
+    }
+
+    pub async fn next_line(&mut self) -> String {
+        if let Some(line) = self.pending.pop_front() {
+            return line;
+        }
+        self.read_line().await
+    }
+
+    async fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        let read = tokio::time::timeout(READ_TIMEOUT, self.reader.read_line(&mut line));
+        let n = read.await.expect("no message received in time").unwrap();
+        assert!(n > 0, "connection of {} closed", self.nickname);
+        line.trim_end().to_string()
+    }
+
+    /// Returns the lines received until the one matching the predicate, included.
+    pub async fn read_until(&mut self, predicate: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut lines = vec![];
+        loop {
+            let line = self.next_line().await;
+            let is_last = predicate(&line);
+            lines.push(line);
+            if is_last {
+                return lines;
+            }
+        }
+    }
+
+    /// Makes sure that the previous commands were handled.
+    pub async fn sync(&mut self, token: &str) {
+        self.send(&format!("PING {token}")).await;
+        self.read_until(|line| line.ends_with(token)).await;
+    }
+
+    /// Sends the command with a label, to be awaited with `labeled_response`.
+    /// The client has to request the labeled-response and batch capabilities.
+    pub async fn send_labeled(&mut self, label: &str, line: &str) {
+        self.send(&format!("@label={label} {line}")).await;
+    }
+
+    /// Returns the replies to the command sent with the label, without the BATCH framing (of the
+    /// nested batches as well) nor the tags. The response is empty if the server only
+    /// acknowledged the command. The other lines received meanwhile are kept for the next reads.
+    pub async fn labeled_response(&mut self, label: &str) -> Vec<String> {
+        let label_tag = format!("label={label}");
+        // the response may be among the lines already read
+        let mut unread = std::mem::take(&mut self.pending);
+        let mut skipped = VecDeque::new();
+        let first = loop {
+            let line = self.next_unread_line(&mut unread).await;
+            if tags(&line).any(|tag| tag == label_tag) {
+                break line;
+            }
+            skipped.push_back(line);
+        };
+
+        let first_message = strip_tags(&first);
+        let batch_id = first_message
+            .split_once(" BATCH +")
+            .and_then(|(_, rest)| rest.strip_suffix(" labeled-response"))
+            .map(str::to_string);
+        let response = match batch_id {
+            None if first_message.ends_with(" ACK") => vec![],
+            None => vec![first_message.to_string()],
+            Some(batch_id) => {
+                let batch_end = format!(" BATCH -{batch_id}");
+                // the labeled-response batch and the batches nested in it
+                let mut batch_tags = vec![format!("batch={batch_id}")];
+                let mut response = vec![];
+                loop {
+                    let line = self.next_unread_line(&mut unread).await;
+                    if line.ends_with(&batch_end) {
+                        break;
+                    }
+                    if !tags(&line).any(|tag| batch_tags.iter().any(|t| t == tag)) {
+                        skipped.push_back(line);
+                        continue;
+                    }
+                    let message = strip_tags(&line);
+                    match message.split_once(" BATCH ") {
+                        Some((_, batch)) => {
+                            if let Some(nested) = batch.strip_prefix('+') {
+                                let nested_id = nested.split(' ').next().unwrap_or_default();
+                                batch_tags.push(format!("batch={nested_id}"));
+                            }
+                        }
+                        None => response.push(message.to_string()),
+                    }
+                }
+                response
+            }
+        };
+        skipped.append(&mut unread);
+        self.pending = skipped;
+        response
+    }
+
+    async fn next_unread_line(&mut self, unread: &mut VecDeque<String>) -> String {
+        match unread.pop_front() {
+            Some(line) => line,
+            None => self.read_line().await,
+        }
+    }
+}
+
+fn tags(line: &str) -> impl Iterator<Item = &str> {
+    line.strip_prefix('@')
+        .and_then(|tagged| tagged.split_once(' '))
+        .map_or("", |(tags, _)| tags)
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+}
+
+pub fn strip_tags(line: &str) -> &str {
+    match line.strip_prefix('@') {
+        Some(tagged) => tagged.split_once(' ').map_or("", |(_, rest)| rest),
+        None => line,
+    }
+}
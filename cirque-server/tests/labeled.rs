@@ -0,0 +1,94 @@
+#![allow(
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::indexing_slicing
+)] // fine in tests
+
+// Labeled responses (labeled-response capability), checked end-to-end through run_server: the
+// replies to a labeled command are found among the messages sent concurrently by other clients.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{strip_tags, TestServer};
+
+const LABELED: &[&str] = &["batch", "labeled-response"];
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_labeled_responses_among_other_messages() {
+    let mut server = TestServer::start(Some(Duration::from_millis(5))).await;
+    let mut alice = server.connect("alice", LABELED).await;
+    alice.send("JOIN #chan").await;
+    alice.sync("joined").await;
+
+    // other clients joining, talking and leaving while alice sends her commands
+    let mut tasks = tokio::task::JoinSet::new();
+    for n in 0..4 {
+        let mut client = server.connect(&format!("user{n}"), &[]).await;
+        tasks.spawn(async move {
+            for i in 0..10 {
+                client.send("JOIN #chan").await;
+                client.send(&format!("PRIVMSG #chan :message {i}")).await;
+                client.send("PART #chan").await;
+            }
+            client.sync("done").await;
+        });
+    }
+
+    for i in 0..10 {
+        let label = format!("who{i}");
+        alice.send_labeled(&label, "WHO #chan").await;
+        let response = alice.labeled_response(&label).await;
+        let end = response.last().unwrap();
+        assert!(end.starts_with(":srv 315 alice #chan "), "{response:?}");
+        assert!(response
+            .iter()
+            .any(|line| line.starts_with(":srv 352 alice #chan alice ")));
+        assert!(response[..response.len() - 1]
+            .iter()
+            .all(|line| line.starts_with(":srv 352 alice #chan ")));
+
+        let label = format!("list{i}");
+        alice.send_labeled(&label, "LIST").await;
+        let response = alice.labeled_response(&label).await;
+        assert!(response
+            .iter()
+            .any(|line| line.starts_with(":srv 322 alice #chan ")));
+        assert!(response.last().unwrap().starts_with(":srv 323 alice "));
+    }
+    tasks.join_all().await;
+
+    // the other messages are still delivered, in order
+    let lines = alice
+        .read_until(|line| strip_tags(line) == ":user0!user0@hidden PRIVMSG #chan :message 9")
+        .await;
+    let messages = lines
+        .iter()
+        .map(|line| strip_tags(line))
+        .filter(|line| line.starts_with(":user0!") && line.contains(" PRIVMSG "))
+        .collect::<Vec<_>>();
+    let expected = (0..10)
+        .map(|i| format!(":user0!user0@hidden PRIVMSG #chan :message {i}"))
+        .collect::<Vec<_>>();
+    assert_eq!(messages, expected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_labeled_responses_in_any_order() {
+    let mut server = TestServer::start(None).await;
+    let mut alice = server.connect("alice", LABELED).await;
+
+    // the responses can be awaited in another order than the commands
+    alice.send_labeled("ping", "PING token").await;
+    alice.send_labeled("pong", "PONG srv").await;
+    alice.send_labeled("join", "JOIN #chan").await;
+    let join = alice.labeled_response("join").await;
+    assert_eq!(join[0], ":alice!alice@hidden JOIN #chan");
+    assert_eq!(alice.labeled_response("pong").await, Vec::<String>::new());
+    assert_eq!(
+        alice.labeled_response("ping").await,
+        vec![":srv PONG srv :token"]
+    );
+}
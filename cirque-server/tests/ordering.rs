@@ -0,0 +1,118 @@
+#![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // fine in tests
+
+// Ordering guarantees of the delivery, checked end-to-end through run_server on the in-memory
+// transport:
+// - the messages caused by a client reach each recipient in the order of its commands, whatever
+//   the coalescing of the membership changes;
+// - the membership changes (JOIN, PART, QUIT), which modify the state, are seen in the same order
+//   by all the recipients.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{strip_tags, Client, TestServer};
+
+/// Keeps the lines sent by the nickname, without their tags and source, and without the BATCH
+/// framing.
+fn lines_from(lines: &[String], nickname: &str) -> Vec<String> {
+    let source = format!(":{nickname}!");
+    lines
+        .iter()
+        .map(|line| strip_tags(line))
+        .filter(|line| line.starts_with(&source))
+        .map(|line| {
+            line.split_once(' ')
+                .map_or("", |(_, rest)| rest)
+                .to_string()
+        })
+        .collect()
+}
+
+async fn membership_changes_of_users(observer: &mut Client, count: usize) -> Vec<String> {
+    let mut membership_changes = vec![];
+    while membership_changes.len() < count {
+        let line = observer.next_line().await;
+        let line = strip_tags(&line);
+        if line.starts_with(":user") {
+            membership_changes.push(line.to_string());
+        }
+    }
+    membership_changes
+}
+
+async fn check_own_messages_order(membership_coalescing: Option<Duration>) {
+    let mut server = TestServer::start(membership_coalescing).await;
+    let mut bob = server.connect("bob", &[]).await;
+    let mut carol = server.connect("carol", &["batch"]).await;
+    let mut alice = server.connect("alice", &[]).await;
+    for observer in [&mut bob, &mut carol] {
+        observer.send("JOIN #chan").await;
+        observer.sync("joined").await;
+    }
+
+    // the commands are relayed as they are sent
+    let mut commands = vec![];
+    for i in 0..20 {
+        commands.push("JOIN #chan".to_string());
+        commands.push(format!("PRIVMSG #chan :message {i}"));
+        commands.push("PART #chan".to_string());
+    }
+    commands.push("JOIN #chan".to_string());
+    commands.push("PRIVMSG #chan :last".to_string());
+    commands.push("QUIT :bye".to_string());
+    for command in &commands {
+        alice.send(command).await;
+    }
+
+    for observer in [&mut bob, &mut carol] {
+        let lines = observer
+            .read_until(|line| strip_tags(line).starts_with(":alice!") && line.contains(" QUIT "))
+            .await;
+        assert_eq!(
+            lines_from(&lines, "alice"),
+            commands,
+            "as seen by {}",
+            observer.nickname
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_own_messages_keep_their_order() {
+    check_own_messages_order(None).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_own_messages_keep_their_order_when_coalesced() {
+    check_own_messages_order(Some(Duration::from_millis(20))).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_membership_changes_have_a_single_order() {
+    let mut server = TestServer::start(Some(Duration::from_millis(5))).await;
+    let mut bob = server.connect("bob", &[]).await;
+    let mut carol = server.connect("carol", &["batch"]).await;
+    for observer in [&mut bob, &mut carol] {
+        observer.send("JOIN #chan").await;
+        observer.sync("joined").await;
+    }
+
+    // clients joining and leaving concurrently, each from its own task
+    let mut tasks = tokio::task::JoinSet::new();
+    for n in 0..4 {
+        let mut client = server.connect(&format!("user{n}"), &[]).await;
+        tasks.spawn(async move {
+            for _ in 0..10 {
+                client.send("JOIN #chan").await;
+                client.send("PART #chan").await;
+            }
+            client.sync("done").await;
+        });
+    }
+    tasks.join_all().await;
+
+    let bob_sequence = membership_changes_of_users(&mut bob, 4 * 20).await;
+    let carol_sequence = membership_changes_of_users(&mut carol, 4 * 20).await;
+    assert_eq!(bob_sequence, carol_sequence);
+}
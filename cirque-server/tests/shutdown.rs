@@ -0,0 +1,49 @@
+#![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // fine in tests
+
+// Shutdown of run_server: the listener is released once the handshakes in progress are completed
+// or aborted, and the clients already connected are kept.
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestServer;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_shutdown_drains_the_handshakes() {
+    let mut server = TestServer::start(None).await;
+    let mut alice = server.connect("alice", &[]).await;
+    let (complete, mut bob) = server.connect_with_handshake("bob");
+    // let the server accept bob's connection before the shutdown
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    server.shutdown();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!server.is_stopped());
+    assert!(!server.is_listening());
+
+    complete.send(()).unwrap();
+    server.stopped().await;
+
+    // both sessions outlive the server
+    bob.register(&[]).await;
+    alice.send("PING token").await;
+    assert_eq!(alice.next_line().await, ":srv PONG srv :token");
+    bob.send("PING token").await;
+    assert_eq!(bob.next_line().await, ":srv PONG srv :token");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_shutdown_aborts_the_stuck_handshakes() {
+    let mut server = TestServer::start(None).await;
+    let (complete, _bob) = server.connect_with_handshake("bob");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    server.shutdown();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!server.is_stopped());
+
+    // the handshake never completes, the server stops after the grace period anyway
+    server.stopped().await;
+    assert!(complete.is_closed());
+}
@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::config::Config;
+
+const USAGE: &str = "Usage: cirque [--port PORT] [--address ADDRESS] [--server-name NAME] \
+                     [--log-level LEVEL] [--smoke-test] <config_path>";
+
+/// Settings given on the command line or through the environment, which take precedence over
+/// the config file (command line first, then environment, then config file).
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    port: Option<u16>,
+    address: Option<String>,
+    server_name: Option<String>,
+    log_level: Option<log::LevelFilter>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(address) = &self.address {
+            config.address.clone_from(address);
+        }
+        if let Some(server_name) = &self.server_name {
+            config.server_name.clone_from(server_name);
+        }
+        if let Some(log_level) = self.log_level {
+            config.logging.level = Some(log_level);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub config_path: PathBuf,
+    pub overrides: Overrides,
+    /// start the server, check that clients can talk to each other, and exit
+    pub smoke_test: bool,
+}
+
+impl Args {
+    pub fn parse(
+        mut args: impl Iterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut config_path = None;
+        let mut smoke_test = false;
+        let mut port = env("CIRQUE_PORT");
+        let mut address = env("CIRQUE_ADDRESS");
+        let mut server_name = env("CIRQUE_SERVER_NAME");
+        let mut log_level = env("CIRQUE_LOG_LEVEL");
+
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--port" => &mut port,
+                "--address" => &mut address,
+                "--server-name" => &mut server_name,
+                "--log-level" => &mut log_level,
+                "--smoke-test" => {
+                    smoke_test = true;
+                    continue;
+                }
+                flag if flag.starts_with("--") => anyhow::bail!("unknown flag {flag}. {USAGE}"),
+                _ if config_path.is_none() => {
+                    config_path = Some(PathBuf::from(arg));
+                    continue;
+                }
+                _ => anyhow::bail!("unexpected argument {arg}. {USAGE}"),
+            };
+            *value = Some(
+                args.next()
+                    .with_context(|| format!("missing value for {arg}. {USAGE}"))?,
+            );
+        }
+
+        let Some(config_path) = config_path else {
+            anyhow::bail!("missing <config_path> parameter. {USAGE}");
+        };
+        let overrides = Overrides {
+            port: port
+                .map(|p| p.parse().with_context(|| format!("invalid port {p:?}")))
+                .transpose()?,
+            address,
+            server_name,
+            log_level: log_level
+                .map(|l| {
+                    l.parse()
+                        .with_context(|| format!("invalid log level {l:?}"))
+                })
+                .transpose()?,
+        };
+        Ok(Self {
+            config_path,
+            overrides,
+            smoke_test,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)] // fine in tests
+    use super::*;
+
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> anyhow::Result<Args> {
+        Args::parse(args.iter().map(|a| a.to_string()), |name| {
+            env.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    #[test]
+    fn test_overrides_precedence() {
+        let args = parse(
+            &["--port", "7000", "config.yml", "--log-level", "debug"],
+            &[("CIRQUE_PORT", "6000"), ("CIRQUE_SERVER_NAME", "env.srv")],
+        )
+        .unwrap();
+        assert_eq!(args.config_path, PathBuf::from("config.yml"));
+        assert_eq!(args.overrides.port, Some(7000));
+        assert_eq!(args.overrides.server_name.as_deref(), Some("env.srv"));
+        assert_eq!(args.overrides.address, None);
+        assert_eq!(args.overrides.log_level, Some(log::LevelFilter::Debug));
+        assert!(!args.smoke_test);
+        assert!(
+            parse(&["--smoke-test", "config.yml"], &[])
+                .unwrap()
+                .smoke_test
+        );
+
+        assert!(parse(&[], &[]).is_err());
+        assert!(parse(&["config.yml", "--port"], &[]).is_err());
+        assert!(parse(&["config.yml", "--verbose"], &[]).is_err());
+        assert!(parse(&["config.yml"], &[("CIRQUE_PORT", "http")]).is_err());
+    }
+}
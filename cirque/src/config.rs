@@ -1,19 +1,28 @@
 use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    num::NonZeroU32,
     path::{Path, PathBuf},
     time::Duration,
 };
 
 use anyhow::Context;
 use serde::Deserialize;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
 use cirque_core::ChannelMode;
 
+#[serde_with::serde_as]
 #[derive(Debug, Deserialize)]
 pub struct TlsConfig {
     #[serde(rename = "cert")]
     pub cert_file_path: PathBuf,
     #[serde(rename = "key")]
     pub private_key_file_path: PathBuf,
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub handshake_timeout: Option<Duration>,
 }
 
 #[serde_with::serde_as]
@@ -23,29 +32,395 @@ struct TimeoutConfig {
     pub base: Duration,
     #[serde_as(as = "serde_with::DurationSeconds<u64>")]
     pub reduced: Duration,
+    #[serde(default)]
+    pub reduced_pings: Option<u8>,
+}
+
+impl TlsConfig {
+    pub fn load_certificate(
+        &self,
+    ) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let certs = {
+            let mut file = File::open(&self.cert_file_path).with_context(|| {
+                format!("cannot open certificate file {:?}", &self.cert_file_path)
+            })?;
+            rustls_pemfile::certs(&mut BufReader::new(&mut file)).collect::<Result<Vec<_>, _>>()?
+        };
+
+        let private_key = {
+            let mut file = File::open(&self.private_key_file_path).with_context(|| {
+                format!(
+                    "cannot open private key file {:?}",
+                    &self.private_key_file_path
+                )
+            })?;
+            rustls_pemfile::private_key(&mut BufReader::new(&mut file))?
+                .ok_or_else(|| anyhow::anyhow!("cannot load private key"))?
+        };
+
+        Ok((certs, private_key))
+    }
 }
 
 impl From<&TimeoutConfig> for cirque_core::TimeoutConfig {
     fn from(val: &TimeoutConfig) -> Self {
+        let default = cirque_core::TimeoutConfig::default();
         cirque_core::TimeoutConfig {
             base_timeout: val.base,
             reduced_timeout: val.reduced,
+            reduced_pings: val.reduced_pings.unwrap_or(default.reduced_pings),
+        }
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Deserialize)]
+struct LookupsConfig {
+    #[serde(default)]
+    pub hostname: bool,
+    #[serde(default)]
+    pub ident: bool,
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Deserialize)]
+struct StsConfig {
+    pub port: u16,
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub duration: Duration,
+}
+
+impl From<&StsConfig> for cirque_core::StsPolicy {
+    fn from(val: &StsConfig) -> Self {
+        cirque_core::StsPolicy {
+            port: val.port,
+            duration: val.duration,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CommandClass {
+    Message,
+    Channel,
+    Query,
+    Other,
+}
+
+impl From<CommandClass> for cirque_core::CommandClass {
+    fn from(val: CommandClass) -> Self {
+        match val {
+            CommandClass::Message => cirque_core::CommandClass::Message,
+            CommandClass::Channel => cirque_core::CommandClass::Channel,
+            CommandClass::Query => cirque_core::CommandClass::Query,
+            CommandClass::Other => cirque_core::CommandClass::Other,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ThrottleAction {
+    #[default]
+    Delay,
+    Disconnect,
+}
+
+#[derive(Debug, Deserialize)]
+struct ThrottlingConfig {
+    rate: NonZeroU32,
+    burst: Option<NonZeroU32>,
+    #[serde(default)]
+    penalties: HashMap<CommandClass, u32>,
+    #[serde(default)]
+    action: ThrottleAction,
+}
+
+impl From<&ThrottlingConfig> for cirque_core::ThrottleConfig {
+    fn from(val: &ThrottlingConfig) -> Self {
+        cirque_core::ThrottleConfig {
+            rate: val.rate.get(),
+            burst: val.burst.map_or(1, NonZeroU32::get),
+            penalties: val
+                .penalties
+                .iter()
+                .map(|(&class, &penalty)| (class.into(), penalty))
+                .collect(),
+            action: match val.action {
+                ThrottleAction::Delay => cirque_core::ThrottleAction::Delay,
+                ThrottleAction::Disconnect => cirque_core::ThrottleAction::Disconnect,
+            },
         }
     }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ContentFilterAction {
+    Block,
+    Censor,
+    Flag,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentFilterConfig {
+    #[serde(default)]
+    words: Vec<String>,
+    #[serde(default)]
+    regexes: Vec<String>,
+    action: ContentFilterAction,
+    channels: Option<Vec<String>>,
+}
+
+impl TryFrom<&ContentFilterConfig> for cirque_core::ContentFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(val: &ContentFilterConfig) -> Result<Self, Self::Error> {
+        let action = match val.action {
+            ContentFilterAction::Block => cirque_core::ContentFilterAction::Block,
+            ContentFilterAction::Censor => cirque_core::ContentFilterAction::Censor,
+            ContentFilterAction::Flag => cirque_core::ContentFilterAction::Flag,
+        };
+        let mut filter = cirque_core::ContentFilter::new(action);
+        for word in &val.words {
+            filter = filter.with_word(word)?;
+        }
+        for regex in &val.regexes {
+            filter = filter
+                .with_regex(regex)
+                .with_context(|| format!("invalid content filter regex {regex:?}"))?;
+        }
+        if let Some(channels) = &val.channels {
+            filter = filter.for_channels(channels.clone());
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProfile {
+    #[default]
+    Default,
+    Tor,
+}
+
+impl From<&ListenerProfile> for cirque_server::ListenerProfile {
+    fn from(val: &ListenerProfile) -> Self {
+        match val {
+            ListenerProfile::Default => cirque_server::ListenerProfile::Default,
+            ListenerProfile::Tor => cirque_server::ListenerProfile::Tor,
+        }
+    }
+}
+
+/// Additional listener, next to the main one configured by `address`, `port` and `tls`.
+#[serde_with::serde_as]
+#[derive(Debug, Deserialize)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    /// uses the certificate of the main listener
+    #[serde(default)]
+    pub tls: bool,
+    pub motd: Option<String>,
+    #[serde(default)]
+    pub profile: ListenerProfile,
+    /// the users have to become operators within this delay, otherwise they are disconnected
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub oper_deadline: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelModeRuleConfig {
+    channels: String,
+    #[serde(deserialize_with = "deserialize_channel_mode")]
+    mode: ChannelMode,
+}
+
+#[derive(Debug, Deserialize)]
+struct RelatedServerConfig {
+    name: String,
+    description: String,
+    address: String,
+}
+
+fn default_kept_log_files() -> usize {
+    5
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Deserialize)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// in bytes
+    pub max_size: Option<u64>,
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub rotate_every: Option<Duration>,
+    /// number of rotated files to keep next to the current one
+    #[serde(default = "default_kept_log_files")]
+    pub keep: usize,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Default, Deserialize)]
+pub struct LoggingConfig {
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub level: Option<log::LevelFilter>,
+    /// levels for specific modules, overriding `level`
+    #[serde_as(as = "BTreeMap<_, serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub modules: BTreeMap<String, log::LevelFilter>,
+    /// logs to stderr if not set
+    pub file: Option<LogFileConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OperConfig {
+    /// name expected by OPER, any name is accepted when not set
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_secret")]
+    pub password: Option<String>,
+    /// masks matched against "username@ip", OPER is allowed from anywhere when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+/// See `cirque_core::HostnamePolicy`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "policy")]
+enum HostnamesConfig {
+    #[default]
+    Hidden,
+    Cloaked {
+        #[serde(deserialize_with = "deserialize_secret")]
+        key: Option<String>,
+    },
+    Resolved,
+    Ip,
+}
+
+/// See `cirque_core::DccPolicy`.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DccConfig {
+    #[default]
+    Allow,
+    CoMembers,
+    Strip,
+}
+
+/// Overrides of the default `cirque_core::CompatFlags`.
+#[derive(Debug, Default, Deserialize)]
+struct CompatConfig {
+    list_start: Option<bool>,
+    topic_who_time: Option<bool>,
+    extended_lusers: Option<bool>,
+    unsupported_commands: Option<bool>,
+}
+
+/// Overrides of the default templates, see `cirque_core::Templates`.
+#[derive(Debug, Default, Deserialize)]
+struct TemplatesConfig {
+    welcome: Option<String>,
+    your_host: Option<String>,
+    created: Option<String>,
+    my_info: Option<String>,
+    closing_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminConfig {
+    location: String,
+    email: String,
+}
+
+/// Translations of the texts generated by the server, keyed by their English templates.
+#[derive(Debug, Deserialize)]
+struct CatalogFile {
+    language: String,
+    translations: HashMap<String, String>,
+}
+
+#[serde_with::serde_as]
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server_name: String,
+    pub network_name: Option<String>,
+    #[serde(default)]
+    templates: TemplatesConfig,
+    #[serde(default)]
+    compat: CompatConfig,
+    #[serde(default)]
+    hostnames: HostnamesConfig,
+    #[serde(default, deserialize_with = "deserialize_secret")]
     pub password: Option<String>,
+    pub oper: Option<OperConfig>,
     pub motd: Option<String>,
+    pub tls_motd: Option<String>,
+    /// path to a text file, read again when the config is reloaded
+    #[serde(rename = "rules_file")]
+    pub rules_file_path: Option<PathBuf>,
+    /// path to a YAML catalog of translations, read again when the config is reloaded
+    #[serde(rename = "catalog_file")]
+    pub catalog_file_path: Option<PathBuf>,
+    admin: Option<AdminConfig>,
+    #[serde(default)]
+    related_servers: Vec<RelatedServerConfig>,
+    #[serde(default)]
+    pub reserved_nicknames: Vec<String>,
     pub port: u16,
     pub address: String,
     #[serde(rename = "tls")]
     pub tls_config: Option<TlsConfig>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
     #[serde(deserialize_with = "deserialize_channel_mode")]
     pub default_channel_mode: ChannelMode,
+    #[serde(default)]
+    channel_modes: Vec<ChannelModeRuleConfig>,
     timeout: Option<TimeoutConfig>,
+    lookups: Option<LookupsConfig>,
+    sts: Option<StsConfig>,
+    #[serde(default)]
+    content_filters: Vec<ContentFilterConfig>,
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub membership_coalescing: Option<Duration>,
+    #[serde(default)]
+    pub utf8_only: bool,
+    #[serde(default)]
+    dcc: DccConfig,
+    pub max_registering_users: Option<usize>,
+    pub max_registering_users_per_ip: Option<usize>,
+    #[serde(default)]
+    exempt_ips: Vec<String>,
+    pub max_unregistered_bytes: Option<u64>,
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub partial_message_deadline: Option<Duration>,
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub registration_deadline: Option<Duration>,
+    #[serde_as(as = "Option<serde_with::DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub nickname_grace_period: Option<Duration>,
+    pub max_channels_per_user: Option<usize>,
+    #[serde_as(as = "Option<serde_with::DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub slow_command_threshold: Option<Duration>,
+    throttling: Option<ThrottlingConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
 fn deserialize_channel_mode<'de, D>(value: D) -> Result<ChannelMode, D::Error>
@@ -72,16 +447,201 @@ where
     value.deserialize_str(Visitor)
 }
 
-impl Config {
-    pub fn load_from_str(str: &str) -> Result<Self, anyhow::Error> {
-        let config: Config = serde_yml::from_str(str)?;
-        Ok(config)
+/// Reads a value from an environment variable (`env:NAME`) or from a file (`file:PATH`, without
+/// its trailing newline), so that it does not have to be written in the config. Other values are
+/// returned as is.
+fn resolve_secret(value: &str) -> anyhow::Result<String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).with_context(|| format!("reading environment variable {name}"))
+    } else if let Some(path) = value.strip_prefix("file:") {
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("reading secret file {path:?}"))?;
+        Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+    } else {
+        Ok(value.to_string())
     }
+}
 
+fn deserialize_secret<'de, D>(value: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(value)?;
+    value
+        .map(|v| resolve_secret(&v).map_err(|err| serde::de::Error::custom(format!("{err:#}"))))
+        .transpose()
+}
+
+/// Merges the top-level keys of the files listed in `include` (relative to `base_dir`) into the
+/// config. A key cannot be defined twice.
+fn merge_included_files(config: &mut serde_yml::Mapping, base_dir: &Path) -> anyhow::Result<()> {
+    let Some(includes) = config.remove("include") else {
+        return Ok(());
+    };
+    let includes: Vec<PathBuf> =
+        serde_yml::from_value(includes).context("include should be a list of paths")?;
+
+    for include in includes {
+        let path = base_dir.join(include);
+        let string = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading included config file {path:?}"))?;
+        let included: serde_yml::Mapping = serde_yml::from_str(&string)
+            .with_context(|| format!("parsing included config file {path:?}"))?;
+        for (key, value) in included {
+            if config.contains_key(&key) {
+                anyhow::bail!("{key:?} of {path:?} is already defined");
+            }
+            config.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Config as written, with the included files merged. The last applied one is kept to fall back
+/// to its sections when a reloaded config is partially invalid.
+#[derive(Debug, Clone)]
+pub struct RawConfig(serde_yml::Mapping);
+
+impl RawConfig {
+    /// The included files are relative to `base_dir`.
+    pub fn load_from_str(str: &str, base_dir: &Path) -> Result<Self, anyhow::Error> {
+        let mut config: serde_yml::Mapping = serde_yml::from_str(str)?;
+        merge_included_files(&mut config, base_dir)?;
+        Ok(Self(config))
+    }
+
+    /// The included files are relative to the directory of the config file.
     pub fn load_from_path(path: &Path) -> Result<Self, anyhow::Error> {
         let string = std::fs::read_to_string(path)
             .with_context(|| format!("reading config file {path:?}"))?;
-        Config::load_from_str(string.as_str())
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        Self::load_from_str(string.as_str(), base_dir)
+    }
+}
+
+/// Top-level sections which are only read at startup, such as the logger installed once.
+const RESTART_SECTIONS: &[&str] = &["logging"];
+
+/// Top-level section of a reloaded config which could not be applied, the previous one is kept.
+#[derive(Debug)]
+pub struct SectionError {
+    pub section: String,
+    pub error: anyhow::Error,
+}
+
+impl Config {
+    /// The included files are relative to `base_dir`.
+    #[cfg(test)]
+    pub fn load_from_str(str: &str, base_dir: &Path) -> Result<Self, anyhow::Error> {
+        Self::from_raw(&RawConfig::load_from_str(str, base_dir)?)
+    }
+
+    /// The config is deserialized from its text, so that the errors tell the path of the faulty
+    /// value (such as `throttling.rate`). Their location is dropped, as it is the one in the merged
+    /// config rather than in the files.
+    pub fn from_raw(raw: &RawConfig) -> Result<Self, anyhow::Error> {
+        let text = serde_yml::to_string(&raw.0)?;
+        serde_yml::from_str(&text).map_err(|err| {
+            let message = err.to_string();
+            let message = match err.location() {
+                Some(_) => message
+                    .rsplit_once(" at line ")
+                    .map_or(message.as_str(), |(message, _)| message),
+                None => &message,
+            };
+            anyhow::anyhow!("{message}")
+        })
+    }
+
+    /// Loads the config and checks the values which are only used when the server is launched.
+    fn from_raw_checked(raw: &RawConfig) -> Result<Self, anyhow::Error> {
+        let config = Self::from_raw(raw)?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Loads the reloaded config, taking from the previous one the top-level sections which are
+    /// not valid, such that a mistake in a section does not prevent the others from being
+    /// applied. Also returns the config as applied, to be the previous one of the next reload.
+    /// The sections read only at startup keep their previous value, and are reported if changed.
+    pub fn reload(
+        raw: &RawConfig,
+        previous: &RawConfig,
+    ) -> anyhow::Result<(Self, RawConfig, Vec<SectionError>)> {
+        let mut raw = raw.clone();
+        let mut errors = vec![];
+        for section in RESTART_SECTIONS {
+            let key = serde_yml::Value::from(*section);
+            let previous_value = previous.0.get(&key);
+            if raw.0.get(&key) == previous_value {
+                continue;
+            }
+            match previous_value {
+                Some(value) => raw.0.insert(key, value.clone()),
+                None => raw.0.remove(&key),
+            };
+            errors.push(SectionError {
+                section: section.to_string(),
+                error: anyhow::anyhow!("changed, but only applied on restart"),
+            });
+        }
+
+        if let Ok(config) = Self::from_raw_checked(&raw) {
+            return Ok((config, raw, errors));
+        }
+
+        let mut sections = raw.0.keys().cloned().collect::<Vec<_>>();
+        sections.extend(
+            previous
+                .0
+                .keys()
+                .filter(|key| !raw.0.contains_key(key))
+                .cloned(),
+        );
+
+        // each section is tried alone with the previous config, to know which one is faulty
+        let mut applied = previous.clone();
+        for section in sections {
+            let value = raw.0.get(&section);
+            let with_section = |mut config: RawConfig| {
+                match value {
+                    Some(value) => config.0.insert(section.clone(), value.clone()),
+                    None => config.0.remove(&section),
+                };
+                config
+            };
+            if let Err(error) = Self::from_raw_checked(&with_section(previous.clone())) {
+                let section = match section {
+                    serde_yml::Value::String(section) => section,
+                    section => format!("{section:?}"),
+                };
+                errors.push(SectionError { section, error });
+                continue;
+            }
+            applied = with_section(applied);
+        }
+
+        let config = Self::from_raw_checked(&applied)
+            .context("the valid sections are not consistent with each other")?;
+        Ok((config, applied, errors))
+    }
+
+    /// Checks what is not checked when the config is loaded, with the top-level section at fault.
+    fn check(&self) -> anyhow::Result<()> {
+        self.hostname_policy().context("hostnames")?;
+        self.exempt_ips()?;
+        self.content_filters().context("content_filters")?;
+        self.catalog().context("catalog_file")?;
+        self.rules().context("rules_file")?;
+        if let Some(tls_config) = &self.tls_config {
+            tls_config.load_certificate().context("tls")?;
+        } else if let Some(listener) = self.listeners.iter().find(|l| l.tls) {
+            anyhow::bail!(
+                "listeners: listener {:?} uses TLS but no certificate is configured",
+                listener.name
+            );
+        }
+        Ok(())
     }
 }
 
@@ -91,26 +651,587 @@ impl Config {
             .as_ref()
             .map(|tc| -> cirque_core::TimeoutConfig { tc.into() })
     }
+
+    pub fn lookup_config(&self) -> cirque_core::LookupConfig {
+        let default = cirque_core::LookupConfig::default();
+        self.lookups
+            .as_ref()
+            .map(|lookups| cirque_core::LookupConfig {
+                resolve_hostname: lookups.hostname,
+                check_ident: lookups.ident,
+                timeout: lookups.timeout.unwrap_or(default.timeout),
+            })
+            .unwrap_or(default)
+    }
+
+    pub fn unregistered_limits(&self) -> cirque_core::UnregisteredLimits {
+        cirque_core::UnregisteredLimits {
+            max_bytes: self.max_unregistered_bytes,
+            partial_message_deadline: self.partial_message_deadline,
+            registration_deadline: self.registration_deadline,
+        }
+    }
+
+    pub fn throttle_config(&self) -> cirque_core::ThrottleConfig {
+        self.throttling
+            .as_ref()
+            .map(|throttling| throttling.into())
+            .unwrap_or_default()
+    }
+
+    pub fn sts_policy(&self) -> Option<cirque_core::StsPolicy> {
+        self.sts.as_ref().map(|sts| sts.into())
+    }
+
+    pub fn templates(&self) -> cirque_core::Templates {
+        let defaults = cirque_core::Templates::default();
+        let or_default =
+            |template: &Option<String>, default: String| template.clone().unwrap_or(default);
+        cirque_core::Templates {
+            welcome: or_default(&self.templates.welcome, defaults.welcome),
+            your_host: or_default(&self.templates.your_host, defaults.your_host),
+            created: or_default(&self.templates.created, defaults.created),
+            my_info: or_default(&self.templates.my_info, defaults.my_info),
+            closing_link: or_default(&self.templates.closing_link, defaults.closing_link),
+        }
+    }
+
+    pub fn hostname_policy(&self) -> anyhow::Result<cirque_core::HostnamePolicy> {
+        use cirque_core::HostnamePolicy;
+        Ok(match &self.hostnames {
+            HostnamesConfig::Hidden => HostnamePolicy::Hidden,
+            HostnamesConfig::Cloaked { key } => HostnamePolicy::Cloaked {
+                key: key
+                    .clone()
+                    .filter(|key| !key.is_empty())
+                    .context("the cloaked hostnames require a key")?,
+            },
+            HostnamesConfig::Resolved => HostnamePolicy::Resolved,
+            HostnamesConfig::Ip => HostnamePolicy::Ip,
+        })
+    }
+
+    pub fn exempt_ips(&self) -> anyhow::Result<Vec<cirque_core::IpMask>> {
+        self.exempt_ips
+            .iter()
+            .map(|mask| mask.parse().map_err(anyhow::Error::msg))
+            .collect::<anyhow::Result<_>>()
+            .context("invalid exempt_ips")
+    }
+
+    pub fn compat_flags(&self) -> cirque_core::CompatFlags {
+        let defaults = cirque_core::CompatFlags::default();
+        cirque_core::CompatFlags {
+            list_start: self.compat.list_start.unwrap_or(defaults.list_start),
+            topic_who_time: self
+                .compat
+                .topic_who_time
+                .unwrap_or(defaults.topic_who_time),
+            extended_lusers: self
+                .compat
+                .extended_lusers
+                .unwrap_or(defaults.extended_lusers),
+            unsupported_commands: self
+                .compat
+                .unsupported_commands
+                .unwrap_or(defaults.unsupported_commands),
+        }
+    }
+
+    pub fn dcc_policy(&self) -> cirque_core::DccPolicy {
+        match self.dcc {
+            DccConfig::Allow => cirque_core::DccPolicy::Allow,
+            DccConfig::CoMembers => cirque_core::DccPolicy::CoMembers,
+            DccConfig::Strip => cirque_core::DccPolicy::Strip,
+        }
+    }
+
+    pub fn admin_info(&self) -> Option<cirque_core::AdminInfo> {
+        self.admin.as_ref().map(|admin| cirque_core::AdminInfo {
+            location: admin.location.clone(),
+            email: admin.email.clone(),
+        })
+    }
+
+    pub fn related_servers(&self) -> Vec<cirque_core::RelatedServer> {
+        self.related_servers
+            .iter()
+            .map(|server| cirque_core::RelatedServer {
+                name: server.name.clone(),
+                description: server.description.clone(),
+                address: server.address.clone(),
+            })
+            .collect()
+    }
+
+    pub fn channel_mode_rules(&self) -> Vec<cirque_core::ChannelModeRule> {
+        self.channel_modes
+            .iter()
+            .map(|rule| cirque_core::ChannelModeRule {
+                mask: rule.channels.clone(),
+                mode: rule.mode.clone(),
+            })
+            .collect()
+    }
+
+    pub fn content_filters(&self) -> anyhow::Result<Vec<cirque_core::ContentFilter>> {
+        self.content_filters.iter().map(|f| f.try_into()).collect()
+    }
+
+    pub fn rules(&self) -> anyhow::Result<Option<String>> {
+        self.rules_file_path
+            .as_ref()
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("reading rules file {path:?}"))
+            })
+            .transpose()
+    }
+
+    pub fn catalog(&self) -> anyhow::Result<Option<cirque_core::Catalog>> {
+        let Some(path) = &self.catalog_file_path else {
+            return Ok(None);
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading catalog file {path:?}"))?;
+        let catalog: CatalogFile = serde_yml::from_str(&content)
+            .with_context(|| format!("parsing catalog file {path:?}"))?;
+        Ok(Some(cirque_core::Catalog::new(
+            &catalog.language,
+            catalog.translations,
+        )))
+    }
+
+    /// Describes what is in effect, logged on startup and reload so that the operators can check
+    /// that a reload was applied.
+    pub fn summary(&self) -> Vec<String> {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        let limit = |l: Option<usize>| l.map_or("unlimited".to_string(), |l| l.to_string());
+        let throttle_config = self.throttle_config();
+
+        let mut lines = vec![format!(
+            "listener (main): {}:{}, TLS {}",
+            self.address,
+            self.port,
+            on_off(self.tls_config.is_some())
+        )];
+        for listener in &self.listeners {
+            let oper_only = listener
+                .oper_deadline
+                .map(|deadline| format!(", OPER within {}s", deadline.as_secs()))
+                .unwrap_or_default();
+            lines.push(format!(
+                "listener {}: {}:{}, TLS {}, profile {:?}{oper_only}",
+                listener.name,
+                listener.address,
+                listener.port,
+                on_off(listener.tls),
+                listener.profile
+            ));
+        }
+        lines.push(format!(
+            "server name {}, password {}, UTF-8 only {}, STS {}, DCC {:?}",
+            self.server_name,
+            on_off(self.password.is_some()),
+            on_off(self.utf8_only),
+            on_off(self.sts.is_some()),
+            self.dcc,
+        ));
+        lines.push(format!(
+            "limits: {} registering users ({} per IP), {} channels per user, \
+             {} commands per second (burst {}), timeout {}, {} content filters",
+            limit(self.max_registering_users),
+            limit(self.max_registering_users_per_ip),
+            limit(self.max_channels_per_user),
+            throttle_config.rate,
+            throttle_config.burst,
+            self.timeout_config()
+                .map_or("default".to_string(), |t| match t.reduced_pings {
+                    0 => format!("{}s (not reduced)", t.base_timeout.as_secs()),
+                    n => format!(
+                        "{}s ({}s reduced for {n} pings)",
+                        t.base_timeout.as_secs(),
+                        t.reduced_timeout.as_secs()
+                    ),
+                }),
+            self.content_filters.len(),
+        ));
+        lines
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    #![allow(clippy::panic_in_result_fn)]
+    #![allow(clippy::panic_in_result_fn, clippy::indexing_slicing)]
 
     use std::{path::PathBuf, str::FromStr};
 
-    use crate::config::Config;
+    use crate::config::{Config, RawConfig};
 
     fn default_yaml_path() -> anyhow::Result<PathBuf> {
         let workspace_path = env!("CARGO_MANIFEST_DIR");
         Ok(PathBuf::from_str(workspace_path)?.join("../config.yml"))
     }
 
+    fn load_example() -> anyhow::Result<Config> {
+        Config::from_raw(&RawConfig::load_from_path(&default_yaml_path()?)?)
+    }
+
+    /// Config made of the required keys and of `yaml`, for the sections which are commented out
+    /// in the example config.
+    fn load_with(yaml: &str) -> anyhow::Result<Config> {
+        let base = "server_name: srv\nport: 6667\naddress: 127.0.0.1\ndefault_channel_mode: n\n";
+        Config::load_from_str(&format!("{base}{yaml}"), &std::env::temp_dir())
+    }
+
     #[test]
     fn load_valid_config_from_path() -> anyhow::Result<()> {
-        let config = Config::load_from_path(&default_yaml_path()?)?;
+        let config = load_example()?;
         assert!(config.tls_config.is_some());
+        let timeout_config = config.timeout_config().unwrap();
+        assert_eq!(timeout_config.reduced_pings, 10);
+
+        let summary = config.summary();
+        assert_eq!(summary.len(), 3);
+        assert_eq!(summary[0], "listener (main): [::]:6679, TLS on");
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_sts() -> anyhow::Result<()> {
+        assert!(load_example()?.sts_policy().is_none());
+        let sts = "sts:\n  port: 6679\n  duration: 2592000\n";
+        assert!(load_with(sts)?.sts_policy().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn load_content_filters() -> anyhow::Result<()> {
+        assert!(load_example()?.content_filters()?.is_empty());
+        let content_filters = "content_filters:\n  - words: [\"badword\"]\n    action: censor\n  \
+                               - regexes: [\"(?i)buy .* now\"]\n    action: block\n    \
+                               channels: [\"#general\"]\n";
+        assert_eq!(load_with(content_filters)?.content_filters()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn load_membership_coalescing() -> anyhow::Result<()> {
+        assert_eq!(load_example()?.membership_coalescing, None);
+        assert_eq!(
+            load_with("membership_coalescing: 5\n")?.membership_coalescing,
+            Some(std::time::Duration::from_millis(5))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_admin() -> anyhow::Result<()> {
+        assert!(load_example()?.admin_info().is_none());
+        let admin = "admin:\n  location: Somewhere\n  email: admin@example.com\n";
+        let admin_info = load_with(admin)?.admin_info().unwrap();
+        assert_eq!(admin_info.email, "admin@example.com");
+        Ok(())
+    }
+
+    #[test]
+    fn load_listeners() -> anyhow::Result<()> {
+        assert!(load_example()?.listeners.is_empty());
+        let listeners = "listeners:\n  - name: onion\n    address: 127.0.0.1\n    port: 6680\n    \
+                         profile: tor\n    motd: hello\n";
+        let config = load_with(listeners)?;
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(
+            config.summary()[1],
+            "listener onion: 127.0.0.1:6680, TLS off, profile Tor"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_reserved_nicknames() -> anyhow::Result<()> {
+        assert!(load_example()?.reserved_nicknames.is_empty());
+        let reserved_nicknames = "reserved_nicknames: [\"NickServ\", \"ChanServ\"]\n";
+        assert_eq!(load_with(reserved_nicknames)?.reserved_nicknames.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn load_related_servers() -> anyhow::Result<()> {
+        assert!(load_example()?.related_servers().is_empty());
+        let related_servers = "related_servers:\n  - name: irc2.example.com\n    \
+                               description: Backup server\n    address: irc2.example.com:6697\n";
+        let related_servers = load_with(related_servers)?.related_servers();
+        assert_eq!(related_servers[0].address, "irc2.example.com:6697");
+        Ok(())
+    }
+
+    #[test]
+    fn load_max_registering_users() -> anyhow::Result<()> {
+        let config = load_example()?;
+        assert_eq!(config.max_registering_users, None);
+        assert_eq!(config.max_registering_users_per_ip, None);
+        let config = load_with("max_registering_users: 1000\nmax_registering_users_per_ip: 10\n")?;
+        assert_eq!(config.max_registering_users, Some(1000));
+        assert_eq!(config.max_registering_users_per_ip, Some(10));
+        Ok(())
+    }
+
+    #[test]
+    fn load_nickname_grace_period() -> anyhow::Result<()> {
+        assert_eq!(load_example()?.nickname_grace_period, None);
+        assert_eq!(
+            load_with("nickname_grace_period: 60\n")?.nickname_grace_period,
+            Some(std::time::Duration::from_secs(60))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_max_channels_per_user() -> anyhow::Result<()> {
+        assert_eq!(load_example()?.max_channels_per_user, None);
+        let max_channels_per_user = load_with("max_channels_per_user: 50\n")?.max_channels_per_user;
+        assert_eq!(max_channels_per_user, Some(50));
+        Ok(())
+    }
+
+    #[test]
+    fn load_throttling() -> anyhow::Result<()> {
+        let throttle_config = load_example()?.throttle_config();
+        assert_eq!((throttle_config.rate, throttle_config.burst), (10, 1));
+        assert_eq!(throttle_config.penalty(b"WHO"), 1);
+        let throttling = "throttling:\n  rate: 10\n  burst: 5\n  penalties:\n    query: 2\n  \
+                          action: delay\n";
+        let throttle_config = load_with(throttling)?.throttle_config();
+        assert_eq!((throttle_config.rate, throttle_config.burst), (10, 5));
+        assert_eq!(throttle_config.penalty(b"WHO"), 2);
+        assert_eq!(throttle_config.penalty(b"PRIVMSG"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn load_unregistered_limits() -> anyhow::Result<()> {
+        let unregistered_limits = load_example()?.unregistered_limits();
+        assert_eq!(unregistered_limits.max_bytes, None);
+        assert_eq!(unregistered_limits.partial_message_deadline, None);
+        let unregistered_limits =
+            load_with("max_unregistered_bytes: 16384\npartial_message_deadline: 10\n")?
+                .unregistered_limits();
+        assert_eq!(unregistered_limits.max_bytes, Some(16384));
+        assert_eq!(
+            unregistered_limits.partial_message_deadline,
+            Some(std::time::Duration::from_secs(10))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_slow_command_threshold() -> anyhow::Result<()> {
+        assert_eq!(load_example()?.slow_command_threshold, None);
+        assert_eq!(
+            load_with("slow_command_threshold: 50\n")?.slow_command_threshold,
+            Some(std::time::Duration::from_millis(50))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_tls_handshake_timeout() -> anyhow::Result<()> {
+        let tls_config = load_example()?.tls_config.unwrap();
+        assert_eq!(tls_config.handshake_timeout, None);
+        let tls = "tls:\n  cert: ./path.cert\n  key: ./path.key\n  handshake_timeout: 10\n";
+        assert_eq!(
+            load_with(tls)?.tls_config.unwrap().handshake_timeout,
+            Some(std::time::Duration::from_secs(10))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_channel_modes() -> anyhow::Result<()> {
+        assert!(load_example()?.channel_mode_rules().is_empty());
+        let channel_modes = "channel_modes:\n  - channels: \"#help-*\"\n    mode: mt\n";
+        let channel_mode_rules = load_with(channel_modes)?.channel_mode_rules();
+        assert_eq!(channel_mode_rules.len(), 1);
+        assert_eq!(channel_mode_rules[0].mask, "#help-*");
+        assert_eq!(
+            channel_mode_rules[0].mode,
+            cirque_core::ChannelMode::try_from("mt").unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_exempt_ips() -> anyhow::Result<()> {
+        assert!(load_example()?.exempt_ips()?.is_empty());
+        let exempt_ips =
+            load_with("exempt_ips: [\"127.0.0.1\", \"10.0.0.0/8\"]\n")?.exempt_ips()?;
+        assert_eq!(exempt_ips.len(), 2);
+        assert!(exempt_ips[1].contains("10.1.2.3".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn load_registration_deadline() -> anyhow::Result<()> {
+        let unregistered_limits = load_example()?.unregistered_limits();
+        assert_eq!(unregistered_limits.registration_deadline, None);
+        let unregistered_limits = load_with("registration_deadline: 60\n")?.unregistered_limits();
+        assert_eq!(
+            unregistered_limits.registration_deadline,
+            Some(std::time::Duration::from_secs(60))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn load_dcc_policy() -> anyhow::Result<()> {
+        assert_eq!(load_example()?.dcc_policy(), cirque_core::DccPolicy::Allow);
+        let dcc_policy = load_with("dcc: co_members\n")?.dcc_policy();
+        assert_eq!(dcc_policy, cirque_core::DccPolicy::CoMembers);
+        Ok(())
+    }
+
+    #[test]
+    fn load_example_catalog() -> anyhow::Result<()> {
+        let mut config = Config::from_raw(&RawConfig::load_from_path(&default_yaml_path()?)?)?;
+        assert!(config.catalog()?.is_none());
+
+        let workspace_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))?;
+        config.catalog_file_path = Some(workspace_path.join("../locales/fr.yml"));
+        let catalog = config.catalog()?.unwrap_or_default();
+        assert_eq!(catalog.language(), "fr");
+        Ok(())
+    }
+
+    #[test]
+    fn load_templates() -> anyhow::Result<()> {
+        let yaml = "server_name: srv\nport: 6667\naddress: 127.0.0.1\ndefault_channel_mode: n\n\
+                    templates:\n  closing_link: 'Bye ({reason})'\n";
+        let config = Config::load_from_str(yaml, &std::env::temp_dir())?;
+        let templates = config.templates();
+        assert_eq!(templates.closing_link, "Bye ({reason})");
+        assert_eq!(templates.welcome, cirque_core::Templates::default().welcome);
+        Ok(())
+    }
+
+    #[test]
+    fn load_hostname_policy() -> anyhow::Result<()> {
+        let base = "server_name: srv\nport: 6667\naddress: 127.0.0.1\ndefault_channel_mode: n\n";
+        let load = |hostnames: &str| {
+            Config::load_from_str(&format!("{base}{hostnames}"), &std::env::temp_dir())
+        };
+        assert_eq!(
+            load("")?.hostname_policy()?,
+            cirque_core::HostnamePolicy::Hidden
+        );
+        assert_eq!(
+            load("hostnames:\n  policy: ip\n")?.hostname_policy()?,
+            cirque_core::HostnamePolicy::Ip
+        );
+        assert_eq!(
+            load("hostnames:\n  policy: cloaked\n  key: abc\n")?.hostname_policy()?,
+            cirque_core::HostnamePolicy::Cloaked {
+                key: "abc".to_string()
+            }
+        );
+        assert!(load("hostnames:\n  policy: cloaked\n  key: ''\n")?
+            .hostname_policy()
+            .is_err());
+
+        assert_eq!(load("")?.lookup_config(), Default::default());
+        let lookup_config = load("lookups:\n  hostname: true\n  timeout: 2\n")?.lookup_config();
+        assert!(lookup_config.resolve_hostname && !lookup_config.check_ident);
+        assert_eq!(lookup_config.timeout, std::time::Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn reload_with_invalid_sections() -> anyhow::Result<()> {
+        let load = |yaml: &str| RawConfig::load_from_str(yaml, &std::env::temp_dir());
+        let previous = load(
+            "server_name: srv\nport: 6667\naddress: 127.0.0.1\ndefault_channel_mode: n\n\
+             motd: hello\nthrottling:\n  rate: 10\n",
+        )?;
+        let reloaded = load(
+            "server_name: new\naddress: 127.0.0.1\ndefault_channel_mode: nq\n\
+             throttling:\n  rate: 0\nexempt_ips: [nonsense]\nutf8_only: true\n",
+        )?;
+
+        let (config, applied, errors) = Config::reload(&reloaded, &previous)?;
+        let mut sections = errors
+            .iter()
+            .map(|e| e.section.as_str())
+            .collect::<Vec<_>>();
+        sections.sort_unstable();
+        assert_eq!(
+            sections,
+            ["default_channel_mode", "exempt_ips", "port", "throttling"]
+        );
+        let throttling = errors.iter().find(|e| e.section == "throttling").unwrap();
+        assert_eq!(
+            format!("{:#}", throttling.error),
+            "throttling.rate: invalid value: integer `0`, expected a nonzero u32"
+        );
+        // the valid changes are applied, including the removed sections
+        assert_eq!(config.server_name, "new");
+        assert!(config.utf8_only);
+        assert_eq!(config.motd, None);
+        // the invalid ones are taken from the previous config
+        assert_eq!(config.port, 6667);
+        assert_eq!(config.throttle_config().rate, 10);
+        assert!(config.exempt_ips.is_empty());
+        assert!(!config.default_channel_mode.is_moderated());
+
+        // the applied config is the base of the next reload
+        let fixed =
+            load("server_name: new\nport: 7000\naddress: 127.0.0.1\ndefault_channel_mode: m\n")?;
+        let (config, _, errors) = Config::reload(&fixed, &applied)?;
+        assert!(errors.is_empty());
+        assert_eq!(config.port, 7000);
+        assert!(config.default_channel_mode.is_moderated());
+
+        // the logger is only installed at startup, so a changed logging section is kept as is
+        let base = "server_name: srv\nport: 6667\naddress: 127.0.0.1\ndefault_channel_mode: n\n";
+        let previous = load(&format!("{base}logging:\n  level: warn\n"))?;
+        let relogged = load(&format!("{base}logging:\n  level: debug\n"))?;
+        let (config, _, errors) = Config::reload(&relogged, &previous)?;
+        let sections = errors
+            .iter()
+            .map(|e| e.section.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sections, ["logging"]);
+        assert_eq!(config.logging.level, Some(log::LevelFilter::Warn));
+        Ok(())
+    }
+
+    #[test]
+    fn load_config_with_includes_and_secrets() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("cirque-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("password"), "hunter2\n")?;
+        std::fs::write(dir.join("motd.yml"), "motd: hello\n")?;
+
+        let base = "server_name: srv\naddress: \"::\"\nport: 6667\n\
+                    default_channel_mode: n\ninclude: [motd.yml]\n";
+        let config = Config::load_from_str(
+            &format!("{base}password: file:{}\n", dir.join("password").display()),
+            &dir,
+        )?;
+        assert_eq!(config.password.as_deref(), Some("hunter2"));
+        assert_eq!(config.motd.as_deref(), Some("hello"));
+
+        let config = Config::load_from_str(&format!("{base}password: literal\n"), &dir)?;
+        assert_eq!(config.password.as_deref(), Some("literal"));
+
+        assert!(Config::load_from_str(
+            &format!("{base}password: env:CIRQUE_TEST_UNSET_VARIABLE\n"),
+            &dir
+        )
+        .is_err());
+        assert!(Config::load_from_str(&format!("{base}motd: twice\n"), &dir).is_err());
 
+        std::fs::remove_dir_all(&dir)?;
         Ok(())
     }
 }
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use pretty_env_logger::env_logger::Target;
+
+use crate::config::{LogFileConfig, LoggingConfig};
+
+/// Installs the logger. It cannot be changed afterwards, so the logging config is not reloaded.
+pub fn init(config: &LoggingConfig) -> anyhow::Result<()> {
+    let mut builder = match &config.file {
+        Some(file_config) => {
+            let mut builder = pretty_env_logger::formatted_timed_builder();
+            builder.target(Target::Pipe(Box::new(RotatingFile::open(file_config)?)));
+            builder
+        }
+        None => pretty_env_logger::formatted_builder(),
+    };
+    builder.filter_level(config.level.unwrap_or(log::LevelFilter::Info));
+    for (module, level) in &config.modules {
+        builder.filter_module(module, *level);
+    }
+    builder.try_init()?;
+    Ok(())
+}
+
+fn current_period(period: Option<Duration>) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    period.map_or(0, |p| now.as_secs() / p.as_secs().max(1))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{index}"));
+    path.into()
+}
+
+/// Log file which is renamed to `<path>.1` when it gets bigger than `max_size` or when a new
+/// `rotate_every` period starts, shifting the older files (`<path>.2`, ...) up to `keep` files.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    period: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFileConfig) -> anyhow::Result<Self> {
+        let file = Self::open_file(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: config.path.clone(),
+            file,
+            size,
+            max_size: config.max_size,
+            rotate_every: config.rotate_every,
+            period: current_period(config.rotate_every),
+            keep: config.keep,
+        })
+    }
+
+    fn open_file(path: &Path) -> anyhow::Result<File> {
+        File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening log file {path:?}"))
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    std::fs::rename(from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = Self::open_file(&self.path).map_err(std::io::Error::other)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let period = current_period(self.rotate_every);
+        let too_big = self
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + buf.len() as u64 > max_size);
+        if too_big || period != self.period {
+            self.period = period;
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)] // fine in tests
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("cirque-logging-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cirque.log");
+        let config = LogFileConfig {
+            path: path.clone(),
+            max_size: Some(10),
+            rotate_every: None,
+            keep: 2,
+        };
+
+        let mut file = RotatingFile::open(&config).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        let read = |index| match index {
+            0 => std::fs::read_to_string(&path).unwrap(),
+            i => std::fs::read_to_string(rotated_path(&path, i)).unwrap(),
+        };
+        assert_eq!(read(0), "fourth\n");
+        assert_eq!(read(1), "third\n");
+        assert_eq!(read(2), "second\n");
+        assert!(!rotated_path(&path, 3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
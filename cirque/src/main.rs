@@ -1,88 +1,271 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::{path::PathBuf, str::FromStr};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use anyhow::Context;
 use tokio::select;
+use tokio::task::JoinSet;
 
-use cirque_core::ServerState;
-use cirque_server::{run_server, ConnectionLimiter};
-use cirque_server::{TCPListener, TLSListener};
+use cirque_core::{MessageHook, ServerState};
+use cirque_server::{run_server, ConnectionLimiter, ShutdownToken};
+use cirque_server::{Listener, TCPListener, TLSListener};
 
+mod cli;
 mod config;
+mod logging;
+mod smoke_test;
 
-fn launch_server(
-    config_path: PathBuf,
+type Server = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Server of the listener, which has its own connection limiter.
+fn serve(
+    listener: impl Listener + Send + Sync + 'static,
     server_state: ServerState,
-) -> anyhow::Result<tokio::task::JoinHandle<()>> {
-    let config = config::Config::load_from_path(&config_path)
-        .with_context(|| format!("loading config file {config_path:?}"))?;
+    shutdown: ShutdownToken,
+    on_ready: impl FnOnce() + Send + 'static,
+) -> Server {
+    Box::pin(async move {
+        let validator = ConnectionLimiter::default();
+        run_server(listener, server_state, validator, shutdown, on_ready).await;
+    })
+}
 
-    server_state.set_server_name(&config.server_name);
+fn launch_server(
+    config: &config::Config,
+    server_state: ServerState,
+    shutdown: &ShutdownToken,
+) -> anyhow::Result<JoinSet<()>> {
     let password = config.password.as_ref().map(|p| p.as_bytes());
-    server_state.set_password(password);
-    let motd = config
-        .motd
+    let oper_name = config.oper.as_ref().and_then(|oper| oper.name.as_deref());
+    let oper_password = config
+        .oper
+        .as_ref()
+        .and_then(|oper| oper.password.as_ref())
+        .map(|p| p.as_bytes());
+    let oper_hosts = config
+        .oper
         .as_ref()
-        .map(|m| m.lines().map(|l| l.as_bytes().to_vec()).collect());
-    server_state.set_motd(motd);
-    server_state.set_default_channel_mode(&config.default_channel_mode);
-    server_state.set_timeout_config(config.timeout_config());
+        .map(|oper| oper.hosts.clone())
+        .unwrap_or_default();
+    let to_lines = |text: &String| {
+        text.lines()
+            .map(|l| l.as_bytes().to_vec())
+            .collect::<Vec<_>>()
+    };
+    let motd = config.motd.as_ref().map(to_lines);
+    let tls_motd = config.tls_motd.as_ref().map(to_lines);
+    let listener_motds = config
+        .listeners
+        .iter()
+        .filter_map(|l| Some((l.name.clone(), to_lines(l.motd.as_ref()?))))
+        .collect::<HashMap<_, _>>();
+    let oper_only_listeners = config
+        .listeners
+        .iter()
+        .filter_map(|l| Some((l.name.clone(), l.oper_deadline?)))
+        .collect::<HashMap<_, _>>();
+    let rules = config.rules()?.as_ref().map(to_lines);
+    let catalog = config.catalog()?.unwrap_or_default();
+    let hostname_policy = config.hostname_policy()?;
+    let exempt_ips = config.exempt_ips()?;
+    let message_hooks = config
+        .content_filters()?
+        .into_iter()
+        .map(|f| -> Arc<dyn MessageHook> { Arc::new(f) })
+        .collect::<Vec<_>>();
+    server_state.update_config(|server_config| {
+        server_config.set_server_name(&config.server_name);
+        server_config.set_password(password);
+        server_config.set_oper_name(oper_name);
+        server_config.set_oper_password(oper_password);
+        server_config.set_oper_hosts(oper_hosts.clone());
+        server_config.set_motd(motd.clone());
+        server_config.set_tls_motd(tls_motd.clone());
+        server_config.set_listener_motds(listener_motds.clone());
+        server_config.set_oper_only_listeners(oper_only_listeners.clone());
+        server_config.set_rules(rules.clone());
+        server_config.set_catalog(catalog.clone());
+        server_config.set_network_name(config.network_name.as_deref());
+        server_config.set_templates(config.templates());
+        server_config.set_compat_flags(config.compat_flags());
+        server_config.set_hostname_policy(hostname_policy.clone());
+        server_config.set_admin_info(config.admin_info());
+        server_config.set_related_servers(config.related_servers());
+        server_config.set_default_channel_mode(&config.default_channel_mode);
+        server_config.set_channel_mode_rules(config.channel_mode_rules());
+        server_config.set_timeout_config(config.timeout_config());
+        server_config.set_sts_policy(config.sts_policy());
+        server_config.set_message_hooks(message_hooks.clone());
+        server_config.set_membership_coalescing(config.membership_coalescing);
+        server_config.set_utf8_only(config.utf8_only);
+        server_config.set_dcc_policy(config.dcc_policy());
+        server_config.set_max_registering_users(
+            config.max_registering_users,
+            config.max_registering_users_per_ip,
+        );
+        server_config.set_exempt_ips(exempt_ips.clone());
+        server_config.set_unregistered_limits(config.unregistered_limits());
+        server_config.set_lookup_config(config.lookup_config());
+        server_config.set_nickname_grace_period(config.nickname_grace_period);
+        server_config.set_max_channels_per_user(config.max_channels_per_user);
+        server_config.set_slow_command_threshold(config.slow_command_threshold);
+        server_config.set_throttle_config(config.throttle_config());
+    });
 
-    log::info!("config loaded");
+    server_state.set_reserved_nicknames(config.reserved_nicknames.clone());
 
-    let connection_limiter = ConnectionLimiter::default();
-    let future = if let Some(tls_config) = config.tls_config {
-        let certs = {
-            let mut file = File::open(&tls_config.cert_file_path).with_context(|| {
-                format!(
-                    "cannot open certificate file {:?}",
-                    &tls_config.cert_file_path
-                )
-            })?;
-            rustls_pemfile::certs(&mut BufReader::new(&mut file)).collect::<Result<Vec<_>, _>>()?
-        };
-
-        let private_key = {
-            let mut file = File::open(&tls_config.private_key_file_path).with_context(|| {
-                format!(
-                    "cannot open private key file {:?}",
-                    &tls_config.private_key_file_path
-                )
-            })?;
-            rustls_pemfile::private_key(&mut BufReader::new(&mut file))?
-                .ok_or_else(|| anyhow::anyhow!("cannot load private key"))?
-        };
-
-        let listener = TLSListener::try_new(&config.address, config.port, certs, private_key)?;
-        tokio::task::spawn(
-            async move { run_server(listener, server_state, connection_limiter).await },
-        )
+    let tls_certificate = config
+        .tls_config
+        .as_ref()
+        .map(config::TlsConfig::load_certificate)
+        .transpose()?;
+
+    let handshake_timeout = config
+        .tls_config
+        .as_ref()
+        .and_then(|tls_config| tls_config.handshake_timeout);
+    let with_handshake_timeout = |listener: TLSListener| match handshake_timeout {
+        Some(handshake_timeout) => listener.with_handshake_timeout(handshake_timeout),
+        None => listener,
+    };
+
+    // the servers are spawned once all the listeners are bound, so that none is left listening
+    // if one of them fails
+    let mut servers: Vec<Server> = vec![];
+    let not_ready = Arc::new(AtomicUsize::new(0));
+    let on_ready = || {
+        let not_ready = not_ready.clone();
+        move || {
+            if not_ready.fetch_sub(1, Ordering::Relaxed) == 1 {
+                log::info!("all the listeners accept connections");
+            }
+        }
+    };
+    if let Some((certs, private_key)) = &tls_certificate {
+        let listener = with_handshake_timeout(TLSListener::try_new(
+            &config.address,
+            config.port,
+            certs.clone(),
+            private_key.clone_key(),
+        )?);
+        servers.push(serve(
+            listener,
+            server_state.clone(),
+            shutdown.clone(),
+            on_ready(),
+        ));
     } else {
         let listener = TCPListener::try_new(&config.address, config.port)?;
-        tokio::task::spawn(
-            async move { run_server(listener, server_state, connection_limiter).await },
-        )
-    };
+        servers.push(serve(
+            listener,
+            server_state.clone(),
+            shutdown.clone(),
+            on_ready(),
+        ));
+    }
 
-    Ok(future)
+    for listener_config in &config.listeners {
+        let server_state = server_state.clone();
+        if listener_config.tls {
+            let Some((certs, private_key)) = &tls_certificate else {
+                anyhow::bail!(
+                    "listener {:?} uses TLS but no certificate is configured",
+                    listener_config.name
+                );
+            };
+            let listener = with_handshake_timeout(TLSListener::try_new(
+                &listener_config.address,
+                listener_config.port,
+                certs.clone(),
+                private_key.clone_key(),
+            )?)
+            .with_name(&listener_config.name)
+            .with_profile((&listener_config.profile).into());
+            servers.push(serve(listener, server_state, shutdown.clone(), on_ready()));
+        } else {
+            let listener = TCPListener::try_new(&listener_config.address, listener_config.port)?
+                .with_name(&listener_config.name)
+                .with_profile((&listener_config.profile).into());
+            servers.push(serve(listener, server_state, shutdown.clone(), on_ready()));
+        }
+    }
+
+    log::info!("config loaded");
+    for line in config.summary() {
+        log::info!("{line}");
+    }
+
+    not_ready.store(servers.len(), Ordering::Relaxed);
+    let mut running = JoinSet::new();
+    for server in servers {
+        running.spawn(server);
+    }
+    Ok(running)
+}
+
+/// Config serving after a reload.
+enum Relaunched {
+    Reloaded,
+    /// the sections in error keep their previous value
+    PartiallyReloaded(Vec<config::SectionError>),
+    KeptPrevious,
+}
+
+/// Reloads the config, applying its valid sections, and relaunches the server. The previous
+/// config is kept if the new one cannot be read or launched.
+fn relaunch_server(
+    config_path: &Path,
+    overrides: &cli::Overrides,
+    applied: &mut config::RawConfig,
+    server_state: &ServerState,
+    shutdown: &ShutdownToken,
+) -> anyhow::Result<(JoinSet<()>, Relaunched)> {
+    let reloaded = config::RawConfig::load_from_path(config_path)
+        .and_then(|raw| config::Config::reload(&raw, applied));
+    match reloaded {
+        Ok((mut config, raw, section_errors)) => {
+            overrides.apply(&mut config);
+            match launch_server(&config, server_state.clone(), shutdown) {
+                Ok(server_handle) => {
+                    *applied = raw;
+                    let relaunched = if section_errors.is_empty() {
+                        Relaunched::Reloaded
+                    } else {
+                        Relaunched::PartiallyReloaded(section_errors)
+                    };
+                    return Ok((server_handle, relaunched));
+                }
+                Err(err) => log::error!("error when relaunching the server: {err:#}"),
+            }
+        }
+        Err(err) => log::error!("error when reloading the config: {err:#}"),
+    }
+
+    log::error!("relaunching the server with the previous config");
+    let mut config = config::Config::from_raw(applied)?;
+    overrides.apply(&mut config);
+    let server_handle = launch_server(&config, server_state.clone(), shutdown)?;
+    Ok((server_handle, Relaunched::KeptPrevious))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    pretty_env_logger::formatted_builder()
-        .filter_level(log::LevelFilter::Info)
-        .try_init()?;
-
     let mut reload_signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
 
-    let Some(config_path) = std::env::args().nth(1) else {
-        anyhow::bail!("missing <config_path> parameter. Usage: cirque <config_path>");
-    };
-    let config_path = PathBuf::from_str(&config_path)?;
+    let cli::Args {
+        config_path,
+        overrides,
+        smoke_test,
+    } = cli::Args::parse(std::env::args().skip(1), |name| std::env::var(name).ok())?;
+
+    let mut applied = config::RawConfig::load_from_path(&config_path)?;
+    let mut config = config::Config::from_raw(&applied)?;
+    overrides.apply(&mut config);
+    logging::init(&config.logging)?;
 
     let server_state = {
-        let config = config::Config::load_from_path(&config_path)?;
         let motd = config
             .motd
             .as_ref()
@@ -97,43 +280,61 @@ async fn main() -> Result<(), anyhow::Error> {
         )
     };
 
-    let mut server_handle = launch_server(config_path.clone(), server_state.clone())?;
+    let mut shutdown = ShutdownToken::new();
+    let mut servers = launch_server(&config, server_state.clone(), &shutdown)?;
+
+    if smoke_test {
+        return smoke_test::run(&config).await;
+    }
 
     loop {
         select! {
             _ = reload_signal.recv() => {
-                server_handle.abort();
-            },
-            result = &mut server_handle => {
-                match result {
-                    Ok(_) => {
-                        unreachable!();
+                // waits for the listeners to be closed, as the new ones may use the same ports;
+                // the connected clients are kept
+                shutdown.cancel();
+                while servers.join_next().await.is_some() {}
+
+                shutdown = ShutdownToken::new();
+                let relaunched =
+                    relaunch_server(&config_path, &overrides, &mut applied, &server_state, &shutdown);
+                match relaunched {
+                    Ok((s, Relaunched::Reloaded)) => {
+                        servers = s;
+                        server_state.metrics().add_reload();
+                        log::info!(
+                            "configuration reloaded, serving for {}s",
+                            server_state.uptime().as_secs()
+                        );
                     },
-                    Err(err) =>{
-                        match err.is_panic() {
-                            true => {
-                                log::error!("panic from the listener");
-                                std::panic::resume_unwind(err.into_panic());
-                            },
-                            false => {
-                                // otherwise, it's just an error due to cancellation of the task
-                                // (when reloading the config)
-                            },
+                    Ok((s, Relaunched::PartiallyReloaded(section_errors))) => {
+                        servers = s;
+                        server_state.metrics().add_reload();
+                        for config::SectionError { section, error } in &section_errors {
+                            log::error!("config section {section} not applied, the previous one is kept: {error:#}");
                         }
+                        log::warn!(
+                            "configuration partially reloaded, serving for {}s",
+                            server_state.uptime().as_secs()
+                        );
                     },
-                }
-
-                match launch_server(config_path.clone(), server_state.clone()) {
-                    Ok(s) => {
-                        server_handle = s;
+                    Ok((s, Relaunched::KeptPrevious)) => {
+                        servers = s;
+                        log::error!("configuration not reloaded, the previous one is kept");
                     },
                     Err(err) => {
                         log::error!("error when relaunching the server: {err:#}");
                         log::error!("fix the config and send SIGHUP again (otherwise new clients cannot connect)");
-                        server_handle = tokio::spawn(std::future::pending());
                     },
                 };
             },
+            // the servers only return when shut down, so this only happens if one of them panics
+            Some(Err(err)) = servers.join_next() => {
+                if err.is_panic() {
+                    log::error!("panic from the listener");
+                    std::panic::resume_unwind(err.into_panic());
+                }
+            },
         }
     }
 }
@@ -0,0 +1,132 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+const DEADLINE: Duration = Duration::from_secs(10);
+const CHANNEL: &str = "#smoke-test";
+
+/// The self-connection does not speak TLS, so it needs a listener without it.
+async fn plain_listener_address(config: &Config) -> anyhow::Result<SocketAddr> {
+    let (address, port) = if config.tls_config.is_none() {
+        (config.address.as_str(), config.port)
+    } else {
+        config
+            .listeners
+            .iter()
+            .find(|l| !l.tls)
+            .map(|l| (l.address.as_str(), l.port))
+            .context("the smoke test needs a listener without TLS")?
+    };
+    let mut addr = tokio::net::lookup_host(format!("{address}:{port}"))
+        .await?
+        .next()
+        .with_context(|| format!("cannot resolve {address}"))?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    Ok(addr)
+}
+
+fn is_error_reply(line: &str) -> bool {
+    let numeric = line.split(' ').nth(1).unwrap_or_default();
+    numeric.len() == 3 && (numeric.starts_with('4') || numeric.starts_with('5'))
+}
+
+struct Client {
+    nickname: &'static str,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(
+        addr: SocketAddr,
+        nickname: &'static str,
+        password: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("connecting to {addr}"))?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            nickname,
+            reader: BufReader::new(reader),
+            writer,
+        };
+        if let Some(password) = password {
+            client.send(&format!("PASS {password}")).await?;
+        }
+        client.send(&format!("NICK {nickname}")).await?;
+        client
+            .send(&format!("USER {nickname} 0 * :{nickname}"))
+            .await?;
+        client.expect(" 001 ").await?;
+        Ok(client)
+    }
+
+    async fn send(&mut self, line: &str) -> anyhow::Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\r\n").await?;
+        Ok(())
+    }
+
+    /// Reads lines until one contains `pattern`, answering the PINGs in the meantime and failing
+    /// on error replies.
+    async fn expect(&mut self, pattern: &str) -> anyhow::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line).await? == 0 {
+                anyhow::bail!(
+                    "{}: connection closed while waiting for {pattern:?}",
+                    self.nickname
+                );
+            }
+            log::debug!("{} received: {}", self.nickname, line.trim_end());
+            if let Some(token) = line.strip_prefix("PING ") {
+                self.send(&format!("PONG {}", token.trim_end())).await?;
+            } else if line.contains(pattern) {
+                return Ok(());
+            } else if line.starts_with("ERROR ") || is_error_reply(&line) {
+                anyhow::bail!("{}: {}", self.nickname, line.trim_end());
+            }
+        }
+    }
+}
+
+async fn exercise(addr: SocketAddr, password: Option<&str>) -> anyhow::Result<()> {
+    let mut client = Client::connect(addr, "smoketest", password).await?;
+
+    client.send(&format!("JOIN {CHANNEL}")).await?;
+    client.expect(" 366 ").await?;
+
+    // the channel message is not echoed, so the client then messages itself to check that the
+    // channel message was accepted (the replies come in order)
+    client.send(&format!("PRIVMSG {CHANNEL} :ping")).await?;
+    client.send("PRIVMSG smoketest :pong").await?;
+    client.expect("PRIVMSG smoketest :pong").await?;
+
+    client.send("QUIT").await?;
+    Ok(())
+}
+
+/// Connects a client to the running server, which registers, joins a channel and sends
+/// messages. A single client is used since the connections are rate limited per IP.
+pub async fn run(config: &Config) -> anyhow::Result<()> {
+    let addr = plain_listener_address(config).await?;
+    log::info!("smoke test: connecting to {addr}");
+    tokio::time::timeout(DEADLINE, exercise(addr, config.password.as_deref()))
+        .await
+        .context("smoke test timed out")??;
+    log::info!("smoke test: passed");
+    Ok(())
+}
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    cirque_parser::parse_bytes_in_chunks_for_fuzzing(data);
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use cirque_core::{ServerState, WelcomeConfig};
+
+fuzz_target!(|data: &[u8]| {
+    let welcome_config = WelcomeConfig::default();
+    let server_state = ServerState::new("srv", &welcome_config, None, None, None);
+
+    // a second user, so that the commands can interact with someone
+    let (mut other, mut other_rx) = server_state.new_registering_user(Default::default());
+    other = server_state.drive_raw_line(other, b"NICK other");
+    other = server_state.drive_raw_line(other, b"USER other 0 * :other");
+    other = server_state.drive_raw_line(other, b"JOIN #chan");
+
+    // each line of the input is sent by the fuzzed user, which starts unregistered
+    let (mut state, mut rx) = server_state.new_registering_user(Default::default());
+    for line in data.split(|&c| c == b'\n') {
+        state = server_state.drive_raw_line(state, line);
+        while rx.try_recv().is_ok() {}
+        while other_rx.try_recv().is_ok() {}
+    }
+
+    server_state.dispose_state(state);
+    server_state.dispose_state(other);
+});
@@ -1,7 +1,7 @@
-use clap::Parser;
+use clap::{ArgAction, Parser};
 
-use cirque_core::{ServerState, WelcomeConfig};
-use cirque_server::{AcceptAll, TCPListener};
+use cirque_core::{CompatFlags, ServerState, ThrottleConfig, WelcomeConfig};
+use cirque_server::{AcceptAll, ShutdownToken, TCPListener};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -11,6 +11,23 @@ struct Args {
 
     #[arg(short, long)]
     oper_password: Option<String>,
+
+    /// Send RPL_LISTSTART (321) before the replies to LIST.
+    #[arg(long, default_value_t = false, action = ArgAction::Set)]
+    list_start: bool,
+
+    /// Send RPL_TOPICWHOTIME (333) after RPL_TOPIC, which chirc does not expect.
+    #[arg(long, default_value_t = false, action = ArgAction::Set)]
+    topic_who_time: bool,
+
+    /// Send RPL_LOCALUSERS and RPL_GLOBALUSERS (265, 266) in reply to LUSERS, which chirc does
+    /// not expect.
+    #[arg(long, default_value_t = false, action = ArgAction::Set)]
+    extended_lusers: bool,
+
+    /// Answer MAP, USERS and SUMMON instead of ERR_UNKNOWNCOMMAND (421).
+    #[arg(long, default_value_t = false, action = ArgAction::Set)]
+    unsupported_commands: bool,
 }
 
 #[tokio::main]
@@ -21,10 +38,26 @@ async fn main() -> anyhow::Result<()> {
     let server_name = "srv";
     let welcome_config = WelcomeConfig {
         send_isupport: false,
+        send_your_id: false,
+        compat: CompatFlags {
+            list_start: args.list_start,
+            topic_who_time: args.topic_who_time,
+            extended_lusers: args.extended_lusers,
+            unsupported_commands: args.unsupported_commands,
+        },
     };
     let motd = None;
 
     let server_state = ServerState::new(server_name, &welcome_config, motd, None, None);
-    server_state.set_messages_per_second_limit(100);
-    cirque_server::run_server(listener, server_state, AcceptAll {}).await
+    server_state.update_config(|server_config| {
+        server_config.set_throttle_config(ThrottleConfig {
+            rate: 100,
+            ..Default::default()
+        });
+        server_config.set_oper_password(args.oper_password.as_deref().map(str::as_bytes));
+    });
+    // runs until the process is killed
+    let shutdown = ShutdownToken::new();
+    cirque_server::run_server(listener, server_state, AcceptAll {}, shutdown, || {}).await;
+    Ok(())
 }
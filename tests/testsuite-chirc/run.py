@@ -34,6 +34,7 @@ if __name__ == "__main__":
         "LIST_VOICE",
         "MODES_TOPIC",
         "BASIC_CHANNEL_OPERATOR",
+        "OPER",
 
         # disabled:
         # "ERR_UNKNOWN",
@@ -45,9 +46,11 @@ if __name__ == "__main__":
         # "MOTD",
         #   because it assumes that the server re-reads the motd file on each /MOTD
         # "CHANNEL_TOPIC",
-        #   because it doesn't accept RPL_TOPICWHOTIME 333
+        #   because it doesn't accept RPL_TOPICWHOTIME 333 (now off by default in chirc-compat,
+        #   see --topic-who-time)
         # "LUSERS",
-        #   because it expects a different string
+        #   because it expects a different string (RPL_LOCALUSERS and RPL_GLOBALUSERS 265/266 are
+        #   now off by default in chirc-compat, see --extended-lusers)
         # "WHO", "WHOIS*", "MODES", "BASIC_MODE"
         #   because tests need OP
         #   or assume some specific string formatting
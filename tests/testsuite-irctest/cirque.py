@@ -20,6 +20,7 @@ class CirqueController(BaseServerController, DirectoryBasedController):
         config: Optional[Any] = None,
     ) -> None:
         args = ["-p", str(port), "--server-name", "My.Little.Server"]
+        args += ["--oper-password", "operpassword"]
         if password:
             args += ["--password", password]
         bin = os.path.join(os.getcwd(), "target/debug/irctest-compat")
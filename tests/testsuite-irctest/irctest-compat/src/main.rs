@@ -1,7 +1,11 @@
-use clap::Parser;
+use std::time::Duration;
 
-use cirque_core::{ServerState, WelcomeConfig};
-use cirque_server::{AcceptAll, TCPListener};
+use clap::{ArgAction, Parser};
+
+use cirque_core::{
+    ChannelMode, CompatFlags, ServerState, ThrottleConfig, TimeoutConfig, WelcomeConfig,
+};
+use cirque_server::{AcceptAll, ShutdownToken, TCPListener};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -14,6 +18,49 @@ struct Args {
 
     #[arg(long)]
     password: Option<String>,
+
+    #[arg(long)]
+    oper_password: Option<String>,
+
+    /// Ping timeout in seconds, enables the ping timeouts if set.
+    #[arg(long)]
+    base_timeout: Option<u64>,
+
+    /// Ping timeout in seconds after an important message was sent to the client.
+    /// Defaults to the base timeout.
+    #[arg(long, requires = "base_timeout")]
+    reduced_timeout: Option<u64>,
+
+    #[arg(long, default_value_t = 100)]
+    messages_per_second_limit: u32,
+
+    /// Mode of the newly created channels, for example "+nt".
+    #[arg(long)]
+    default_channel_mode: Option<String>,
+
+    /// Don't send RPL_ISUPPORT on registration.
+    #[arg(long)]
+    no_isupport: bool,
+
+    /// Send RPL_LISTSTART (321) before the replies to LIST.
+    #[arg(long, default_value_t = CompatFlags::default().list_start, action = ArgAction::Set)]
+    list_start: bool,
+
+    /// Send RPL_TOPICWHOTIME (333) after RPL_TOPIC.
+    #[arg(long, default_value_t = CompatFlags::default().topic_who_time, action = ArgAction::Set)]
+    topic_who_time: bool,
+
+    /// Send RPL_LOCALUSERS and RPL_GLOBALUSERS (265, 266) in reply to LUSERS.
+    #[arg(long, default_value_t = CompatFlags::default().extended_lusers, action = ArgAction::Set)]
+    extended_lusers: bool,
+
+    /// Answer MAP, USERS and SUMMON instead of ERR_UNKNOWNCOMMAND (421).
+    #[arg(
+        long,
+        default_value_t = CompatFlags::default().unsupported_commands,
+        action = ArgAction::Set
+    )]
+    unsupported_commands: bool,
 }
 
 #[tokio::main]
@@ -23,12 +70,43 @@ async fn main() -> anyhow::Result<()> {
 
     let server_name = &args.server_name;
     let welcome_config = WelcomeConfig {
-        send_isupport: true,
+        send_isupport: !args.no_isupport,
+        send_your_id: true,
+        compat: CompatFlags {
+            list_start: args.list_start,
+            topic_who_time: args.topic_who_time,
+            extended_lusers: args.extended_lusers,
+            unsupported_commands: args.unsupported_commands,
+        },
     };
     let motd = None;
     let password = args.password.map(|p| p.as_bytes().into());
+    let timeout_config = args.base_timeout.map(|base| TimeoutConfig {
+        base_timeout: Duration::from_secs(base),
+        reduced_timeout: Duration::from_secs(args.reduced_timeout.unwrap_or(base)),
+        ..Default::default()
+    });
+    let default_channel_mode = args
+        .default_channel_mode
+        .as_deref()
+        .map(ChannelMode::try_from)
+        .transpose()
+        .map_err(anyhow::Error::msg)?;
 
-    let server_state = ServerState::new(server_name, &welcome_config, motd, password, None);
-    server_state.set_messages_per_second_limit(100);
-    cirque_server::run_server(listener, server_state, AcceptAll {}).await
+    let server_state =
+        ServerState::new(server_name, &welcome_config, motd, password, timeout_config);
+    server_state.update_config(|server_config| {
+        server_config.set_throttle_config(ThrottleConfig {
+            rate: args.messages_per_second_limit,
+            ..Default::default()
+        });
+        server_config.set_oper_password(args.oper_password.as_deref().map(str::as_bytes));
+        if let Some(mode) = &default_channel_mode {
+            server_config.set_default_channel_mode(mode);
+        }
+    });
+    // runs until the process is killed
+    let shutdown = ShutdownToken::new();
+    cirque_server::run_server(listener, server_state, AcceptAll {}, shutdown, || {}).await;
+    Ok(())
 }